@@ -1,28 +1,172 @@
 //! The p2ptun's daemon. It is responsible for the most of the program's functionality.
 
 pub mod actors;
+pub mod admission;
+pub mod advertisements;
+pub mod control;
+pub mod discovery;
+pub mod events;
+pub mod firewall;
+pub mod forward;
+pub mod gossip;
+pub mod handle;
+pub mod hooks;
+pub mod invite;
+pub mod mdns;
+pub mod netlink;
 pub mod packet;
+pub mod pairing;
+pub mod pipeline;
+pub mod proxy;
+pub mod revocation;
+pub mod rotation;
+pub mod shutdown;
+pub mod state;
+pub mod store;
+pub mod supervisor;
+pub mod wasm_filter;
 
-use iroh_net::key::SecretKey;
-use tokio::{select, task::JoinSet};
+use std::{net::IpAddr, path::PathBuf, sync::Arc};
 
-use crate::daemon::actors::{
-    packet_logger::PacketLogger, packet_router::PacketRouter, peer_collection::PeerCollection,
-    peer_source::PeerSource, tun::Tun, Actor,
+use ipnet::IpNet;
+
+use iroh_net::{key::SecretKey, NodeId};
+use tokio::{net::TcpListener, sync::Mutex};
+use tracing::{info, warn};
+
+use crate::daemon::{
+    actors::{
+        exit_node::{ExitNode, ExitPolicy},
+        mesh_router::{MeshRouter, MeshRouterMessage},
+        netstack::{NetStack, NetStackConfig, NetStackMessage},
+        packet_logger::PacketLogger,
+        packet_router::PacketRouter,
+        peer_collection::{FailbackPolicy, PeerCollection},
+        peer_source::PeerSource,
+        route_manager::{RouteManager, RouteManagerMessage},
+        subnet_router::{RoutePolicy, SubnetRouter, SubnetRouterMessage},
+        tun::{Tun, TunConfig, TunSetting},
+        Actor, Addr, AskError,
+    },
+    admission::AdmissionPolicy,
+    advertisements::{Advertisement, Advertisements},
+    control::ControlServer,
+    discovery::{Discovery, DiscoveryConfig},
+    events::{Event, Events},
+    firewall::Firewall,
+    forward::{ForwardPolicy, Forwarding},
+    gossip::{Gossip, GossipConfig},
+    handle::DaemonHandle,
+    hooks::{HookConfig, Hooks},
+    invite::{InviteConfig, InviteError, Invites},
+    mdns::{Mdns, MdnsConfig},
+    pairing::{PairingConfig, PairingError},
+    pipeline::{Direction, PacketProcessor},
+    proxy::ProxyKind,
+    revocation::Revocations,
+    rotation::{RotationConfig, Rotations},
+    shutdown::{Shutdown, SHUTDOWN_DEADLINE},
+    state::{NetworkState, Record, RecordKey, StateConfig},
+    supervisor::{ExitReason, RestartPolicy, Supervisor},
+    wasm_filter::WasmFilter,
 };
 
 /// The p2ptun's daemon configuration
 #[derive(Default)]
 pub struct DaemonConfig {
+    /// The secret key of this node. A new one is generated when not set.
+    pub secret_key: Option<SecretKey>,
     pub enable_tun: bool,
+    /// Settings of the TUN device, used when [DaemonConfig::enable_tun] is set.
+    pub tun: TunConfig,
+    /// Settings of the userspace network stack, used instead of the TUN device when set.
+    ///
+    /// It can't be used together with [DaemonConfig::enable_tun].
+    pub netstack: Option<NetStackConfig>,
+    /// Path to the firewall rules file. The firewall is disabled when not set.
+    ///
+    /// The rules can be reloaded from the file with [DaemonHandle::reload].
+    pub firewall_rules: Option<PathBuf>,
+    /// Paths to WebAssembly filter modules, run after the firewall.
+    ///
+    /// The modules can be reloaded from the files with [DaemonHandle::reload].
+    pub wasm_filters: Vec<PathBuf>,
+    /// Additional packet processing stages, run after the built-in ones.
+    pub stages: Vec<Box<dyn PacketProcessor>>,
+    /// Names of stages run on incoming packets, in order. All stages are run when not set.
+    pub incoming_stage_order: Option<Vec<String>>,
+    /// Names of stages run on outgoing packets, in order. All stages are run when not set.
+    pub outgoing_stage_order: Option<Vec<String>>,
+    /// Path of the control socket. The control socket is disabled when not set.
+    pub control_socket: Option<PathBuf>,
+    /// Executables run when peers come and go, and when the daemon starts and stops.
+    pub hooks: HookConfig,
+    /// The targets peers may reach through port forwards. Nothing is allowed by default.
+    pub forward_policy: ForwardPolicy,
+    /// Whether the peers can reach the internet through this node.
+    pub exit_node: bool,
+    /// The peers allowed to reach the internet through this node. Nobody is allowed by default.
+    pub exit_policy: ExitPolicy,
+    /// The prefixes of the local networks the peers can reach through this node.
+    ///
+    /// The node forwards the traffic between the TUN device and the networks through the kernel.
+    pub advertised_routes: Vec<IpNet>,
+    /// The priority of this node among the peers advertising the same routes.
+    /// The highest one is preferred.
+    pub route_priority: u32,
+    /// The prefixes advertised by peers that are routed through them. Nothing is accepted by default.
+    pub route_policy: RoutePolicy,
+    /// When the traffic to a prefix moves back to a preferred gateway after a failover.
+    pub failback: FailbackPolicy,
+    /// Whether routes are exchanged with the peers, so the traffic between peers that can't
+    /// connect to each other directly goes through the peers they are both connected to.
+    /// It needs the replicated state, which assigns the addresses of the nodes.
+    pub mesh: bool,
+    /// The members of the network. Connections with other nodes are closed.
+    pub admission: AdmissionPolicy,
+    /// Settings of the gossip of the addresses of the members.
+    pub gossip: GossipConfig,
+    /// Settings of the discovery of the nodes on the local network.
+    pub mdns: MdnsConfig,
+    /// Settings of the state replicated between the nodes. It isn't kept when not set.
+    pub state: Option<StateConfig>,
+    /// Settings of the invites to join the network.
+    pub invites: InviteConfig,
+    /// Settings of the pairing with a short code.
+    pub pairing: PairingConfig,
+    /// Settings of the rotations of node keys.
+    pub rotations: RotationConfig,
+    /// Settings of the discovery of the addresses of nodes by their IDs.
+    pub discovery: DiscoveryConfig,
 }
 
 /// Enum representing errors that can happen in p2ptun's daemon
 #[derive(Debug)]
 pub enum DaemonError {
     TunError(tun::Error),
+    TunSettingError(TunSetting, tun::Error),
+    TunDisabled,
+    MeshDisabled,
+    NotAnAdmin,
+    StateDisabled,
+    NoKeyFile,
+    KeyReplaced,
+    Unauthorized(RecordKey),
+    InvalidInvite(InviteError),
+    PairingError(PairingError),
+    TunAndNetStack,
+    NotAnExitNode(NodeId),
+    NotDiscovered(NodeId),
+    NetlinkError(rtnetlink::Error),
     AnyhowError(anyhow::Error),
-    Died,
+    IoError(std::io::Error),
+    TomlError(toml::de::Error),
+    UnknownStage(String),
+    WasmError(wasmi::Error),
+    MissingWasmExport(&'static str),
+    WasmBufferOutOfBounds(usize),
+    AskError(AskError),
+    ActorDied(String, ExitReason),
 }
 
 impl From<tun::Error> for DaemonError {
@@ -31,49 +175,842 @@ impl From<tun::Error> for DaemonError {
     }
 }
 
+impl From<rtnetlink::Error> for DaemonError {
+    fn from(error: rtnetlink::Error) -> Self {
+        Self::NetlinkError(error)
+    }
+}
+
 impl From<anyhow::Error> for DaemonError {
     fn from(error: anyhow::Error) -> Self {
         Self::AnyhowError(error)
     }
 }
 
-/// The p2ptun's daemon
-pub async fn run_daemon(config: DaemonConfig) -> Result<(), DaemonError> {
-    // Create the secret key
-    let secret_key = SecretKey::generate();
-    println!("Node ID: {}", secret_key.public());
-
-    // Initialize actors
-    let mut packet_router = PacketRouter::new();
-    let packet_logger = PacketLogger::new();
-    let peer_collection = PeerCollection::new(packet_router.get_addr());
-    let peer_source = PeerSource::new(&peer_collection, secret_key).await?;
-    println!("Node ticket: {}", peer_source.node_ticket().await?);
-    let tun = if config.enable_tun {
-        let tun = Tun::new(packet_router.get_addr())?;
-        packet_router.add_incoming_packet_receiver(tun.get_addr());
-        Some(tun)
-    } else {
-        None
-    };
-    packet_router.add_incoming_packet_receiver(packet_logger.get_addr());
-    packet_router.add_outgoing_packet_receiver(packet_logger.get_addr());
-    packet_router.add_outgoing_packet_receiver(peer_collection.get_addr());
-
-    // Run
-    let mut join_set = JoinSet::new();
-    join_set.spawn(packet_logger.run());
-    join_set.spawn(packet_router.run());
-    join_set.spawn(peer_collection.run());
-    join_set.spawn(peer_source.run());
-    if let Some(tun) = tun {
-        join_set.spawn(tun.run());
-    }
-    select! {
-        _ = join_set.join_next() => {Err(DaemonError::Died)}
-        _ = tokio::signal::ctrl_c() => {
-            println!("\nStopping...");
-            Ok(())
+impl From<PairingError> for DaemonError {
+    fn from(error: PairingError) -> Self {
+        Self::PairingError(error)
+    }
+}
+
+impl From<std::io::Error> for DaemonError {
+    fn from(error: std::io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+impl From<toml::de::Error> for DaemonError {
+    fn from(error: toml::de::Error) -> Self {
+        Self::TomlError(error)
+    }
+}
+
+impl From<wasmi::Error> for DaemonError {
+    fn from(error: wasmi::Error) -> Self {
+        Self::WasmError(error)
+    }
+}
+
+impl From<AskError> for DaemonError {
+    fn from(error: AskError) -> Self {
+        Self::AskError(error)
+    }
+}
+
+/// Builds and starts the p2ptun's daemon.
+#[derive(Default)]
+pub struct DaemonBuilder {
+    config: DaemonConfig,
+}
+
+impl DaemonBuilder {
+    /// Creates a builder with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the whole configuration.
+    pub fn config(mut self, config: DaemonConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Sets the secret key of this node.
+    pub fn secret_key(mut self, secret_key: SecretKey) -> Self {
+        self.config.secret_key = Some(secret_key);
+        self
+    }
+
+    /// Sets whether a TUN device is created.
+    pub fn enable_tun(mut self, enable_tun: bool) -> Self {
+        self.config.enable_tun = enable_tun;
+        self
+    }
+
+    /// Sets the settings of the TUN device.
+    pub fn tun(mut self, tun: TunConfig) -> Self {
+        self.config.tun = tun;
+        self
+    }
+
+    /// Uses a userspace network stack instead of the TUN device.
+    pub fn netstack(mut self, netstack: NetStackConfig) -> Self {
+        self.config.netstack = Some(netstack);
+        self
+    }
+
+    /// Enables the firewall with the rules from the file at `path`.
+    pub fn firewall_rules(mut self, path: PathBuf) -> Self {
+        self.config.firewall_rules = Some(path);
+        self
+    }
+
+    /// Adds a WebAssembly filter module from the file at `path`.
+    pub fn wasm_filter(mut self, path: PathBuf) -> Self {
+        self.config.wasm_filters.push(path);
+        self
+    }
+
+    /// Adds a packet processing stage.
+    pub fn stage(mut self, stage: Box<dyn PacketProcessor>) -> Self {
+        self.config.stages.push(stage);
+        self
+    }
+
+    /// Sets the names of stages run on packets going in `direction`, in order.
+    pub fn stage_order(mut self, direction: Direction, names: Vec<String>) -> Self {
+        match direction {
+            Direction::Incoming => self.config.incoming_stage_order = Some(names),
+            Direction::Outgoing => self.config.outgoing_stage_order = Some(names),
         }
+        self
+    }
+
+    /// Enables the control socket at `path`.
+    pub fn control_socket(mut self, path: PathBuf) -> Self {
+        self.config.control_socket = Some(path);
+        self
+    }
+
+    /// Sets the hook executables.
+    pub fn hooks(mut self, hooks: HookConfig) -> Self {
+        self.config.hooks = hooks;
+        self
+    }
+
+    /// Sets the targets peers may reach through port forwards.
+    pub fn forward_policy(mut self, forward_policy: ForwardPolicy) -> Self {
+        self.config.forward_policy = forward_policy;
+        self
+    }
+
+    /// Sets whether the peers can reach the internet through this node.
+    pub fn exit_node(mut self, exit_node: bool) -> Self {
+        self.config.exit_node = exit_node;
+        self
+    }
+
+    /// Sets the peers allowed to reach the internet through this node.
+    pub fn exit_policy(mut self, exit_policy: ExitPolicy) -> Self {
+        self.config.exit_policy = exit_policy;
+        self
+    }
+
+    /// Advertises to the peers that they can reach `prefix` through this node.
+    pub fn advertise_route(mut self, prefix: IpNet) -> Self {
+        self.config.advertised_routes.push(prefix);
+        self
+    }
+
+    /// Sets the priority of this node among the peers advertising the same routes.
+    pub fn route_priority(mut self, route_priority: u32) -> Self {
+        self.config.route_priority = route_priority;
+        self
+    }
+
+    /// Sets when the traffic to a prefix moves back to a preferred gateway after a failover.
+    pub fn failback(mut self, failback: FailbackPolicy) -> Self {
+        self.config.failback = failback;
+        self
+    }
+
+    /// Sets whether routes are exchanged with the peers, so the traffic between peers that can't
+    /// connect to each other directly goes through the peers they are both connected to.
+    ///
+    /// It needs the replicated state, see [DaemonBuilder::state].
+    pub fn mesh(mut self, mesh: bool) -> Self {
+        self.config.mesh = mesh;
+        self
+    }
+
+    /// Sets the members of the network.
+    pub fn admission(mut self, admission: AdmissionPolicy) -> Self {
+        self.config.admission = admission;
+        self
+    }
+
+    /// Sets the settings of the gossip of the addresses of the members.
+    pub fn gossip(mut self, gossip: GossipConfig) -> Self {
+        self.config.gossip = gossip;
+        self
     }
+
+    /// Sets the settings of the discovery of the nodes on the local network.
+    pub fn mdns(mut self, mdns: MdnsConfig) -> Self {
+        self.config.mdns = mdns;
+        self
+    }
+
+    /// Keeps the state replicated between the nodes, with the given settings.
+    pub fn state(mut self, state: StateConfig) -> Self {
+        self.config.state = Some(state);
+        self
+    }
+
+    /// Sets the settings of the invites to join the network.
+    pub fn invites(mut self, invites: InviteConfig) -> Self {
+        self.config.invites = invites;
+        self
+    }
+
+    /// Sets the settings of the pairing with a short code.
+    pub fn pairing(mut self, pairing: PairingConfig) -> Self {
+        self.config.pairing = pairing;
+        self
+    }
+
+    /// Sets the settings of the rotations of node keys.
+    pub fn rotations(mut self, rotations: RotationConfig) -> Self {
+        self.config.rotations = rotations;
+        self
+    }
+
+    /// Sets the settings of the discovery of the addresses of nodes by their IDs.
+    pub fn discovery(mut self, discovery: DiscoveryConfig) -> Self {
+        self.config.discovery = discovery;
+        self
+    }
+
+    /// Sets the prefixes advertised by peers that are routed through them.
+    pub fn route_policy(mut self, route_policy: RoutePolicy) -> Self {
+        self.config.route_policy = route_policy;
+        self
+    }
+
+    /// Starts the daemon in the background.
+    ///
+    /// The daemon runs until [DaemonHandle::shutdown] is called or one of its actors dies.
+    pub async fn start(self) -> Result<DaemonHandle, DaemonError> {
+        let config = self.config;
+        let overlay = overlay_prefixes(&config);
+        let addresses = own_addresses(&config);
+        // Traffic to these prefixes is neither routed through peers nor sent to the internet
+        let local: Vec<IpNet> = overlay
+            .iter()
+            .chain(&config.advertised_routes)
+            .copied()
+            .collect();
+        let secret_key = config.secret_key.unwrap_or_else(SecretKey::generate);
+        let node_id = secret_key.public();
+        let events = Events::new();
+        let shutdown = Shutdown::new();
+        if config.enable_tun && config.netstack.is_some() {
+            return Err(DaemonError::TunAndNetStack);
+        }
+        if config.mesh && config.state.is_none() {
+            return Err(DaemonError::StateDisabled);
+        }
+        let control_server = match config.control_socket {
+            Some(path) => Some(ControlServer::bind(path)?),
+            None => None,
+        };
+
+        // Initialize actors
+        let mut packet_router = PacketRouter::new();
+        let packet_logger = PacketLogger::new();
+        let peer_collection = PeerCollection::new(
+            packet_router.get_addr(),
+            overlay.clone(),
+            config.failback,
+            events.clone(),
+        );
+        let forwarding = Forwarding::new(config.forward_policy, shutdown.listener());
+        let state = match config.state {
+            Some(state) => Some(NetworkState::new(
+                state,
+                secret_key.clone(),
+                events.clone(),
+            )?),
+            None => None,
+        };
+        if let Some(state) = &state {
+            publish_own(state, node_id, &addresses, &config.advertised_routes)?;
+        }
+        let invites = Invites::new(
+            config.invites,
+            secret_key.clone(),
+            state.clone(),
+            events.clone(),
+        )?;
+        let advertisements = Advertisements::new(
+            Advertisement {
+                version: 0,
+                exit_node: config.exit_node,
+                routes: config.advertised_routes.clone(),
+                route_priority: config.route_priority,
+                addresses: addresses.clone(),
+                mesh_routes: Vec::new(),
+                members: Vec::new(),
+            },
+            state.clone(),
+            events.clone(),
+        );
+        let revocations = Revocations::new(state.clone());
+        let rotations = Rotations::new(
+            config.rotations,
+            secret_key.clone(),
+            invites.clone(),
+            state.clone(),
+        );
+        let discovery = if config.discovery.is_enabled() {
+            Some(Discovery::new(config.discovery, secret_key.clone())?)
+        } else {
+            None
+        };
+        let peer_source = PeerSource::new(
+            &peer_collection,
+            secret_key.clone(),
+            events.clone(),
+            forwarding.clone(),
+            advertisements.clone(),
+            config.admission.clone(),
+            invites.clone(),
+            revocations.clone(),
+            rotations.clone(),
+            discovery.clone(),
+        )
+        .await?
+        .packet_buffer_size(actors::tun::buffer_size(config.tun.mtu));
+        let start_mdns = {
+            let (config, admission, invites, rotations, advertisements, peer_source, events) = (
+                config.mdns.clone(),
+                config.admission.clone(),
+                invites.clone(),
+                rotations.clone(),
+                advertisements.clone(),
+                peer_source.get_addr(),
+                events.clone(),
+            );
+            move || {
+                Mdns::new(
+                    config.clone(),
+                    node_id,
+                    admission.clone(),
+                    invites.clone(),
+                    rotations.clone(),
+                    advertisements.clone(),
+                    peer_source.clone(),
+                    &events,
+                )
+            }
+        };
+        let mdns = if config.mdns.enabled {
+            Some(start_mdns()?)
+        } else {
+            None
+        };
+        let start_gossip = {
+            let (config, admission, rotations, advertisements, peer_source, events) = (
+                config.gossip.clone(),
+                config.admission.clone(),
+                rotations.clone(),
+                advertisements.clone(),
+                peer_source.get_addr(),
+                events.clone(),
+            );
+            move || {
+                Gossip::new(
+                    config.clone(),
+                    secret_key.clone(),
+                    admission.clone(),
+                    rotations.clone(),
+                    advertisements.clone(),
+                    peer_source.clone(),
+                    &events,
+                )
+            }
+        };
+        let gossip = config.gossip.enabled.then(&start_gossip);
+        let routes = config.tun.routes.clone();
+        let tun = if config.enable_tun {
+            let tun = Tun::new(packet_router.get_addr(), config.tun.clone()).await?;
+            packet_router.add_incoming_packet_receiver(tun.get_addr());
+            Some(tun)
+        } else {
+            None
+        };
+        let mut proxies = Vec::new();
+        let netstack = match &config.netstack {
+            Some(netstack_config) => {
+                let netstack = NetStack::new(packet_router.get_addr(), netstack_config)?;
+                packet_router.add_incoming_packet_receiver(netstack.get_addr());
+                if let Some(address) = netstack_config.socks_proxy {
+                    proxies.push((TcpListener::bind(address).await?, ProxyKind::Socks5));
+                }
+                if let Some(address) = netstack_config.http_proxy {
+                    proxies.push((TcpListener::bind(address).await?, ProxyKind::Http));
+                }
+                Some(netstack)
+            }
+            None => None,
+        };
+        let exit_node_overlay = local.clone();
+        let exit_node = if config.exit_node {
+            let exit_node = ExitNode::new(
+                packet_router.get_addr(),
+                exit_node_overlay.clone(),
+                config.exit_policy.clone(),
+                state.clone(),
+            );
+            packet_router.set_exit_receiver(exit_node.get_addr(), exit_node_overlay.clone());
+            Some(exit_node)
+        } else {
+            None
+        };
+        let route_manager = match &tun {
+            Some(tun) => Some(RouteManager::new(&tun.name()?, &routes, events.clone()).await?),
+            None => None,
+        };
+        let subnet_router = SubnetRouter::new(
+            config.route_policy,
+            local,
+            peer_collection.get_addr(),
+            route_manager.as_ref().map(Actor::get_addr),
+            advertisements.clone(),
+            events.clone(),
+        );
+        let mesh_router = state.as_ref().filter(|_| config.mesh).map(|state| {
+            MeshRouter::new(
+                node_id,
+                peer_collection.get_addr(),
+                advertisements.clone(),
+                state.clone(),
+                &events,
+            )
+        });
+        let firewall_handle = match config.firewall_rules {
+            Some(path) => {
+                let firewall = Firewall::new(
+                    path,
+                    events.clone(),
+                    Some(invites.clone()),
+                    Some(rotations.clone()),
+                )?
+                .route_table(peer_collection.route_table());
+                let handle = firewall.handle();
+                packet_router.add_stage(Box::new(firewall));
+                Some(handle)
+            }
+            None => None,
+        };
+        let mut wasm_filter_handles = Vec::new();
+        for path in config.wasm_filters {
+            let wasm_filter = WasmFilter::load(path)?;
+            wasm_filter_handles.push(wasm_filter.handle());
+            packet_router.add_stage(Box::new(wasm_filter));
+        }
+        for stage in config.stages {
+            packet_router.add_stage(stage);
+        }
+        if let Some(order) = config.incoming_stage_order {
+            packet_router.set_stage_order(Direction::Incoming, &order)?;
+        }
+        if let Some(order) = config.outgoing_stage_order {
+            packet_router.set_stage_order(Direction::Outgoing, &order)?;
+        }
+        packet_router.add_incoming_packet_receiver(packet_logger.get_addr());
+        packet_router.add_outgoing_packet_receiver(packet_logger.get_addr());
+        packet_router.add_dropped_packet_receiver(packet_logger.dropped_addr());
+        packet_router.add_outgoing_packet_receiver(peer_collection.get_addr());
+        let peer_source_addr = peer_source.get_addr();
+        let peer_collection_addr = peer_collection.get_addr();
+        let packet_router_addr = packet_router.get_addr();
+        let route_manager_addr = route_manager.as_ref().map(Actor::get_addr);
+        let subnet_router_addr: Addr<SubnetRouterMessage> = subnet_router.get_addr();
+        let mesh_router_addr: Option<Addr<MeshRouterMessage>> =
+            mesh_router.as_ref().map(Actor::get_addr);
+
+        // Run
+        let mut supervisor = Supervisor::new(shutdown.clone(), events.clone());
+        let (packets, dropped) = packet_logger.mailbox_keepers();
+        let mut first = Some(packet_logger);
+        let listener = shutdown.listener();
+        supervisor.spawn("packet_logger", RestartPolicy::restart(), move || {
+            let packet_logger = first.take().unwrap_or_else(|| {
+                PacketLogger::from_mailboxes(
+                    (packets.addr(), packets.reopen()),
+                    (dropped.addr(), dropped.reopen()),
+                )
+            });
+            listener.clone().run_until(packet_logger.run())
+        });
+        {
+            let mut restart = packet_router.restarter();
+            let mut first = Some(packet_router);
+            let listener = shutdown.listener();
+            supervisor.spawn("packet_router", RestartPolicy::restart(), move || {
+                let packet_router = first.take().unwrap_or_else(&mut restart);
+                listener.clone().run_until(packet_router.run())
+            });
+        }
+        {
+            let (messages, packets) = peer_collection.mailbox_keepers();
+            let route_table = peer_collection.route_table();
+            let (packet_router, events) = (packet_router_addr.clone(), events.clone());
+            let mut first = Some(peer_collection);
+            let listener = shutdown.listener();
+            supervisor.spawn("peer_collection", RestartPolicy::restart(), move || {
+                let peer_collection = first.take().unwrap_or_else(|| {
+                    PeerCollection::from_mailboxes(
+                        (messages.addr(), messages.reopen()),
+                        (packets.addr(), packets.reopen()),
+                        packet_router.clone(),
+                        overlay.clone(),
+                        config.failback,
+                        route_table.clone(),
+                        events.clone(),
+                    )
+                });
+                listener.clone().run_until(peer_collection.run())
+            });
+        }
+        {
+            let mut restart = peer_source.restarter();
+            let mut first = Some(peer_source);
+            let listener = shutdown.listener();
+            supervisor.spawn("peer_source", RestartPolicy::restart(), move || {
+                let peer_source = first.take().unwrap_or_else(&mut restart);
+                peer_source.run(listener.clone())
+            });
+        }
+        if let Some(tun) = tun {
+            let keeper = tun.mailbox_keeper();
+            let (packet_router, route_manager) =
+                (packet_router_addr.clone(), route_manager_addr.clone());
+            let tun_config = config.tun;
+            let mut first = Some(tun);
+            let listener = shutdown.listener();
+            supervisor.spawn("tun", RestartPolicy::restart(), move || {
+                // The first instance, or the mailbox of the next one
+                let tun = first.take().ok_or_else(|| (keeper.addr(), keeper.reopen()));
+                let (packet_router, route_manager) = (packet_router.clone(), route_manager.clone());
+                let (tun_config, listener) = (tun_config.clone(), listener.clone());
+                async move {
+                    let tun = match tun {
+                        Ok(tun) => tun,
+                        Err((address, receiver)) => {
+                            let tun =
+                                Tun::from_mailbox(address, receiver, packet_router, tun_config);
+                            let tun = match tun.await {
+                                Ok(tun) => tun,
+                                Err(error) => {
+                                    warn!("Failed to create the TUN device again: {:?}", error);
+                                    return;
+                                }
+                            };
+                            if let Some(route_manager) = route_manager {
+                                reattach_routes(&route_manager).await;
+                            }
+                            tun
+                        }
+                    };
+                    tun.run(listener).await
+                }
+            });
+        }
+        if let (Some(netstack), Some(netstack_config)) = (netstack, config.netstack) {
+            let netstack_addr: Addr<NetStackMessage> = netstack.get_addr();
+            let (packets, messages) = netstack.mailbox_keepers();
+            let packet_router = packet_router_addr.clone();
+            let mut first = Some(netstack);
+            let listener = shutdown.listener();
+            supervisor.spawn("netstack", RestartPolicy::restart(), move || {
+                let netstack = first.take().map(Ok).unwrap_or_else(|| {
+                    NetStack::from_mailboxes(
+                        (packets.addr(), packets.reopen()),
+                        (messages.addr(), messages.reopen()),
+                        packet_router.clone(),
+                        &netstack_config,
+                    )
+                });
+                let listener = listener.clone();
+                async move {
+                    match netstack {
+                        Ok(netstack) => listener.run_until(netstack.run()).await,
+                        Err(error) => warn!("Failed to start the netstack: {:?}", error),
+                    }
+                }
+            });
+            for (listener, kind) in proxies {
+                supervisor.spawn_once(
+                    &format!("{:?}_proxy", kind).to_lowercase(),
+                    shutdown.listener().run_until(proxy::serve(
+                        listener,
+                        kind,
+                        netstack_addr.clone(),
+                    )),
+                );
+            }
+        }
+        if let Some(exit_node) = exit_node {
+            let keeper = exit_node.mailbox_keeper();
+            let packet_router = packet_router_addr.clone();
+            let overlay = exit_node_overlay;
+            let policy = config.exit_policy;
+            let state = state.clone();
+            let mut first = Some(exit_node);
+            let listener = shutdown.listener();
+            supervisor.spawn("exit_node", RestartPolicy::restart(), move || {
+                let exit_node = first.take().unwrap_or_else(|| {
+                    ExitNode::from_mailbox(
+                        keeper.addr(),
+                        keeper.reopen(),
+                        packet_router.clone(),
+                        overlay.clone(),
+                        policy.clone(),
+                        state.clone(),
+                    )
+                });
+                listener.clone().run_until(exit_node.run())
+            });
+        }
+        supervisor.spawn_once(
+            "subnet_router",
+            shutdown.listener().run_until(subnet_router.run()),
+        );
+        if let Some(mesh_router) = mesh_router {
+            supervisor.spawn_once(
+                "mesh_router",
+                shutdown.listener().run_until(mesh_router.run()),
+            );
+        }
+        {
+            let (rotations, events, listener) =
+                (rotations.clone(), events.clone(), shutdown.listener());
+            supervisor.spawn("rotations", RestartPolicy::restart(), move || {
+                listener
+                    .clone()
+                    .run_until(rotations.clone().run(events.subscribe()))
+            });
+        }
+        if let Some(gossip) = gossip {
+            let mut first = Some(gossip);
+            let listener = shutdown.listener();
+            supervisor.spawn("gossip", RestartPolicy::restart(), move || {
+                let gossip = first.take().unwrap_or_else(&start_gossip);
+                listener.clone().run_until(gossip.run())
+            });
+        }
+        if let Some(mdns) = mdns {
+            let mut first = Some(Ok(mdns));
+            let listener = shutdown.listener();
+            supervisor.spawn("mdns", RestartPolicy::restart(), move || {
+                let mdns = first.take().unwrap_or_else(&start_mdns);
+                let listener = listener.clone();
+                async move {
+                    match mdns {
+                        Ok(mdns) => listener.run_until(mdns.run()).await,
+                        Err(error) => warn!("Failed to start mDNS: {:?}", error),
+                    }
+                }
+            });
+        }
+        if let Some(discovery) = discovery {
+            let (peer_source, listener) = (peer_source_addr.clone(), shutdown.listener());
+            supervisor.spawn("discovery", RestartPolicy::restart(), move || {
+                listener
+                    .clone()
+                    .run_until(discovery.clone().run(peer_source.clone()))
+            });
+        }
+        if let Some(route_manager) = route_manager {
+            let keeper = route_manager.mailbox_keeper();
+            let (interface, events) = (route_manager.interface().to_string(), events.clone());
+            let mut first = Some(route_manager);
+            let listener = shutdown.listener();
+            supervisor.spawn("route_manager", RestartPolicy::restart(), move || {
+                // The first instance, or the mailbox of the next one
+                let route_manager = first.take().ok_or_else(|| (keeper.addr(), keeper.reopen()));
+                let (interface, routes, events) =
+                    (interface.clone(), routes.clone(), events.clone());
+                let listener = listener.clone();
+                async move {
+                    let route_manager = match route_manager {
+                        Ok(route_manager) => route_manager,
+                        Err((address, receiver)) => {
+                            let route_manager = RouteManager::from_mailbox(
+                                address, receiver, &interface, &routes, events,
+                            );
+                            match route_manager.await {
+                                Ok(route_manager) => route_manager,
+                                Err(error) => {
+                                    warn!("Failed to start the route manager again: {:?}", error);
+                                    return;
+                                }
+                            }
+                        }
+                    };
+                    route_manager.run(listener).await
+                }
+            });
+        }
+        if !config.hooks.is_empty() {
+            // Runs until the daemon starts stopping, so the stop hooks can finish
+            let (hooks, events, state) = (config.hooks, events.clone(), state.clone());
+            supervisor.spawn("hooks", RestartPolicy::restart(), move || {
+                let mut runner = Hooks::new(hooks.clone(), node_id);
+                if let Some(state) = &state {
+                    runner = runner.state(state.clone());
+                }
+                runner.run(events.subscribe())
+            });
+        }
+        let task = tokio::spawn(supervise(supervisor, events.clone()));
+        info!("Started node {}", node_id);
+        events.emit(Event::DaemonStarted { node_id });
+
+        let handle = DaemonHandle {
+            node_id,
+            peer_source: peer_source_addr,
+            peer_collection: peer_collection_addr,
+            packet_router: packet_router_addr,
+            route_manager: route_manager_addr,
+            subnet_router: subnet_router_addr,
+            mesh_router: mesh_router_addr,
+            firewall: firewall_handle,
+            wasm_filters: wasm_filter_handles,
+            forwarding,
+            advertisements,
+            state,
+            invites,
+            pairing: config.pairing,
+            revocations,
+            rotations,
+            events,
+            shutdown: shutdown.clone(),
+            task: Arc::new(Mutex::new(Some(task))),
+        };
+        if let Some(control_server) = control_server {
+            tokio::spawn(control_server.run(handle.clone(), shutdown.listener()));
+        }
+        Ok(handle)
+    }
+}
+
+/// Asks `route_manager` to install its routes again on the TUN device, once created again.
+async fn reattach_routes(route_manager: &Addr<RouteManagerMessage>) {
+    match route_manager.ask(RouteManagerMessage::Reattach).await {
+        Ok(Ok(())) => {}
+        Ok(Err(error)) => warn!("Couldn't install the routes again: {:?}", error),
+        // A restarted route manager installs them on its own
+        Err(error) => warn!("Couldn't reach the route manager: {:?}", error),
+    }
+}
+
+/// Returns the prefixes of the overlay: the networks of the addresses of this node,
+/// and the routes to the TUN device.
+fn overlay_prefixes(config: &DaemonConfig) -> Vec<IpNet> {
+    let mut overlay = Vec::new();
+    if config.enable_tun {
+        overlay.extend(config.tun.addresses.iter().map(IpNet::trunc));
+        overlay.extend(&config.tun.routes);
+    }
+    if let Some(netstack) = &config.netstack {
+        overlay.extend(netstack.addresses.iter().map(IpNet::trunc));
+    }
+    overlay
+}
+
+/// Returns the overlay addresses of this node.
+fn own_addresses(config: &DaemonConfig) -> Vec<IpAddr> {
+    let mut addresses = Vec::new();
+    if config.enable_tun {
+        addresses.extend(config.tun.addresses.iter().map(IpNet::addr));
+    }
+    if let Some(netstack) = &config.netstack {
+        addresses.extend(netstack.addresses.iter().map(IpNet::addr));
+    }
+    addresses
+}
+
+/// Writes the addresses and routes of this node to the replicated state, when they changed.
+fn publish_own(
+    state: &NetworkState,
+    node_id: NodeId,
+    addresses: &[IpAddr],
+    advertised_routes: &[IpNet],
+) -> Result<(), DaemonError> {
+    let current: Vec<Record> = state
+        .records()
+        .into_iter()
+        .map(|info| info.record)
+        .collect();
+    let addresses = addresses.iter().map(|address| Record::Address {
+        address: *address,
+        node_id,
+    });
+    let routes = advertised_routes.iter().map(|prefix| Record::Route {
+        node_id,
+        prefix: *prefix,
+    });
+    for record in addresses.chain(routes) {
+        if current.contains(&record) {
+            continue;
+        }
+        if let Record::Address { address, .. } = record {
+            if let Some(owner) = state
+                .address_owner(address)
+                .filter(|owner| *owner != node_id)
+            {
+                warn!(
+                    "Address {} is assigned to {}, not claiming it",
+                    address, owner
+                );
+                continue;
+            }
+        }
+        match state.set(record) {
+            Err(DaemonError::Unauthorized(key)) => {
+                warn!(
+                    "Couldn't write {:?} to the state, this node isn't a member",
+                    key
+                );
+            }
+            result => result?,
+        }
+    }
+    // Routes this node doesn't advertise anymore
+    for record in current {
+        if let Record::Route {
+            node_id: owner,
+            prefix,
+        } = record
+        {
+            if owner == node_id && !advertised_routes.contains(&prefix) {
+                state.remove(record.key())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits until an actor stops the daemon or the shutdown is triggered, then stops all actors.
+async fn supervise(mut supervisor: Supervisor, events: Events) -> Result<(), DaemonError> {
+    let result = supervisor.wait().await;
+
+    // Shut down, giving the actors time to clean up
+    info!("Stopping...");
+    events.emit(Event::DaemonStopping);
+    supervisor.stop(SHUTDOWN_DEADLINE).await;
+    result
 }
@@ -3,40 +3,182 @@
 //! Actors can receive messages through addresses ([Addr]) and handle them asynchronously.
 //! Each actor implements the [Actor] trait, allowing it to send and receive messages.
 
+pub mod exit_node;
+pub mod mailbox;
+pub mod mesh_router;
+pub mod netstack;
 pub mod packet_logger;
 pub mod packet_router;
 pub mod peer;
 pub mod peer_collection;
 pub mod peer_source;
+pub mod route_manager;
+pub mod subnet_router;
 pub mod tun;
 
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::Arc, time::Duration};
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, oneshot};
 
-/// Represents the address of an actor to which messages can be sent.
+use self::mailbox::{MailboxStats, OverflowPolicy, Shared, TrySendError};
+
+/// How long [Addr::ask] waits for a reply.
+pub const ASK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The channel used by an actor to reply to a request sent with [Addr::ask].
 #[derive(Debug)]
+pub struct Reply<T> {
+    sender: oneshot::Sender<T>,
+}
+
+impl<T> Reply<T> {
+    /// Sends the reply to the asking side
+    pub fn send(self, value: T) {
+        let _ = self.sender.send(value);
+    }
+}
+
+/// Errors that can happen when asking an actor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AskError {
+    /// The actor no longer receives messages.
+    Closed,
+    /// The actor dropped the request without replying.
+    NoReply,
+    /// The actor didn't reply in time.
+    Timeout,
+}
+
+/// Where the messages sent through an [Addr] go.
+enum Destination<Message> {
+    /// The mailbox of the actor.
+    Mailbox(Arc<Shared<Message>>),
+    /// A channel created by the actor, see [Addr::new].
+    Channel(mpsc::Sender<Message>, Arc<MailboxStats>),
+}
+
+/// Represents the address of an actor to which messages can be sent.
 pub struct Addr<Message> {
-    /// The message sender of this actor
-    sender: mpsc::Sender<Message>,
+    /// The mailbox of this actor
+    destination: Destination<Message>,
+}
+
+impl<Message> Debug for Addr<Message> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.destination {
+            Destination::Mailbox(shared) => f
+                .debug_struct("Addr")
+                .field("policy", &shared.policy())
+                .field("stats", shared.stats())
+                .finish(),
+            Destination::Channel(sender, _) => {
+                f.debug_struct("Addr").field("sender", sender).finish()
+            }
+        }
+    }
 }
 
 impl<Message> Clone for Addr<Message> {
     fn clone(&self) -> Self {
-        Self {
-            sender: self.sender.clone(),
+        match &self.destination {
+            Destination::Mailbox(shared) => Self::from_shared(shared.clone()),
+            Destination::Channel(sender, stats) => Self {
+                destination: Destination::Channel(sender.clone(), stats.clone()),
+            },
+        }
+    }
+}
+
+impl<Message> Drop for Addr<Message> {
+    fn drop(&mut self) {
+        if let Destination::Mailbox(shared) = &self.destination {
+            shared.remove_sender();
         }
     }
 }
 
 impl<Message> Addr<Message> {
     /// Creates an address from a [`mpsc::Sender`]
+    ///
+    /// Sending waits for space in the channel, like [OverflowPolicy::Block].
     pub fn new(sender: mpsc::Sender<Message>) -> Self {
-        Self { sender }
+        Self {
+            destination: Destination::Channel(sender, Default::default()),
+        }
+    }
+    /// Creates an address of a mailbox, see [mailbox::mailbox]
+    pub(super) fn from_shared(shared: Arc<Shared<Message>>) -> Self {
+        shared.add_sender();
+        Self {
+            destination: Destination::Mailbox(shared),
+        }
     }
-    /// Sends a message to the addressed actor
+    /// Sends a message to the addressed actor, applying the [OverflowPolicy] of its mailbox
+    ///
+    /// Waits only if the mailbox is full and its policy is [OverflowPolicy::Block].
     pub async fn send_message(&self, message: Message) {
-        let _ = self.sender.send(message).await;
+        let _ = match &self.destination {
+            Destination::Mailbox(shared) => match shared.policy() {
+                OverflowPolicy::Block => shared.push(message).await,
+                OverflowPolicy::DropNewest | OverflowPolicy::DropOldest => shared.try_push(message),
+            },
+            Destination::Channel(sender, _) => sender
+                .send(message)
+                .await
+                .map_err(|error| TrySendError::Closed(error.0)),
+        };
+    }
+    /// Sends a message to the addressed actor without waiting
+    pub fn try_send_message(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        match &self.destination {
+            Destination::Mailbox(shared) => shared.try_push(message),
+            Destination::Channel(sender, _) => {
+                sender.try_send(message).map_err(|error| match error {
+                    mpsc::error::TrySendError::Full(message) => TrySendError::Full(message),
+                    mpsc::error::TrySendError::Closed(message) => TrySendError::Closed(message),
+                })
+            }
+        }
+    }
+    /// Sends a request to the addressed actor and waits for its reply, up to [ASK_TIMEOUT]
+    ///
+    /// The request is built by `make_request` from the [Reply] the actor should answer with.
+    pub async fn ask<T>(
+        &self,
+        make_request: impl FnOnce(Reply<T>) -> Message,
+    ) -> Result<T, AskError> {
+        self.ask_with_timeout(make_request, ASK_TIMEOUT).await
+    }
+    /// Sends a request to the addressed actor and waits for its reply, up to `timeout`
+    ///
+    /// The request waits for space in the mailbox regardless of its [OverflowPolicy].
+    pub async fn ask_with_timeout<T>(
+        &self,
+        make_request: impl FnOnce(Reply<T>) -> Message,
+        timeout: Duration,
+    ) -> Result<T, AskError> {
+        let (sender, receiver) = oneshot::channel();
+        let request = make_request(Reply { sender });
+        let ask = async {
+            let sent = match &self.destination {
+                Destination::Mailbox(shared) => shared.push(request).await.is_ok(),
+                Destination::Channel(sender, _) => sender.send(request).await.is_ok(),
+            };
+            if !sent {
+                return Err(AskError::Closed);
+            }
+            receiver.await.map_err(|_| AskError::NoReply)
+        };
+        tokio::time::timeout(timeout, ask)
+            .await
+            .unwrap_or(Err(AskError::Timeout))
+    }
+    /// Returns the counters of the addressed actor's mailbox
+    pub fn mailbox_stats(&self) -> &MailboxStats {
+        match &self.destination {
+            Destination::Mailbox(shared) => shared.stats(),
+            Destination::Channel(_, stats) => stats,
+        }
     }
 }
 
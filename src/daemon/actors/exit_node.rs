@@ -0,0 +1,519 @@
+//! Module for [ExitNode] actor.
+//!
+//! It lets peers reach the internet through this node, masquerading their traffic
+//! without any firewall configuration of the host. TCP connections of peers are terminated
+//! by a userspace TCP/IP stack answering for any address, and opened again from the host.
+//! UDP packets are sent from a host socket for each flow. Either way, the host translates
+//! the source address and port, like a NAT.
+//!
+//! Only the peers allowed by the [ExitPolicy] can use the exit node, from their own overlay
+//! address: the one assigned to them in the replicated state, or without it, the first peer
+//! sending from an address keeps it. Private and link-local destinations aren't reached, so
+//! peers can't reach the local networks of the node.
+//!
+//! Only IPv4 TCP and UDP are supported. Other packets, such as ICMP or IPv6, are dropped.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use iroh_net::NodeId;
+use serde::Deserialize;
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::ChecksumCapabilities,
+    socket::tcp,
+    time::Instant,
+    wire::{
+        HardwareAddress, IpAddress, IpCidr, IpListenEndpoint, IpProtocol, Ipv4Address, Ipv4Packet,
+        Ipv4Repr, TcpPacket, UdpPacket, UdpRepr,
+    },
+};
+use tokio::{
+    net::{TcpStream, UdpSocket},
+    select,
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+};
+use tracing::debug;
+
+use crate::daemon::{
+    packet::{
+        ip::{Flow, PROTOCOL_TCP, PROTOCOL_UDP},
+        Packet,
+    },
+    state::NetworkState,
+};
+
+use super::{
+    mailbox::{mailbox, Mailbox, MailboxKeeper, OverflowPolicy},
+    netstack::{
+        connection, service_connection, tcp_socket, Connection, QueueDevice, MAX_POLL_DELAY, MTU,
+    },
+    Actor, Addr,
+};
+
+/// The address of the userspace stack itself.
+///
+/// It never appears in packets, since the stack answers for the addresses of internet hosts.
+const STACK_ADDRESS: Ipv4Address = Ipv4Address([192, 0, 0, 8]);
+/// How long connecting to an internet host may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a UDP flow is kept without packets from the internet host.
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// The size of the IPv4 and UDP headers of the packets built for UDP replies.
+const UDP_HEADERS_SIZE: usize = 28;
+/// The largest UDP packet read from a socket.
+const MAX_UDP_PACKET: usize = 65535;
+/// The most flows waiting for their host connection. New flows are dropped beyond it.
+const MAX_PENDING: usize = 256;
+
+/// The peers allowed to reach the internet through this node. Nobody is allowed by default.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExitPolicy {
+    #[serde(default)]
+    pub peers: Vec<NodeId>,
+}
+
+impl ExitPolicy {
+    /// Checks whether `peer` may reach the internet through this node.
+    pub fn allows(&self, peer: NodeId) -> bool {
+        self.peers.contains(&peer)
+    }
+}
+
+/// A connection of a peer to an internet host, accepted by the userspace stack.
+struct ExitConnection {
+    connection: Connection,
+    flow: Flow,
+}
+
+/// A socket listening for the SYN of a flow, whose host connection is established.
+struct Listener {
+    handle: SocketHandle,
+    flow: Flow,
+    host: TcpStream,
+}
+
+/// An actor letting peers reach the internet through this node.
+pub struct ExitNode {
+    packet_address: Addr<Packet>,
+    packet_receiver: Mailbox<Packet>,
+    packet_router: Addr<Packet>,
+    /// The overlay prefixes, whose traffic doesn't go to the internet.
+    overlay: Vec<IpNet>,
+    policy: ExitPolicy,
+    /// The replicated state, assigning the overlay addresses of the peers.
+    state: Option<NetworkState>,
+    interface: Interface,
+    device: QueueDevice,
+    sockets: SocketSet<'static>,
+    /// The peer owning each overlay address, learned from the accepted packets of the peers.
+    peers: HashMap<IpAddr, NodeId>,
+    /// The SYN of each flow waiting for its host connection.
+    pending: HashMap<Flow, Arc<[u8]>>,
+    connected_sender: mpsc::UnboundedSender<(Flow, io::Result<TcpStream>)>,
+    connected_receiver: mpsc::UnboundedReceiver<(Flow, io::Result<TcpStream>)>,
+    listeners: Vec<Listener>,
+    connections: Vec<ExitConnection>,
+    /// The flows that have a listener or a connection.
+    active: HashSet<Flow>,
+    /// The socket of each UDP flow, with the task sending the replies to the peer.
+    udp_flows: HashMap<Flow, (Arc<UdpSocket>, JoinHandle<()>)>,
+    /// Woken up by the connections when they send or receive data.
+    wake: Arc<Notify>,
+}
+
+impl ExitNode {
+    /// Creates a new [ExitNode]. Packets to the `overlay` prefixes are left to other receivers.
+    pub fn new(
+        packet_router: Addr<Packet>,
+        overlay: Vec<IpNet>,
+        policy: ExitPolicy,
+        state: Option<NetworkState>,
+    ) -> Self {
+        let (packet_address, packet_receiver) = mailbox(16, OverflowPolicy::DropNewest);
+        Self::from_mailbox(
+            packet_address,
+            packet_receiver,
+            packet_router,
+            overlay,
+            policy,
+            state,
+        )
+    }
+
+    /// Creates an [ExitNode] receiving the packets of an existing mailbox, see [ExitNode::new].
+    pub fn from_mailbox(
+        packet_address: Addr<Packet>,
+        packet_receiver: Mailbox<Packet>,
+        packet_router: Addr<Packet>,
+        overlay: Vec<IpNet>,
+        policy: ExitPolicy,
+        state: Option<NetworkState>,
+    ) -> Self {
+        let (connected_sender, connected_receiver) = mpsc::unbounded_channel();
+        let mut device = QueueDevice::new();
+        let mut interface_config = Config::new(HardwareAddress::Ip);
+        interface_config.random_seed = rand::random();
+        let mut interface = Interface::new(interface_config, &mut device, Instant::now());
+        interface.update_ip_addrs(|addresses| {
+            let _ = addresses.push(IpCidr::new(STACK_ADDRESS.into(), 32));
+        });
+        // Packets to any address routed through the stack's own address are accepted
+        interface.set_any_ip(true);
+        let _ = interface.routes_mut().add_default_ipv4_route(STACK_ADDRESS);
+        Self {
+            packet_address,
+            packet_receiver,
+            packet_router,
+            overlay,
+            policy,
+            state,
+            interface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            peers: HashMap::new(),
+            pending: HashMap::new(),
+            connected_sender,
+            connected_receiver,
+            listeners: Vec::new(),
+            connections: Vec::new(),
+            active: HashSet::new(),
+            udp_flows: HashMap::new(),
+            wake: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Checks whether a packet going to `destination` should go to the internet.
+    fn is_exit_traffic(&self, destination: IpAddr) -> bool {
+        match destination {
+            IpAddr::V4(destination) => {
+                !destination.is_unspecified()
+                    && !destination.is_multicast()
+                    && !destination.is_broadcast()
+                    && !destination.is_loopback()
+                    && !destination.is_private()
+                    && !destination.is_link_local()
+                    && !self
+                        .overlay
+                        .iter()
+                        .any(|prefix| prefix.contains(&IpAddr::V4(destination)))
+            }
+            IpAddr::V6(_) => false,
+        }
+    }
+
+    /// Checks whether `source` is the overlay address of `node_id`.
+    fn is_own_address(&self, node_id: NodeId, source: IpAddr) -> bool {
+        match &self.state {
+            Some(state) => state.address_owner(source) == Some(node_id),
+            None => {
+                self.overlay.iter().any(|prefix| prefix.contains(&source))
+                    && self
+                        .peers
+                        .get(&source)
+                        .is_none_or(|owner| *owner == node_id)
+            }
+        }
+    }
+
+    /// Handles a packet received from a peer.
+    async fn handle_packet(&mut self, node_id: NodeId, data: Arc<[u8]>) {
+        let Some(flow) = Flow::parse(&data) else {
+            return;
+        };
+        if !self.is_exit_traffic(flow.destination) || !self.policy.allows(node_id) {
+            return;
+        }
+        if !self.is_own_address(node_id, flow.source) {
+            debug!("Dropped a packet of {} from {}", node_id, flow.source);
+            return;
+        }
+        self.peers.insert(flow.source, node_id);
+        match flow.protocol {
+            PROTOCOL_TCP => self.handle_tcp(flow, data),
+            PROTOCOL_UDP => self.handle_udp(node_id, flow, &data).await,
+            _ => {}
+        }
+    }
+
+    /// Handles a TCP packet, connecting to the host before a new flow is accepted.
+    fn handle_tcp(&mut self, flow: Flow, data: Arc<[u8]>) {
+        if self.active.contains(&flow) {
+            self.device.received.push_back(data);
+            return;
+        }
+        if self.pending.contains_key(&flow) {
+            // A retransmitted SYN
+            return;
+        }
+        if !is_syn(&data) {
+            // Answered with a reset by the stack
+            self.device.received.push_back(data);
+            return;
+        }
+        if self.pending.len() >= MAX_PENDING {
+            debug!(
+                "Dropped the SYN of {:?}, too many connections are pending",
+                flow
+            );
+            return;
+        }
+        self.pending.insert(flow, data);
+        let connected = self.connected_sender.clone();
+        let host = SocketAddr::new(flow.destination, flow.destination_port);
+        tokio::spawn(async move {
+            let result = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(host))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut)));
+            let _ = connected.send((flow, result));
+        });
+    }
+
+    /// Listens for the SYN of a flow once its host connection is established, and passes the SYN on.
+    ///
+    /// When the host couldn't be reached, the stack answers the SYN with a reset.
+    fn handle_connected(&mut self, flow: Flow, result: io::Result<TcpStream>) {
+        let Some(syn) = self.pending.remove(&flow) else {
+            return;
+        };
+        match result {
+            Ok(host) => {
+                let mut socket = tcp_socket();
+                let endpoint = IpListenEndpoint {
+                    addr: Some(IpAddress::from(flow.destination)),
+                    port: flow.destination_port,
+                };
+                if let Err(error) = socket.listen(endpoint) {
+                    debug!("Couldn't accept the flow {:?}: {}", flow, error);
+                    return;
+                }
+                let handle = self.sockets.add(socket);
+                self.listeners.push(Listener { handle, flow, host });
+                self.active.insert(flow);
+            }
+            Err(error) => debug!(
+                "Couldn't connect to {}:{}. Reason: {:?}",
+                flow.destination, flow.destination_port, error
+            ),
+        }
+        self.device.received.push_back(syn);
+    }
+
+    /// Handles a UDP packet, sending its payload from the socket of its flow.
+    async fn handle_udp(&mut self, node_id: NodeId, flow: Flow, data: &[u8]) {
+        let Some(payload) = udp_payload(data) else {
+            return;
+        };
+        self.udp_flows.retain(|_, (_, task)| !task.is_finished());
+        if !self.udp_flows.contains_key(&flow) {
+            let socket = match self.open_udp_flow(node_id, flow).await {
+                Ok(flow) => flow,
+                Err(error) => {
+                    debug!("Couldn't open the UDP flow {:?}: {:?}", flow, error);
+                    return;
+                }
+            };
+            self.udp_flows.insert(flow, socket);
+        }
+        let (socket, _) = &self.udp_flows[&flow];
+        if let Err(error) = socket.send(payload).await {
+            debug!("Couldn't send a UDP packet of {:?}: {:?}", flow, error);
+        }
+    }
+
+    /// Opens a socket for a UDP flow, with a task sending the replies to the peer.
+    async fn open_udp_flow(
+        &self,
+        node_id: NodeId,
+        flow: Flow,
+    ) -> io::Result<(Arc<UdpSocket>, JoinHandle<()>)> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+        let host = SocketAddr::new(flow.destination, flow.destination_port);
+        socket.connect(host).await?;
+        let socket = Arc::new(socket);
+        let peer = SocketAddr::new(flow.source, flow.source_port);
+        let task = tokio::spawn(send_udp_replies(
+            socket.clone(),
+            host,
+            peer,
+            node_id,
+            self.packet_router.clone(),
+        ));
+        Ok((socket, task))
+    }
+
+    /// Turns the listeners that accepted their flow into connections piped to their hosts.
+    fn accept_connections(&mut self) {
+        let mut index = 0;
+        while index < self.listeners.len() {
+            let handle = self.listeners[index].handle;
+            if self.sockets.get::<tcp::Socket>(handle).state() == tcp::State::Listen {
+                index += 1;
+                continue;
+            }
+            let Listener { flow, host, .. } = self.listeners.swap_remove(index);
+            let (connection, stream) = connection(handle, &self.wake);
+            self.connections.push(ExitConnection { connection, flow });
+            tokio::spawn(async move {
+                if let Err(error) = stream.pipe(host).await {
+                    debug!("Exit connection {:?} failed: {:?}", flow, error);
+                }
+            });
+        }
+    }
+
+    /// Moves data between the sockets and the hosts, and removes closed connections.
+    fn service_connections(&mut self) {
+        let sockets = &mut self.sockets;
+        let active = &mut self.active;
+        self.connections.retain_mut(|exit_connection| {
+            let handle = exit_connection.connection.handle;
+            let socket = sockets.get_mut::<tcp::Socket>(handle);
+            let keep = service_connection(&mut exit_connection.connection, socket);
+            if !keep {
+                sockets.remove(handle);
+                active.remove(&exit_connection.flow);
+            }
+            keep
+        });
+    }
+
+    /// Polls the stack and sends the packets it produced to the peers owning their destinations.
+    async fn poll(&mut self) {
+        self.interface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        self.accept_connections();
+        self.service_connections();
+        // Send the data received from the hosts right away
+        self.interface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        while let Some(packet) = self.device.transmitted.pop_front() {
+            let node_id = Flow::parse(&packet).and_then(|flow| self.peers.get(&flow.destination));
+            if let Some(node_id) = node_id {
+                self.packet_router
+                    .send_message(Packet::Redirected(*node_id, Arc::from(packet)))
+                    .await;
+            }
+        }
+    }
+
+    /// Returns a keeper of the mailbox, to start the actor again with [ExitNode::from_mailbox].
+    pub fn mailbox_keeper(&self) -> MailboxKeeper<Packet> {
+        self.packet_receiver.keeper()
+    }
+
+    /// Runs the actor, continuously processing packets and connections.
+    pub async fn run(mut self) {
+        loop {
+            self.poll().await;
+            let delay = self
+                .interface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map_or(MAX_POLL_DELAY, |delay| {
+                    Duration::from_micros(delay.total_micros()).min(MAX_POLL_DELAY)
+                });
+            select! {
+                Some(packet) = self.packet_receiver.recv() => {
+                    if let Packet::Incoming(node_id, data) = packet {
+                        self.handle_packet(node_id, data).await;
+                    }
+                }
+                Some((flow, result)) = self.connected_receiver.recv() => {
+                    self.handle_connected(flow, result);
+                }
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+}
+
+/// Checks whether an IPv4 packet is a TCP SYN opening a connection.
+fn is_syn(data: &[u8]) -> bool {
+    let Ok(packet) = Ipv4Packet::new_checked(data) else {
+        return false;
+    };
+    TcpPacket::new_checked(packet.payload()).is_ok_and(|segment| segment.syn() && !segment.ack())
+}
+
+/// Returns the payload of an IPv4 UDP packet.
+fn udp_payload(data: &[u8]) -> Option<&[u8]> {
+    let packet = Ipv4Packet::new_checked(data).ok()?;
+    let header_length = usize::from(packet.header_len());
+    let datagram = UdpPacket::new_checked(data.get(header_length..)?).ok()?;
+    Some(datagram.payload())
+}
+
+/// Builds an IPv4 UDP packet.
+fn udp_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Option<Vec<u8>> {
+    let (IpAddr::V4(source_address), IpAddr::V4(destination_address)) =
+        (source.ip(), destination.ip())
+    else {
+        return None;
+    };
+    let udp = UdpRepr {
+        src_port: source.port(),
+        dst_port: destination.port(),
+    };
+    let ip = Ipv4Repr {
+        src_addr: source_address.into(),
+        dst_addr: destination_address.into(),
+        next_header: IpProtocol::Udp,
+        payload_len: udp.header_len() + payload.len(),
+        hop_limit: 64,
+    };
+    let checksums = ChecksumCapabilities::default();
+    let mut buffer = vec![0u8; ip.buffer_len() + ip.payload_len];
+    let mut packet = Ipv4Packet::new_unchecked(&mut buffer);
+    ip.emit(&mut packet, &checksums);
+    let mut datagram = UdpPacket::new_unchecked(packet.payload_mut());
+    udp.emit(
+        &mut datagram,
+        &ip.src_addr.into(),
+        &ip.dst_addr.into(),
+        payload.len(),
+        |buffer| buffer.copy_from_slice(payload),
+        &checksums,
+    );
+    Some(buffer)
+}
+
+/// Sends the packets received from `host` to the peer owning the address `peer`,
+/// until none is received for [UDP_FLOW_TIMEOUT].
+///
+/// Packets that don't fit in the [MTU] are dropped, since they can't be fragmented.
+async fn send_udp_replies(
+    socket: Arc<UdpSocket>,
+    host: SocketAddr,
+    peer: SocketAddr,
+    node_id: NodeId,
+    packet_router: Addr<Packet>,
+) {
+    let mut buffer = vec![0u8; MAX_UDP_PACKET];
+    while let Ok(Ok(size)) = tokio::time::timeout(UDP_FLOW_TIMEOUT, socket.recv(&mut buffer)).await
+    {
+        if size > MTU - UDP_HEADERS_SIZE {
+            debug!("Dropped a UDP packet of {} bytes from {}", size, host);
+            continue;
+        }
+        if let Some(packet) = udp_packet(host, peer, &buffer[..size]) {
+            packet_router
+                .send_message(Packet::Redirected(node_id, Arc::from(packet)))
+                .await;
+        }
+    }
+}
+
+impl Actor<Packet> for ExitNode {
+    fn get_addr(&self) -> Addr<Packet> {
+        self.packet_address.clone()
+    }
+}
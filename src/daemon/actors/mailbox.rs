@@ -0,0 +1,220 @@
+//! Module for actor mailboxes.
+//!
+//! A mailbox is a bounded queue of messages for a single actor. Messages are sent to it through
+//! [Addr]s and received through the [Mailbox]. What happens to a message sent to a full mailbox
+//! is decided by the [OverflowPolicy] of the mailbox.
+//!
+//! A [MailboxKeeper] opens the mailbox again for the next instance of an actor, so its [Addr]s
+//! stay valid when the actor is restarted.
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::Notify;
+
+use super::Addr;
+
+/// Decides what happens to a message sent to a full mailbox.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Waits until there is space in the mailbox.
+    Block,
+    /// Drops the message being sent.
+    DropNewest,
+    /// Drops the oldest message in the mailbox to make space for the message being sent.
+    DropOldest,
+}
+
+/// Errors returned when a message can't be put into a mailbox without waiting.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TrySendError<Message> {
+    /// The mailbox is full.
+    Full(Message),
+    /// The actor no longer receives messages.
+    Closed(Message),
+}
+
+/// Counters of a mailbox.
+#[derive(Debug, Default)]
+pub struct MailboxStats {
+    /// Messages dropped because the mailbox was full.
+    pub dropped: AtomicU64,
+}
+
+/// The queue and its state, guarded by a mutex.
+struct Queue<Message> {
+    messages: VecDeque<Message>,
+    closed: bool,
+}
+
+/// The state shared between the [Addr]s and the [Mailbox].
+pub(super) struct Shared<Message> {
+    queue: Mutex<Queue<Message>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    stats: MailboxStats,
+    /// Number of living [Addr]s.
+    senders: AtomicUsize,
+    /// Notified when a message is put into the queue or the last [Addr] is dropped.
+    message_sent: Notify,
+    /// Notified when a message is taken from the queue or the [Mailbox] is dropped.
+    message_received: Notify,
+}
+
+impl<Message> Shared<Message> {
+    pub(super) fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+
+    pub(super) fn stats(&self) -> &MailboxStats {
+        &self.stats
+    }
+
+    pub(super) fn add_sender(&self) {
+        self.senders.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn remove_sender(&self) {
+        if self.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.message_sent.notify_one();
+        }
+    }
+
+    /// Puts a message into the queue if there is space, or applies a dropping policy.
+    ///
+    /// [OverflowPolicy::Block] is treated like [OverflowPolicy::DropNewest], but without
+    /// counting the message as dropped.
+    pub(super) fn try_push(&self, message: Message) -> Result<(), TrySendError<Message>> {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.closed {
+            return Err(TrySendError::Closed(message));
+        }
+        if queue.messages.len() >= self.capacity {
+            match self.policy {
+                OverflowPolicy::Block => return Err(TrySendError::Full(message)),
+                OverflowPolicy::DropNewest => {
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    return Err(TrySendError::Full(message));
+                }
+                OverflowPolicy::DropOldest => {
+                    self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                    queue.messages.pop_front();
+                }
+            }
+        }
+        queue.messages.push_back(message);
+        drop(queue);
+        self.message_sent.notify_one();
+        Ok(())
+    }
+
+    /// Puts a message into the queue, waiting for space if needed.
+    pub(super) async fn push(&self, mut message: Message) -> Result<(), TrySendError<Message>> {
+        loop {
+            let space_freed = self.message_received.notified();
+            match self.try_push(message) {
+                Err(TrySendError::Full(returned)) => message = returned,
+                result => return result,
+            }
+            space_freed.await;
+        }
+    }
+}
+
+/// Receives the messages sent to an actor.
+pub struct Mailbox<Message> {
+    shared: Arc<Shared<Message>>,
+}
+
+impl<Message> Mailbox<Message> {
+    /// Receives the next message.
+    ///
+    /// Returns [None] when all [Addr]s of the mailbox are dropped and no messages are left.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            let message_sent = self.shared.message_sent.notified();
+            {
+                let mut queue = self.shared.queue.lock().unwrap();
+                if let Some(message) = queue.messages.pop_front() {
+                    drop(queue);
+                    self.shared.message_received.notify_one();
+                    return Some(message);
+                }
+                if self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return None;
+                }
+            }
+            message_sent.await;
+        }
+    }
+
+    /// Returns a [MailboxKeeper] of this mailbox.
+    pub fn keeper(&self) -> MailboxKeeper<Message> {
+        MailboxKeeper {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<Message> Drop for Mailbox<Message> {
+    /// Closes the mailbox and drops the queued messages, failing the requests among them.
+    fn drop(&mut self) {
+        let messages = {
+            let mut queue = self.shared.queue.lock().unwrap();
+            queue.closed = true;
+            std::mem::take(&mut queue.messages)
+        };
+        self.shared.message_received.notify_waiters();
+        drop(messages);
+    }
+}
+
+/// Opens a mailbox again once it is dropped, keeping its [Addr]s valid.
+///
+/// Messages sent while the mailbox is dropped are refused as if the actor was gone,
+/// and the next instance of the actor starts with an empty mailbox.
+pub struct MailboxKeeper<Message> {
+    shared: Arc<Shared<Message>>,
+}
+
+impl<Message> MailboxKeeper<Message> {
+    /// Returns an [Addr] sending to the mailbox.
+    pub fn addr(&self) -> Addr<Message> {
+        Addr::from_shared(self.shared.clone())
+    }
+
+    /// Opens the mailbox again, for a new instance of the actor.
+    ///
+    /// The mailbox must have been dropped, otherwise both receive its messages.
+    pub fn reopen(&self) -> Mailbox<Message> {
+        self.shared.queue.lock().unwrap().closed = false;
+        Mailbox {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// Creates a mailbox holding up to `capacity` messages, and an [Addr] sending to it.
+pub fn mailbox<Message>(
+    capacity: usize,
+    policy: OverflowPolicy,
+) -> (Addr<Message>, Mailbox<Message>) {
+    let shared = Arc::new(Shared {
+        queue: Mutex::new(Queue {
+            messages: VecDeque::with_capacity(capacity),
+            closed: false,
+        }),
+        capacity,
+        policy,
+        stats: MailboxStats::default(),
+        senders: AtomicUsize::new(0),
+        message_sent: Notify::new(),
+        message_received: Notify::new(),
+    });
+    (Addr::from_shared(shared.clone()), Mailbox { shared })
+}
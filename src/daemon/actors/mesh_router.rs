@@ -0,0 +1,292 @@
+//! Module for [MeshRouter] actor.
+//!
+//! It lets peers that can't connect to each other directly reach each other through the
+//! peers they are both connected to.
+//!
+//! Every node advertises its overlay addresses, and the [MeshRoute]s to the nodes it reaches,
+//! in its [Advertisement](crate::daemon::advertisements::Advertisement). A route carries the
+//! whole path to its node, so a node ignores the routes going through itself, and the routes
+//! longer than [MAX_HOPS]. Of the routes to a node, the one with the lowest round-trip time wins,
+//! the direct connection being a route like any other. Only the addresses assigned to a node in
+//! the replicated state are routed to it, so a peer can't draw the traffic of other nodes.
+//!
+//! The addresses of every reachable node are routed in the
+//! [PeerCollection](super::peer_collection::PeerCollection) through the first peer of the path.
+//! A node on the path forwards the packets to the next one without passing them to its TUN
+//! device, decrementing their TTL. They still go through its pipeline, so its firewall applies.
+//!
+//! Every node of the mesh should run a [MeshRouter]. Otherwise, the packets broadcast by a node
+//! that doesn't may reach their destination twice.
+
+use std::{collections::HashMap, net::IpAddr, time::Duration};
+
+use iroh_net::NodeId;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+    time::{interval, Interval, MissedTickBehavior},
+};
+use tracing::{info, warn};
+
+use crate::daemon::{
+    advertisements::{Advertisement, Advertisements},
+    events::{Event, Events},
+    state::NetworkState,
+};
+
+use super::{
+    mailbox::{mailbox, Mailbox, OverflowPolicy},
+    peer_collection::PeerCollectionMessage,
+    Actor, Addr, Reply,
+};
+
+/// The most peers a route may go through, including its node.
+pub const MAX_HOPS: usize = 8;
+/// How often the routes are selected again, following the round-trip times.
+const SELECTION_INTERVAL: Duration = Duration::from_secs(5);
+/// How much the round-trip time of a route may change before it is advertised again.
+const RTT_TOLERANCE: Duration = Duration::from_millis(10);
+
+/// A node reachable through the advertising node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MeshRoute {
+    pub node_id: NodeId,
+    /// The peers the traffic goes through after the advertising node, ending with the node.
+    pub path: Vec<NodeId>,
+    /// The round-trip time from the advertising node to the node, in milliseconds.
+    pub rtt_ms: u64,
+    /// The overlay addresses of the node.
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Information about the route selected to a node.
+#[derive(Debug, Clone, Serialize)]
+pub struct MeshRouteInfo {
+    pub node_id: NodeId,
+    /// The peers the traffic goes through, ending with the node.
+    pub path: Vec<NodeId>,
+    /// The round-trip time to the node, in milliseconds.
+    pub rtt_ms: u64,
+    pub addresses: Vec<IpAddr>,
+}
+
+/// Messages that can be sent to [MeshRouter].
+pub enum MeshRouterMessage {
+    /// Asks [MeshRouter] for the routes selected to the reachable nodes.
+    ListRoutes(Reply<Vec<MeshRouteInfo>>),
+}
+
+/// Exchanges routes with the peers, and routes the traffic to the nodes of the mesh.
+pub struct MeshRouter {
+    address: Addr<MeshRouterMessage>,
+    receiver: Mailbox<MeshRouterMessage>,
+    node_id: NodeId,
+    peer_collection: Addr<PeerCollectionMessage>,
+    advertisements: Advertisements,
+    /// The replicated state, assigning the overlay addresses of the nodes.
+    state: NetworkState,
+    /// The advertisement of each connected peer.
+    peers: HashMap<NodeId, Advertisement>,
+    /// The route selected to each reachable node.
+    routes: HashMap<NodeId, MeshRoute>,
+    selection_timer: Interval,
+    event_receiver: broadcast::Receiver<Event>,
+}
+
+impl MeshRouter {
+    /// Creates a new [MeshRouter] for the node with the given [NodeId].
+    ///
+    /// The routes are exchanged with the peers through `advertisements`, and the addresses of
+    /// the nodes are checked against `state`.
+    pub fn new(
+        node_id: NodeId,
+        peer_collection: Addr<PeerCollectionMessage>,
+        advertisements: Advertisements,
+        state: NetworkState,
+        events: &Events,
+    ) -> Self {
+        let (address, receiver) = mailbox(16, OverflowPolicy::Block);
+        let mut selection_timer = interval(SELECTION_INTERVAL);
+        selection_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            address,
+            receiver,
+            node_id,
+            peer_collection,
+            advertisements,
+            state,
+            peers: HashMap::new(),
+            routes: HashMap::new(),
+            selection_timer,
+            event_receiver: events.subscribe(),
+        }
+    }
+
+    /// Handles a received message.
+    fn handle_message(&mut self, message: MeshRouterMessage) {
+        match message {
+            MeshRouterMessage::ListRoutes(reply) => {
+                reply.send(
+                    self.routes
+                        .values()
+                        .map(|route| MeshRouteInfo {
+                            node_id: route.node_id,
+                            path: route.path.clone(),
+                            rtt_ms: route.rtt_ms,
+                            addresses: route.addresses.clone(),
+                        })
+                        .collect(),
+                );
+            }
+        }
+    }
+
+    /// Selects the route with the lowest round-trip time to every reachable node,
+    /// then updates the routes in the peer collection and the advertisement of this node.
+    async fn select_routes(&mut self) {
+        let mut routes: HashMap<NodeId, MeshRoute> = HashMap::new();
+        for (peer, advertisement) in &self.peers {
+            let Some(rtt) = self.advertisements.rtt(*peer) else {
+                continue;
+            };
+            let rtt_ms = rtt.as_millis() as u64;
+            let direct = MeshRoute {
+                node_id: *peer,
+                path: vec![*peer],
+                rtt_ms,
+                addresses: self.assigned(*peer, &advertisement.addresses),
+            };
+            let through_peer = advertisement
+                .mesh_routes
+                .iter()
+                // Routes through this node would loop
+                .filter(|route| !route.path.contains(&self.node_id) && route.path.len() < MAX_HOPS)
+                .map(|route| MeshRoute {
+                    node_id: route.node_id,
+                    path: std::iter::once(*peer)
+                        .chain(route.path.iter().copied())
+                        .collect(),
+                    rtt_ms: rtt_ms.saturating_add(route.rtt_ms),
+                    addresses: self.assigned(route.node_id, &route.addresses),
+                });
+            for route in std::iter::once(direct).chain(through_peer) {
+                match routes.get(&route.node_id) {
+                    Some(selected) if !is_better(&route, selected) => {}
+                    _ => {
+                        routes.insert(route.node_id, route);
+                    }
+                }
+            }
+        }
+
+        let paths_changed = routes.len() != self.routes.len()
+            || routes.iter().any(|(node_id, route)| {
+                self.routes
+                    .get(node_id)
+                    .is_none_or(|selected| selected.path != route.path)
+            });
+        let rtts_changed = routes.iter().any(|(node_id, route)| {
+            self.routes.get(node_id).is_some_and(|selected| {
+                selected.rtt_ms.abs_diff(route.rtt_ms) > RTT_TOLERANCE.as_millis() as u64
+            })
+        });
+        let addresses_changed = routes.iter().any(|(node_id, route)| {
+            self.routes
+                .get(node_id)
+                .is_some_and(|selected| selected.addresses != route.addresses)
+        });
+        if !paths_changed && !rtts_changed && !addresses_changed {
+            return;
+        }
+        if paths_changed {
+            for route in routes.values() {
+                if self.routes.get(&route.node_id).map(|r| &r.path) != Some(&route.path) {
+                    info!("Reaching {} through {:?}", route.node_id, route.path);
+                }
+            }
+            for node_id in self.routes.keys() {
+                if !routes.contains_key(node_id) {
+                    info!("Can't reach {} anymore", node_id);
+                }
+            }
+        }
+        self.routes = routes;
+        if paths_changed || addresses_changed {
+            self.peer_collection
+                .send_message(PeerCollectionMessage::SetMeshRoutes(self.next_hops()))
+                .await;
+        }
+        let mut advertised: Vec<MeshRoute> = self.routes.values().cloned().collect();
+        advertised.sort_by_key(|route| route.node_id);
+        self.advertisements
+            .update_own(|own| own.mesh_routes = advertised);
+    }
+
+    /// Returns the `addresses` assigned to the node with the given [NodeId] in the state.
+    fn assigned(&self, node_id: NodeId, addresses: &[IpAddr]) -> Vec<IpAddr> {
+        addresses
+            .iter()
+            .copied()
+            .filter(|address| self.state.address_owner(*address) == Some(node_id))
+            .collect()
+    }
+
+    /// Returns the peer the traffic to each address of the reachable nodes goes to.
+    fn next_hops(&self) -> HashMap<IpAddr, NodeId> {
+        // Sorted, so the same node wins an address claimed by several whatever the order of the map
+        let mut routes: Vec<&MeshRoute> = self.routes.values().collect();
+        routes.sort_by_key(|route| (route.rtt_ms, route.node_id));
+        let mut next_hops = HashMap::new();
+        for route in routes {
+            for address in &route.addresses {
+                next_hops.entry(*address).or_insert(route.path[0]);
+            }
+        }
+        next_hops
+    }
+
+    /// Runs the actor, following the advertisements and disconnections of the peers.
+    pub async fn run(mut self) {
+        loop {
+            select! {
+                Some(message) = self.receiver.recv() => {
+                    self.handle_message(message);
+                }
+                _ = self.selection_timer.tick() => {
+                    self.select_routes().await;
+                }
+                event = self.event_receiver.recv() => match event {
+                    Ok(Event::Advertised { node_id, advertisement }) => {
+                        self.peers.insert(node_id, advertisement);
+                        self.select_routes().await;
+                    }
+                    Ok(Event::PeerDisconnected { node_id, .. }) => {
+                        self.peers.remove(&node_id);
+                        self.select_routes().await;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Mesh router missed {} events", missed);
+                        self.peers = self.advertisements.peers().into_iter().collect();
+                        self.select_routes().await;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+}
+
+/// Checks whether `route` is preferred over `selected`: it has a lower round-trip time,
+/// then fewer hops, then a lower first peer.
+fn is_better(route: &MeshRoute, selected: &MeshRoute) -> bool {
+    (route.rtt_ms, route.path.len(), route.path[0])
+        < (selected.rtt_ms, selected.path.len(), selected.path[0])
+}
+
+impl Actor<MeshRouterMessage> for MeshRouter {
+    fn get_addr(&self) -> Addr<MeshRouterMessage> {
+        self.address.clone()
+    }
+}
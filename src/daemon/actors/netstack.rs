@@ -0,0 +1,561 @@
+//! Module for [NetStack] actor.
+//!
+//! It is responsible for a userspace TCP/IP stack, used instead of [Tun](super::tun::Tun)
+//! where a TUN device can't be created. It takes the packets received from peers from the
+//! packet router, and sends the packets it produces back to the packet router, like [Tun](super::tun::Tun).
+//!
+//! Local applications reach the overlay through TCP connections opened with
+//! [NetStackMessage::Connect], and local ports are published to the overlay with
+//! [NetStackConfig::published_ports].
+
+use std::{collections::VecDeque, io, net::SocketAddr, sync::Arc, time::Duration};
+
+use ipnet::IpNet;
+use smoltcp::{
+    iface::{Config, Interface, SocketHandle, SocketSet},
+    phy::{self, DeviceCapabilities, Medium},
+    socket::tcp,
+    time::Instant,
+    wire::{HardwareAddress, IpAddress, IpCidr, IpEndpoint},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    select,
+    sync::{
+        mpsc::{self, error::TryRecvError, error::TrySendError},
+        Notify,
+    },
+};
+use tracing::{debug, warn};
+
+use crate::daemon::{packet::Packet, DaemonError};
+
+use super::{
+    mailbox::{mailbox, Mailbox, MailboxKeeper, OverflowPolicy},
+    Actor, Addr, Reply,
+};
+
+/// The MTU of the userspace stack.
+pub(super) const MTU: usize = 1500;
+/// The size of the receive and the send buffer of each TCP socket.
+const SOCKET_BUFFER_SIZE: usize = 64 * 1024;
+/// How many chunks of data can wait in each direction of a connection.
+const CHANNEL_CAPACITY: usize = 16;
+/// The size of the chunks data is read in.
+const CHUNK_SIZE: usize = 16 * 1024;
+/// The longest time between two polls of the stack.
+pub(super) const MAX_POLL_DELAY: Duration = Duration::from_millis(100);
+/// The first local port used for outgoing connections.
+const FIRST_EPHEMERAL_PORT: u16 = 49152;
+
+/// Settings of the userspace network stack.
+#[derive(Debug, Clone, Default)]
+pub struct NetStackConfig {
+    /// Overlay addresses of this node, with their prefix lengths. At most 8 are allowed.
+    pub addresses: Vec<IpNet>,
+    /// Local address of the SOCKS5 proxy into the overlay. Disabled when not set.
+    pub socks_proxy: Option<SocketAddr>,
+    /// Local address of the HTTP proxy into the overlay. Disabled when not set.
+    pub http_proxy: Option<SocketAddr>,
+    /// Local ports published to the overlay.
+    pub published_ports: Vec<PublishedPort>,
+}
+
+/// A local port published to the overlay.
+#[derive(Debug, Clone, Copy)]
+pub struct PublishedPort {
+    /// The TCP port accepting connections on the overlay addresses.
+    pub port: u16,
+    /// The local address the connections are forwarded to.
+    pub target: SocketAddr,
+}
+
+/// Messages that can be sent to [NetStack].
+#[derive(Debug)]
+pub enum NetStackMessage {
+    /// Opens a TCP connection to a host in the overlay.
+    ///
+    /// The reply is sent once the connection is established or has failed.
+    Connect(SocketAddr, Reply<io::Result<StackStream>>),
+}
+
+/// One end of a TCP connection through the userspace stack.
+#[derive(Debug)]
+pub struct StackStream {
+    to_stack: mpsc::Sender<Vec<u8>>,
+    from_stack: mpsc::Receiver<Vec<u8>>,
+    wake: Arc<Notify>,
+}
+
+impl StackStream {
+    /// Sends `data` through this connection.
+    pub async fn send(&self, data: &[u8]) -> io::Result<()> {
+        self.to_stack
+            .send(data.to_vec())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.wake.notify_one();
+        Ok(())
+    }
+
+    /// Copies data in both directions between this connection and `tcp`, until both are closed.
+    pub async fn pipe(self, tcp: TcpStream) -> io::Result<()> {
+        let Self {
+            to_stack,
+            mut from_stack,
+            wake,
+        } = self;
+        let (mut tcp_read, mut tcp_write) = tcp.into_split();
+        let upload = async {
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            loop {
+                let size = tcp_read.read(&mut buffer).await?;
+                if size == 0 {
+                    break;
+                }
+                if to_stack.send(buffer[..size].to_vec()).await.is_err() {
+                    return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+                }
+                wake.notify_one();
+            }
+            // Dropping the sender closes the connection in the stack
+            drop(to_stack);
+            wake.notify_one();
+            Ok(())
+        };
+        let download = async {
+            while let Some(data) = from_stack.recv().await {
+                wake.notify_one();
+                tcp_write.write_all(&data).await?;
+            }
+            tcp_write.shutdown().await
+        };
+        tokio::try_join!(upload, download)?;
+        Ok(())
+    }
+}
+
+/// The [phy::Device] of the stack, backed by queues of packets.
+pub(super) struct QueueDevice {
+    pub(super) received: VecDeque<Arc<[u8]>>,
+    pub(super) transmitted: VecDeque<Vec<u8>>,
+}
+
+impl QueueDevice {
+    /// Creates a device with empty queues.
+    pub(super) fn new() -> Self {
+        Self {
+            received: VecDeque::new(),
+            transmitted: VecDeque::new(),
+        }
+    }
+}
+
+pub(super) struct QueueRxToken(Vec<u8>);
+
+pub(super) struct QueueTxToken<'a>(&'a mut VecDeque<Vec<u8>>);
+
+impl phy::RxToken for QueueRxToken {
+    fn consume<R, F>(mut self, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        f(&mut self.0)
+    }
+}
+
+impl phy::TxToken for QueueTxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut packet = vec![0u8; len];
+        let result = f(&mut packet);
+        self.0.push_back(packet);
+        result
+    }
+}
+
+impl phy::Device for QueueDevice {
+    type RxToken<'a> = QueueRxToken;
+    type TxToken<'a> = QueueTxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let packet = self.received.pop_front()?;
+        Some((
+            QueueRxToken(packet.to_vec()),
+            QueueTxToken(&mut self.transmitted),
+        ))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
+        Some(QueueTxToken(&mut self.transmitted))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = MTU;
+        capabilities
+    }
+}
+
+/// The state of a TCP connection kept by [NetStack].
+pub(super) struct Connection {
+    pub(super) handle: SocketHandle,
+    /// Sends data received by the socket. [None] once the peer closed its side.
+    to_app: Option<mpsc::Sender<Vec<u8>>>,
+    from_app: mpsc::Receiver<Vec<u8>>,
+    /// Data from the application not yet accepted by the socket.
+    pending: Vec<u8>,
+    /// Whether the application closed its side.
+    app_closed: bool,
+    /// The reply to an outgoing connection, with the stream to send once it is established.
+    connecting: Option<(Reply<io::Result<StackStream>>, StackStream)>,
+}
+
+/// Creates a [Connection] for the socket with the given handle, and the [StackStream] of the application.
+pub(super) fn connection(handle: SocketHandle, wake: &Arc<Notify>) -> (Connection, StackStream) {
+    let (to_app, from_stack) = mpsc::channel(CHANNEL_CAPACITY);
+    let (to_stack, from_app) = mpsc::channel(CHANNEL_CAPACITY);
+    let connection = Connection {
+        handle,
+        to_app: Some(to_app),
+        from_app,
+        pending: Vec::new(),
+        app_closed: false,
+        connecting: None,
+    };
+    let stream = StackStream {
+        to_stack,
+        from_stack,
+        wake: wake.clone(),
+    };
+    (connection, stream)
+}
+
+/// Creates a TCP socket with the default buffers.
+pub(super) fn tcp_socket() -> tcp::Socket<'static> {
+    tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0u8; SOCKET_BUFFER_SIZE]),
+    )
+}
+
+/// An actor running a userspace TCP/IP stack on the overlay.
+pub struct NetStack {
+    packet_address: Addr<Packet>,
+    packet_receiver: Mailbox<Packet>,
+    message_address: Addr<NetStackMessage>,
+    message_receiver: Mailbox<NetStackMessage>,
+    packet_router: Addr<Packet>,
+    interface: Interface,
+    device: QueueDevice,
+    sockets: SocketSet<'static>,
+    connections: Vec<Connection>,
+    /// Listening sockets of the published ports.
+    listeners: Vec<(SocketHandle, PublishedPort)>,
+    next_port: u16,
+    /// Woken up by the applications when they send or receive data.
+    wake: Arc<Notify>,
+}
+
+impl NetStack {
+    /// Creates a new [NetStack] with the addresses and published ports from `config`.
+    pub fn new(packet_router: Addr<Packet>, config: &NetStackConfig) -> Result<Self, DaemonError> {
+        let packets = mailbox(16, OverflowPolicy::DropNewest);
+        let messages = mailbox(16, OverflowPolicy::Block);
+        Self::from_mailboxes(packets, messages, packet_router, config)
+    }
+
+    /// Creates a [NetStack] receiving the packets and messages of existing mailboxes.
+    ///
+    /// The stack starts over, without the connections of the previous one.
+    pub fn from_mailboxes(
+        (packet_address, packet_receiver): (Addr<Packet>, Mailbox<Packet>),
+        (message_address, message_receiver): (Addr<NetStackMessage>, Mailbox<NetStackMessage>),
+        packet_router: Addr<Packet>,
+        config: &NetStackConfig,
+    ) -> Result<Self, DaemonError> {
+        let mut device = QueueDevice::new();
+        let mut interface_config = Config::new(HardwareAddress::Ip);
+        interface_config.random_seed = rand::random();
+        let mut interface = Interface::new(interface_config, &mut device, Instant::now());
+        let mut too_many_addresses = false;
+        interface.update_ip_addrs(|addresses| {
+            for address in &config.addresses {
+                let cidr = IpCidr::new(IpAddress::from(address.addr()), address.prefix_len());
+                too_many_addresses |= addresses.push(cidr).is_err();
+            }
+        });
+        if too_many_addresses {
+            return Err(DaemonError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the userspace stack supports at most 8 addresses",
+            )));
+        }
+        // Hosts outside of the overlay prefixes, such as advertised subnets, are reached
+        // the same way, through the packet router
+        for address in &config.addresses {
+            let _ = match IpAddress::from(address.addr()) {
+                IpAddress::Ipv4(address) => interface.routes_mut().add_default_ipv4_route(address),
+                IpAddress::Ipv6(address) => interface.routes_mut().add_default_ipv6_route(address),
+            };
+        }
+        let mut net_stack = Self {
+            packet_address,
+            packet_receiver,
+            message_address,
+            message_receiver,
+            packet_router,
+            interface,
+            device,
+            sockets: SocketSet::new(Vec::new()),
+            connections: Vec::new(),
+            listeners: Vec::new(),
+            next_port: FIRST_EPHEMERAL_PORT,
+            wake: Arc::new(Notify::new()),
+        };
+        for published_port in &config.published_ports {
+            net_stack.listen(*published_port)?;
+        }
+        Ok(net_stack)
+    }
+
+    /// Returns keepers of the mailboxes, to start the actor again with [NetStack::from_mailboxes].
+    pub fn mailbox_keepers(&self) -> (MailboxKeeper<Packet>, MailboxKeeper<NetStackMessage>) {
+        (
+            self.packet_receiver.keeper(),
+            self.message_receiver.keeper(),
+        )
+    }
+
+    /// Adds a socket listening on the port of `published_port`.
+    fn listen(&mut self, published_port: PublishedPort) -> Result<(), DaemonError> {
+        let mut socket = tcp_socket();
+        socket.listen(published_port.port).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("can't publish port {}: {}", published_port.port, error),
+            )
+        })?;
+        let handle = self.sockets.add(socket);
+        self.listeners.push((handle, published_port));
+        Ok(())
+    }
+
+    /// Handles a received message.
+    fn handle_message(&mut self, message: NetStackMessage) {
+        match message {
+            NetStackMessage::Connect(remote, reply) => {
+                let mut socket = tcp_socket();
+                let local_port = self.ephemeral_port();
+                let result = socket.connect(
+                    self.interface.context(),
+                    IpEndpoint::from(remote),
+                    local_port,
+                );
+                if let Err(error) = result {
+                    reply.send(Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        error.to_string(),
+                    )));
+                    return;
+                }
+                let handle = self.sockets.add(socket);
+                let (mut connection, stream) = connection(handle, &self.wake);
+                connection.connecting = Some((reply, stream));
+                self.connections.push(connection);
+            }
+        }
+    }
+
+    /// Returns the next local port for an outgoing connection.
+    fn ephemeral_port(&mut self) -> u16 {
+        let port = self.next_port;
+        self.next_port = self
+            .next_port
+            .checked_add(1)
+            .unwrap_or(FIRST_EPHEMERAL_PORT);
+        port
+    }
+
+    /// Turns the listening sockets that accepted a connection into connections
+    /// forwarded to their targets, and listens again on their ports.
+    fn accept_connections(&mut self) {
+        let mut index = 0;
+        while index < self.listeners.len() {
+            let (handle, published_port) = self.listeners[index];
+            if self.sockets.get::<tcp::Socket>(handle).state() == tcp::State::Listen {
+                index += 1;
+                continue;
+            }
+            self.listeners.swap_remove(index);
+            let (connection, stream) = connection(handle, &self.wake);
+            self.connections.push(connection);
+            tokio::spawn(forward_to_local(stream, published_port.target));
+            if let Err(error) = self.listen(published_port) {
+                warn!("Couldn't listen again. Reason: {:?}", error);
+            }
+        }
+    }
+
+    /// Moves data between the sockets and the applications, and removes closed connections.
+    fn service_connections(&mut self) {
+        let sockets = &mut self.sockets;
+        self.connections.retain_mut(|connection| {
+            let socket = sockets.get_mut::<tcp::Socket>(connection.handle);
+            let keep = service_connection(connection, socket);
+            if !keep {
+                sockets.remove(connection.handle);
+            }
+            keep
+        });
+    }
+
+    /// Polls the stack and sends the packets it produced to the packet router.
+    async fn poll(&mut self) {
+        self.interface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        self.accept_connections();
+        self.service_connections();
+        // Send the data written by the applications right away
+        self.interface
+            .poll(Instant::now(), &mut self.device, &mut self.sockets);
+        while let Some(packet) = self.device.transmitted.pop_front() {
+            self.packet_router
+                .send_message(Packet::Outgoing(Arc::from(packet)))
+                .await;
+        }
+    }
+
+    /// Runs the actor, continuously processing packets, messages and connections.
+    pub async fn run(mut self) {
+        loop {
+            self.poll().await;
+            let delay = self
+                .interface
+                .poll_delay(Instant::now(), &self.sockets)
+                .map_or(MAX_POLL_DELAY, |delay| {
+                    Duration::from_micros(delay.total_micros()).min(MAX_POLL_DELAY)
+                });
+            select! {
+                Some(packet) = self.packet_receiver.recv() => {
+                    if let Packet::Incoming(_, data) = packet {
+                        self.device.received.push_back(data);
+                    }
+                }
+                Some(message) = self.message_receiver.recv() => {
+                    self.handle_message(message);
+                }
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(delay) => {}
+            }
+        }
+    }
+}
+
+/// Moves data between `socket` and the application of `connection`.
+///
+/// Returns whether the connection should be kept.
+pub(super) fn service_connection(connection: &mut Connection, socket: &mut tcp::Socket) -> bool {
+    if let Some((reply, stream)) = connection.connecting.take() {
+        match socket.state() {
+            tcp::State::SynSent | tcp::State::SynReceived => {
+                connection.connecting = Some((reply, stream));
+                return true;
+            }
+            tcp::State::Closed | tcp::State::TimeWait => {
+                reply.send(Err(io::Error::from(io::ErrorKind::ConnectionRefused)));
+                return false;
+            }
+            _ => reply.send(Ok(stream)),
+        }
+    }
+
+    // From the application to the socket
+    loop {
+        if connection.pending.is_empty() && !connection.app_closed {
+            match connection.from_app.try_recv() {
+                Ok(data) => connection.pending = data,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    connection.app_closed = true;
+                    socket.close();
+                    break;
+                }
+            }
+        }
+        if connection.pending.is_empty() || !socket.can_send() {
+            break;
+        }
+        match socket.send_slice(&connection.pending) {
+            Ok(size) => {
+                connection.pending.drain(..size);
+            }
+            Err(_) => break,
+        }
+    }
+
+    // From the socket to the application
+    if let Some(to_app) = &connection.to_app {
+        while socket.can_recv() {
+            let permit = match to_app.try_reserve() {
+                Ok(permit) => permit,
+                Err(TrySendError::Full(())) => break,
+                Err(TrySendError::Closed(())) => {
+                    socket.abort();
+                    break;
+                }
+            };
+            let mut buffer = vec![0u8; CHUNK_SIZE];
+            match socket.recv_slice(&mut buffer) {
+                Ok(size) => {
+                    buffer.truncate(size);
+                    permit.send(buffer);
+                }
+                Err(_) => break,
+            }
+        }
+        let opening = matches!(
+            socket.state(),
+            tcp::State::Listen | tcp::State::SynSent | tcp::State::SynReceived
+        );
+        if !opening && !socket.may_recv() && !socket.can_recv() {
+            // The peer closed its side
+            connection.to_app = None;
+        }
+    }
+
+    socket.state() != tcp::State::Closed
+}
+
+/// Forwards a connection accepted on a published port to `target`.
+async fn forward_to_local(stream: StackStream, target: SocketAddr) {
+    let tcp = match TcpStream::connect(target).await {
+        Ok(tcp) => tcp,
+        Err(error) => {
+            warn!(
+                "Couldn't forward a connection to {}. Reason: {:?}",
+                target, error
+            );
+            return;
+        }
+    };
+    if let Err(error) = stream.pipe(tcp).await {
+        debug!("Forwarded connection to {} failed: {:?}", target, error);
+    }
+}
+
+impl Actor<Packet> for NetStack {
+    fn get_addr(&self) -> Addr<Packet> {
+        self.packet_address.clone()
+    }
+}
+
+impl Actor<NetStackMessage> for NetStack {
+    fn get_addr(&self) -> Addr<NetStackMessage> {
+        self.message_address.clone()
+    }
+}
@@ -2,48 +2,77 @@
 //!
 //! It is responsible for keeping a log of packets going through the program.
 
-use tokio::sync::mpsc;
+use tokio::select;
+use tracing::debug;
 
 use crate::daemon::packet::Packet;
 
-use super::{Actor, Addr};
+use super::{
+    mailbox::{mailbox, Mailbox, MailboxKeeper, OverflowPolicy},
+    Actor, Addr,
+};
 
 /// Represents a packet logger actor responsible for logging all packets.
 pub struct PacketLogger {
     /// The address used to send packets to this logger.
     address: Addr<Packet>,
 
-    /// The receiver channel for incoming packets.
-    receiver: mpsc::Receiver<Packet>,
+    /// The mailbox for incoming packets.
+    receiver: Mailbox<Packet>,
+
+    /// The address used to send the packets dropped by the pipeline to this logger.
+    dropped_address: Addr<Packet>,
+
+    /// The mailbox for dropped packets.
+    dropped_receiver: Mailbox<Packet>,
 }
 
 impl PacketLogger {
     /// Creates a new [PacketLogger] instance.
     ///
-    /// Returns a [PacketLogger] with its associated [Addr] for sending packets
-    /// and a receiver channel for receiving packets.
+    /// Returns a [PacketLogger] with its associated [Addr]s for sending packets
+    /// and dropped packets, and mailboxes for receiving them.
     pub fn new() -> Self {
-        // Create a channel for communication between sender and receiver.
-        let (sender, receiver) = mpsc::channel(16);
-        // Create a new `Addr` using the sender channel.
-        let address = Addr::new(sender);
-        Self { address, receiver }
+        // Create mailboxes dropping packets when full, so logging never stalls the router.
+        let packets = mailbox(16, OverflowPolicy::DropNewest);
+        let dropped = mailbox(16, OverflowPolicy::DropNewest);
+        Self::from_mailboxes(packets, dropped)
+    }
+
+    /// Creates a [PacketLogger] receiving the packets and dropped packets of existing mailboxes.
+    pub fn from_mailboxes(
+        (address, receiver): (Addr<Packet>, Mailbox<Packet>),
+        (dropped_address, dropped_receiver): (Addr<Packet>, Mailbox<Packet>),
+    ) -> Self {
+        Self {
+            address,
+            receiver,
+            dropped_address,
+            dropped_receiver,
+        }
+    }
+
+    /// Returns keepers of the mailboxes of packets and dropped packets, to start the actor again
+    /// with [PacketLogger::from_mailboxes].
+    pub fn mailbox_keepers(&self) -> (MailboxKeeper<Packet>, MailboxKeeper<Packet>) {
+        (self.receiver.keeper(), self.dropped_receiver.keeper())
+    }
+
+    /// Returns the address used to send the packets dropped by the pipeline to this logger.
+    pub fn dropped_addr(&self) -> Addr<Packet> {
+        self.dropped_address.clone()
     }
 
     /// Runs the packet logger asynchronously.
     ///
-    /// This method continuously receives packets from the receiver channel
-    /// and logs each received packet to standard error ([eprintln]).
+    /// This method continuously receives packets from the mailboxes
+    /// and logs each received packet at the debug level, tagging the dropped ones.
     pub async fn run(mut self) {
         loop {
-            // Attempt to receive a packet from the receiver channel.
-            let packet = match self.receiver.recv().await {
-                Some(packet) => packet,
-                None => continue, // If receive fails, continue to the next iteration.
-            };
-
-            // Log the received packet to standard error.
-            eprintln!("{:?}", packet);
+            select! {
+                Some(packet) = self.receiver.recv() => debug!("{:?}", packet),
+                Some(packet) = self.dropped_receiver.recv() => debug!("Dropped {:?}", packet),
+            }
         }
     }
 }
@@ -56,6 +85,6 @@ impl Default for PacketLogger {
 
 impl Actor<Packet> for PacketLogger {
     fn get_addr(&self) -> super::Addr<Packet> {
-        return self.address.clone();
+        self.address.clone()
     }
 }
@@ -1,17 +1,32 @@
 //! Module for [PacketRouter] actor.
 //!
 //! It is responsible for sending packet to right actors.
+//!
+//! Incoming packets going outside the overlay are only sent to the exit receiver, when set.
+//! Without one, they are sent to the incoming packet receivers like the others.
+//!
+//! Packets dropped by the pipeline are sent as they were received to the dropped packet receivers,
+//! such as the [PacketLogger](super::packet_logger::PacketLogger).
+
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 
-use tokio::sync::mpsc;
+use ipnet::IpNet;
 
-use crate::daemon::packet::Packet;
+use crate::daemon::{
+    packet::{ip::Flow, Packet},
+    pipeline::{Direction, Outcome, PacketProcessor, Pipeline},
+    DaemonError,
+};
 
-use super::{Actor, Addr};
+use super::{
+    mailbox::{mailbox, Mailbox, OverflowPolicy},
+    Actor, Addr,
+};
 
 /// Represents a packet routing actor responsible for distributing packets to multiple receivers.
 pub struct PacketRouter {
-    /// The receiver channel for incoming packets.
-    packet_receiver: mpsc::Receiver<Packet>,
+    /// The mailbox for incoming packets.
+    packet_receiver: Mailbox<Packet>,
 
     /// The address used to send packets to this router.
     address: Addr<Packet>,
@@ -21,23 +36,62 @@ pub struct PacketRouter {
 
     /// Collection of addresses of outgoing packet receivers connected to this router.
     outgoing_packet_receivers: Vec<Addr<Packet>>,
+
+    /// Collection of addresses of the receivers of the packets dropped by the pipeline.
+    dropped_packet_receivers: Vec<Addr<Packet>>,
+
+    /// The receiver of incoming packets going outside the overlay prefixes.
+    exit_receiver: Option<(Addr<Packet>, Vec<IpNet>)>,
+
+    /// The stages processing packets before they are forwarded, kept when the router is restarted.
+    pipeline: Arc<Mutex<Pipeline>>,
 }
 
 impl PacketRouter {
     /// Creates a new [PacketRouter] instance.
     ///
-    /// Returns a [PacketRouter] with its associated receiver channel for incoming packets,
+    /// Returns a [PacketRouter] with its associated mailbox for incoming packets,
     /// an [Addr] for sending packets to this router, and an empty list of packet receivers.
     pub fn new() -> Self {
-        let (packet_sender, packet_receiver) = mpsc::channel(16);
+        let (address, packet_receiver) = mailbox(16, OverflowPolicy::DropNewest);
         Self {
             packet_receiver,
-            address: Addr::new(packet_sender),
+            address,
             incoming_packet_receivers: Vec::new(),
             outgoing_packet_receivers: Vec::new(),
+            dropped_packet_receivers: Vec::new(),
+            exit_receiver: None,
+            pipeline: Default::default(),
+        }
+    }
+
+    /// Returns a function creating a [PacketRouter] on the same mailbox, with the same receivers
+    /// and stages, to start the actor again.
+    ///
+    /// Receivers and stages added afterwards are only known to this router.
+    pub fn restarter(&self) -> impl FnMut() -> Self + Send + 'static {
+        let keeper = self.packet_receiver.keeper();
+        let incoming_packet_receivers = self.incoming_packet_receivers.clone();
+        let outgoing_packet_receivers = self.outgoing_packet_receivers.clone();
+        let dropped_packet_receivers = self.dropped_packet_receivers.clone();
+        let exit_receiver = self.exit_receiver.clone();
+        let pipeline = self.pipeline.clone();
+        move || Self {
+            packet_receiver: keeper.reopen(),
+            address: keeper.addr(),
+            incoming_packet_receivers: incoming_packet_receivers.clone(),
+            outgoing_packet_receivers: outgoing_packet_receivers.clone(),
+            dropped_packet_receivers: dropped_packet_receivers.clone(),
+            exit_receiver: exit_receiver.clone(),
+            pipeline: pipeline.clone(),
         }
     }
 
+    /// Returns the pipeline, even if a stage panicked while processing a packet.
+    fn pipeline(&self) -> MutexGuard<'_, Pipeline> {
+        self.pipeline.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
     /// Adds a new incoming packet receiver to this router.
     ///
     /// Parameters:
@@ -54,31 +108,89 @@ impl PacketRouter {
         self.outgoing_packet_receivers.push(addr);
     }
 
+    /// Adds a new receiver of the packets dropped by the pipeline to this router.
+    ///
+    /// Parameters:
+    /// - `addr`: The address of the packet receiver to add.
+    pub fn add_dropped_packet_receiver(&mut self, addr: Addr<Packet>) {
+        self.dropped_packet_receivers.push(addr);
+    }
+
+    /// Sets the receiver of the incoming packets going outside the overlay.
+    ///
+    /// Parameters:
+    /// - `addr`: The address of the exit receiver.
+    /// - `overlay`: The overlay prefixes, whose packets go to the incoming packet receivers.
+    pub fn set_exit_receiver(&mut self, addr: Addr<Packet>, overlay: Vec<IpNet>) {
+        self.exit_receiver = Some((addr, overlay));
+    }
+
+    /// Returns the receivers of an incoming packet.
+    fn incoming_receivers(&self, packet: &Packet) -> &[Addr<Packet>] {
+        if let Some((addr, overlay)) = &self.exit_receiver {
+            let leaves_overlay = Flow::parse(packet.data()).is_some_and(|flow| {
+                !overlay
+                    .iter()
+                    .any(|prefix| prefix.contains(&flow.destination))
+            });
+            if leaves_overlay {
+                return std::slice::from_ref(addr);
+            }
+        }
+        &self.incoming_packet_receivers
+    }
+
+    /// Adds a processing stage at the end of the pipeline for both directions.
+    ///
+    /// Parameters:
+    /// - `stage`: The stage to add.
+    pub fn add_stage(&mut self, stage: Box<dyn PacketProcessor>) {
+        self.pipeline().add_stage(stage);
+    }
+
+    /// Sets the order of processing stages for a direction.
+    ///
+    /// Parameters:
+    /// - `direction`: The direction of packets the order applies to.
+    /// - `names`: The names of stages, in order. Stages not listed are skipped.
+    pub fn set_stage_order(
+        &mut self,
+        direction: Direction,
+        names: &[String],
+    ) -> Result<(), DaemonError> {
+        self.pipeline().set_order(direction, names)
+    }
+
     /// Runs the packet router asynchronously.
     ///
-    /// This method continuously receives packets from the receiver channel
-    /// and forwards each received packet to all connected packet receivers.
+    /// This method continuously receives packets from the mailbox
+    /// and forwards each received packet to all connected packet receivers of its outcome.
     pub async fn run(mut self) {
         loop {
-            // Attempt to receive a packet from the receiver channel.
+            // Attempt to receive a packet from the mailbox.
             let packet = match self.packet_receiver.recv().await {
                 Some(packet) => packet,
                 None => continue, // If receive fails, continue to the next iteration.
             };
 
-            match packet {
-                packet @ Packet::Outgoing(_) => {
-                    // Send the received packet to each connected outgoing packet receiver.
-                    for addr in &self.outgoing_packet_receivers {
-                        addr.send_message(packet.clone()).await;
-                    }
-                }
-                packet @ Packet::Incoming(_) => {
-                    // Send the received packet to each connected incoming packet receiver.
-                    for addr in &self.incoming_packet_receivers {
-                        addr.send_message(packet.clone()).await;
-                    }
+            // Run the packet through the processing stages.
+            let outcome = self.pipeline().process(packet.clone());
+            let (packet, receivers) = match outcome {
+                Outcome::Drop => (packet, &self.dropped_packet_receivers[..]),
+                Outcome::Deliver(packet @ Packet::Incoming(..)) => {
+                    let receivers = self.incoming_receivers(&packet);
+                    (packet, receivers)
                 }
+                Outcome::Deliver(packet) => (packet, &self.outgoing_packet_receivers[..]),
+                Outcome::Redirect(node_id, data) => (
+                    Packet::Redirected(node_id, data),
+                    &self.outgoing_packet_receivers[..],
+                ),
+            };
+
+            // Send the packet to each connected packet receiver of its direction.
+            for addr in receivers {
+                addr.send_message(packet.clone()).await;
             }
         }
     }
@@ -4,56 +4,93 @@
 
 use std::sync::Arc;
 
-use quinn::{RecvStream, SendStream};
-use tokio::{select, sync::mpsc};
+use iroh_net::NodeId;
+use quinn::{ConnectionError, ReadError, RecvStream, SendStream};
+use tokio::select;
+use tracing::info;
 
-use crate::daemon::packet::Packet;
+use crate::daemon::{events::DisconnectReason, packet::Packet, shutdown::SHUTDOWN_ERROR_CODE};
 
-use super::{Actor, Addr};
+use super::{
+    mailbox::{mailbox, Mailbox, OverflowPolicy},
+    Actor, Addr,
+};
 
 /// Represents a peer actor responsible for transmitting data to and from a peer.
 pub struct Peer {
+    node_id: NodeId,
     packet_address: Addr<Packet>,
-    packet_receiver: mpsc::Receiver<Packet>,
+    packet_receiver: Mailbox<Packet>,
     peer_collection: Addr<Packet>,
     send_stream: SendStream,
     recv_stream: RecvStream,
+    /// The size of the buffer packets are read into.
+    buffer_size: usize,
 }
 
 impl Peer {
     /// Creates a new instance with the given parameters.
+    ///
+    /// Packets are read into a buffer of `buffer_size` bytes, which should fit the MTU.
     pub fn new(
+        node_id: NodeId,
         peer_collection: Addr<Packet>,
         send_stream: SendStream,
         recv_stream: RecvStream,
+        buffer_size: usize,
     ) -> Self {
-        let (packet_sender, packet_receiver) = mpsc::channel(16);
+        let (packet_address, packet_receiver) = mailbox(16, OverflowPolicy::DropNewest);
         Self {
-            packet_address: Addr::new(packet_sender),
+            node_id,
+            packet_address,
             packet_receiver,
             peer_collection,
             send_stream,
             recv_stream,
+            buffer_size,
         }
     }
     /// Sends received packets from the peer's receive stream to the peer collection.
-    async fn send_packets(mut recv_stream: RecvStream, peer_collection: Addr<Packet>) {
-        let mut buffer = vec![0u8; 1518];
-        while let Ok(Some(size)) = recv_stream.read(&mut buffer).await {
-            peer_collection
-                .send_message(Packet::Incoming(Arc::from(&buffer[0..size])))
-                .await;
+    async fn send_packets(
+        node_id: NodeId,
+        mut recv_stream: RecvStream,
+        peer_collection: Addr<Packet>,
+        buffer_size: usize,
+    ) -> DisconnectReason {
+        let mut buffer = vec![0u8; buffer_size];
+        loop {
+            match recv_stream.read(&mut buffer).await {
+                Ok(Some(size)) => {
+                    peer_collection
+                        .send_message(Packet::Incoming(node_id, Arc::from(&buffer[0..size])))
+                        .await;
+                }
+                Err(ReadError::ConnectionLost(ConnectionError::ApplicationClosed(close)))
+                    if close.error_code == SHUTDOWN_ERROR_CODE =>
+                {
+                    info!("Peer {} is shutting down", node_id);
+                    return DisconnectReason::PeerShutdown;
+                }
+                Ok(None) => return DisconnectReason::Closed,
+                Err(error) => {
+                    return DisconnectReason::ConnectionLost {
+                        error: error.to_string(),
+                    }
+                }
+            }
         }
     }
     /// Receives outgoing packets from the peer collection and sends them via the send stream.
     async fn recv_packets(
         mut send_stream: SendStream,
-        mut packet_receiver: mpsc::Receiver<Packet>,
-    ) {
+        mut packet_receiver: Mailbox<Packet>,
+    ) -> DisconnectReason {
         loop {
             if let Some(Packet::Outgoing(packet)) = packet_receiver.recv().await {
-                if send_stream.write(&packet).await.is_err() {
-                    return;
+                if let Err(error) = send_stream.write(&packet).await {
+                    return DisconnectReason::ConnectionLost {
+                        error: error.to_string(),
+                    };
                 }
             } else {
                 continue;
@@ -61,10 +98,17 @@ impl Peer {
         }
     }
     /// Runs the actor, handling send and receive operations concurrently.
-    pub async fn run(self) {
+    ///
+    /// Returns why the peer was disconnected.
+    pub async fn run(self) -> DisconnectReason {
         select! {
-            _ = Self::send_packets(self.recv_stream, self.peer_collection) => {}
-            _ = Self::recv_packets(self.send_stream, self.packet_receiver) => {}
+            reason = Self::send_packets(
+                self.node_id,
+                self.recv_stream,
+                self.peer_collection,
+                self.buffer_size,
+            ) => reason,
+            reason = Self::recv_packets(self.send_stream, self.packet_receiver) => reason,
         }
     }
 }
@@ -1,15 +1,59 @@
 //! Module for [PeerCollection] actor.
 //!
 //! It is responsible for managing connected peers.
+//!
+//! Outgoing packets are sent to every peer, except:
+//! - the packets to a prefix routed through a peer, sent only to that peer,
+//!   the longest matching prefix winning;
+//! - when an exit node is selected, the other packets leaving the overlay prefixes,
+//!   sent only to the exit node.
+//!
+//! A prefix can have several gateways, the peers advertising it. Its packets are sent only to
+//! the primary gateway: the connected gateway answering keepalives with the highest priority.
+//! When the primary disconnects or stops answering keepalives, the traffic fails over to the
+//! next one. Moving back to a preferred gateway follows the [FailbackPolicy].
+//!
+//! The packets to the addresses of the nodes of the mesh are sent only to the first peer of
+//! the path selected by the [MeshRouter](super::mesh_router::MeshRouter). The packets received
+//! from a peer for the address of another node are forwarded on its path through the packet
+//! router, which runs them through the pipeline instead of passing them to the TUN device.
+//!
+//! Where the outgoing packets go is published in a [RouteTable], so the stages of the pipeline,
+//! such as the [Firewall](crate::daemon::firewall::Firewall), know which peer they are sent to.
 
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
+use ipnet::IpNet;
 use iroh_net::NodeId;
-use tokio::{select, sync::mpsc, task::AbortHandle};
+use serde::Serialize;
+use tokio::{
+    select,
+    task::AbortHandle,
+    time::{interval, Interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
 
-use crate::daemon::packet::Packet;
+use crate::daemon::{
+    events::{DisconnectReason, Event, Events, FailoverReason},
+    packet::{
+        ip::{decrement_hop_limit, Flow},
+        Packet,
+    },
+};
 
-use super::{peer::Peer, Actor, Addr};
+use super::{
+    mailbox::{mailbox, Mailbox, MailboxKeeper, OverflowPolicy},
+    peer::Peer,
+    Actor, Addr, Reply,
+};
 
 /// Messages that can be sent to [PeerCollection].
 pub enum PeerCollectionMessage {
@@ -17,33 +61,288 @@ pub enum PeerCollectionMessage {
     AddPeer(NodeId, Peer),
     /// Instructs [PeerCollection] to remove a peer identified by the given [NodeId].
     DisconnectPeer(NodeId),
+    /// Tells [PeerCollection] that the [Peer] of the given generation with the given [NodeId]
+    /// stopped for the given reason.
+    PeerStopped(NodeId, u64, DisconnectReason),
+    /// Asks [PeerCollection] for the information about all connected peers.
+    ListPeers(Reply<Vec<PeerInfo>>),
+    /// Instructs [PeerCollection] to send the traffic leaving the overlay only to the peer
+    /// with the given [NodeId], or to every peer when [None].
+    SetExit(Option<NodeId>),
+    /// Asks [PeerCollection] for the selected exit node.
+    GetExit(Reply<Option<NodeId>>),
+    /// Instructs [PeerCollection] to send the packets to a prefix only to the peer
+    /// with the given [NodeId], until it disconnects.
+    AddRoute(IpNet, NodeId),
+    /// Instructs [PeerCollection] to remove the route to a prefix.
+    RemoveRoute(IpNet),
+    /// Instructs [PeerCollection] to add the peer with the given [NodeId] to the gateways
+    /// of a prefix, or to update its priority.
+    AddGateway(IpNet, NodeId, u32),
+    /// Instructs [PeerCollection] to remove the peer with the given [NodeId] from the gateways
+    /// of a prefix. The route to the prefix is removed with its last gateway.
+    RemoveGateway(IpNet, NodeId),
+    /// Tells [PeerCollection] whether the peer with the given [NodeId] answers keepalives.
+    SetResponsive(NodeId, bool),
+    /// Asks [PeerCollection] for the prefixes routed through gateways.
+    ListGateways(Reply<Vec<GatewayGroupInfo>>),
+    /// Asks [PeerCollection] how many times the traffic to a prefix moved to another gateway.
+    GetFailovers(Reply<u64>),
+    /// Instructs [PeerCollection] to send the packets to each address of the nodes of the mesh
+    /// only to the peer with the given [NodeId], replacing the previous mesh routes.
+    SetMeshRoutes(HashMap<IpAddr, NodeId>),
 }
+
+/// Where the outgoing packets of a [PeerCollection] go, shared with the stages of the pipeline.
+#[derive(Clone, Default)]
+pub struct RouteTable {
+    routes: Arc<RwLock<Routes>>,
+}
+
+impl RouteTable {
+    /// Returns the peer the outgoing packets to `destination` are sent to.
+    ///
+    /// The peer is [None] when the packets are dropped, while no gateway of their prefix is
+    /// connected. Returns [None] when they are sent to every peer.
+    pub fn lookup(&self, destination: IpAddr) -> Option<Option<NodeId>> {
+        self.routes.read().unwrap().lookup(destination)
+    }
+}
+
+/// The routes of a [PeerCollection], as last published to its [RouteTable].
+#[derive(Default)]
+struct Routes {
+    /// The peer of each prefix routed through a peer, [None] when no gateway is connected.
+    prefixes: Vec<(IpNet, Option<NodeId>)>,
+    mesh: HashMap<IpAddr, NodeId>,
+    exit: Option<NodeId>,
+    overlay: Vec<IpNet>,
+}
+
+impl Routes {
+    /// Returns the peer of the longest prefix routed through a peer containing `destination`,
+    /// or else the exit node when `destination` leaves the overlay.
+    ///
+    /// The addresses of the nodes of the mesh come first.
+    fn lookup(&self, destination: IpAddr) -> Option<Option<NodeId>> {
+        if let Some(node_id) = self.mesh.get(&destination) {
+            return Some(Some(*node_id));
+        }
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| prefix.contains(&destination))
+            .max_by_key(|(prefix, _)| prefix.prefix_len())
+            .map(|(_, via)| *via)
+            .or_else(|| {
+                self.exit
+                    .filter(|_| self.leaves_overlay(destination))
+                    .map(Some)
+            })
+    }
+    /// Checks whether `destination` is a unicast address outside of the overlay prefixes.
+    fn leaves_overlay(&self, destination: IpAddr) -> bool {
+        let unicast = match destination {
+            IpAddr::V4(destination) => !destination.is_multicast() && !destination.is_broadcast(),
+            IpAddr::V6(destination) => !destination.is_multicast(),
+        };
+        unicast
+            && !self
+                .overlay
+                .iter()
+                .any(|prefix| prefix.contains(&destination))
+    }
+}
+
+/// How long [FailbackPolicy::default] waits before moving back to a preferred gateway.
+const FAILBACK_DELAY: Duration = Duration::from_secs(30);
+/// How often the primary gateways are elected again, so delayed failbacks happen.
+const ELECTION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The generation of the next peer. It isn't reset when the collection is restarted,
+/// so a [PeerCollectionMessage::PeerStopped] left in its mailbox matches no peer.
+static NEXT_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// When the traffic to a prefix moves back to a gateway with a higher priority than the primary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailbackPolicy {
+    /// Keeps the primary until it fails.
+    Never,
+    /// Moves back as soon as the preferred gateway is available.
+    Immediate,
+    /// Moves back once the preferred gateway has been available for the given time.
+    Delayed(Duration),
+}
+
+impl Default for FailbackPolicy {
+    fn default() -> Self {
+        Self::Delayed(FAILBACK_DELAY)
+    }
+}
+
+/// Information about a connected peer.
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerInfo {
+    /// The [NodeId] of the peer.
+    pub node_id: NodeId,
+    /// Number of packets for the peer dropped because its mailbox was full.
+    pub dropped_packets: u64,
+    /// Whether the peer answers keepalives.
+    pub responsive: bool,
+}
+
+/// Information about a gateway of a prefix.
+#[derive(Debug, Clone, Serialize)]
+pub struct GatewayInfo {
+    pub node_id: NodeId,
+    pub priority: u32,
+    pub connected: bool,
+    /// Whether the gateway answers keepalives.
+    pub responsive: bool,
+}
+
+/// Information about a prefix routed through gateways.
+#[derive(Debug, Clone, Serialize)]
+pub struct GatewayGroupInfo {
+    pub prefix: IpNet,
+    /// The gateway the packets to the prefix are sent to. [None] when no gateway is connected.
+    pub primary: Option<NodeId>,
+    pub gateways: Vec<GatewayInfo>,
+    /// How many times the traffic to the prefix moved to another gateway.
+    pub failovers: u64,
+}
+
 struct PeerWrapper {
     abort_handle: AbortHandle,
     address: Addr<Packet>,
+    /// Tells the peer apart from the previous and next peers of the same node.
+    generation: u64,
+    /// Since when the peer answers keepalives. [None] while it doesn't.
+    responsive_since: Option<Instant>,
+}
+
+/// The gateways of a prefix.
+struct GatewayGroup {
+    /// The priority of each gateway.
+    gateways: HashMap<NodeId, u32>,
+    primary: Option<NodeId>,
+    failovers: u64,
 }
 /// Manages a collection of peers and handles peer-related messages and packet routing.
 pub struct PeerCollection {
     message_address: Addr<PeerCollectionMessage>,
-    message_receiver: mpsc::Receiver<PeerCollectionMessage>,
+    message_receiver: Mailbox<PeerCollectionMessage>,
     router_address: Addr<Packet>,
     packet_address: Addr<Packet>,
-    packet_receiver: mpsc::Receiver<Packet>,
+    packet_receiver: Mailbox<Packet>,
     peers: HashMap<NodeId, PeerWrapper>,
+    /// The overlay prefixes, whose traffic never goes to the exit node.
+    overlay: Vec<IpNet>,
+    /// The peer the traffic leaving the overlay is sent to.
+    exit: Option<NodeId>,
+    /// The peer the traffic to each prefix is sent to.
+    routes: HashMap<IpNet, NodeId>,
+    /// The gateways the traffic to each prefix can be sent to.
+    gateways: HashMap<IpNet, GatewayGroup>,
+    failback: FailbackPolicy,
+    /// How many times the traffic to a prefix moved to another gateway.
+    failovers: u64,
+    election_timer: Interval,
+    /// The peer the traffic to each address of the nodes of the mesh is sent to.
+    mesh: HashMap<IpAddr, NodeId>,
+    route_table: RouteTable,
+    events: Events,
 }
 impl PeerCollection {
-    /// Creates a new instance with the specified `router_address`.
-    pub fn new(router_address: Addr<Packet>) -> Self {
-        let (message_sender, message_receiver) = mpsc::channel(16);
-        let (packet_sender, packet_receiver) = mpsc::channel(16);
-        Self {
-            message_address: Addr::new(message_sender),
+    /// Creates a new instance with the specified `router_address` and `overlay` prefixes.
+    ///
+    /// The traffic to a prefix moves back to a preferred gateway according to `failback`.
+    /// Connections and disconnections of peers, and failovers are reported to `events`.
+    pub fn new(
+        router_address: Addr<Packet>,
+        overlay: Vec<IpNet>,
+        failback: FailbackPolicy,
+        events: Events,
+    ) -> Self {
+        let messages = mailbox(16, OverflowPolicy::Block);
+        let packets = mailbox(16, OverflowPolicy::DropNewest);
+        let route_table = RouteTable::default();
+        Self::from_mailboxes(
+            messages,
+            packets,
+            router_address,
+            overlay,
+            failback,
+            route_table,
+            events,
+        )
+    }
+    /// Creates an instance receiving the messages and packets of existing mailboxes,
+    /// publishing its routes to `route_table`.
+    ///
+    /// It starts without peers, routes nor gateways.
+    pub fn from_mailboxes(
+        (message_address, message_receiver): (
+            Addr<PeerCollectionMessage>,
+            Mailbox<PeerCollectionMessage>,
+        ),
+        (packet_address, packet_receiver): (Addr<Packet>, Mailbox<Packet>),
+        router_address: Addr<Packet>,
+        overlay: Vec<IpNet>,
+        failback: FailbackPolicy,
+        route_table: RouteTable,
+        events: Events,
+    ) -> Self {
+        let mut election_timer = interval(ELECTION_INTERVAL);
+        election_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let peer_collection = Self {
+            message_address,
             message_receiver,
             router_address,
-            packet_address: Addr::new(packet_sender),
+            packet_address,
             packet_receiver,
             peers: HashMap::new(),
-        }
+            overlay,
+            exit: None,
+            routes: HashMap::new(),
+            gateways: HashMap::new(),
+            failback,
+            failovers: 0,
+            election_timer,
+            mesh: HashMap::new(),
+            route_table,
+            events,
+        };
+        peer_collection.publish_routes();
+        peer_collection
+    }
+    /// Returns the table the routes of this collection are published to.
+    pub fn route_table(&self) -> RouteTable {
+        self.route_table.clone()
+    }
+    /// Publishes the current routes to the [RouteTable].
+    fn publish_routes(&self) {
+        let routes = self
+            .routes
+            .iter()
+            .map(|(prefix, node_id)| (*prefix, Some(*node_id)));
+        let gateways = self
+            .gateways
+            .iter()
+            .map(|(prefix, group)| (*prefix, group.primary));
+        *self.route_table.routes.write().unwrap() = Routes {
+            prefixes: routes.chain(gateways).collect(),
+            mesh: self.mesh.clone(),
+            exit: self.exit,
+            overlay: self.overlay.clone(),
+        };
+    }
+    /// Returns keepers of the mailboxes, to start the actor again with
+    /// [PeerCollection::from_mailboxes].
+    pub fn mailbox_keepers(&self) -> (MailboxKeeper<PeerCollectionMessage>, MailboxKeeper<Packet>) {
+        (
+            self.message_receiver.keeper(),
+            self.packet_receiver.keeper(),
+        )
     }
     /// Handles a received message.
     async fn handle_message(&mut self, message: PeerCollectionMessage) {
@@ -52,47 +351,334 @@ impl PeerCollection {
                 self.add_peer(node_id, peer);
             }
             PeerCollectionMessage::DisconnectPeer(node_id) => {
-                self.disconnect_peer(node_id);
+                self.disconnect_peer(node_id, DisconnectReason::Requested);
+            }
+            PeerCollectionMessage::PeerStopped(node_id, generation, reason) => {
+                // A peer replaced by a new connection to the same node stops on its own
+                if self
+                    .peers
+                    .get(&node_id)
+                    .is_some_and(|peer| peer.generation == generation)
+                {
+                    self.disconnect_peer(node_id, reason);
+                }
+            }
+            PeerCollectionMessage::ListPeers(reply) => {
+                reply.send(self.list_peers());
+            }
+            PeerCollectionMessage::SetExit(exit) => {
+                if exit != self.exit {
+                    match exit {
+                        Some(node_id) => info!("Using exit node {}", node_id),
+                        None => info!("Stopped using an exit node"),
+                    }
+                    self.exit = exit;
+                    self.events.emit(Event::ExitChanged { node_id: exit });
+                }
+            }
+            PeerCollectionMessage::GetExit(reply) => {
+                reply.send(self.exit);
+            }
+            PeerCollectionMessage::AddRoute(prefix, node_id) => {
+                self.routes.insert(prefix, node_id);
+            }
+            PeerCollectionMessage::RemoveRoute(prefix) => {
+                self.routes.remove(&prefix);
+            }
+            PeerCollectionMessage::AddGateway(prefix, node_id, priority) => {
+                self.add_gateway(prefix, node_id, priority);
+            }
+            PeerCollectionMessage::RemoveGateway(prefix, node_id) => {
+                self.remove_gateway(prefix, node_id);
+            }
+            PeerCollectionMessage::SetResponsive(node_id, responsive) => {
+                self.set_responsive(node_id, responsive);
+            }
+            PeerCollectionMessage::ListGateways(reply) => {
+                reply.send(self.list_gateways());
+            }
+            PeerCollectionMessage::GetFailovers(reply) => {
+                reply.send(self.failovers);
+            }
+            PeerCollectionMessage::SetMeshRoutes(mesh) => {
+                self.mesh = mesh;
             }
         }
     }
     /// Adds a peer to the collection identified by the provided [NodeId].
     fn add_peer(&mut self, node_id: NodeId, peer: Peer) {
-        println!("Connected to peer {}", node_id);
+        info!("Connected to peer {}", node_id);
+        self.events.emit(Event::PeerConnected { node_id });
         let peer_addr = peer.get_addr();
         let message_address = self.message_address.clone();
+        let generation = NEXT_GENERATION.fetch_add(1, Ordering::Relaxed);
         let join_handle = tokio::spawn(async move {
-            peer.run().await;
+            let reason = peer.run().await;
             message_address
-                .send_message(PeerCollectionMessage::DisconnectPeer(node_id))
+                .send_message(PeerCollectionMessage::PeerStopped(
+                    node_id, generation, reason,
+                ))
                 .await;
         });
-        self.peers.insert(
+        let replaced = self.peers.insert(
             node_id,
             PeerWrapper {
                 abort_handle: join_handle.abort_handle(),
                 address: peer_addr,
+                generation,
+                responsive_since: Some(Instant::now()),
             },
         );
+        if let Some(replaced) = replaced {
+            replaced.abort_handle.abort();
+        }
+        self.elect_primaries();
     }
     /// Disconnects from a peer identified by the provided [NodeId].
-    fn disconnect_peer(&mut self, node_id: NodeId) {
+    fn disconnect_peer(&mut self, node_id: NodeId, reason: DisconnectReason) {
         if let Some(peer) = self.peers.remove(&node_id) {
-            println!("Disconnected from peer {}", node_id);
+            info!("Disconnected from peer {}. Reason: {:?}", node_id, reason);
             peer.abort_handle.abort();
+            self.routes.retain(|_, via| *via != node_id);
+            self.events
+                .emit(Event::PeerDisconnected { node_id, reason });
+            self.elect_primaries();
+        }
+    }
+    /// Tells whether the peer with the given [NodeId] answers keepalives.
+    fn set_responsive(&mut self, node_id: NodeId, responsive: bool) {
+        let Some(peer) = self.peers.get_mut(&node_id) else {
+            return;
+        };
+        if peer.responsive_since.is_some() == responsive {
+            return;
+        }
+        if responsive {
+            info!("Peer {} answers keepalives again", node_id);
+            peer.responsive_since = Some(Instant::now());
+            self.events.emit(Event::PeerResponsive { node_id });
+        } else {
+            warn!("Peer {} stopped answering keepalives", node_id);
+            peer.responsive_since = None;
+            self.events.emit(Event::PeerUnresponsive { node_id });
+        }
+        self.elect_primaries();
+    }
+    /// Adds the peer with the given [NodeId] to the gateways of `prefix`, or updates its priority.
+    fn add_gateway(&mut self, prefix: IpNet, node_id: NodeId, priority: u32) {
+        match self.gateways.get_mut(&prefix) {
+            Some(group) => {
+                group.gateways.insert(node_id, priority);
+                self.elect_primary(prefix);
+            }
+            None => {
+                // The first primary isn't a failover
+                let mut group = GatewayGroup {
+                    gateways: HashMap::from([(node_id, priority)]),
+                    primary: None,
+                    failovers: 0,
+                };
+                group.primary = self.best_gateway(&group);
+                self.gateways.insert(prefix, group);
+            }
+        }
+    }
+    /// Removes the peer with the given [NodeId] from the gateways of `prefix`.
+    fn remove_gateway(&mut self, prefix: IpNet, node_id: NodeId) {
+        let Some(group) = self.gateways.get_mut(&prefix) else {
+            return;
+        };
+        group.gateways.remove(&node_id);
+        if group.gateways.is_empty() {
+            self.gateways.remove(&prefix);
+        } else {
+            self.elect_primary(prefix);
+        }
+    }
+    /// Elects the primary gateway of every prefix.
+    fn elect_primaries(&mut self) {
+        let prefixes: Vec<IpNet> = self.gateways.keys().copied().collect();
+        for prefix in prefixes {
+            self.elect_primary(prefix);
+        }
+    }
+    /// Elects the primary gateway of `prefix`, reporting when the traffic moves to another one.
+    fn elect_primary(&mut self, prefix: IpNet) {
+        let Some(group) = self.gateways.get(&prefix) else {
+            return;
+        };
+        let from = group.primary;
+        let (to, reason) = self.choose_primary(group);
+        if to == from {
+            return;
+        }
+        let group = self.gateways.get_mut(&prefix).unwrap();
+        group.primary = to;
+        // Losing the last gateway or recovering from it doesn't move the traffic to another one
+        if from.is_some() && to.is_some() {
+            group.failovers += 1;
+            self.failovers += 1;
+        }
+        match to {
+            Some(to) => info!(
+                "Failing over {} from {:?} to {}. Reason: {:?}",
+                prefix, from, to, reason
+            ),
+            None => warn!(
+                "No gateway left for {} after {:?}. Reason: {:?}",
+                prefix, from, reason
+            ),
+        }
+        self.events.emit(Event::Failover {
+            prefix,
+            from,
+            to,
+            reason,
+        });
+    }
+    /// Returns the gateway the traffic of `group` should go to, and why it would move there.
+    fn choose_primary(&self, group: &GatewayGroup) -> (Option<NodeId>, FailoverReason) {
+        let best = self.best_gateway(group);
+        let Some(current) = group.primary else {
+            return (best, FailoverReason::Recovered);
+        };
+        let Some(&priority) = group.gateways.get(&current) else {
+            return (best, FailoverReason::Withdrawn);
+        };
+        let Some(peer) = self.peers.get(&current) else {
+            return (best, FailoverReason::Disconnected);
+        };
+        if peer.responsive_since.is_none() {
+            // Kept while no other gateway answers keepalives either
+            let responsive = best.filter(|node_id| self.is_responsive(*node_id));
+            return (responsive.or(Some(current)), FailoverReason::Unresponsive);
+        }
+        let preferred = best
+            .filter(|node_id| group.gateways[node_id] > priority && self.allows_failback(*node_id));
+        (preferred.or(Some(current)), FailoverReason::Failback)
+    }
+    /// Returns the connected gateway of `group` preferred, answering keepalives if possible.
+    ///
+    /// Ties of priority are broken by [NodeId], so every node prefers the same gateway.
+    fn best_gateway(&self, group: &GatewayGroup) -> Option<NodeId> {
+        group
+            .gateways
+            .iter()
+            .filter(|(node_id, _)| self.peers.contains_key(*node_id))
+            .max_by_key(|(node_id, priority)| {
+                (self.is_responsive(**node_id), **priority, **node_id)
+            })
+            .map(|(node_id, _)| *node_id)
+    }
+    /// Checks whether the peer with the given [NodeId] is connected and answers keepalives.
+    fn is_responsive(&self, node_id: NodeId) -> bool {
+        self.peers
+            .get(&node_id)
+            .is_some_and(|peer| peer.responsive_since.is_some())
+    }
+    /// Checks whether the [FailbackPolicy] allows moving back to the peer with the given [NodeId].
+    fn allows_failback(&self, node_id: NodeId) -> bool {
+        let Some(since) = self
+            .peers
+            .get(&node_id)
+            .and_then(|peer| peer.responsive_since)
+        else {
+            return false;
+        };
+        match self.failback {
+            FailbackPolicy::Never => false,
+            FailbackPolicy::Immediate => true,
+            FailbackPolicy::Delayed(delay) => since.elapsed() >= delay,
         }
     }
+    /// Returns the information about the prefixes routed through gateways.
+    fn list_gateways(&self) -> Vec<GatewayGroupInfo> {
+        self.gateways
+            .iter()
+            .map(|(prefix, group)| GatewayGroupInfo {
+                prefix: *prefix,
+                primary: group.primary,
+                gateways: group
+                    .gateways
+                    .iter()
+                    .map(|(node_id, priority)| GatewayInfo {
+                        node_id: *node_id,
+                        priority: *priority,
+                        connected: self.peers.contains_key(node_id),
+                        responsive: self.is_responsive(*node_id),
+                    })
+                    .collect(),
+                failovers: group.failovers,
+            })
+            .collect()
+    }
+    /// Returns the information about all connected peers.
+    fn list_peers(&self) -> Vec<PeerInfo> {
+        self.peers
+            .iter()
+            .map(|(node_id, peer)| PeerInfo {
+                node_id: *node_id,
+                dropped_packets: peer.address.mailbox_stats().dropped.load(Ordering::Relaxed),
+                responsive: peer.responsive_since.is_some(),
+            })
+            .collect()
+    }
     /// Handles a received packet.
     async fn handle_packet(&self, packet: Packet) {
         match &packet {
-            packet @ Packet::Outgoing(_) => {
-                self.send_packet_to_peers(packet).await;
+            packet @ Packet::Outgoing(data) => {
+                let via =
+                    Flow::parse(data).and_then(|flow| self.route_table.lookup(flow.destination));
+                match via {
+                    // Dropped while the peer is disconnected, so nothing leaks to other peers
+                    Some(via) => {
+                        if let Some(peer) = via.and_then(|node_id| self.peers.get(&node_id)) {
+                            peer.address.send_message(packet.clone()).await;
+                        }
+                    }
+                    None => self.send_packet_to_peers(packet).await,
+                }
             }
-            packet @ Packet::Incoming(_) => {
-                self.router_address.send_message(packet.clone()).await;
+            packet @ Packet::Incoming(from, data) => match self.mesh_peer(data) {
+                Some(next_hop) => self.forward(*from, next_hop, data).await,
+                None => self.router_address.send_message(packet.clone()).await,
+            },
+            Packet::Redirected(node_id, data) | Packet::Transit(_, node_id, data) => {
+                if let Some(peer) = self.peers.get(node_id) {
+                    peer.address
+                        .send_message(Packet::Outgoing(data.clone()))
+                        .await;
+                }
             }
         }
     }
+    /// Returns the peer the packet goes to when its destination is a node of the mesh.
+    fn mesh_peer(&self, data: &[u8]) -> Option<NodeId> {
+        if self.mesh.is_empty() {
+            return None;
+        }
+        let flow = Flow::parse(data)?;
+        self.mesh.get(&flow.destination).copied()
+    }
+    /// Forwards a packet received from the peer `from` to the next peer of its path,
+    /// through the packet router so it runs through the pipeline.
+    async fn forward(&self, from: NodeId, next_hop: NodeId, data: &[u8]) {
+        if next_hop == from {
+            debug!("Dropped a packet from {} that would go back to it", from);
+            return;
+        }
+        let mut data = data.to_vec();
+        if !decrement_hop_limit(&mut data) {
+            debug!(
+                "Dropped a packet from {} whose hop limit was exceeded",
+                from
+            );
+            return;
+        }
+        self.router_address
+            .send_message(Packet::Transit(from, next_hop, Arc::from(data)))
+            .await;
+    }
     /// Sends a packet to all connected peers in the collection.
     async fn send_packet_to_peers(&self, packet: &Packet) {
         for peer in self.peers.values() {
@@ -104,10 +690,15 @@ impl PeerCollection {
         select! {
             Some(message) = self.message_receiver.recv() => {
                 self.handle_message(message).await;
+                self.publish_routes();
             },
             Some(packet) = self.packet_receiver.recv() => {
                 self.handle_packet(packet).await;
             }
+            _ = self.election_timer.tick() => {
+                self.elect_primaries();
+                self.publish_routes();
+            }
         };
     }
     /// Runs the actor, continuously processing messages and packets.
@@ -118,6 +709,19 @@ impl PeerCollection {
     }
 }
 
+impl Drop for PeerCollection {
+    /// Disconnects the peers, so they don't outlive the collection when it is restarted.
+    fn drop(&mut self) {
+        for (node_id, peer) in self.peers.drain() {
+            peer.abort_handle.abort();
+            self.events.emit(Event::PeerDisconnected {
+                node_id,
+                reason: DisconnectReason::Stopped,
+            });
+        }
+    }
+}
+
 impl Actor<PeerCollectionMessage> for PeerCollection {
     fn get_addr(&self) -> super::Addr<PeerCollectionMessage> {
         self.message_address.clone()
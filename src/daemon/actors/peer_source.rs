@@ -1,23 +1,58 @@
 //! Module for [PeerSource] actor.
 //!
 //! It is responsible for acquiring connections with other peers.
+//!
+//! The first bidirectional stream of a connection carries the packets. It starts with a [Hello]
+//! from the dialing side, prefixed by its length as a big-endian `u32`, carrying the invite the
+//! dialing node joins with, if any.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
+use bytes::Bytes;
+use futures::StreamExt;
 use iroh_net::{
     key::SecretKey, magic_endpoint::accept_conn, relay::RelayMode, ticket::NodeTicket,
     MagicEndpoint, NodeAddr, NodeId,
 };
-use quinn::Connection;
-use tokio::sync::mpsc;
+use quinn::{Connection, RecvStream, SendStream, VarInt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::oneshot;
+use tracing::{debug, info, warn};
 
-use crate::daemon::{packet::Packet, DaemonError};
+use crate::daemon::{
+    admission::AdmissionPolicy,
+    advertisements::Advertisements,
+    discovery::Discovery,
+    events::{Event, Events},
+    forward::Forwarding,
+    invite::{Invites, SignedInvite},
+    packet::Packet,
+    revocation::Revocations,
+    rotation::Rotations,
+    shutdown::{ShutdownListener, SHUTDOWN_ERROR_CODE, SHUTDOWN_REASON},
+    DaemonError,
+};
 
-use super::{peer::Peer, peer_collection::PeerCollectionMessage, Actor, Addr};
+use super::{
+    mailbox::{mailbox, Mailbox, OverflowPolicy},
+    peer::Peer,
+    peer_collection::PeerCollectionMessage,
+    tun, Actor, Addr, Reply,
+};
 
 /// Messages that can be sent to [PeerSource].
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum PeerSourceMessage {
     /// Instructs [PeerSource] to initiate a connection with the specified [NodeAddr].
     DialPeer(NodeAddr),
+    /// Instructs [PeerSource] to initiate a connection with the node with the specified [NodeId],
+    /// resolving its addresses through the discovery.
+    DialNode(NodeId),
+    /// Asks [PeerSource] for the current [NodeTicket] of this node.
+    GetTicket(Reply<Result<NodeTicket, DaemonError>>),
 }
 
 /// Creates a future from a closure returning an option.
@@ -30,7 +65,27 @@ async fn future_option<T>(f: impl Fn() -> Option<T>) -> T {
     }
 }
 
-const ALPN: &[u8] = "p2ptun".as_bytes();
+/// The ALPN of the connections between nodes.
+pub const ALPN: &[u8] = "p2ptun".as_bytes();
+
+/// The error code closing the connections of nodes that aren't members of the network.
+const NOT_ADMITTED_ERROR_CODE: VarInt = VarInt::from_u32(2);
+/// The error code closing the connections of nodes whose keys are revoked.
+const REVOKED_ERROR_CODE: VarInt = VarInt::from_u32(3);
+
+/// How long the first path to a peer is waited for before the peer is reported as connected.
+const FIRST_PATH_TIMEOUT: Duration = Duration::from_secs(1);
+/// How long the dialing side may take to send its [Hello].
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest [Hello] accepted.
+const MAX_HELLO_SIZE: usize = 64 * 1024;
+
+/// How often the connection to the home relay is checked.
+const RELAY_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+/// How often a keepalive is sent to every peer.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a peer may send nothing before it is considered unresponsive.
+const KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(3);
 
 /// Represents the mode of establishing streams on [Connection].
 enum ChannelMode {
@@ -38,19 +93,53 @@ enum ChannelMode {
     Open,
 }
 
-/// An actor that initiates and accepts connections to peers using [MagicEndpoint].
-pub struct PeerSource {
-    address: Addr<PeerSourceMessage>,
-    receiver: mpsc::Receiver<PeerSourceMessage>,
+/// The first message of a connection, sent by the dialing side.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Hello {
+    /// The invite the dialing node joins the network with.
+    #[serde(default)]
+    invite: Option<SignedInvite>,
+}
+
+/// What is needed to register the peers of new connections.
+#[derive(Clone)]
+struct ConnectionContext {
     peers_message_addr: Addr<PeerCollectionMessage>,
     peers_packet_addr: Addr<Packet>,
     magic_endpoint: MagicEndpoint,
+    events: Events,
+    forwarding: Forwarding,
+    advertisements: Advertisements,
+    admission: Arc<AdmissionPolicy>,
+    invites: Invites,
+    revocations: Revocations,
+    rotations: Rotations,
+    discovery: Option<Discovery>,
+    /// The size of the buffer the packets of a peer are read into.
+    buffer_size: usize,
+}
+
+/// An actor that initiates and accepts connections to peers using [MagicEndpoint].
+pub struct PeerSource {
+    address: Addr<PeerSourceMessage>,
+    receiver: Mailbox<PeerSourceMessage>,
+    context: ConnectionContext,
 }
 impl PeerSource {
-    /// Creates a new [PeerSource] actor.
+    /// Creates a new [PeerSource] actor, once connected to a relay.
+    ///
+    /// See [PeerSource::from_endpoint].
     pub async fn new<PeerCollectionActor>(
         peer_collection: &PeerCollectionActor,
         secret_key: SecretKey,
+        events: Events,
+        forwarding: Forwarding,
+        advertisements: Advertisements,
+        admission: AdmissionPolicy,
+        invites: Invites,
+        revocations: Revocations,
+        rotations: Rotations,
+        discovery: Option<Discovery>,
     ) -> Result<Self, DaemonError>
     where
         PeerCollectionActor: Actor<PeerCollectionMessage> + Actor<Packet>,
@@ -65,28 +154,97 @@ impl PeerSource {
         // Wait for connection to a relay
         // TODO: Handle the case when connection to a relay can't succeed
         future_option(|| magic_endpoint.my_relay()).await;
-        // Create the message channel
-        let (sender, receiver) = mpsc::channel(16);
+        Ok(Self::from_endpoint(
+            peer_collection,
+            magic_endpoint,
+            events,
+            forwarding,
+            advertisements,
+            admission,
+            invites,
+            revocations,
+            rotations,
+            discovery,
+        ))
+    }
+    /// Creates a new [PeerSource] actor accepting and initiating connections on `magic_endpoint`,
+    /// which must accept the [ALPN].
+    ///
+    /// Failed dials, path changes and the state of the home relay are reported to `events`.
+    /// The port forwards of every connected peer are served by `forwarding`,
+    /// and advertisements are exchanged with them through `advertisements`.
+    /// Connections with nodes not admitted by `admission` are closed,
+    /// unless they present an invite accepted by `invites`.
+    /// Connections with nodes revoked by `revocations` are refused, and closed once revoked.
+    /// The members of `admission` are followed to their new keys with `rotations`,
+    /// and the connections with replaced keys are refused.
+    /// The addresses of nodes dialed by their [NodeId] are resolved through `discovery`.
+    pub fn from_endpoint<PeerCollectionActor>(
+        peer_collection: &PeerCollectionActor,
+        magic_endpoint: MagicEndpoint,
+        events: Events,
+        forwarding: Forwarding,
+        advertisements: Advertisements,
+        admission: AdmissionPolicy,
+        invites: Invites,
+        revocations: Revocations,
+        rotations: Rotations,
+        discovery: Option<Discovery>,
+    ) -> Self
+    where
+        PeerCollectionActor: Actor<PeerCollectionMessage> + Actor<Packet>,
+    {
+        // Create the mailbox
+        let (address, receiver) = mailbox(16, OverflowPolicy::Block);
         // Pack the struct
-        Ok(Self {
-            address: Addr::new(sender),
+        Self {
+            address,
             receiver,
-            peers_message_addr: peer_collection.get_addr(),
-            peers_packet_addr: peer_collection.get_addr(),
-            magic_endpoint,
-        })
+            context: ConnectionContext {
+                peers_message_addr: peer_collection.get_addr(),
+                peers_packet_addr: peer_collection.get_addr(),
+                magic_endpoint,
+                events,
+                forwarding,
+                advertisements,
+                admission: Arc::new(admission),
+                invites,
+                revocations,
+                rotations,
+                discovery,
+                buffer_size: tun::buffer_size(None),
+            },
+        }
+    }
+    /// Sets the size of the buffer the packets of a peer are read into, see [tun::buffer_size].
+    pub fn packet_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.context.buffer_size = buffer_size;
+        self
+    }
+    /// Returns a function creating a [PeerSource] on the same mailbox and endpoint,
+    /// to start the actor again.
+    pub fn restarter(&self) -> impl FnMut() -> Self + Send + 'static {
+        let keeper = self.receiver.keeper();
+        let context = self.context.clone();
+        move || Self {
+            address: keeper.addr(),
+            receiver: keeper.reopen(),
+            context: context.clone(),
+        }
     }
     /// Retrieves the [NodeTicket] for this [PeerSource].
     pub async fn node_ticket(&self) -> Result<NodeTicket, DaemonError> {
-        let node_addr = self.magic_endpoint.my_addr().await?;
+        Self::ticket(&self.context.magic_endpoint).await
+    }
+    /// Creates a [NodeTicket] with the current addresses of the [MagicEndpoint].
+    async fn ticket(magic_endpoint: &MagicEndpoint) -> Result<NodeTicket, DaemonError> {
+        let node_addr = magic_endpoint.my_addr().await?;
         Ok(NodeTicket::new(node_addr)?)
     }
     /// Handles incoming messages to [PeerSource].
     async fn handle_messages(
-        peers_packet_addr: &Addr<Packet>,
-        peers_message_addr: &Addr<PeerCollectionMessage>,
-        receiver: &mut mpsc::Receiver<PeerSourceMessage>,
-        magic_endpoint: &MagicEndpoint,
+        receiver: &mut Mailbox<PeerSourceMessage>,
+        context: &ConnectionContext,
     ) {
         loop {
             let message = match receiver.recv().await {
@@ -95,105 +253,356 @@ impl PeerSource {
             };
             match message {
                 PeerSourceMessage::DialPeer(node_addr) => {
-                    tokio::spawn(Self::dial_peer(
-                        peers_packet_addr.clone(),
-                        peers_message_addr.clone(),
-                        node_addr,
-                        magic_endpoint.clone(),
-                    ));
+                    tokio::spawn(Self::dial_peer(node_addr, context.clone()));
+                }
+                PeerSourceMessage::DialNode(node_id) => {
+                    tokio::spawn(Self::dial_node(node_id, context.clone()));
+                }
+                PeerSourceMessage::GetTicket(reply) => {
+                    reply.send(Self::ticket(&context.magic_endpoint).await);
                 }
             }
         }
     }
     /// Handles incoming connections from [MagicEndpoint].
-    async fn handle_connections(
-        peers_packet_addr: &Addr<Packet>,
-        peers_message_addr: &Addr<PeerCollectionMessage>,
-        magic_endpoint: &MagicEndpoint,
-    ) {
-        while let Some(connecting) = magic_endpoint.accept().await {
-            tokio::spawn(Self::handle_connecting(
-                connecting,
-                peers_packet_addr.clone(),
-                peers_message_addr.clone(),
-            ));
+    async fn handle_connections(context: &ConnectionContext) {
+        while let Some(connecting) = context.magic_endpoint.accept().await {
+            tokio::spawn(Self::handle_connecting(connecting, context.clone()));
         }
     }
     /// Handles one incoming connection.
-    async fn handle_connecting(
-        connecting: quinn::Connecting,
-        peers_packet_addr: Addr<Packet>,
-        peers_message_addr: Addr<PeerCollectionMessage>,
-    ) {
+    async fn handle_connecting(connecting: quinn::Connecting, context: ConnectionContext) {
         if let Ok((node_id, _, connection)) = accept_conn(connecting).await {
-            // TODO: Check if the connection should be blocked
-            Self::handle_connection(
-                node_id,
-                connection,
-                peers_packet_addr.clone(),
-                peers_message_addr.clone(),
-                ChannelMode::Accept,
-            )
-            .await;
+            if context.revocations.is_revoked(node_id) {
+                warn!("Refusing the connection of {}, its key is revoked", node_id);
+                connection.close(REVOKED_ERROR_CODE, b"revoked");
+                return;
+            }
+            Self::handle_connection(node_id, connection, ChannelMode::Accept, context).await;
         }
     }
     /// Creates a connection to the peer with the specified [NodeAddr].
-    async fn dial_peer(
-        peers_packet_addr: Addr<Packet>,
-        peers_message_addr: Addr<PeerCollectionMessage>,
-        node_addr: NodeAddr,
-        magic_endpoint: MagicEndpoint,
-    ) {
-        match magic_endpoint.connect(node_addr.clone(), ALPN).await {
+    async fn dial_peer(node_addr: NodeAddr, context: ConnectionContext) {
+        if context.revocations.is_revoked(node_addr.node_id) {
+            warn!("Not dialing {}, its key is revoked", node_addr.node_id);
+            return;
+        }
+        match context
+            .magic_endpoint
+            .connect(node_addr.clone(), ALPN)
+            .await
+        {
             Ok(connection) => {
                 tokio::spawn(Self::handle_connection(
                     node_addr.node_id,
                     connection,
-                    peers_packet_addr,
-                    peers_message_addr,
                     ChannelMode::Open,
+                    context,
                 ));
             }
             Err(error) => {
-                eprintln!(
+                warn!(
                     "Couldn't dial the peer {}. Reason: {:?}",
                     node_addr.node_id, error
                 );
+                context.events.emit(Event::DialFailed {
+                    node_id: node_addr.node_id,
+                    reason: error.to_string(),
+                });
+            }
+        }
+    }
+    /// Resolves the addresses of the node with the specified [NodeId], then creates a
+    /// connection to it.
+    async fn dial_node(node_id: NodeId, context: ConnectionContext) {
+        let resolved = match &context.discovery {
+            Some(discovery) => discovery.resolve(node_id).await,
+            None => Err(DaemonError::NotDiscovered(node_id)),
+        };
+        match resolved {
+            Ok(node_addr) => Self::dial_peer(node_addr, context).await,
+            Err(error) => {
+                warn!(
+                    "Couldn't resolve the addresses of {}. Reason: {:?}",
+                    node_id, error
+                );
+                context.events.emit(Event::DialFailed {
+                    node_id,
+                    reason: format!("{:?}", error),
+                });
             }
         }
     }
     /// Handles an established connection to a peer by opening streams on the connection and registers the peer.
+    ///
+    /// The first stream carries the packets. Other streams, accepted afterwards, carry port forwards.
     async fn handle_connection(
         node_id: NodeId,
         connection: Connection,
-        peers_packet_addr: Addr<Packet>,
-        peers_message_addr: Addr<PeerCollectionMessage>,
         channel_mode: ChannelMode,
+        context: ConnectionContext,
     ) {
         let streams = match channel_mode {
             ChannelMode::Accept => connection.accept_bi().await,
             ChannelMode::Open => connection.open_bi().await,
         };
-        let (send_stream, recv_stream) = match streams {
+        let (mut send_stream, mut recv_stream) = match streams {
             Ok(streams) => streams,
             Err(error) => {
-                eprintln!(
+                warn!(
                     "Error establishing streams with {}, Reason: {:?}",
                     node_id, error
                 );
                 return;
             }
         };
-        let peer = Peer::new(peers_packet_addr, send_stream, recv_stream);
-        peers_message_addr
+        let hello = match channel_mode {
+            ChannelMode::Accept => {
+                tokio::time::timeout(HANDSHAKE_TIMEOUT, Self::read_hello(&mut recv_stream))
+                    .await
+                    .map_err(anyhow::Error::from)
+                    .and_then(|hello| hello)
+            }
+            ChannelMode::Open => {
+                let hello = Hello {
+                    invite: context.invites.invite_for(node_id),
+                };
+                Self::write_hello(&mut send_stream, &hello)
+                    .await
+                    .map(|_| hello)
+            }
+        };
+        let hello = match hello {
+            Ok(hello) => hello,
+            Err(error) => {
+                warn!("Handshake with {} failed. Reason: {:?}", node_id, error);
+                return;
+            }
+        };
+        if !Self::admits(node_id, &channel_mode, hello, &context) {
+            warn!("Closing the connection with {}, not a member", node_id);
+            connection.close(NOT_ADMITTED_ERROR_CODE, b"not a member");
+            return;
+        }
+        // The other streams are only served once the peer is admitted
+        context.advertisements.attach(node_id, connection.clone());
+        context.forwarding.attach(node_id, connection.clone());
+        tokio::spawn(Self::watch_revocation(
+            node_id,
+            connection.clone(),
+            context.revocations.clone(),
+            context.peers_message_addr.clone(),
+        ));
+        tokio::spawn(Self::keep_alive(
+            node_id,
+            connection.clone(),
+            context.peers_message_addr.clone(),
+        ));
+        // The path is reported first, so it is known when the peer is reported as connected
+        let (path_known, first_path) = oneshot::channel();
+        tokio::spawn(Self::watch_path(
+            node_id,
+            connection,
+            context.magic_endpoint,
+            context.events,
+            path_known,
+        ));
+        let _ = tokio::time::timeout(FIRST_PATH_TIMEOUT, first_path).await;
+        let peer = Peer::new(
+            node_id,
+            context.peers_packet_addr,
+            send_stream,
+            recv_stream,
+            context.buffer_size,
+        );
+        context
+            .peers_message_addr
             .send_message(PeerCollectionMessage::AddPeer(node_id, peer))
             .await;
     }
-    /// Runs the actor.
-    pub async fn run(mut self) {
+    /// Checks whether the node with the given [NodeId] is a member of the network,
+    /// admitting it if it presented in `hello` an invite issued by this node.
+    fn admits(
+        node_id: NodeId,
+        channel_mode: &ChannelMode,
+        hello: Hello,
+        context: &ConnectionContext,
+    ) -> bool {
+        if let Some(successor) = context.rotations.successor(node_id) {
+            warn!("The key of {} was replaced by {}", node_id, successor);
+            return false;
+        }
+        if context.rotations.admits(&context.admission, node_id) || context.invites.admits(node_id)
+        {
+            return true;
+        }
+        // Only the issuer of the invite, dialed by the joining node, admits it
+        let (ChannelMode::Accept, Some(invite)) = (channel_mode, hello.invite) else {
+            return false;
+        };
+        match context.invites.redeem(node_id, &invite) {
+            Ok(()) => true,
+            Err(error) => {
+                warn!("Refused the invite of {}: {:?}", node_id, error);
+                false
+            }
+        }
+    }
+    /// Sends `hello` at the start of the packet stream.
+    async fn write_hello(send_stream: &mut SendStream, hello: &Hello) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(hello)?;
+        send_stream
+            .write_all(&(data.len() as u32).to_be_bytes())
+            .await?;
+        send_stream.write_all(&data).await?;
+        Ok(())
+    }
+    /// Receives the [Hello] at the start of the packet stream.
+    async fn read_hello(recv_stream: &mut RecvStream) -> anyhow::Result<Hello> {
+        let mut length = [0u8; 4];
+        recv_stream.read_exact(&mut length).await?;
+        let length = u32::from_be_bytes(length) as usize;
+        anyhow::ensure!(length <= MAX_HELLO_SIZE, "hello too long: {} bytes", length);
+        let mut data = vec![0u8; length];
+        recv_stream.read_exact(&mut data).await?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+    /// Disconnects from a peer as soon as its key is revoked.
+    async fn watch_revocation(
+        node_id: NodeId,
+        connection: Connection,
+        revocations: Revocations,
+        peer_collection: Addr<PeerCollectionMessage>,
+    ) {
+        tokio::select! {
+            _ = revocations.revoked(node_id) => {}
+            _ = connection.closed() => return,
+        }
+        warn!("Disconnecting from {}, its key is revoked", node_id);
+        peer_collection
+            .send_message(PeerCollectionMessage::DisconnectPeer(node_id))
+            .await;
+        connection.close(REVOKED_ERROR_CODE, b"revoked");
+    }
+    /// Reports the changes of the path to a peer until the connection is closed.
+    ///
+    /// `path_known` is notified once the first path is reported.
+    async fn watch_path(
+        node_id: NodeId,
+        connection: Connection,
+        magic_endpoint: MagicEndpoint,
+        events: Events,
+        path_known: oneshot::Sender<()>,
+    ) {
+        let mut connection_types = match magic_endpoint.conn_type_stream(&node_id) {
+            Ok(connection_types) => connection_types,
+            Err(error) => {
+                warn!(
+                    "Couldn't watch the path to {}. Reason: {:?}",
+                    node_id, error
+                );
+                return;
+            }
+        };
+        let report = async {
+            let mut path_known = Some(path_known);
+            while let Some(connection_type) = connection_types.next().await {
+                events.emit(Event::PathChanged {
+                    node_id,
+                    path: connection_type.into(),
+                });
+                if let Some(path_known) = path_known.take() {
+                    let _ = path_known.send(());
+                }
+            }
+        };
+        tokio::select! {
+            _ = report => {}
+            _ = connection.closed() => {}
+        }
+    }
+    /// Sends keepalives to a peer until the connection is closed, reporting to `peer_collection`
+    /// whether the peer sends anything.
+    ///
+    /// A keepalive is an empty datagram, so the peers send each other something every
+    /// [KEEPALIVE_INTERVAL] even when idle.
+    async fn keep_alive(
+        node_id: NodeId,
+        connection: Connection,
+        peer_collection: Addr<PeerCollectionMessage>,
+    ) {
+        let mut interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+        let mut received = connection.stats().udp_rx.datagrams;
+        let mut last_received = Instant::now();
+        let mut responsive = true;
+        let check = async {
+            loop {
+                interval.tick().await;
+                if let Err(error) = connection.send_datagram(Bytes::new()) {
+                    debug!("Couldn't send a keepalive to {}: {:?}", node_id, error);
+                }
+                let now_received = connection.stats().udp_rx.datagrams;
+                if now_received != received {
+                    received = now_received;
+                    last_received = Instant::now();
+                }
+                let now_responsive = last_received.elapsed() < KEEPALIVE_TIMEOUT;
+                if now_responsive != responsive {
+                    responsive = now_responsive;
+                    peer_collection
+                        .send_message(PeerCollectionMessage::SetResponsive(node_id, responsive))
+                        .await;
+                }
+            }
+        };
         tokio::select! {
-            _ = Self::handle_messages(&self.peers_packet_addr, &self.peers_message_addr, &mut self.receiver, &self.magic_endpoint) => {}
-            _ = Self::handle_connections(&self.peers_packet_addr, &self.peers_message_addr, &self.magic_endpoint) => {}
+            _ = check => {}
+            _ = connection.closed() => {}
+        }
+    }
+    /// Reports when the home relay is connected, changed or lost.
+    async fn watch_relay(magic_endpoint: &MagicEndpoint, events: &Events) {
+        let mut current_relay = None;
+        let mut interval = tokio::time::interval(RELAY_CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let relay = magic_endpoint.my_relay();
+            if relay == current_relay {
+                continue;
+            }
+            match &relay {
+                Some(url) => {
+                    info!("Connected to the relay {}", url);
+                    events.emit(Event::RelayConnected {
+                        url: url.to_string(),
+                    });
+                }
+                None => {
+                    warn!("Lost the connection to the relay");
+                    events.emit(Event::RelayLost);
+                }
+            }
+            current_relay = relay;
+        }
+    }
+    /// Runs the actor until the shutdown is triggered.
+    ///
+    /// On shutdown, it stops accepting connections and closes all connections,
+    /// telling the peers that this node is shutting down.
+    pub async fn run(mut self, mut shutdown: ShutdownListener) {
+        tokio::select! {
+            _ = Self::handle_messages(&mut self.receiver, &self.context) => {}
+            _ = Self::handle_connections(&self.context) => {}
+            _ = Self::watch_relay(&self.context.magic_endpoint, &self.context.events) => {}
+            _ = shutdown.wait() => {}
+        }
+        if let Err(error) = self
+            .context
+            .magic_endpoint
+            .close(SHUTDOWN_ERROR_CODE, SHUTDOWN_REASON)
+            .await
+        {
+            warn!("Couldn't close the endpoint. Reason: {:?}", error);
         }
     }
 }
@@ -0,0 +1,347 @@
+//! Module for [RouteManager] actor.
+//!
+//! It is responsible for the kernel routes sending traffic into the TUN device.
+//!
+//! Every route it installs is marked with [ROUTE_PROTOCOL], so the routes left behind
+//! by a crashed daemon can be found and removed when the next one starts. Only the routes to
+//! its own TUN device are removed, leaving those of other daemons alone.
+//!
+//! When an exit node is used, the default routes to the TUN device are put in the
+//! [EXIT_TABLE] routing table, looked up by rules with priorities in [EXIT_RULE_PRIORITIES]:
+//!
+//! ```text
+//! 11200: from all uidrange <daemon user>-<daemon user> lookup main
+//! 11201: from all lookup main suppress_prefixlength 0
+//! 11202: from all lookup 112
+//! ```
+//!
+//! The daemon's own traffic, such as its connection to the exit node, keeps going through
+//! the main table, so it should run as a dedicated user for the traffic of other processes
+//! of the same user to go through the exit node.
+
+use std::{collections::HashMap, ops::Range, os::unix::fs::MetadataExt};
+
+use ipnet::IpNet;
+use iroh_net::NodeId;
+use netlink_packet_route::{nlas::rule::Nla, RT_TABLE_MAIN};
+use rtnetlink::IpVersion;
+use serde::Serialize;
+use tokio::{select, sync::broadcast::error::RecvError};
+use tracing::{info, warn};
+
+use crate::daemon::{
+    events::{Event, Events},
+    netlink::Netlink,
+    shutdown::ShutdownListener,
+    DaemonError,
+};
+
+use super::{
+    mailbox::{mailbox, Mailbox, MailboxKeeper, OverflowPolicy},
+    Actor, Addr, Reply,
+};
+
+/// The routing protocol ID marking the routes installed by p2ptun.
+///
+/// It is unassigned in `/etc/iproute2/rt_protos`, so `ip route show proto 112` lists them.
+pub const ROUTE_PROTOCOL: u8 = 112;
+
+/// The routing table holding the default routes to the TUN device while an exit node is used.
+pub const EXIT_TABLE: u8 = 112;
+
+/// The priorities of the routing rules installed while an exit node is used.
+pub const EXIT_RULE_PRIORITIES: Range<u32> = 11200..11203;
+
+/// Messages that can be sent to [RouteManager].
+#[derive(Debug)]
+pub enum RouteManagerMessage {
+    /// Instructs [RouteManager] to route a prefix through the peer with the given [NodeId].
+    AddRoute(IpNet, NodeId, Reply<Result<(), DaemonError>>),
+    /// Instructs [RouteManager] to remove the route to a prefix.
+    RemoveRoute(IpNet, Reply<Result<(), DaemonError>>),
+    /// Asks [RouteManager] for all installed routes.
+    ListRoutes(Reply<Vec<RouteInfo>>),
+    /// Instructs [RouteManager] to send all traffic not routed elsewhere to the TUN device,
+    /// or to stop doing so.
+    SetExit(bool, Reply<Result<(), DaemonError>>),
+    /// Instructs [RouteManager] to install its routes again, once the TUN device was created again.
+    Reattach(Reply<Result<(), DaemonError>>),
+}
+
+/// Information about an installed route.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteInfo {
+    /// The destination prefix.
+    pub prefix: IpNet,
+    /// The peer the prefix is routed through. [None] for the routes of the overlay prefix.
+    pub node_id: Option<NodeId>,
+}
+
+/// Installs and removes the kernel routes to the TUN device.
+pub struct RouteManager {
+    address: Addr<RouteManagerMessage>,
+    receiver: Mailbox<RouteManagerMessage>,
+    netlink: Netlink,
+    /// Name of the TUN interface.
+    interface: String,
+    /// Index of the TUN interface.
+    index: u32,
+    routes: HashMap<IpNet, Option<NodeId>>,
+    /// Whether the default routes to the TUN device are installed.
+    exit: bool,
+    events: Events,
+}
+
+impl RouteManager {
+    /// Creates a new [RouteManager] for the interface named `interface`.
+    ///
+    /// It removes the routes left by a previous run, then routes `overlay_routes` to the interface.
+    pub async fn new(
+        interface: &str,
+        overlay_routes: &[IpNet],
+        events: Events,
+    ) -> Result<Self, DaemonError> {
+        let (address, receiver) = mailbox(16, OverflowPolicy::Block);
+        Self::from_mailbox(address, receiver, interface, overlay_routes, events).await
+    }
+
+    /// Creates a [RouteManager] receiving the messages of an existing mailbox.
+    ///
+    /// Like [RouteManager::new], it starts over from the routes of the overlay.
+    pub async fn from_mailbox(
+        address: Addr<RouteManagerMessage>,
+        receiver: Mailbox<RouteManagerMessage>,
+        interface: &str,
+        overlay_routes: &[IpNet],
+        events: Events,
+    ) -> Result<Self, DaemonError> {
+        let netlink = Netlink::connect()?;
+        let index = netlink.link_index(interface).await?;
+        let stale = netlink.remove_routes(index, ROUTE_PROTOCOL).await?;
+        if stale > 0 {
+            info!("Removed {} routes left by a previous run", stale);
+        }
+        let stale = netlink.remove_rules(EXIT_RULE_PRIORITIES).await?;
+        if stale > 0 {
+            info!("Removed {} routing rules left by a previous run", stale);
+        }
+        let mut route_manager = Self {
+            address,
+            receiver,
+            netlink,
+            interface: interface.to_string(),
+            index,
+            routes: HashMap::new(),
+            exit: false,
+            events,
+        };
+        for prefix in overlay_routes {
+            route_manager.add_route(*prefix, None).await?;
+        }
+        Ok(route_manager)
+    }
+
+    /// Returns the name of the TUN interface.
+    pub fn interface(&self) -> &str {
+        &self.interface
+    }
+
+    /// Returns a keeper of the mailbox, to start the actor again with [RouteManager::from_mailbox].
+    pub fn mailbox_keeper(&self) -> MailboxKeeper<RouteManagerMessage> {
+        self.receiver.keeper()
+    }
+
+    /// Handles a received message.
+    async fn handle_message(&mut self, message: RouteManagerMessage) {
+        match message {
+            RouteManagerMessage::AddRoute(prefix, node_id, reply) => {
+                reply.send(self.add_route(prefix, Some(node_id)).await);
+            }
+            RouteManagerMessage::RemoveRoute(prefix, reply) => {
+                reply.send(self.remove_route(prefix).await);
+            }
+            RouteManagerMessage::ListRoutes(reply) => {
+                reply.send(
+                    self.routes
+                        .iter()
+                        .map(|(prefix, node_id)| RouteInfo {
+                            prefix: *prefix,
+                            node_id: *node_id,
+                        })
+                        .collect(),
+                );
+            }
+            RouteManagerMessage::SetExit(enabled, reply) => {
+                reply.send(self.set_exit(enabled).await);
+            }
+            RouteManagerMessage::Reattach(reply) => {
+                reply.send(self.reattach().await);
+            }
+        }
+    }
+
+    /// Routes `prefix` to the TUN device.
+    async fn add_route(
+        &mut self,
+        prefix: IpNet,
+        node_id: Option<NodeId>,
+    ) -> Result<(), DaemonError> {
+        self.netlink
+            .add_route(self.index, prefix, ROUTE_PROTOCOL)
+            .await?;
+        info!("Added route to {}", prefix);
+        self.routes.insert(prefix, node_id);
+        self.events.emit(Event::RouteAdded { prefix, node_id });
+        Ok(())
+    }
+
+    /// Removes the route to `prefix`.
+    async fn remove_route(&mut self, prefix: IpNet) -> Result<(), DaemonError> {
+        let Some(node_id) = self.routes.remove(&prefix) else {
+            return Ok(());
+        };
+        self.netlink
+            .remove_route(self.index, prefix, ROUTE_PROTOCOL)
+            .await?;
+        info!("Removed route to {}", prefix);
+        self.events.emit(Event::RouteRemoved { prefix, node_id });
+        Ok(())
+    }
+
+    /// Installs or removes the default routes to the TUN device and their rules.
+    async fn set_exit(&mut self, enabled: bool) -> Result<(), DaemonError> {
+        if enabled == self.exit {
+            return Ok(());
+        }
+        if enabled {
+            if let Err(error) = self.add_exit_routes().await {
+                if let Err(error) = self.remove_exit_routes().await {
+                    warn!("Couldn't remove the exit routes. Reason: {:?}", error);
+                }
+                return Err(error);
+            }
+            info!("Added the exit routes");
+        } else {
+            self.remove_exit_routes().await?;
+            info!("Removed the exit routes");
+        }
+        self.exit = enabled;
+        Ok(())
+    }
+
+    /// Adds the default routes to the TUN device in [EXIT_TABLE], and the rules looking it up.
+    async fn add_exit_routes(&self) -> Result<(), DaemonError> {
+        let uid = std::fs::metadata("/proc/self")?.uid();
+        let uid_range = [uid.to_ne_bytes(), uid.to_ne_bytes()].concat();
+        for prefix in [IpNet::V4(Default::default()), IpNet::V6(Default::default())] {
+            self.netlink
+                .add_table_route(self.index, prefix, ROUTE_PROTOCOL, EXIT_TABLE)
+                .await?;
+        }
+        let priority = EXIT_RULE_PRIORITIES.start;
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            self.netlink
+                .add_rule(
+                    ip_version.clone(),
+                    priority,
+                    RT_TABLE_MAIN,
+                    vec![Nla::UidRange(uid_range.clone())],
+                )
+                .await?;
+            self.netlink
+                .add_rule(
+                    ip_version.clone(),
+                    priority + 1,
+                    RT_TABLE_MAIN,
+                    vec![Nla::SuppressPrefixLen(0)],
+                )
+                .await?;
+            self.netlink
+                .add_rule(ip_version, priority + 2, EXIT_TABLE, Vec::new())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Installs the routes again on the TUN device, created again under the same name.
+    async fn reattach(&mut self) -> Result<(), DaemonError> {
+        self.index = self.netlink.link_index(&self.interface).await?;
+        // A persistent device keeps its routes
+        self.netlink
+            .remove_routes(self.index, ROUTE_PROTOCOL)
+            .await?;
+        for prefix in self.routes.keys() {
+            self.netlink
+                .add_route(self.index, *prefix, ROUTE_PROTOCOL)
+                .await?;
+        }
+        if self.exit {
+            self.remove_exit_routes().await?;
+            self.add_exit_routes().await?;
+        }
+        info!("Installed the routes again on {}", self.interface);
+        Ok(())
+    }
+
+    /// Removes the rules and the routes added by [RouteManager::add_exit_routes].
+    async fn remove_exit_routes(&self) -> Result<(), DaemonError> {
+        self.netlink.remove_rules(EXIT_RULE_PRIORITIES).await?;
+        self.netlink
+            .remove_table_routes(self.index, EXIT_TABLE, ROUTE_PROTOCOL)
+            .await?;
+        Ok(())
+    }
+
+    /// Removes the routes through the peer with the given [NodeId].
+    async fn remove_peer_routes(&mut self, node_id: NodeId) {
+        let prefixes: Vec<IpNet> = self
+            .routes
+            .iter()
+            .filter(|(_, via)| **via == Some(node_id))
+            .map(|(prefix, _)| *prefix)
+            .collect();
+        for prefix in prefixes {
+            if let Err(error) = self.remove_route(prefix).await {
+                warn!("Couldn't remove route to {}. Reason: {:?}", prefix, error);
+            }
+        }
+    }
+
+    /// Runs the actor until the shutdown is triggered.
+    ///
+    /// The routes through a peer are removed when it disconnects.
+    /// On shutdown, all routes are removed.
+    pub async fn run(mut self, mut shutdown: ShutdownListener) {
+        let mut events = self.events.subscribe();
+        loop {
+            select! {
+                Some(message) = self.receiver.recv() => {
+                    self.handle_message(message).await;
+                }
+                event = events.recv() => match event {
+                    Ok(Event::PeerDisconnected { node_id, .. }) => {
+                        self.remove_peer_routes(node_id).await;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Route manager missed {} events", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = shutdown.wait() => break,
+            }
+        }
+        if let Err(error) = self.netlink.remove_rules(EXIT_RULE_PRIORITIES).await {
+            warn!("Couldn't remove the routing rules. Reason: {:?}", error);
+        }
+        if let Err(error) = self.netlink.remove_routes(self.index, ROUTE_PROTOCOL).await {
+            warn!("Couldn't remove the routes. Reason: {:?}", error);
+        }
+    }
+}
+
+impl Actor<RouteManagerMessage> for RouteManager {
+    fn get_addr(&self) -> Addr<RouteManagerMessage> {
+        self.address.clone()
+    }
+}
@@ -0,0 +1,437 @@
+//! Module for [SubnetRouter] actor.
+//!
+//! It routes the networks advertised by peers acting as subnet routers through those peers.
+//!
+//! A subnet router advertises the prefixes of the local networks it forwards the traffic of
+//! its peers to, in its [Advertisement](crate::daemon::advertisements::Advertisement).
+//! The prefixes allowed by the [RoutePolicy] of this node are routed through the advertising
+//! peer in the [PeerCollection](super::peer_collection::PeerCollection) and, with the TUN
+//! device, in the kernel. They are withdrawn when the peer stops advertising them or disconnects.
+//!
+//! A prefix overlapping the overlay, the prefixes advertised by this node or a different prefix
+//! already routed through another peer is a conflict. It is reported with [Event::RouteConflict]
+//! and isn't routed, until the prefix it conflicts with is withdrawn.
+//!
+//! The same prefix advertised by several peers isn't a conflict: the peers become its gateways,
+//! and the [PeerCollection](super::peer_collection::PeerCollection) sends its traffic to one of
+//! them, failing over to another when it fails.
+//!
+//! The subnet router passes the packets between the TUN device and its local networks through
+//! the kernel. IP forwarding has to be enabled on it, and the hosts of the local networks need
+//! a route back to the overlay, unless the traffic is masqueraded.
+
+use std::collections::{HashMap, HashSet};
+
+use ipnet::IpNet;
+use iroh_net::NodeId;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{info, warn};
+
+use crate::daemon::{
+    advertisements::{Advertisement, Advertisements},
+    events::{Event, Events},
+    DaemonError,
+};
+
+use super::{
+    mailbox::{mailbox, Mailbox, OverflowPolicy},
+    peer_collection::PeerCollectionMessage,
+    route_manager::RouteManagerMessage,
+    Actor, Addr, Reply,
+};
+
+/// Allows prefixes advertised by peers to be routed through them. Every field that is set must match.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteGrant {
+    /// The peer allowed. Every peer is allowed when not set.
+    pub peer: Option<NodeId>,
+    /// The prefix containing the allowed prefixes.
+    pub prefix: IpNet,
+}
+
+impl RouteGrant {
+    /// Checks whether the grant allows `prefix` to be routed through `peer`.
+    fn allows(&self, peer: NodeId, prefix: IpNet) -> bool {
+        self.peer.is_none_or(|allowed| allowed == peer) && self.prefix.contains(&prefix)
+    }
+}
+
+/// The prefixes advertised by peers that are routed through them.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoutePolicy {
+    #[serde(default)]
+    pub grants: Vec<RouteGrant>,
+}
+
+impl RoutePolicy {
+    /// Checks whether any grant allows `prefix` to be routed through `peer`.
+    pub fn allows(&self, peer: NodeId, prefix: IpNet) -> bool {
+        self.grants.iter().any(|grant| grant.allows(peer, prefix))
+    }
+}
+
+/// Whether a prefix advertised by a peer is routed through it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubnetRouteState {
+    /// The prefix is routed through the peer.
+    Installed,
+    /// The [RoutePolicy] doesn't allow the prefix.
+    NotAllowed,
+    /// The prefix overlaps a local prefix or a different prefix routed through another peer.
+    Conflict,
+}
+
+/// Information about a prefix advertised by a peer.
+#[derive(Debug, Clone, Serialize)]
+pub struct SubnetRouteInfo {
+    pub prefix: IpNet,
+    /// The peer advertising the prefix.
+    pub node_id: NodeId,
+    pub state: SubnetRouteState,
+}
+
+/// Messages that can be sent to [SubnetRouter].
+pub enum SubnetRouterMessage {
+    /// Asks [SubnetRouter] for the prefixes advertised by the connected peers.
+    ListRoutes(Reply<Vec<SubnetRouteInfo>>),
+}
+
+/// Routes the prefixes advertised by peers through them.
+pub struct SubnetRouter {
+    address: Addr<SubnetRouterMessage>,
+    receiver: Mailbox<SubnetRouterMessage>,
+    policy: RoutePolicy,
+    /// The prefixes never routed through peers: the overlay and the prefixes advertised by this node.
+    local: Vec<IpNet>,
+    peer_collection: Addr<PeerCollectionMessage>,
+    /// [None] when the TUN device is disabled.
+    route_manager: Option<Addr<RouteManagerMessage>>,
+    /// The prefixes advertised by each connected peer.
+    advertised: HashMap<NodeId, Vec<IpNet>>,
+    /// The route priority advertised by each connected peer.
+    priorities: HashMap<NodeId, u32>,
+    /// The gateways of each routed prefix, with their priorities.
+    installed: HashMap<IpNet, HashMap<NodeId, u32>>,
+    /// The advertised prefixes rejected because of a conflict, so each is reported once.
+    conflicts: HashSet<(NodeId, IpNet)>,
+    advertisements: Advertisements,
+    events: Events,
+    event_receiver: broadcast::Receiver<Event>,
+}
+
+impl SubnetRouter {
+    /// Creates a new [SubnetRouter] accepting the prefixes allowed by `policy`.
+    ///
+    /// The advertised prefixes overlapping the `local` prefixes are never accepted.
+    /// The prefixes are learned again from `advertisements` when advertisements are missed.
+    pub fn new(
+        policy: RoutePolicy,
+        local: Vec<IpNet>,
+        peer_collection: Addr<PeerCollectionMessage>,
+        route_manager: Option<Addr<RouteManagerMessage>>,
+        advertisements: Advertisements,
+        events: Events,
+    ) -> Self {
+        let (address, receiver) = mailbox(16, OverflowPolicy::Block);
+        Self {
+            address,
+            receiver,
+            policy,
+            local,
+            peer_collection,
+            route_manager,
+            advertised: HashMap::new(),
+            priorities: HashMap::new(),
+            installed: HashMap::new(),
+            conflicts: HashSet::new(),
+            advertisements,
+            event_receiver: events.subscribe(),
+            events,
+        }
+    }
+
+    /// Handles a received message.
+    fn handle_message(&mut self, message: SubnetRouterMessage) {
+        match message {
+            SubnetRouterMessage::ListRoutes(reply) => {
+                reply.send(self.list_routes());
+            }
+        }
+    }
+
+    /// Returns the prefixes advertised by the connected peers.
+    fn list_routes(&self) -> Vec<SubnetRouteInfo> {
+        self.advertised
+            .iter()
+            .flat_map(|(node_id, prefixes)| {
+                prefixes.iter().map(|prefix| SubnetRouteInfo {
+                    prefix: *prefix,
+                    node_id: *node_id,
+                    state: if self.is_installed(*node_id, *prefix) {
+                        SubnetRouteState::Installed
+                    } else if self.policy.allows(*node_id, *prefix) {
+                        SubnetRouteState::Conflict
+                    } else {
+                        SubnetRouteState::NotAllowed
+                    },
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces the prefixes advertised by the peer with the given [NodeId], with their priority.
+    async fn set_advertised(&mut self, node_id: NodeId, prefixes: Vec<IpNet>, priority: u32) {
+        for prefix in &prefixes {
+            if !self.policy.allows(node_id, *prefix) {
+                info!("Ignored route to {} advertised by {}", prefix, node_id);
+            }
+        }
+        if prefixes.is_empty() {
+            self.advertised.remove(&node_id);
+            self.priorities.remove(&node_id);
+        } else {
+            self.advertised.insert(node_id, prefixes);
+            self.priorities.insert(node_id, priority);
+        }
+        self.reconcile().await;
+    }
+
+    /// Withdraws the prefixes that aren't advertised anymore, updates the priorities of the
+    /// gateways, then routes the allowed prefixes that don't conflict.
+    async fn reconcile(&mut self) {
+        let withdrawn: Vec<(IpNet, NodeId)> = self
+            .installed
+            .iter()
+            .flat_map(|(prefix, gateways)| gateways.keys().map(|node_id| (*prefix, *node_id)))
+            .filter(|(prefix, node_id)| !self.is_advertised(*node_id, *prefix))
+            .collect();
+        for (prefix, node_id) in withdrawn {
+            self.withdraw(prefix, node_id).await;
+        }
+        let reprioritized: Vec<(IpNet, NodeId, u32)> = self
+            .installed
+            .iter()
+            .flat_map(|(prefix, gateways)| {
+                gateways
+                    .iter()
+                    .map(|(node_id, priority)| (*prefix, *node_id, *priority))
+            })
+            .filter_map(|(prefix, node_id, priority)| {
+                let advertised = self.priority(node_id);
+                (advertised != priority).then_some((prefix, node_id, advertised))
+            })
+            .collect();
+        for (prefix, node_id, priority) in reprioritized {
+            self.add_gateway(prefix, node_id, priority).await;
+        }
+        let advertised = &self.advertised;
+        self.conflicts.retain(|(node_id, prefix)| {
+            advertised.get(node_id).is_some_and(|p| p.contains(prefix))
+        });
+
+        // Sorted, so the same peer wins a conflict whatever the order of the map
+        let mut candidates: Vec<(IpNet, NodeId)> = self
+            .advertised
+            .iter()
+            .flat_map(|(node_id, prefixes)| prefixes.iter().map(|prefix| (*prefix, *node_id)))
+            .filter(|(prefix, node_id)| {
+                !self.is_installed(*node_id, *prefix) && self.policy.allows(*node_id, *prefix)
+            })
+            .collect();
+        candidates.sort();
+        for (prefix, node_id) in candidates {
+            if self.installed.contains_key(&prefix) {
+                self.add_gateway(prefix, node_id, self.priority(node_id))
+                    .await;
+                continue;
+            }
+            match self.conflict(prefix) {
+                Some(conflicting) => {
+                    if self.conflicts.insert((node_id, prefix)) {
+                        match conflicting {
+                            Some(conflicting) => warn!(
+                                "Route to {} advertised by {} conflicts with a route through {}",
+                                prefix, node_id, conflicting
+                            ),
+                            None => warn!(
+                                "Route to {} advertised by {} conflicts with a local prefix",
+                                prefix, node_id
+                            ),
+                        }
+                        self.events.emit(Event::RouteConflict {
+                            prefix,
+                            node_id,
+                            conflicting,
+                        });
+                    }
+                }
+                None => self.install(prefix, node_id).await,
+            }
+        }
+    }
+
+    /// Checks whether `prefix` is routed through the peer with the given [NodeId].
+    fn is_installed(&self, node_id: NodeId, prefix: IpNet) -> bool {
+        self.installed
+            .get(&prefix)
+            .is_some_and(|gateways| gateways.contains_key(&node_id))
+    }
+
+    /// Returns the route priority advertised by the peer with the given [NodeId].
+    fn priority(&self, node_id: NodeId) -> u32 {
+        self.priorities.get(&node_id).copied().unwrap_or_default()
+    }
+
+    /// Checks whether the peer with the given [NodeId] advertises `prefix`.
+    fn is_advertised(&self, node_id: NodeId, prefix: IpNet) -> bool {
+        self.advertised
+            .get(&node_id)
+            .is_some_and(|prefixes| prefixes.contains(&prefix))
+    }
+
+    /// Returns what `prefix` conflicts with: a peer a prefix is routed through,
+    /// or [None] for a local prefix.
+    fn conflict(&self, prefix: IpNet) -> Option<Option<NodeId>> {
+        if self.local.iter().any(|local| overlaps(*local, prefix)) {
+            return Some(None);
+        }
+        self.installed
+            .iter()
+            .find(|(installed, _)| overlaps(**installed, prefix))
+            .map(|(_, gateways)| gateways.keys().min().copied())
+    }
+
+    /// Adds the peer with the given [NodeId] to the gateways of the routed `prefix`,
+    /// or updates its priority.
+    async fn add_gateway(&mut self, prefix: IpNet, node_id: NodeId, priority: u32) {
+        self.peer_collection
+            .send_message(PeerCollectionMessage::AddGateway(prefix, node_id, priority))
+            .await;
+        let gateways = self.installed.entry(prefix).or_default();
+        if gateways.insert(node_id, priority).is_none() {
+            info!("Routing {} through {} too", prefix, node_id);
+        }
+    }
+
+    /// Routes `prefix` through the peer with the given [NodeId].
+    async fn install(&mut self, prefix: IpNet, node_id: NodeId) {
+        if let Some(route_manager) = &self.route_manager {
+            let result = route_manager
+                .ask(|reply| RouteManagerMessage::AddRoute(prefix, node_id, reply))
+                .await
+                .map_err(DaemonError::from)
+                .and_then(|result| result);
+            if let Err(error) = result {
+                warn!(
+                    "Couldn't add route to {} through {}. Reason: {:?}",
+                    prefix, node_id, error
+                );
+                return;
+            }
+        }
+        let priority = self.priority(node_id);
+        self.peer_collection
+            .send_message(PeerCollectionMessage::AddGateway(prefix, node_id, priority))
+            .await;
+        info!("Routing {} through {}", prefix, node_id);
+        self.installed
+            .insert(prefix, HashMap::from([(node_id, priority)]));
+    }
+
+    /// Stops routing `prefix` through the peer with the given [NodeId].
+    ///
+    /// The route is removed with the last gateway of the prefix.
+    async fn withdraw(&mut self, prefix: IpNet, node_id: NodeId) {
+        self.peer_collection
+            .send_message(PeerCollectionMessage::RemoveGateway(prefix, node_id))
+            .await;
+        let Some(gateways) = self.installed.get_mut(&prefix) else {
+            return;
+        };
+        gateways.remove(&node_id);
+        if !gateways.is_empty() {
+            info!("Stopped routing {} through {}", prefix, node_id);
+            return;
+        }
+        self.installed.remove(&prefix);
+        if let Some(route_manager) = &self.route_manager {
+            let result = route_manager
+                .ask(|reply| RouteManagerMessage::RemoveRoute(prefix, reply))
+                .await
+                .map_err(DaemonError::from)
+                .and_then(|result| result);
+            if let Err(error) = result {
+                warn!("Couldn't remove route to {}. Reason: {:?}", prefix, error);
+            }
+        }
+        info!("Stopped routing {} through {}", prefix, node_id);
+    }
+
+    /// Sets the prefixes advertised by each connected peer again, after advertisements or
+    /// disconnections were missed.
+    async fn resync(&mut self) {
+        let peers: HashMap<NodeId, Advertisement> =
+            self.advertisements.peers().into_iter().collect();
+        let gone: Vec<NodeId> = self
+            .advertised
+            .keys()
+            .filter(|node_id| !peers.contains_key(node_id))
+            .copied()
+            .collect();
+        for node_id in gone {
+            self.set_advertised(node_id, Vec::new(), 0).await;
+        }
+        for (node_id, advertisement) in peers {
+            self.set_advertised(node_id, advertisement.routes, advertisement.route_priority)
+                .await;
+        }
+    }
+
+    /// Runs the actor, following the advertisements and disconnections of the peers.
+    pub async fn run(mut self) {
+        loop {
+            select! {
+                Some(message) = self.receiver.recv() => {
+                    self.handle_message(message);
+                }
+                event = self.event_receiver.recv() => match event {
+                    Ok(Event::Advertised { node_id, advertisement }) => {
+                        self.set_advertised(
+                            node_id,
+                            advertisement.routes,
+                            advertisement.route_priority,
+                        )
+                        .await;
+                    }
+                    Ok(Event::PeerDisconnected { node_id, .. }) => {
+                        self.set_advertised(node_id, Vec::new(), 0).await;
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Subnet router missed {} events", missed);
+                        self.resync().await;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+}
+
+/// Checks whether two prefixes have addresses in common.
+fn overlaps(a: IpNet, b: IpNet) -> bool {
+    a.contains(&b.network()) || b.contains(&a.network())
+}
+
+impl Actor<SubnetRouterMessage> for SubnetRouter {
+    fn get_addr(&self) -> Addr<SubnetRouterMessage> {
+        self.address.clone()
+    }
+}
@@ -2,18 +2,62 @@
 //!
 //! It is responsible for managing the TUN device.
 
-use std::sync::Arc;
+use std::{io, sync::Arc};
 
+use ipnet::IpNet;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
     select,
-    sync::mpsc,
 };
-use tun::{configure, AsyncDevice};
+use tracing::warn;
+use tun::{configure, AsyncDevice, Device};
 
-use crate::daemon::packet::Packet;
+use crate::daemon::{netlink::Netlink, packet::Packet, shutdown::ShutdownListener, DaemonError};
 
-use super::{Actor, Addr};
+use super::{
+    mailbox::{mailbox, Mailbox, MailboxKeeper, OverflowPolicy},
+    Actor, Addr,
+};
+
+/// Settings of the TUN device.
+#[derive(Debug, Clone, Default)]
+pub struct TunConfig {
+    /// Name of the interface. The kernel picks one when not set.
+    pub name: Option<String>,
+    /// Addresses assigned to the interface, with their prefix lengths. Both IPv4 and IPv6 are allowed.
+    pub addresses: Vec<IpNet>,
+    /// MTU of the interface. The system default is kept when not set.
+    pub mtu: Option<u16>,
+    /// Prefixes routed to the interface, such as the overlay prefix.
+    ///
+    /// The routes are installed through netlink and removed on shutdown.
+    pub routes: Vec<IpNet>,
+    /// Attach to the existing persistent device named [TunConfig::name] instead of creating one.
+    ///
+    /// The device is neither brought up nor down, so it can be owned by a non-root user,
+    /// as created by `ip tuntap add mode tun user <user>`.
+    pub persistent: bool,
+}
+
+/// A setting of the TUN device, reported when applying it fails.
+#[derive(Debug, Clone)]
+pub enum TunSetting {
+    Name(Option<String>),
+    Persistent,
+    Mtu(u16),
+    Address(IpNet),
+    Up,
+}
+
+/// The size of the buffer packets are read into, when the MTU isn't set.
+const DEFAULT_BUFFER_SIZE: usize = 1518;
+
+/// Returns the size of the buffer packets are read into, for the given MTU.
+pub fn buffer_size(mtu: Option<u16>) -> usize {
+    mtu.map_or(DEFAULT_BUFFER_SIZE, |mtu| {
+        DEFAULT_BUFFER_SIZE.max(mtu.into())
+    })
+}
 
 /// Represents a TUN (network tunnel) actor for handling network traffic.
 pub struct Tun {
@@ -23,11 +67,17 @@ pub struct Tun {
     /// The address of the packet router to forward packets to.
     packet_router: Addr<Packet>,
 
-    /// The receiver channel for incoming packets to be sent via the TUN device.
-    receiver: mpsc::Receiver<Packet>,
+    /// The mailbox for incoming packets to be sent via the TUN device.
+    receiver: Mailbox<Packet>,
 
     /// The TUN device used for reading and writing network packets.
     tun: AsyncDevice,
+
+    /// The size of the buffer packets are read into.
+    buffer_size: usize,
+
+    /// Whether the device is persistent, so it is left up on shutdown.
+    persistent: bool,
 }
 
 impl Tun {
@@ -35,41 +85,114 @@ impl Tun {
     ///
     /// Parameters:
     /// - `packet_router`: The address of the packet router to forward packets to.
+    /// - `config`: The settings of the TUN device.
     ///
-    /// Returns a [Tun] instance with its associated receiver channel and TUN device.
-    pub fn new(packet_router: Addr<Packet>) -> tun::Result<Self> {
-        let (sender, receiver) = mpsc::channel(16);
+    /// Returns a [Tun] instance with its associated mailbox and TUN device.
+    /// If a setting can't be applied, the error tells which one.
+    pub async fn new(packet_router: Addr<Packet>, config: TunConfig) -> Result<Self, DaemonError> {
+        let (address, receiver) = mailbox(16, OverflowPolicy::DropNewest);
+        Self::from_mailbox(address, receiver, packet_router, config).await
+    }
+
+    /// Creates a [Tun] receiving the packets of an existing mailbox, creating the device again.
+    pub async fn from_mailbox(
+        address: Addr<Packet>,
+        receiver: Mailbox<Packet>,
+        packet_router: Addr<Packet>,
+        config: TunConfig,
+    ) -> Result<Self, DaemonError> {
+        let tun = Self::create_device(&config).await?;
         Ok(Self {
-            address: Addr::new(sender),
+            address,
             receiver,
             packet_router,
-            tun: tun::create_as_async(configure().up())?, // Create the TUN device
+            tun,
+            buffer_size: buffer_size(config.mtu),
+            persistent: config.persistent,
         })
     }
 
+    /// Returns a keeper of the mailbox, to start the actor again with [Tun::from_mailbox].
+    pub fn mailbox_keeper(&self) -> MailboxKeeper<Packet> {
+        self.receiver.keeper()
+    }
+
+    /// Returns the name of the TUN interface.
+    pub fn name(&self) -> tun::Result<String> {
+        self.tun.get_ref().name()
+    }
+
+    /// Creates or attaches to the TUN device and applies `config` to it.
+    async fn create_device(config: &TunConfig) -> Result<AsyncDevice, DaemonError> {
+        let mut configuration = configure();
+        if let Some(name) = &config.name {
+            configuration.name(name);
+        } else if config.persistent {
+            return Err(DaemonError::TunSettingError(
+                TunSetting::Persistent,
+                tun::Error::InvalidName,
+            ));
+        }
+        let mut tun = tun::create_as_async(&configuration).map_err(|error| {
+            DaemonError::TunSettingError(TunSetting::Name(config.name.clone()), error)
+        })?;
+        if let Some(mtu) = config.mtu {
+            tun.get_mut()
+                .set_mtu(mtu.into())
+                .map_err(|error| DaemonError::TunSettingError(TunSetting::Mtu(mtu), error))?;
+        }
+        if !config.addresses.is_empty() {
+            let netlink_error = |setting: TunSetting, error: rtnetlink::Error| {
+                DaemonError::TunSettingError(setting, io::Error::other(error).into())
+            };
+            let netlink = Netlink::connect()?;
+            let name = tun.get_ref().name()?;
+            let index = netlink
+                .link_index(&name)
+                .await
+                .map_err(|error| netlink_error(TunSetting::Name(Some(name)), error))?;
+            for address in &config.addresses {
+                netlink
+                    .add_address(index, *address)
+                    .await
+                    .map_err(|error| netlink_error(TunSetting::Address(*address), error))?;
+            }
+        }
+        if !config.persistent {
+            tun.get_mut()
+                .enabled(true)
+                .map_err(|error| DaemonError::TunSettingError(TunSetting::Up, error))?;
+        }
+        Ok(tun)
+    }
+
     /// Asynchronously sends packets received from the TUN device to the packet router.
-    async fn send_packets(mut tun_read: ReadHalf<AsyncDevice>, packet_router: Addr<Packet>) {
+    ///
+    /// Returns the error reading from the device, which is no longer usable.
+    async fn send_packets(
+        tun_read: &mut ReadHalf<AsyncDevice>,
+        packet_router: &Addr<Packet>,
+        buffer_size: usize,
+    ) -> io::Error {
         loop {
-            let mut buffer = vec![0u8; 1518];
+            let mut buffer = vec![0u8; buffer_size];
             match tun_read.read(&mut buffer).await {
-                Ok(size) if size > 0 => {
+                Ok(0) => return io::ErrorKind::UnexpectedEof.into(),
+                Ok(size) => {
                     // Send the outgoing packet to the packet router
                     packet_router
                         .send_message(Packet::Outgoing(Arc::from(&buffer[0..size])))
                         .await;
                 }
-                _ => continue, // Ignore empty or error packets
+                Err(error) => return error,
             }
         }
     }
 
     /// Asynchronously receives packets from the packet receiver and writes them to the TUN device.
-    async fn recv_packets(
-        mut tun_write: WriteHalf<AsyncDevice>,
-        mut receiver: mpsc::Receiver<Packet>,
-    ) {
+    async fn recv_packets(tun_write: &mut WriteHalf<AsyncDevice>, receiver: &mut Mailbox<Packet>) {
         loop {
-            if let Some(Packet::Incoming(packet)) = receiver.recv().await {
+            if let Some(Packet::Incoming(_, packet)) = receiver.recv().await {
                 // Write the incoming packet to the TUN device
                 let _ = tun_write.write(&packet).await;
             } else {
@@ -79,19 +202,34 @@ impl Tun {
     }
 
     /// Runs the TUN actor asynchronously, handling packet I/O operations.
-    pub async fn run(self) {
-        let (tun_read, tun_write) = tokio::io::split(self.tun);
+    ///
+    /// It stops when reading from the device fails, so it can be started again on a new device.
+    /// On shutdown or failure, it brings the TUN device down, unless it is persistent.
+    pub async fn run(mut self, mut shutdown: ShutdownListener) {
+        let (mut tun_read, mut tun_write) = tokio::io::split(self.tun);
 
         // Use `select!` to concurrently handle packet sending and receiving
         select! {
-            _ = Self::send_packets(tun_read, self.packet_router) => {} // Handle packet sending
-            _ = Self::recv_packets(tun_write, self.receiver) => {} // Handle packet receiving
+            error = Self::send_packets(&mut tun_read, &self.packet_router, self.buffer_size) => {
+                warn!("Couldn't read from the TUN device. Reason: {:?}", error);
+            }
+            _ = Self::recv_packets(&mut tun_write, &mut self.receiver) => {} // Handle packet receiving
+            _ = shutdown.wait() => {} // Stop on shutdown
+        }
+
+        // Bring the TUN device down
+        if self.persistent {
+            return;
+        }
+        let mut tun = tun_read.unsplit(tun_write);
+        if let Err(error) = tun.get_mut().enabled(false) {
+            warn!("Couldn't bring the TUN device down. Reason: {:?}", error);
         }
     }
 }
 
 impl Actor<Packet> for Tun {
     fn get_addr(&self) -> super::Addr<Packet> {
-        return self.address.clone();
+        self.address.clone()
     }
 }
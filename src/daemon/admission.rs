@@ -0,0 +1,26 @@
+//! Module for the admission policy of the network.
+//!
+//! The [AdmissionPolicy] tells which nodes are members of the network. Connections with other
+//! nodes are closed right after they are established, and their addresses aren't gossiped.
+
+use std::collections::HashSet;
+
+use iroh_net::NodeId;
+use serde::Deserialize;
+
+/// Which nodes are members of the network.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdmissionPolicy {
+    /// The members of the network. Every node is a member when not set.
+    pub members: Option<HashSet<NodeId>>,
+}
+
+impl AdmissionPolicy {
+    /// Checks whether the node with the given [NodeId] is a member of the network.
+    pub fn admits(&self, node_id: NodeId) -> bool {
+        self.members
+            .as_ref()
+            .is_none_or(|members| members.contains(&node_id))
+    }
+}
@@ -0,0 +1,225 @@
+//! Module for the advertisements nodes send to their peers.
+//!
+//! An [Advertisement] tells the peers what a node offers them, such as acting as an exit node
+//! or as a router to its local networks.
+//! Right after a connection is established, each side opens a unidirectional stream
+//! carrying its advertisement as JSON, and opens a new one whenever the advertisement changes.
+//! Streams may arrive out of order, so each advertisement carries a version
+//! and the ones older than the last received on the connection are ignored.
+//! The same streams carry the entries of the [NetworkState], told apart by their first byte.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use iroh_net::NodeId;
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::daemon::{
+    actors::mesh_router::MeshRoute,
+    events::{Event, Events},
+    gossip::SignedNodeAddr,
+    state::{NetworkState, MAX_STATE_SIZE, STATE_STREAM},
+};
+
+/// The longest advertisement accepted from a peer.
+const MAX_ADVERTISEMENT_SIZE: usize = 64 * 1024;
+
+/// What a node offers to its peers.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advertisement {
+    /// Increases with each change of the advertisement.
+    #[serde(default)]
+    pub version: u64,
+    /// Whether the peers can reach the internet through the node.
+    #[serde(default)]
+    pub exit_node: bool,
+    /// The prefixes of the local networks the peers can reach through the node.
+    #[serde(default)]
+    pub routes: Vec<IpNet>,
+    /// The priority of the node among the peers advertising the same routes.
+    /// The highest one is preferred.
+    #[serde(default)]
+    pub route_priority: u32,
+    /// The overlay addresses of the node.
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+    /// The nodes the peers can reach through the node, when it forwards traffic between them.
+    #[serde(default)]
+    pub mesh_routes: Vec<MeshRoute>,
+    /// The signed addresses of the members of the network the node knows.
+    #[serde(default)]
+    pub members: Vec<SignedNodeAddr>,
+}
+
+/// Sends the advertisement of this node to its peers, and keeps the advertisements of the peers.
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct Advertisements {
+    own: Arc<RwLock<Advertisement>>,
+    peers: Arc<Mutex<HashMap<NodeId, Advertisement>>>,
+    connections: Arc<Mutex<HashMap<NodeId, Connection>>>,
+    state: Option<NetworkState>,
+    events: Events,
+}
+
+impl Advertisements {
+    /// Creates a new [Advertisements] sending `own` to the peers.
+    ///
+    /// Advertisements received from peers are reported to `events`,
+    /// and the entries of the replicated state are exchanged when `state` is set.
+    pub fn new(own: Advertisement, state: Option<NetworkState>, events: Events) -> Self {
+        Self {
+            own: Arc::new(RwLock::new(own)),
+            peers: Default::default(),
+            connections: Default::default(),
+            state,
+            events,
+        }
+    }
+
+    /// Sends the advertisement of this node on `connection`,
+    /// and receives the advertisements of the peer until the connection is closed.
+    pub fn attach(&self, node_id: NodeId, connection: Connection) {
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(node_id, connection.clone());
+        tokio::spawn(send(connection.clone(), self.own.read().unwrap().clone()));
+        if let Some(state) = &self.state {
+            state.attach(node_id, connection.clone());
+        }
+        tokio::spawn(self.clone().receive(node_id, connection));
+    }
+
+    /// Returns the advertisement of this node.
+    pub fn own(&self) -> Advertisement {
+        self.own.read().unwrap().clone()
+    }
+
+    /// Replaces the advertisement of this node and sends it to every peer.
+    ///
+    /// The version of `advertisement` is replaced by the next one.
+    pub fn set_own(&self, advertisement: Advertisement) {
+        self.update_own(|own| *own = advertisement);
+    }
+
+    /// Changes the advertisement of this node with `update` and sends it to every peer.
+    pub fn update_own(&self, update: impl FnOnce(&mut Advertisement)) {
+        let mut own = self.own.write().unwrap();
+        let version = own.version + 1;
+        update(&mut own);
+        own.version = version;
+        for connection in self.connections.lock().unwrap().values() {
+            tokio::spawn(send(connection.clone(), own.clone()));
+        }
+    }
+
+    /// Returns the advertisement of the peer with the given [NodeId],
+    /// [None] if it isn't connected or hasn't sent it yet.
+    pub fn peer(&self, node_id: NodeId) -> Option<Advertisement> {
+        self.peers.lock().unwrap().get(&node_id).cloned()
+    }
+
+    /// Returns the round-trip time of the connection to the peer with the given [NodeId],
+    /// [None] if it isn't connected.
+    pub fn rtt(&self, node_id: NodeId) -> Option<Duration> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&node_id)
+            .map(Connection::rtt)
+    }
+
+    /// Checks whether the peer with the given [NodeId] is connected.
+    pub fn is_connected(&self, node_id: NodeId) -> bool {
+        self.connections.lock().unwrap().contains_key(&node_id)
+    }
+
+    /// Returns the advertisements of all connected peers.
+    pub fn peers(&self) -> Vec<(NodeId, Advertisement)> {
+        self.peers
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(node_id, advertisement)| (*node_id, advertisement.clone()))
+            .collect()
+    }
+
+    /// Receives the advertisements of a peer until the connection is closed.
+    async fn receive(self, node_id: NodeId, connection: Connection) {
+        let mut latest = None;
+        while let Ok(mut recv) = connection.accept_uni().await {
+            let advertisement = match recv.read_to_end(MAX_STATE_SIZE).await {
+                Ok(data) if data.first() == Some(&STATE_STREAM) => {
+                    match &self.state {
+                        Some(state) => state.receive(node_id, &data[1..]),
+                        None => debug!("Ignoring the state entries of {}", node_id),
+                    }
+                    continue;
+                }
+                Ok(data) if data.len() > MAX_ADVERTISEMENT_SIZE => {
+                    debug!("Advertisement of {} is too long", node_id);
+                    continue;
+                }
+                Ok(data) => serde_json::from_slice::<Advertisement>(&data),
+                Err(error) => {
+                    debug!("Couldn't read an advertisement of {}: {:?}", node_id, error);
+                    continue;
+                }
+            };
+            match advertisement {
+                Ok(advertisement)
+                    if latest.is_some_and(|latest| advertisement.version <= latest) =>
+                {
+                    debug!(
+                        "Ignoring the outdated advertisement {} of {}",
+                        advertisement.version, node_id
+                    );
+                }
+                Ok(advertisement) => {
+                    latest = Some(advertisement.version);
+                    info!("Peer {} advertised {:?}", node_id, advertisement);
+                    self.peers
+                        .lock()
+                        .unwrap()
+                        .insert(node_id, advertisement.clone());
+                    self.events.emit(Event::Advertised {
+                        node_id,
+                        advertisement,
+                    });
+                }
+                Err(error) => debug!("Invalid advertisement from {}: {:?}", node_id, error),
+            }
+        }
+        // Only forget the peer if it hasn't connected again in the meantime
+        let mut connections = self.connections.lock().unwrap();
+        if connections
+            .get(&node_id)
+            .is_some_and(|current| current.stable_id() == connection.stable_id())
+        {
+            connections.remove(&node_id);
+            self.peers.lock().unwrap().remove(&node_id);
+        }
+    }
+}
+
+/// Sends `advertisement` on a new stream of `connection`.
+async fn send(connection: Connection, advertisement: Advertisement) {
+    let result = async {
+        let mut send = connection.open_uni().await?;
+        send.write_all(&serde_json::to_vec(&advertisement)?).await?;
+        send.finish().await?;
+        anyhow::Ok(())
+    };
+    if let Err(error) = result.await {
+        debug!("Couldn't send the advertisement: {:?}", error);
+    }
+}
@@ -0,0 +1,600 @@
+//! Module for the control socket.
+//!
+//! The control socket is a Unix socket speaking newline-delimited JSON. Each request is an
+//! object with a `command` field, answered with `{"ok": ...}` or `{"error": "..."}`:
+//!
+//! ```text
+//! {"command": "ticket"}
+//! {"command": "peers"}
+//! {"command": "stats"}
+//! {"command": "dial", "ticket": "<node ticket>"}
+//! {"command": "dial_node", "node_id": "<node id>"}
+//! {"command": "invite", "valid_for": 86400, "max_uses": 1, "tags": ["laptops"], "address": "10.0.0.7"}
+//! {"command": "invites"}
+//! {"command": "revoke_invite", "id": 0}
+//! {"command": "join", "invite": "<invite>"}
+//! {"command": "pair", "code": "7-purple-sausages"}
+//! {"command": "pair_with", "code": "7-purple-sausages"}
+//! {"command": "revoke", "node_id": "<node id>"}
+//! {"command": "revocations"}
+//! {"command": "rotate_key"}
+//! {"command": "successions"}
+//! {"command": "routes"}
+//! {"command": "add_route", "prefix": "192.168.1.0/24", "node_id": "<node id>"}
+//! {"command": "remove_route", "prefix": "192.168.1.0/24"}
+//! {"command": "subnet_routes"}
+//! {"command": "gateways"}
+//! {"command": "mesh_routes"}
+//! {"command": "state"}
+//! {"command": "set_record", "record": {"kind": "name", "node_id": "<node id>", "name": "laptop"}}
+//! {"command": "remove_record", "key": {"kind": "name", "node_id": "<node id>"}}
+//! {"command": "reload"}
+//! {"command": "swap_filter", "name": "wasm:allowlist", "path": "/etc/p2ptun/allowlist.wasm"}
+//! {"command": "shutdown"}
+//! {"command": "events"}
+//! {"command": "forward", "protocol": "tcp", "listen": "127.0.0.1:2222", "node_id": "<node id>", "target": "localhost:22"}
+//! {"command": "forwards"}
+//! {"command": "remove_forward", "id": 0}
+//! {"command": "publish", "protocol": "tcp", "target": "localhost:5432", "node_id": "<node id>"}
+//! {"command": "exit_nodes"}
+//! {"command": "set_exit", "node_id": "<node id>"}
+//! ```
+//!
+//! The `protocol` and `node_id` of `publish` are optional, allowing both protocols and every peer.
+//! `set_exit` without `node_id` stops using an exit node.
+//! The `valid_for` of `invite` is in seconds, its `tags` and `address` are optional.
+//! Only the authorities of the state issue invites.
+//! `pair` shows the code to the other node, and `pair_with` is given it.
+//! Both are answered once the nodes are paired, with the node ID of the other node.
+//! `dial_node` resolves the addresses of the node through the discovery.
+//! The `path` of `swap_filter` is optional, loading the module file of the filter again.
+//! `rotate_key` writes the new key to the key file of the daemon, and is answered with the new
+//! node ID. The daemon keeps its current key until it is restarted.
+//!
+//! The socket is only accessible to the user running the daemon, and a request line is at most
+//! [MAX_REQUEST_LENGTH] bytes long.
+//!
+//! After `events` is answered, every [Event](super::events::Event) is written as one line,
+//! until the client disconnects.
+
+use std::{
+    fs::{DirBuilder, Permissions},
+    io,
+    net::{IpAddr, SocketAddr},
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use iroh_net::{ticket::NodeTicket, NodeId};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::broadcast::error::RecvError,
+};
+use tracing::{debug, warn};
+
+use crate::daemon::{
+    firewall::PortRange,
+    forward::{ForwardGrant, ForwardProtocol, Target},
+    handle::DaemonHandle,
+    invite::SignedInvite,
+    pairing::PairingCode,
+    shutdown::ShutdownListener,
+    state::{Record, RecordKey},
+};
+
+/// The maximum length of a request line, in bytes.
+const MAX_REQUEST_LENGTH: usize = 64 * 1024;
+
+/// A request sent to the control socket.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Ticket,
+    Peers,
+    Stats,
+    Dial {
+        ticket: String,
+    },
+    DialNode {
+        node_id: NodeId,
+    },
+    Invite {
+        valid_for: u64,
+        max_uses: u32,
+        #[serde(default)]
+        tags: Vec<String>,
+        #[serde(default)]
+        address: Option<IpAddr>,
+    },
+    Invites,
+    RevokeInvite {
+        id: u64,
+    },
+    Join {
+        invite: String,
+    },
+    Pair {
+        code: String,
+    },
+    PairWith {
+        code: String,
+    },
+    Revoke {
+        node_id: NodeId,
+    },
+    Revocations,
+    RotateKey,
+    Successions,
+    Routes,
+    AddRoute {
+        prefix: IpNet,
+        node_id: NodeId,
+    },
+    RemoveRoute {
+        prefix: IpNet,
+    },
+    SubnetRoutes,
+    Gateways,
+    MeshRoutes,
+    State,
+    SetRecord {
+        record: Record,
+    },
+    RemoveRecord {
+        key: RecordKey,
+    },
+    Reload,
+    SwapFilter {
+        name: String,
+        #[serde(default)]
+        path: Option<PathBuf>,
+    },
+    Shutdown,
+    Events,
+    Forward {
+        protocol: ForwardProtocol,
+        listen: SocketAddr,
+        node_id: NodeId,
+        target: Target,
+    },
+    Forwards,
+    RemoveForward {
+        id: u64,
+    },
+    Publish {
+        protocol: Option<ForwardProtocol>,
+        target: Target,
+        node_id: Option<NodeId>,
+    },
+    ExitNodes,
+    SetExit {
+        node_id: Option<NodeId>,
+    },
+}
+
+/// A response written to the control socket.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Ok(Value),
+    Error(String),
+}
+
+/// Serves the control socket of a daemon.
+pub struct ControlServer {
+    path: PathBuf,
+    listener: UnixListener,
+}
+
+impl ControlServer {
+    /// Binds the control socket at `path`, replacing a socket left by a previous run.
+    ///
+    /// Only the user running the daemon can connect to the socket.
+    pub fn bind(path: PathBuf) -> io::Result<Self> {
+        remove_stale_socket(&path)?;
+        // Bound in a private directory, so no client connects before the permissions are set
+        let staging = path.with_extension("staging");
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        DirBuilder::new().mode(0o700).create(&staging)?;
+        let staged = staging.join("socket");
+        let listener = UnixListener::bind(&staged)?;
+        std::fs::set_permissions(&staged, Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, &path)?;
+        std::fs::remove_dir(&staging)?;
+        Ok(Self { path, listener })
+    }
+
+    /// Accepts clients until the shutdown is triggered, then removes the socket.
+    pub async fn run(self, daemon: DaemonHandle, shutdown: ShutdownListener) {
+        let accept = async {
+            loop {
+                match self.listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(
+                            shutdown
+                                .clone()
+                                .run_until(serve_client(stream, daemon.clone())),
+                        );
+                    }
+                    Err(error) => warn!("Couldn't accept a control client. Reason: {:?}", error),
+                }
+            }
+        };
+        shutdown.clone().run_until(accept).await;
+        if let Err(error) = std::fs::remove_file(&self.path) {
+            warn!("Couldn't remove the control socket. Reason: {:?}", error);
+        }
+    }
+}
+
+/// Removes the socket at `path` if no daemon listens on it anymore.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the control socket path exists and isn't a socket",
+        ));
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another daemon listens on the control socket",
+        )),
+        Err(error) if error.kind() == io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path)
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Sends one request to the control socket at `path` and returns the response.
+///
+/// [Request::Events] is answered only with the first response.
+pub async fn request(path: &Path, request: &Request) -> io::Result<Response> {
+    let stream = UnixStream::connect(path).await?;
+    let (reader, mut writer) = stream.into_split();
+    write_line(&mut writer, request).await?;
+    let line = BufReader::new(reader)
+        .lines()
+        .next_line()
+        .await?
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Returns the default path of the control socket, in `$XDG_RUNTIME_DIR` when it is set.
+pub fn default_socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("p2ptun.sock")
+}
+
+/// Serves one client until it disconnects.
+async fn serve_client(stream: UnixStream, daemon: DaemonHandle) {
+    if let Err(error) = handle_requests(stream, daemon).await {
+        debug!("Control client disconnected. Reason: {:?}", error);
+    }
+}
+
+/// Reads requests from `stream` and answers them.
+async fn handle_requests(stream: UnixStream, daemon: DaemonHandle) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    while let Some(line) = read_request(&mut reader).await? {
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => request,
+            Err(error) => {
+                write_line(&mut writer, &Response::Error(error.to_string())).await?;
+                continue;
+            }
+        };
+        if let Request::Events = request {
+            write_line(&mut writer, &Response::Ok(Value::Null)).await?;
+            return stream_events(&mut writer, &daemon).await;
+        }
+        let response = match execute(request, &daemon).await {
+            Ok(value) => Response::Ok(value),
+            Err(error) => Response::Error(error),
+        };
+        write_line(&mut writer, &response).await?;
+    }
+    Ok(())
+}
+
+/// Reads the next request line, without its newline. [None] when the client disconnected.
+///
+/// Fails if the line is longer than [MAX_REQUEST_LENGTH].
+async fn read_request(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Option<String>> {
+    let mut line = Vec::new();
+    let read = reader
+        .take(MAX_REQUEST_LENGTH as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if read == 0 {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+    } else if line.len() > MAX_REQUEST_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "request line too long",
+        ));
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
+/// Executes a request other than [Request::Events].
+async fn execute(request: Request, daemon: &DaemonHandle) -> Result<Value, String> {
+    let value = match request {
+        Request::Ticket => {
+            let ticket = daemon
+                .ticket()
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            Value::String(ticket.to_string())
+        }
+        Request::Peers => {
+            let peers = daemon
+                .peers()
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            serde_json::to_value(peers).map_err(|error| error.to_string())?
+        }
+        Request::Stats => {
+            let stats = daemon
+                .stats()
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            serde_json::to_value(stats).map_err(|error| error.to_string())?
+        }
+        Request::Dial { ticket } => {
+            let ticket = NodeTicket::from_str(&ticket).map_err(|error| error.to_string())?;
+            daemon.dial(ticket.node_addr().clone()).await;
+            Value::Null
+        }
+        Request::DialNode { node_id } => {
+            daemon.dial_node(node_id).await;
+            Value::Null
+        }
+        Request::Invite {
+            valid_for,
+            max_uses,
+            tags,
+            address,
+        } => {
+            let invite = daemon
+                .create_invite(Duration::from_secs(valid_for), max_uses, tags, address)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            Value::String(invite.to_string())
+        }
+        Request::Invites => {
+            serde_json::to_value(daemon.invites()).map_err(|error| error.to_string())?
+        }
+        Request::RevokeInvite { id } => {
+            daemon
+                .revoke_invite(id)
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::Join { invite } => {
+            let invite = SignedInvite::from_str(&invite)?;
+            daemon
+                .join(invite)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::Pair { code } => {
+            let code = PairingCode::from_str(&code)?;
+            let node_id = daemon
+                .pair(&code)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            Value::String(node_id.to_string())
+        }
+        Request::PairWith { code } => {
+            let code = PairingCode::from_str(&code)?;
+            let node_id = daemon
+                .pair_with(&code)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            Value::String(node_id.to_string())
+        }
+        Request::Revoke { node_id } => {
+            daemon
+                .revoke(node_id)
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::Revocations => {
+            serde_json::to_value(daemon.revocations()).map_err(|error| error.to_string())?
+        }
+        Request::RotateKey => {
+            let node_id = daemon
+                .rotate_key()
+                .map_err(|error| format!("{:?}", error))?;
+            json!({ "node_id": node_id })
+        }
+        Request::Successions => {
+            serde_json::to_value(daemon.successions()).map_err(|error| error.to_string())?
+        }
+        Request::Routes => {
+            let routes = daemon
+                .routes()
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            serde_json::to_value(routes).map_err(|error| error.to_string())?
+        }
+        Request::AddRoute { prefix, node_id } => {
+            daemon
+                .add_route(prefix, node_id)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::RemoveRoute { prefix } => {
+            daemon
+                .remove_route(prefix)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::SubnetRoutes => {
+            let routes = daemon
+                .subnet_routes()
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            serde_json::to_value(routes).map_err(|error| error.to_string())?
+        }
+        Request::Gateways => {
+            let gateways = daemon
+                .gateways()
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            serde_json::to_value(gateways).map_err(|error| error.to_string())?
+        }
+        Request::MeshRoutes => {
+            let routes = daemon
+                .mesh_routes()
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            serde_json::to_value(routes).map_err(|error| error.to_string())?
+        }
+        Request::State => {
+            let records = daemon
+                .state_records()
+                .map_err(|error| format!("{:?}", error))?;
+            serde_json::to_value(records).map_err(|error| error.to_string())?
+        }
+        Request::SetRecord { record } => {
+            daemon
+                .set_record(record)
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::RemoveRecord { key } => {
+            daemon
+                .remove_record(key)
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::Reload => {
+            daemon.reload().map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::SwapFilter { name, path } => {
+            daemon
+                .swap_wasm_filter(&name, path.as_deref())
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::Shutdown => {
+            daemon.shutdown_trigger().trigger();
+            Value::Null
+        }
+        Request::Forward {
+            protocol,
+            listen,
+            node_id,
+            target,
+        } => {
+            let forward = daemon
+                .forward(protocol, listen, node_id, target)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            serde_json::to_value(forward).map_err(|error| error.to_string())?
+        }
+        Request::Forwards => {
+            serde_json::to_value(daemon.forwards()).map_err(|error| error.to_string())?
+        }
+        Request::RemoveForward { id } => {
+            if !daemon.remove_forward(id) {
+                return Err(format!("no forward with ID {}", id));
+            }
+            Value::Null
+        }
+        Request::Publish {
+            protocol,
+            target,
+            node_id,
+        } => {
+            daemon.publish(ForwardGrant {
+                peer: node_id,
+                protocol,
+                host: target.host,
+                ports: PortRange {
+                    first: target.port,
+                    last: target.port,
+                },
+            });
+            Value::Null
+        }
+        Request::ExitNodes => {
+            let exit = daemon
+                .exit()
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            let exit_nodes: Vec<Value> = daemon
+                .exit_nodes()
+                .into_iter()
+                .map(|node_id| json!({ "node_id": node_id, "selected": exit == Some(node_id) }))
+                .collect();
+            Value::Array(exit_nodes)
+        }
+        Request::SetExit { node_id } => {
+            daemon
+                .set_exit(node_id)
+                .await
+                .map_err(|error| format!("{:?}", error))?;
+            Value::Null
+        }
+        Request::Events => unreachable!("events are streamed by handle_requests"),
+    };
+    Ok(value)
+}
+
+/// Writes every event to `writer` until the client disconnects.
+async fn stream_events(
+    writer: &mut (impl AsyncWrite + Unpin),
+    daemon: &DaemonHandle,
+) -> io::Result<()> {
+    let mut events = daemon.subscribe();
+    loop {
+        match events.recv().await {
+            Ok(event) => write_line(writer, &event).await?,
+            Err(RecvError::Lagged(missed)) => {
+                let response = Response::Error(format!("missed {} events", missed));
+                write_line(writer, &response).await?;
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        }
+    }
+}
+
+/// Writes `value` as one line of JSON.
+async fn write_line(
+    writer: &mut (impl AsyncWrite + Unpin),
+    value: &impl Serialize,
+) -> io::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line).await
+}
@@ -0,0 +1,634 @@
+//! Module for the discovery of the addresses of nodes by their [NodeId].
+//!
+//! Every node publishes its home relay and direct addresses to [DiscoveryConfig::server] as a
+//! [SignedPacket], in the layout of pkarr relays: a DNS packet with a TXT record named
+//! [RECORD_NAME], signed with the key of the node. Other nodes then dial it with its [NodeId]
+//! alone, see [PeerSourceMessage::DialNode], resolving its addresses from the server, or from
+//! the TXT record `_p2ptun.<node id>.<zone>` of [DiscoveryConfig::zone] when it is set. The TXT
+//! record carries the whole signed packet, see [SignedPacket::to_txt].
+//!
+//! Packets are verified when resolved, wherever they come from, and the ones signed more than
+//! [MAX_PACKET_AGE] ago are rejected, so a server or zone can't replay old addresses of a node.
+//! Nodes publish their addresses again every hour, even if they didn't change.
+//!
+//! The [DiscoveryServer] is a stand-in for the server and the DNS zone, keeping the packets
+//! in memory.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, ResponseCode},
+    rr::{rdata::TXT, Name, RData, Record, RecordType},
+};
+use hickory_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    system_conf::read_system_conf,
+    TokioAsyncResolver,
+};
+use iroh_net::{
+    key::{SecretKey, Signature},
+    relay::RelayUrl,
+    NodeAddr, NodeId,
+};
+use reqwest::{StatusCode, Url};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
+    select,
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+use crate::daemon::{
+    actors::{peer_source::PeerSourceMessage, Addr},
+    store::unix_time,
+    DaemonError,
+};
+
+/// The name of the TXT record carrying the addresses, relative to the node.
+pub const RECORD_NAME: &str = "_p2ptun";
+/// How long the resolved records are valid, in seconds.
+const TTL: u32 = 300;
+/// How often a node publishes its addresses again, even if they didn't change.
+const REPUBLISH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long ago a resolved packet may have been signed.
+pub const MAX_PACKET_AGE: Duration = Duration::from_secs(3 * 60 * 60);
+/// The longest packet accepted, as pkarr relays do.
+const MAX_PACKET_SIZE: usize = 1000;
+/// The longest signed packet accepted: the signature, the timestamp and the packet.
+const MAX_SIGNED_PACKET_SIZE: usize = 72 + MAX_PACKET_SIZE;
+/// The longest character string of a TXT record, kept even to split hex digits in pairs.
+const MAX_TXT_STRING: usize = 254;
+/// The longest HTTP request head accepted by the [DiscoveryServer].
+const MAX_HTTP_HEAD: usize = 8192;
+
+/// Settings of the discovery of the addresses of nodes.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// The server the addresses of this node are published to, and the addresses of other nodes
+    /// resolved from. Nothing is published when not set.
+    pub server: Option<Url>,
+    /// The DNS zone the addresses of other nodes are resolved from, instead of the server.
+    pub zone: Option<String>,
+    /// The name server queried for the records of [DiscoveryConfig::zone].
+    /// The name servers of the system are queried when not set.
+    pub name_server: Option<SocketAddr>,
+    /// How often the addresses of this node are checked, and published when they changed.
+    pub interval: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            server: None,
+            zone: None,
+            name_server: None,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+impl DiscoveryConfig {
+    /// Checks whether the addresses of nodes are published or resolved.
+    pub fn is_enabled(&self) -> bool {
+        self.server.is_some() || self.zone.is_some()
+    }
+}
+
+/// The addresses of a node in a DNS packet, signed by the node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedPacket {
+    pub node_id: NodeId,
+    /// When the node signed the packet, in microseconds since the Unix epoch.
+    /// Newer packets replace older ones.
+    pub timestamp: u64,
+    /// The DNS packet, with the TXT record [RECORD_NAME].
+    pub packet: Vec<u8>,
+    /// The signature of the timestamp and the packet by the node.
+    pub signature: Vec<u8>,
+}
+
+impl SignedPacket {
+    /// Signs the addresses of the node of `secret_key`.
+    pub fn sign(
+        secret_key: &SecretKey,
+        node_addr: &NodeAddr,
+        timestamp: u64,
+    ) -> Result<Self, DaemonError> {
+        let mut entries: Vec<String> = node_addr
+            .relay_url()
+            .map(|url| format!("relay={}", url))
+            .into_iter()
+            .collect();
+        entries.extend(
+            node_addr
+                .direct_addresses()
+                .map(|address| format!("addr={}", address)),
+        );
+        let name = Name::from_ascii(format!("{}.", RECORD_NAME)).map_err(anyhow::Error::from)?;
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true);
+        message.add_answer(Record::from_rdata(name, TTL, RData::TXT(TXT::new(entries))));
+        let packet = message.to_vec().map_err(anyhow::Error::from)?;
+        let signature = secret_key.sign(&Self::signed_data(timestamp, &packet));
+        Ok(Self {
+            node_id: secret_key.public(),
+            timestamp,
+            packet,
+            signature: signature.to_bytes().to_vec(),
+        })
+    }
+
+    /// Checks whether the packet is signed by its node.
+    pub fn verify(&self) -> bool {
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        let data = Self::signed_data(self.timestamp, &self.packet);
+        self.node_id.verify(&data, &signature).is_ok()
+    }
+
+    /// Checks whether the packet was signed more than [MAX_PACKET_AGE] ago.
+    pub fn is_stale(&self) -> bool {
+        unix_time().saturating_sub(Duration::from_micros(self.timestamp)) > MAX_PACKET_AGE
+    }
+
+    /// Returns the addresses of the node in the packet.
+    pub fn node_addr(&self) -> Result<NodeAddr, DaemonError> {
+        let message = Message::from_vec(&self.packet).map_err(anyhow::Error::from)?;
+        let txts: Vec<TXT> = message
+            .answers()
+            .iter()
+            .filter_map(|record| match record.data() {
+                Some(RData::TXT(txt)) => Some(txt.clone()),
+                _ => None,
+            })
+            .collect();
+        Ok(node_addr_from_txts(self.node_id, &txts))
+    }
+
+    /// Encodes the packet as sent to pkarr relays: the signature, the timestamp as a
+    /// big-endian `u64`, then the DNS packet.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = self.signature.clone();
+        data.extend_from_slice(&self.timestamp.to_be_bytes());
+        data.extend_from_slice(&self.packet);
+        data
+    }
+
+    /// Decodes a packet of the node with the given [NodeId] encoded with
+    /// [SignedPacket::to_bytes].
+    pub fn from_bytes(node_id: NodeId, data: &[u8]) -> Result<Self, DaemonError> {
+        if !(72..=MAX_SIGNED_PACKET_SIZE).contains(&data.len()) {
+            let error = anyhow::anyhow!("invalid packet length: {} bytes", data.len());
+            return Err(error.into());
+        }
+        let (signature, rest) = data.split_at(64);
+        let (timestamp, packet) = rest.split_at(8);
+        Ok(Self {
+            node_id,
+            timestamp: u64::from_be_bytes(timestamp.try_into().expect("the timestamp is 8 bytes")),
+            packet: packet.to_vec(),
+            signature: signature.to_vec(),
+        })
+    }
+
+    /// Encodes the packet as served by DNS zones: [SignedPacket::to_bytes] in hex, split in
+    /// character strings of a TXT record.
+    pub fn to_txt(&self) -> TXT {
+        let hex: String = self
+            .to_bytes()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        let strings = hex
+            .as_bytes()
+            .chunks(MAX_TXT_STRING)
+            .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+            .collect();
+        TXT::new(strings)
+    }
+
+    /// Decodes a packet of the node with the given [NodeId] encoded with
+    /// [SignedPacket::to_txt].
+    pub fn from_txt(node_id: NodeId, txt: &TXT) -> Result<Self, DaemonError> {
+        let hex: Vec<u8> = txt.txt_data().iter().flatten().copied().collect();
+        if hex.len() % 2 != 0 {
+            return Err(anyhow::anyhow!("odd number of hex digits").into());
+        }
+        let data = hex
+            .chunks(2)
+            .map(|pair| {
+                let pair = std::str::from_utf8(pair).ok()?;
+                u8::from_str_radix(pair, 16).ok()
+            })
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| anyhow::anyhow!("invalid hex digits"))?;
+        Self::from_bytes(node_id, &data)
+    }
+
+    /// Returns the data covered by the signature, encoded as pkarr does.
+    fn signed_data(timestamp: u64, packet: &[u8]) -> Vec<u8> {
+        let mut data = format!("3:seqi{}e1:v{}:", timestamp, packet.len()).into_bytes();
+        data.extend_from_slice(packet);
+        data
+    }
+}
+
+/// Reads the addresses of the node with the given [NodeId] from the entries of `txts`.
+///
+/// Entries this node can't read are skipped.
+fn node_addr_from_txts(node_id: NodeId, txts: &[TXT]) -> NodeAddr {
+    let mut node_addr = NodeAddr::new(node_id);
+    let mut addresses = Vec::new();
+    let entries = txts
+        .iter()
+        .flat_map(|txt| txt.txt_data())
+        .filter_map(|entry| std::str::from_utf8(entry).ok()?.split_once('='));
+    for (key, value) in entries {
+        match key {
+            "relay" => {
+                if let Ok(url) = value.parse::<RelayUrl>() {
+                    node_addr = node_addr.with_relay_url(url);
+                }
+            }
+            "addr" => addresses.extend(value.parse::<SocketAddr>()),
+            _ => {}
+        }
+    }
+    node_addr.with_direct_addresses(addresses)
+}
+
+/// Publishes the addresses of this node, and resolves the addresses of other nodes.
+///
+/// Clones share the same HTTP client and resolver.
+#[derive(Clone)]
+pub struct Discovery {
+    config: Arc<DiscoveryConfig>,
+    secret_key: SecretKey,
+    client: reqwest::Client,
+    /// [None] when [DiscoveryConfig::zone] isn't set.
+    resolver: Option<TokioAsyncResolver>,
+}
+
+impl Discovery {
+    /// Creates a new [Discovery] publishing the addresses signed with `secret_key`.
+    pub fn new(config: DiscoveryConfig, secret_key: SecretKey) -> Result<Self, DaemonError> {
+        let resolver = match (&config.zone, config.name_server) {
+            (None, _) => None,
+            (Some(_), Some(name_server)) => {
+                let name_servers = NameServerConfigGroup::from_ips_clear(
+                    &[name_server.ip()],
+                    name_server.port(),
+                    true,
+                );
+                let resolver_config = ResolverConfig::from_parts(None, vec![], name_servers);
+                Some((resolver_config, ResolverOpts::default()))
+            }
+            (Some(_), None) => Some(read_system_conf().map_err(anyhow::Error::from)?),
+        };
+        // The signed packets don't always fit in a DNS response without EDNS
+        let resolver = resolver.map(|(resolver_config, mut options)| {
+            options.edns0 = true;
+            TokioAsyncResolver::tokio(resolver_config, options)
+        });
+        Ok(Self {
+            config: Arc::new(config),
+            secret_key,
+            client: reqwest::Client::new(),
+            resolver,
+        })
+    }
+
+    /// Returns the URL of the packet of the node with the given [NodeId] on `server`.
+    fn url(server: &Url, node_id: NodeId) -> Result<Url, DaemonError> {
+        let url = format!("{}/{}", server.as_str().trim_end_matches('/'), node_id);
+        Ok(Url::parse(&url).map_err(anyhow::Error::from)?)
+    }
+
+    /// Publishes `node_addr`, the addresses of this node, to [DiscoveryConfig::server].
+    pub async fn publish(&self, node_addr: &NodeAddr) -> Result<(), DaemonError> {
+        let Some(server) = &self.config.server else {
+            return Ok(());
+        };
+        let packet =
+            SignedPacket::sign(&self.secret_key, node_addr, unix_time().as_micros() as u64)?;
+        self.client
+            .put(Self::url(server, packet.node_id)?)
+            .body(packet.to_bytes())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(anyhow::Error::from)?;
+        Ok(())
+    }
+
+    /// Resolves the addresses of the node with the given [NodeId], from [DiscoveryConfig::zone]
+    /// when it is set, and from [DiscoveryConfig::server] otherwise.
+    pub async fn resolve(&self, node_id: NodeId) -> Result<NodeAddr, DaemonError> {
+        let node_addr = match (&self.resolver, &self.config.zone, &self.config.server) {
+            (Some(resolver), Some(zone), _) => {
+                let name = format!(
+                    "{}.{}.{}.",
+                    RECORD_NAME,
+                    node_id,
+                    zone.trim_end_matches('.')
+                );
+                let lookup = match resolver.txt_lookup(name).await {
+                    Ok(lookup) => lookup,
+                    Err(error) => {
+                        debug!("Couldn't resolve {}: {:?}", node_id, error);
+                        return Err(DaemonError::NotDiscovered(node_id));
+                    }
+                };
+                // The newest of the valid packets, if the zone serves several
+                let packet = lookup
+                    .iter()
+                    .filter_map(|txt| SignedPacket::from_txt(node_id, txt).ok())
+                    .filter(|packet| Self::is_valid(packet, "DNS zone"))
+                    .max_by_key(|packet| packet.timestamp)
+                    .ok_or(DaemonError::NotDiscovered(node_id))?;
+                packet.node_addr()?
+            }
+            (_, _, Some(server)) => {
+                let response = self
+                    .client
+                    .get(Self::url(server, node_id)?)
+                    .send()
+                    .await
+                    .map_err(anyhow::Error::from)?;
+                if response.status() == StatusCode::NOT_FOUND {
+                    return Err(DaemonError::NotDiscovered(node_id));
+                }
+                let mut response = response.error_for_status().map_err(anyhow::Error::from)?;
+                let mut data = Vec::new();
+                while let Some(chunk) = response.chunk().await.map_err(anyhow::Error::from)? {
+                    if data.len() + chunk.len() > MAX_SIGNED_PACKET_SIZE {
+                        warn!(
+                            "The discovery server sent an oversized packet of {}",
+                            node_id
+                        );
+                        return Err(DaemonError::NotDiscovered(node_id));
+                    }
+                    data.extend_from_slice(&chunk);
+                }
+                let packet = SignedPacket::from_bytes(node_id, &data)?;
+                if !Self::is_valid(&packet, "discovery server") {
+                    return Err(DaemonError::NotDiscovered(node_id));
+                }
+                packet.node_addr()?
+            }
+            _ => return Err(DaemonError::NotDiscovered(node_id)),
+        };
+        if node_addr.relay_url().is_none() && node_addr.direct_addresses().next().is_none() {
+            return Err(DaemonError::NotDiscovered(node_id));
+        }
+        Ok(node_addr)
+    }
+
+    /// Checks whether `packet`, sent by `source`, is signed by its node and recent.
+    fn is_valid(packet: &SignedPacket, source: &str) -> bool {
+        if !packet.verify() {
+            warn!("The {} sent a forged packet of {}", source, packet.node_id);
+            false
+        } else if packet.is_stale() {
+            debug!("The {} sent a stale packet of {}", source, packet.node_id);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Publishes the addresses of this node, got from `peer_source`, whenever they change.
+    pub async fn run(self, peer_source: Addr<PeerSourceMessage>) {
+        if self.config.server.is_none() {
+            return std::future::pending().await;
+        }
+        let mut ticks = interval(self.config.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut published: Option<(NodeAddr, Instant)> = None;
+        loop {
+            ticks.tick().await;
+            let node_addr = match peer_source.ask(PeerSourceMessage::GetTicket).await {
+                Ok(Ok(ticket)) => ticket.node_addr().clone(),
+                Ok(Err(error)) => {
+                    warn!(
+                        "Couldn't get the addresses of this node. Reason: {:?}",
+                        error
+                    );
+                    continue;
+                }
+                Err(error) => {
+                    warn!(
+                        "Couldn't get the addresses of this node. Reason: {:?}",
+                        error
+                    );
+                    continue;
+                }
+            };
+            let outdated = published.as_ref().is_none_or(|(published, at)| {
+                *published != node_addr || at.elapsed() >= REPUBLISH_INTERVAL
+            });
+            if !outdated {
+                continue;
+            }
+            match self.publish(&node_addr).await {
+                Ok(()) => {
+                    debug!("Published the addresses of this node");
+                    published = Some((node_addr, Instant::now()));
+                }
+                Err(error) => warn!("Couldn't publish the addresses. Reason: {:?}", error),
+            }
+        }
+    }
+}
+
+/// A stand-in for the discovery server and its DNS zone, keeping the packets in memory.
+///
+/// Packets are published with `PUT /<node id>` and resolved with `GET /<node id>` over HTTP.
+/// The DNS server answers the queries for the TXT records `_p2ptun.<node id>.<zone>`,
+/// whatever the zone, with the packets encoded by [SignedPacket::to_txt].
+pub struct DiscoveryServer {
+    http: TcpListener,
+    dns: UdpSocket,
+    packets: Arc<Mutex<HashMap<NodeId, SignedPacket>>>,
+}
+
+impl DiscoveryServer {
+    /// Binds the HTTP server to `http` and the DNS server to `dns`.
+    pub async fn bind(http: SocketAddr, dns: SocketAddr) -> Result<Self, DaemonError> {
+        Ok(Self {
+            http: TcpListener::bind(http).await?,
+            dns: UdpSocket::bind(dns).await?,
+            packets: Default::default(),
+        })
+    }
+
+    /// Returns the URL of the HTTP server.
+    pub fn url(&self) -> Result<Url, DaemonError> {
+        let url = format!("http://{}", self.http.local_addr()?);
+        Ok(Url::parse(&url).map_err(anyhow::Error::from)?)
+    }
+
+    /// Returns the address of the DNS server.
+    pub fn dns_address(&self) -> Result<SocketAddr, DaemonError> {
+        Ok(self.dns.local_addr()?)
+    }
+
+    /// Serves the HTTP and DNS requests.
+    pub async fn run(self) {
+        let mut buffer = vec![0u8; 512];
+        loop {
+            select! {
+                accepted = self.http.accept() => match accepted {
+                    Ok((client, _)) => {
+                        let packets = self.packets.clone();
+                        tokio::spawn(async move {
+                            if let Err(error) = serve_http(client, packets).await {
+                                debug!("Couldn't serve an HTTP client. Reason: {:?}", error);
+                            }
+                        });
+                    }
+                    Err(error) => warn!("Couldn't accept an HTTP client. Reason: {:?}", error),
+                },
+                received = self.dns.recv_from(&mut buffer) => match received {
+                    Ok((length, from)) => {
+                        let Some(response) = answer_dns(&buffer[..length], &self.packets) else {
+                            continue;
+                        };
+                        if let Err(error) = self.dns.send_to(&response, from).await {
+                            debug!("Couldn't answer {}. Reason: {:?}", from, error);
+                        }
+                    }
+                    Err(error) => warn!("Couldn't receive a DNS query. Reason: {:?}", error),
+                },
+            }
+        }
+    }
+}
+
+/// Serves one HTTP request of `client`, then closes the connection.
+async fn serve_http(
+    client: TcpStream,
+    packets: Arc<Mutex<HashMap<NodeId, SignedPacket>>>,
+) -> anyhow::Result<()> {
+    let mut client = BufReader::new(client);
+    let mut request_line = String::new();
+    client.read_line(&mut request_line).await?;
+    let mut content_length = 0;
+    let mut head_length = request_line.len();
+    loop {
+        let mut line = String::new();
+        head_length += client.read_line(&mut line).await?;
+        anyhow::ensure!(head_length <= MAX_HTTP_HEAD, "request head too long");
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse()?;
+            }
+        }
+    }
+    anyhow::ensure!(
+        content_length <= MAX_SIGNED_PACKET_SIZE,
+        "request body too long"
+    );
+    let mut body = vec![0u8; content_length];
+    client.read_exact(&mut body).await?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let node_id = parts
+        .next()
+        .and_then(|path| path.trim_start_matches('/').parse::<NodeId>().ok());
+    let (status, body) = match (method, node_id) {
+        ("GET", Some(node_id)) => match packets.lock().unwrap().get(&node_id) {
+            Some(packet) => ("200 OK", packet.to_bytes()),
+            None => ("404 Not Found", Vec::new()),
+        },
+        ("PUT", Some(node_id)) => match SignedPacket::from_bytes(node_id, &body) {
+            Ok(packet) if packet.verify() => {
+                let mut packets = packets.lock().unwrap();
+                let newer = packets
+                    .get(&node_id)
+                    .is_none_or(|known| known.timestamp < packet.timestamp);
+                if newer {
+                    info!("Stored the addresses of {}", node_id);
+                    packets.insert(node_id, packet);
+                    ("204 No Content", Vec::new())
+                } else {
+                    ("409 Conflict", Vec::new())
+                }
+            }
+            _ => ("400 Bad Request", Vec::new()),
+        },
+        (_, None) => ("404 Not Found", Vec::new()),
+        _ => ("405 Method Not Allowed", Vec::new()),
+    };
+    let mut client = client.into_inner();
+    let head = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    client.write_all(head.as_bytes()).await?;
+    client.write_all(&body).await?;
+    client.shutdown().await?;
+    Ok(())
+}
+
+/// Answers a DNS query for the TXT record of a node with its packet.
+///
+/// Returns [None] when `query` isn't a valid DNS query.
+fn answer_dns(query: &[u8], packets: &Mutex<HashMap<NodeId, SignedPacket>>) -> Option<Vec<u8>> {
+    let query = Message::from_vec(query).ok()?;
+    if query.message_type() != MessageType::Query {
+        return None;
+    }
+    let mut response = Message::new();
+    response
+        .set_id(query.id())
+        .set_message_type(MessageType::Response)
+        .set_op_code(OpCode::Query)
+        .set_authoritative(true)
+        .set_recursion_desired(query.recursion_desired());
+    response.add_queries(query.queries().to_vec());
+    let mut found = false;
+    for question in query.queries() {
+        let labels: Vec<String> = question
+            .name()
+            .iter()
+            .map(|label| String::from_utf8_lossy(label).to_lowercase())
+            .collect();
+        let node_id = match labels.as_slice() {
+            [record, node_id, ..] if record == RECORD_NAME => node_id.parse::<NodeId>().ok(),
+            _ => None,
+        };
+        let packet = node_id.and_then(|node_id| packets.lock().unwrap().get(&node_id).cloned());
+        let Some(packet) = packet else {
+            continue;
+        };
+        found = true;
+        if matches!(question.query_type(), RecordType::TXT | RecordType::ANY) {
+            response.add_answer(Record::from_rdata(
+                question.name().clone(),
+                TTL,
+                RData::TXT(packet.to_txt()),
+            ));
+        }
+    }
+    if !found {
+        response.set_response_code(ResponseCode::NXDomain);
+    }
+    response.to_vec().ok()
+}
@@ -0,0 +1,206 @@
+//! Module for the events emitted by the daemon.
+//!
+//! Every [Event] is broadcast to all subscribers, which can be obtained from
+//! [DaemonHandle::subscribe](super::handle::DaemonHandle::subscribe) or streamed over the
+//! control socket. A subscriber that falls behind misses the oldest events.
+
+use std::net::SocketAddr;
+
+use ipnet::IpNet;
+use iroh_net::{magicsock::ConnectionType, NodeId};
+use serde::Serialize;
+use tokio::sync::broadcast;
+
+use crate::daemon::{advertisements::Advertisement, packet::ip::Flow, supervisor::ExitReason};
+
+/// How many events are kept for subscribers that haven't received them yet.
+const EVENT_CAPACITY: usize = 256;
+
+/// Something that happened in the daemon.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// The daemon has started.
+    DaemonStarted { node_id: NodeId },
+    /// The daemon started stopping.
+    DaemonStopping,
+    /// An actor stopped before the shutdown. `restarting` tells whether it is started again.
+    ActorExited {
+        name: String,
+        reason: ExitReason,
+        restarting: bool,
+    },
+    /// A peer connected to this node, or this node connected to a peer.
+    PeerConnected { node_id: NodeId },
+    /// A peer was disconnected.
+    PeerDisconnected {
+        node_id: NodeId,
+        reason: DisconnectReason,
+    },
+    /// The path used to reach a peer changed.
+    PathChanged { node_id: NodeId, path: Path },
+    /// Dialing a peer failed.
+    DialFailed { node_id: NodeId, reason: String },
+    /// This node connected to its home relay, or switched to another one.
+    RelayConnected { url: String },
+    /// This node lost the connection to its home relay.
+    RelayLost,
+    /// A route to the TUN device was added.
+    ///
+    /// `node_id` is the peer the prefix is routed through, [None] for the overlay prefix.
+    RouteAdded {
+        prefix: IpNet,
+        node_id: Option<NodeId>,
+    },
+    /// A route to the TUN device was removed.
+    RouteRemoved {
+        prefix: IpNet,
+        node_id: Option<NodeId>,
+    },
+    /// The firewall dropped a packet received from a peer.
+    AclDrop {
+        node_id: NodeId,
+        /// The flow of the packet. [None] if it isn't an IP packet.
+        flow: Option<Flow>,
+    },
+    /// A peer sent what it offers to other nodes.
+    Advertised {
+        node_id: NodeId,
+        advertisement: Advertisement,
+    },
+    /// A prefix advertised by a peer wasn't routed through it, because it overlaps another prefix.
+    ///
+    /// `conflicting` is the peer the other prefix is routed through,
+    /// [None] for the overlay and the prefixes advertised by this node.
+    RouteConflict {
+        prefix: IpNet,
+        node_id: NodeId,
+        conflicting: Option<NodeId>,
+    },
+    /// Another exit node was selected. [None] when traffic stopped going through an exit node.
+    ExitChanged { node_id: Option<NodeId> },
+    /// A connected peer stopped answering keepalives.
+    PeerUnresponsive { node_id: NodeId },
+    /// An unresponsive peer answers keepalives again.
+    PeerResponsive { node_id: NodeId },
+    /// The traffic to a prefix advertised by several peers moved to another of them.
+    ///
+    /// `from` is [None] when no peer was available, `to` is [None] when none is available anymore.
+    Failover {
+        prefix: IpNet,
+        from: Option<NodeId>,
+        to: Option<NodeId>,
+        reason: FailoverReason,
+    },
+    /// Entries of the replicated state were merged, received from the peer with `node_id`,
+    /// or written by this node when it's [None].
+    StateChanged {
+        node_id: Option<NodeId>,
+        entries: usize,
+    },
+    /// A node joined the network with an invite issued by this node.
+    InviteRedeemed { node_id: NodeId, invite_id: u64 },
+    /// This node was paired with the node with `node_id` with a short code.
+    Paired { node_id: NodeId },
+    /// The key of the node with `node_id` was revoked by the admin `issuer`.
+    Revoked { node_id: NodeId, issuer: NodeId },
+    /// The key of the node `predecessor` was replaced by the key of `successor`.
+    KeyRotated {
+        predecessor: NodeId,
+        successor: NodeId,
+    },
+}
+
+/// Why the traffic to a prefix moved to another peer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailoverReason {
+    /// The previous peer was disconnected.
+    Disconnected,
+    /// The previous peer stopped answering keepalives.
+    Unresponsive,
+    /// The previous peer stopped advertising the prefix.
+    Withdrawn,
+    /// A preferred peer became available again, according to the
+    /// [FailbackPolicy](super::actors::peer_collection::FailbackPolicy).
+    Failback,
+    /// A peer became available while none was.
+    Recovered,
+}
+
+/// Why a peer was disconnected.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DisconnectReason {
+    /// The peer is shutting down.
+    PeerShutdown,
+    /// The peer closed the stream.
+    Closed,
+    /// The connection was lost.
+    ConnectionLost { error: String },
+    /// This node asked for the disconnection.
+    Requested,
+    /// The actor holding the peers stopped.
+    Stopped,
+}
+
+/// The path used to reach a peer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Path {
+    /// A direct UDP path.
+    Direct { address: SocketAddr },
+    /// A path through a relay.
+    Relay { url: String },
+    /// Both a direct path, not confirmed recently, and a relay.
+    Mixed { address: SocketAddr, url: String },
+    /// No verified path.
+    None,
+}
+
+impl From<ConnectionType> for Path {
+    fn from(connection_type: ConnectionType) -> Self {
+        match connection_type {
+            ConnectionType::Direct(address) => Self::Direct { address },
+            ConnectionType::Relay(url) => Self::Relay {
+                url: url.to_string(),
+            },
+            ConnectionType::Mixed(address, url) => Self::Mixed {
+                address,
+                url: url.to_string(),
+            },
+            ConnectionType::None => Self::None,
+        }
+    }
+}
+
+/// Broadcasts [Event]s to their subscribers.
+#[derive(Debug, Clone)]
+pub struct Events {
+    sender: broadcast::Sender<Event>,
+}
+
+impl Events {
+    /// Creates a broadcaster without subscribers.
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CAPACITY);
+        Self { sender }
+    }
+
+    /// Sends `event` to all current subscribers.
+    pub fn emit(&self, event: Event) {
+        // An error only means there are no subscribers
+        let _ = self.sender.send(event);
+    }
+
+    /// Subscribes to the events emitted from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
+}
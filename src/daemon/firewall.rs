@@ -0,0 +1,504 @@
+//! Module for the stateful packet firewall.
+//!
+//! The firewall filters packets received from peers using an ordered list of [Rule]s,
+//! and tracks the flows started by this host, so the return traffic of those flows
+//! passes without a matching rule.
+//!
+//! Flows are tracked per peer. A flow sent to a single peer only lets its replies from that
+//! peer through. The peer of an outgoing packet is the one its [RouteTable] sends it to.
+//! A flow sent to every peer is bound to the first peer replying to it.
+//!
+//! Rules are loaded from a TOML file:
+//!
+//! ```toml
+//! default_action = "deny"
+//!
+//! [tags]
+//! "<node id>" = ["contractors"]
+//!
+//! [[rules]]
+//! action = "allow"
+//! tag = "contractors"
+//! protocol = "tcp"
+//! destination = "10.0.0.5/32"
+//! destination_ports = 443
+//! ```
+//!
+//! Peers also have the tags granted by the [Invites] they joined with. The rules and tags naming
+//! a node apply to the keys that replaced its key, see [Rotations].
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use ipnet::IpNet;
+use iroh_net::NodeId;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::daemon::{
+    actors::peer_collection::RouteTable,
+    events::{Event, Events},
+    invite::Invites,
+    packet::{
+        ip::{Flow, PROTOCOL_ICMP, PROTOCOL_ICMPV6, PROTOCOL_TCP, PROTOCOL_UDP},
+        Packet,
+    },
+    pipeline::{PacketProcessor, Verdict},
+    rotation::Rotations,
+    DaemonError,
+};
+
+/// How long an idle TCP flow is kept in the connection table.
+const TCP_FLOW_TIMEOUT: Duration = Duration::from_secs(600);
+/// How long an idle flow of any other protocol is kept in the connection table.
+const FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often expired flows are removed from the connection table.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The action taken on a packet matched by a [Rule].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Allow,
+    #[default]
+    Deny,
+}
+
+/// The transport protocol matched by a [Rule].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+    /// Matches both ICMP and ICMPv6.
+    Icmp,
+}
+
+impl Protocol {
+    /// Checks whether the IANA protocol number belongs to this protocol.
+    fn matches(self, protocol: u8) -> bool {
+        match self {
+            Self::Tcp => protocol == PROTOCOL_TCP,
+            Self::Udp => protocol == PROTOCOL_UDP,
+            Self::Icmp => protocol == PROTOCOL_ICMP || protocol == PROTOCOL_ICMPV6,
+        }
+    }
+}
+
+/// An inclusive range of ports, written as `443` or `"8000-8100"`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "PortRangeConfig")]
+pub struct PortRange {
+    pub first: u16,
+    pub last: u16,
+}
+
+impl PortRange {
+    /// Checks whether `port` is in the range.
+    pub fn contains(&self, port: u16) -> bool {
+        (self.first..=self.last).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .map_err(|error| format!("invalid port `{}`: {}", port, error))
+        };
+        let (first, last) = match s.split_once('-') {
+            Some((first, last)) => (parse(first)?, parse(last)?),
+            None => (parse(s)?, parse(s)?),
+        };
+        if first > last {
+            return Err(format!("invalid port range `{}`", s));
+        }
+        Ok(Self { first, last })
+    }
+}
+
+/// The representations of [PortRange] accepted in the rules file.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PortRangeConfig {
+    Single(u16),
+    Range(String),
+}
+
+impl TryFrom<PortRangeConfig> for PortRange {
+    type Error = String;
+
+    fn try_from(config: PortRangeConfig) -> Result<Self, Self::Error> {
+        match config {
+            PortRangeConfig::Single(port) => Ok(Self {
+                first: port,
+                last: port,
+            }),
+            PortRangeConfig::Range(range) => range.parse(),
+        }
+    }
+}
+
+/// A single firewall rule. Every field that is set must match for the rule to apply.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// The action taken on matching packets.
+    pub action: Action,
+    /// The peer that sent the packet.
+    pub peer: Option<NodeId>,
+    /// A tag assigned to the peer that sent the packet.
+    pub tag: Option<String>,
+    /// The transport protocol of the packet.
+    pub protocol: Option<Protocol>,
+    /// The network containing the source address.
+    pub source: Option<IpNet>,
+    /// The network containing the destination address.
+    pub destination: Option<IpNet>,
+    /// The source ports.
+    pub source_ports: Option<PortRange>,
+    /// The destination ports.
+    pub destination_ports: Option<PortRange>,
+}
+
+impl Rule {
+    /// Checks whether the rule matches a packet of `flow` sent by one of `peers`, the keys of
+    /// the same node, with `tags`.
+    ///
+    /// Packets that aren't IP packets only match rules without any L3 or L4 criteria.
+    fn matches(&self, peers: &[NodeId], tags: &[String], flow: Option<&Flow>) -> bool {
+        if self
+            .peer
+            .is_some_and(|rule_peer| !peers.contains(&rule_peer))
+        {
+            return false;
+        }
+        if self.tag.as_ref().is_some_and(|tag| !tags.contains(tag)) {
+            return false;
+        }
+        let Some(flow) = flow else {
+            return self.protocol.is_none()
+                && self.source.is_none()
+                && self.destination.is_none()
+                && self.source_ports.is_none()
+                && self.destination_ports.is_none();
+        };
+        let has_ports = flow.protocol == PROTOCOL_TCP || flow.protocol == PROTOCOL_UDP;
+        self.protocol.is_none_or(|p| p.matches(flow.protocol))
+            && self.source.is_none_or(|net| net.contains(&flow.source))
+            && self
+                .destination
+                .is_none_or(|net| net.contains(&flow.destination))
+            && self
+                .source_ports
+                .is_none_or(|ports| has_ports && ports.contains(flow.source_port))
+            && self
+                .destination_ports
+                .is_none_or(|ports| has_ports && ports.contains(flow.destination_port))
+    }
+}
+
+/// The complete firewall configuration, as loaded from a rules file.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Ruleset {
+    /// The action taken on packets not matched by any rule.
+    #[serde(default)]
+    pub default_action: Action,
+    /// Tags assigned to peers, used by [Rule::tag].
+    #[serde(default)]
+    pub tags: HashMap<NodeId, Vec<String>>,
+    /// The rules, evaluated in order until the first match.
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+impl Ruleset {
+    /// Loads a ruleset from a TOML file.
+    pub fn load(path: &Path) -> Result<Self, DaemonError> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    /// Evaluates the rules for a packet of `flow` sent by one of `peers`, the current key of
+    /// a node followed by the keys it replaced, granted the tags `granted`.
+    fn evaluate(&self, peers: &[NodeId], granted: &[String], flow: Option<&Flow>) -> Action {
+        let mut tags: Vec<String> = peers
+            .iter()
+            .filter_map(|peer| self.tags.get(peer))
+            .flatten()
+            .cloned()
+            .collect();
+        tags.extend_from_slice(granted);
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(peers, &tags, flow))
+            .map_or(self.default_action, |rule| rule.action)
+    }
+}
+
+/// Counters of packets handled by the firewall.
+#[derive(Debug, Default)]
+pub struct FirewallStats {
+    /// Incoming packets let through.
+    pub accepted: AtomicU64,
+    /// Incoming packets dropped.
+    pub dropped: AtomicU64,
+}
+
+/// A handle that replaces the rules of a running [Firewall].
+#[derive(Debug, Clone)]
+pub struct FirewallHandle {
+    path: PathBuf,
+    ruleset: Arc<RwLock<Ruleset>>,
+    stats: Arc<FirewallStats>,
+}
+
+impl FirewallHandle {
+    /// Loads the rules file again and makes the firewall use the new rules.
+    ///
+    /// On error, the firewall keeps using the previous rules.
+    pub fn reload(&self) -> Result<(), DaemonError> {
+        let ruleset = Ruleset::load(&self.path)?;
+        *self.ruleset.write().unwrap() = ruleset;
+        Ok(())
+    }
+
+    /// Returns the counters of the firewall.
+    pub fn stats(&self) -> &FirewallStats {
+        &self.stats
+    }
+}
+
+/// A stateful firewall filtering the packets received from peers.
+pub struct Firewall {
+    path: PathBuf,
+    ruleset: Arc<RwLock<Ruleset>>,
+    stats: Arc<FirewallStats>,
+    events: Events,
+    /// The invites granting tags to the peers that joined with them.
+    invites: Option<Invites>,
+    /// The rotations following the peers named by the rules to their new keys.
+    rotations: Option<Rotations>,
+    /// The routes telling which peer the outgoing packets are sent to.
+    route_table: Option<RouteTable>,
+    /// Flows started by this host, with the time a packet of the flow was last seen,
+    /// by the peer they were sent to. Flows sent to every peer are kept under [None]
+    /// until a peer replies.
+    connections: HashMap<(Option<NodeId>, Flow), Instant>,
+    /// The peers the flows sent to every peer are bound to.
+    bindings: HashMap<Flow, NodeId>,
+    last_prune: Instant,
+}
+
+impl Firewall {
+    /// Creates a new [Firewall] with the rules loaded from the file at `path`.
+    ///
+    /// Dropped packets are reported to `events`.
+    /// Peers have the tags granted by `invites`, in addition to the tags of the rules file.
+    /// The rules naming a peer apply to the keys that replaced its key in `rotations`.
+    pub fn new(
+        path: PathBuf,
+        events: Events,
+        invites: Option<Invites>,
+        rotations: Option<Rotations>,
+    ) -> Result<Self, DaemonError> {
+        let ruleset = Ruleset::load(&path)?;
+        Ok(Self {
+            path,
+            ruleset: Arc::new(RwLock::new(ruleset)),
+            stats: Arc::new(FirewallStats::default()),
+            events,
+            invites,
+            rotations,
+            route_table: None,
+            connections: HashMap::new(),
+            bindings: HashMap::new(),
+            last_prune: Instant::now(),
+        })
+    }
+
+    /// Tracks the outgoing packets under the peer `route_table` sends them to.
+    ///
+    /// Without it, they are tracked as sent to every peer.
+    pub fn route_table(mut self, route_table: RouteTable) -> Self {
+        self.route_table = Some(route_table);
+        self
+    }
+
+    /// Returns a handle used to reload the rules while the firewall is running.
+    pub fn handle(&self) -> FirewallHandle {
+        FirewallHandle {
+            path: self.path.clone(),
+            ruleset: self.ruleset.clone(),
+            stats: self.stats.clone(),
+        }
+    }
+
+    /// Returns the counters of this firewall.
+    pub fn stats(&self) -> Arc<FirewallStats> {
+        self.stats.clone()
+    }
+
+    /// Returns the peer an outgoing packet is sent to, see [RouteTable::lookup].
+    fn routed_peer(&self, packet: &[u8]) -> Option<Option<NodeId>> {
+        let route_table = self.route_table.as_ref()?;
+        route_table.lookup(Flow::parse(packet)?.destination)
+    }
+
+    /// Records an outgoing packet sent to `peer`, or to every peer when [None],
+    /// so the replies to it are let through.
+    fn track_outgoing(&mut self, peer: Option<NodeId>, packet: &[u8]) {
+        let now = Instant::now();
+        self.prune(now);
+        let Some(flow) = Flow::parse(packet) else {
+            return;
+        };
+        // Flows sent to every peer stay bound to the peer that replied first
+        let peer = peer.or_else(|| self.bindings.get(&flow).copied());
+        self.connections.insert((peer, flow), now);
+    }
+
+    /// Checks whether `flow`, received from `peer`, replies to a flow started by this host.
+    ///
+    /// A reply to a flow sent to every peer binds the flow to `peer`.
+    fn is_reply(&mut self, peer: NodeId, flow: &Flow, now: Instant) -> bool {
+        let started = flow.reversed();
+        if let Some(last_seen) = self.connections.get_mut(&(Some(peer), started)) {
+            *last_seen = now;
+            return true;
+        }
+        if self.connections.remove(&(None, started)).is_some() {
+            self.connections.insert((Some(peer), started), now);
+            self.bindings.insert(started, peer);
+            return true;
+        }
+        false
+    }
+
+    /// Decides whether an incoming packet sent by `peer` may pass.
+    fn filter_incoming(&mut self, peer: &NodeId, packet: &[u8]) -> bool {
+        let now = Instant::now();
+        self.prune(now);
+        let flow = Flow::parse(packet);
+        let is_reply = flow.is_some_and(|flow| self.is_reply(*peer, &flow, now));
+        let action = if is_reply {
+            Action::Allow
+        } else {
+            let granted = self
+                .invites
+                .as_ref()
+                .map(|invites| invites.tags(*peer))
+                .unwrap_or_default();
+            let peers = self
+                .rotations
+                .as_ref()
+                .map_or_else(|| vec![*peer], |rotations| rotations.lineage(*peer));
+            self.ruleset
+                .read()
+                .unwrap()
+                .evaluate(&peers, &granted, flow.as_ref())
+        };
+        match action {
+            Action::Allow => {
+                self.stats.accepted.fetch_add(1, Ordering::Relaxed);
+                true
+            }
+            Action::Deny => {
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+                debug!(
+                    "Firewall dropped a packet from {}: {}",
+                    peer,
+                    FlowDisplay(flow)
+                );
+                self.events.emit(Event::AclDrop {
+                    node_id: *peer,
+                    flow,
+                });
+                false
+            }
+        }
+    }
+
+    /// Removes the flows that have been idle for too long.
+    fn prune(&mut self, now: Instant) {
+        if now.duration_since(self.last_prune) < PRUNE_INTERVAL {
+            return;
+        }
+        self.last_prune = now;
+        self.connections.retain(|(_, flow), last_seen| {
+            let timeout = if flow.protocol == PROTOCOL_TCP {
+                TCP_FLOW_TIMEOUT
+            } else {
+                FLOW_TIMEOUT
+            };
+            now.duration_since(*last_seen) < timeout
+        });
+        let connections = &self.connections;
+        self.bindings
+            .retain(|flow, peer| connections.contains_key(&(Some(*peer), *flow)));
+    }
+}
+
+impl PacketProcessor for Firewall {
+    fn name(&self) -> &str {
+        "firewall"
+    }
+
+    fn process(&mut self, packet: &Packet) -> Verdict {
+        match packet {
+            Packet::Incoming(peer, data) | Packet::Transit(peer, _, data) => {
+                if self.filter_incoming(peer, data) {
+                    Verdict::Pass
+                } else {
+                    Verdict::Drop
+                }
+            }
+            Packet::Outgoing(data) => {
+                match self.routed_peer(data) {
+                    Some(Some(peer)) => self.track_outgoing(Some(peer), data),
+                    // Dropped by the peer collection
+                    Some(None) => {}
+                    None => self.track_outgoing(None, data),
+                }
+                Verdict::Pass
+            }
+            Packet::Redirected(peer, data) => {
+                self.track_outgoing(Some(*peer), data);
+                Verdict::Pass
+            }
+        }
+    }
+}
+
+/// Formats an optional [Flow] for the drop log.
+struct FlowDisplay(Option<Flow>);
+
+impl Display for FlowDisplay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.0 {
+            Some(flow) => write!(
+                f,
+                "protocol {} {}:{} -> {}:{}",
+                flow.protocol,
+                flow.source,
+                flow.source_port,
+                flow.destination,
+                flow.destination_port
+            ),
+            None => write!(f, "non-IP packet"),
+        }
+    }
+}
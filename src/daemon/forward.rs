@@ -0,0 +1,830 @@
+//! Module for forwarding ports over the connections to peers.
+//!
+//! A local port can be forwarded to a `host:port` reachable from a peer, without routing
+//! any traffic through the TUN device or the userspace network stack.
+//!
+//! Every forwarded TCP connection is carried on its own QUIC stream of the connection to the
+//! peer. The stream starts with a line of JSON naming the target, such as
+//! `{"target": "localhost:22"}`, answered with `{"ok": null}` or `{"error": "..."}`,
+//! after which it carries the bytes of the connection.
+//!
+//! UDP packets are carried in QUIC datagrams. The packets of one local client form a flow:
+//!
+//! ```text
+//! request: 0, flow (u32), target length (u8), target, payload
+//! reply:   1, flow (u32), payload
+//! ```
+//!
+//! Empty datagrams are keepalives, ignored here.
+//!
+//! The peer serving a forward checks every target against its [ForwardPolicy],
+//! which denies everything that isn't granted.
+
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use bytes::{BufMut, Bytes, BytesMut};
+use ipnet::IpNet;
+use iroh_net::NodeId;
+use quinn::{Connection, RecvStream, SendStream};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{
+        AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+        BufReader,
+    },
+    net::{TcpListener, TcpStream, UdpSocket},
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn};
+
+use crate::daemon::{firewall::PortRange, shutdown::ShutdownListener, DaemonError};
+
+/// The longest line accepted at the start of a forward stream.
+const MAX_HEADER: u64 = 1024;
+/// How long a UDP flow served for a peer is kept without packets from the peer
+/// or without replies from its target,
+/// and a flow of a local client without packets from the client.
+const UDP_FLOW_TIMEOUT: Duration = Duration::from_secs(60);
+/// The most UDP flows served at once for a peer.
+/// The flow idle for the longest is dropped to make space for a new one.
+const MAX_UDP_FLOWS: usize = 256;
+/// The largest UDP packet read from a socket.
+const MAX_UDP_PACKET: usize = 65535;
+
+const DATAGRAM_REQUEST: u8 = 0;
+const DATAGRAM_REPLY: u8 = 1;
+
+/// The transport protocol of a forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+impl FromStr for ForwardProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tcp" => Ok(Self::Tcp),
+            "udp" => Ok(Self::Udp),
+            _ => Err(format!("invalid protocol `{}`", s)),
+        }
+    }
+}
+
+/// A host and a port, written as `host:port` or `[IPv6 address]:port`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Target {
+    /// A name or an IP address.
+    pub host: String,
+    pub port: u16,
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| format!("missing port in `{}`", s))?;
+        let host = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'))
+            .unwrap_or(host);
+        if host.is_empty() {
+            return Err(format!("invalid host in `{}`", s));
+        }
+        let port = port
+            .parse()
+            .map_err(|error| format!("invalid port `{}`: {}", port, error))?;
+        let target = Self {
+            host: host.to_string(),
+            port,
+        };
+        // The length of the target is a single byte in UDP datagrams
+        if target.to_string().len() > u8::MAX.into() {
+            return Err(format!("target `{}` is longer than {} bytes", s, u8::MAX));
+        }
+        Ok(target)
+    }
+}
+
+impl TryFrom<String> for Target {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Target> for String {
+    fn from(target: Target) -> Self {
+        target.to_string()
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Allows peers to reach targets through forwards. Every field that is set must match.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardGrant {
+    /// The peer allowed. Every peer is allowed when not set.
+    pub peer: Option<NodeId>,
+    /// The protocol allowed. Both are allowed when not set.
+    pub protocol: Option<ForwardProtocol>,
+    /// A name, an IP address or a prefix of IP addresses.
+    ///
+    /// Names are compared with the requested host as written, before it is resolved.
+    pub host: String,
+    /// The ports allowed.
+    pub ports: PortRange,
+}
+
+impl ForwardGrant {
+    /// Checks whether the grant allows `peer` to reach `target`.
+    fn allows(&self, peer: NodeId, protocol: ForwardProtocol, target: &Target) -> bool {
+        self.peer.is_none_or(|allowed| allowed == peer)
+            && self.protocol.is_none_or(|allowed| allowed == protocol)
+            && self.ports.contains(target.port)
+            && host_matches(&self.host, &target.host)
+    }
+}
+
+/// Checks whether `host` is matched by the host of a [ForwardGrant].
+fn host_matches(pattern: &str, host: &str) -> bool {
+    if let Ok(address) = host.parse::<IpAddr>() {
+        if let Ok(prefix) = pattern.parse::<IpNet>() {
+            return prefix.contains(&address);
+        }
+        if let Ok(pattern) = pattern.parse::<IpAddr>() {
+            return pattern == address;
+        }
+    }
+    pattern.eq_ignore_ascii_case(host)
+}
+
+/// The targets peers may reach through forwards served by this node.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardPolicy {
+    #[serde(default)]
+    pub grants: Vec<ForwardGrant>,
+}
+
+impl ForwardPolicy {
+    /// Checks whether any grant allows `peer` to reach `target`.
+    pub fn allows(&self, peer: NodeId, protocol: ForwardProtocol, target: &Target) -> bool {
+        self.grants
+            .iter()
+            .any(|grant| grant.allows(peer, protocol, target))
+    }
+}
+
+/// Information about a local port forwarded to a peer.
+#[derive(Debug, Clone, Serialize)]
+pub struct ForwardInfo {
+    pub id: u64,
+    pub protocol: ForwardProtocol,
+    /// The local address clients connect to.
+    pub listen: SocketAddr,
+    /// The peer the clients are forwarded through.
+    pub node_id: NodeId,
+    /// The target reached from the peer.
+    pub target: Target,
+}
+
+/// The first line of a forward stream.
+#[derive(Debug, Serialize, Deserialize)]
+struct ForwardRequest {
+    target: Target,
+}
+
+/// The answer to a [ForwardRequest].
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ForwardResponse {
+    Ok(()),
+    Error(String),
+}
+
+/// A UDP datagram carried between peers.
+enum Datagram<'a> {
+    Request {
+        flow: u32,
+        target: &'a str,
+        payload: &'a [u8],
+    },
+    Reply {
+        flow: u32,
+        payload: &'a [u8],
+    },
+}
+
+impl<'a> Datagram<'a> {
+    /// Parses a datagram, returning [None] when it is malformed.
+    fn parse(data: &'a [u8]) -> Option<Self> {
+        let (&kind, data) = data.split_first()?;
+        let flow = u32::from_be_bytes(data.get(..4)?.try_into().ok()?);
+        let data = &data[4..];
+        match kind {
+            DATAGRAM_REQUEST => {
+                let (&length, data) = data.split_first()?;
+                let target = std::str::from_utf8(data.get(..length.into())?).ok()?;
+                Some(Self::Request {
+                    flow,
+                    target,
+                    payload: &data[length.into()..],
+                })
+            }
+            DATAGRAM_REPLY => Some(Self::Reply {
+                flow,
+                payload: data,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Encodes the datagram.
+    fn encode(&self) -> Bytes {
+        let mut buffer = BytesMut::new();
+        match self {
+            Self::Request {
+                flow,
+                target,
+                payload,
+            } => {
+                buffer.put_u8(DATAGRAM_REQUEST);
+                buffer.put_u32(*flow);
+                // Longer targets are refused by [Target::from_str]
+                buffer.put_u8(target.len() as u8);
+                buffer.put_slice(target.as_bytes());
+                buffer.put_slice(payload);
+            }
+            Self::Reply { flow, payload } => {
+                buffer.put_u8(DATAGRAM_REPLY);
+                buffer.put_u32(*flow);
+                buffer.put_slice(payload);
+            }
+        }
+        buffer.freeze()
+    }
+}
+
+/// A UDP flow served for a peer, aborting the task sending its replies when dropped.
+struct ServedUdpFlow {
+    socket: Arc<UdpSocket>,
+    task: JoinHandle<()>,
+    /// When the peer last sent a packet of the flow.
+    last_request: Instant,
+}
+
+impl Drop for ServedUdpFlow {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A local UDP client of a forward, receiving the replies of its flow.
+struct UdpClient {
+    socket: Arc<UdpSocket>,
+    address: SocketAddr,
+}
+
+/// A forward started by [Forwarding::forward].
+struct ActiveForward {
+    info: ForwardInfo,
+    task: JoinHandle<()>,
+}
+
+/// Forwards local ports to peers, and serves the forwards of peers to this node.
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct Forwarding {
+    policy: Arc<RwLock<ForwardPolicy>>,
+    /// The connection to every peer, used to open forward streams and send datagrams.
+    connections: Arc<Mutex<HashMap<NodeId, Connection>>>,
+    /// The local UDP clients, by the peer their packets are forwarded to and their flow.
+    udp_clients: Arc<Mutex<HashMap<(NodeId, u32), UdpClient>>>,
+    next_flow: Arc<AtomicU32>,
+    forwards: Arc<Mutex<HashMap<u64, ActiveForward>>>,
+    next_forward: Arc<AtomicU64>,
+    shutdown: ShutdownListener,
+}
+
+impl Forwarding {
+    /// Creates a new [Forwarding] serving peers according to `policy`.
+    ///
+    /// The local forwards stop when the shutdown is triggered.
+    pub fn new(policy: ForwardPolicy, shutdown: ShutdownListener) -> Self {
+        Self {
+            policy: Arc::new(RwLock::new(policy)),
+            connections: Default::default(),
+            udp_clients: Default::default(),
+            next_flow: Default::default(),
+            forwards: Default::default(),
+            next_forward: Default::default(),
+            shutdown,
+        }
+    }
+
+    /// Serves the forwards of a peer on `connection` until it is closed.
+    ///
+    /// `connection` must not be used to accept other streams.
+    pub fn attach(&self, node_id: NodeId, connection: Connection) {
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(node_id, connection.clone());
+        tokio::spawn(self.clone().serve_connection(node_id, connection));
+    }
+
+    /// Allows more targets to be reached by peers.
+    pub fn publish(&self, grant: ForwardGrant) {
+        info!(
+            "Published {} to {}",
+            grant.host,
+            grant
+                .peer
+                .map_or_else(|| "every peer".to_string(), |peer| peer.to_string())
+        );
+        self.policy.write().unwrap().grants.push(grant);
+    }
+
+    /// Forwards the clients of `listen` to `target`, through the peer with the given [NodeId].
+    ///
+    /// The peer doesn't have to be connected yet. Clients are refused while it isn't.
+    pub async fn forward(
+        &self,
+        protocol: ForwardProtocol,
+        listen: SocketAddr,
+        node_id: NodeId,
+        target: Target,
+    ) -> Result<ForwardInfo, DaemonError> {
+        // Catches the targets built without parsing them
+        let target: Target = target
+            .to_string()
+            .parse()
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+        let (listen, task) = match protocol {
+            ForwardProtocol::Tcp => {
+                let listener = TcpListener::bind(listen).await?;
+                let listen = listener.local_addr()?;
+                let task = self.clone().forward_tcp(listener, node_id, target.clone());
+                (listen, tokio::spawn(self.shutdown.clone().run_until(task)))
+            }
+            ForwardProtocol::Udp => {
+                let socket = UdpSocket::bind(listen).await?;
+                let listen = socket.local_addr()?;
+                let task = self
+                    .clone()
+                    .forward_udp(Arc::new(socket), node_id, target.clone());
+                (listen, tokio::spawn(self.shutdown.clone().run_until(task)))
+            }
+        };
+        let info = ForwardInfo {
+            id: self.next_forward.fetch_add(1, Ordering::Relaxed),
+            protocol,
+            listen,
+            node_id,
+            target,
+        };
+        info!(
+            "Forwarding {} to {} through {}",
+            info.listen, info.target, info.node_id
+        );
+        self.forwards.lock().unwrap().insert(
+            info.id,
+            ActiveForward {
+                info: info.clone(),
+                task,
+            },
+        );
+        Ok(info)
+    }
+
+    /// Stops the forward with the given ID. Returns whether it existed.
+    pub fn remove_forward(&self, id: u64) -> bool {
+        match self.forwards.lock().unwrap().remove(&id) {
+            Some(forward) => {
+                forward.task.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the forwards started by [Forwarding::forward].
+    pub fn forwards(&self) -> Vec<ForwardInfo> {
+        self.forwards
+            .lock()
+            .unwrap()
+            .values()
+            .map(|forward| forward.info.clone())
+            .collect()
+    }
+
+    /// Returns the connection to the peer with the given [NodeId].
+    fn connection(&self, node_id: NodeId) -> io::Result<Connection> {
+        self.connections
+            .lock()
+            .unwrap()
+            .get(&node_id)
+            .cloned()
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotConnected,
+                    format!("peer {} is not connected", node_id),
+                )
+            })
+    }
+
+    /// Accepts the forward streams and datagrams of a peer until the connection is closed.
+    async fn serve_connection(self, node_id: NodeId, connection: Connection) {
+        tokio::select! {
+            _ = self.accept_streams(node_id, &connection) => {}
+            _ = self.receive_datagrams(node_id, &connection) => {}
+        }
+        let mut connections = self.connections.lock().unwrap();
+        if connections
+            .get(&node_id)
+            .is_some_and(|current| current.stable_id() == connection.stable_id())
+        {
+            connections.remove(&node_id);
+        }
+    }
+
+    /// Accepts forward streams, serving each one in its own task.
+    async fn accept_streams(&self, node_id: NodeId, connection: &Connection) {
+        while let Ok((send, recv)) = connection.accept_bi().await {
+            let policy = self.policy.clone();
+            tokio::spawn(async move {
+                if let Err(error) = serve_stream(node_id, send, recv, &policy).await {
+                    debug!("Forward stream of {} failed: {:?}", node_id, error);
+                }
+            });
+        }
+    }
+
+    /// Receives datagrams, delivering replies to local clients and serving requests.
+    ///
+    /// At most [MAX_UDP_FLOWS] flows are served for the peer, and the ones idle for
+    /// [UDP_FLOW_TIMEOUT] are dropped.
+    async fn receive_datagrams(&self, node_id: NodeId, connection: &Connection) {
+        let mut udp_flows: HashMap<u32, ServedUdpFlow> = HashMap::new();
+        while let Ok(data) = connection.read_datagram().await {
+            if data.is_empty() {
+                continue;
+            }
+            match Datagram::parse(&data) {
+                Some(Datagram::Reply { flow, payload }) => {
+                    let client = self
+                        .udp_clients
+                        .lock()
+                        .unwrap()
+                        .get(&(node_id, flow))
+                        .map(|client| (client.socket.clone(), client.address));
+                    if let Some((socket, address)) = client {
+                        if let Err(error) = socket.send_to(payload, address).await {
+                            debug!("Couldn't send a reply to {}: {:?}", address, error);
+                        }
+                    }
+                }
+                Some(Datagram::Request {
+                    flow,
+                    target,
+                    payload,
+                }) => {
+                    udp_flows.retain(|_, served| {
+                        !served.task.is_finished()
+                            && served.last_request.elapsed() < UDP_FLOW_TIMEOUT
+                    });
+                    let socket = match udp_flows.get_mut(&flow) {
+                        Some(served) => {
+                            served.last_request = Instant::now();
+                            served.socket.clone()
+                        }
+                        None => {
+                            let socket = match open_udp_flow(node_id, target, &self.policy).await {
+                                Ok(socket) => Arc::new(socket),
+                                Err(error) => {
+                                    debug!(
+                                        "Couldn't forward UDP of {} to {}: {:?}",
+                                        node_id, target, error
+                                    );
+                                    continue;
+                                }
+                            };
+                            if udp_flows.len() >= MAX_UDP_FLOWS {
+                                let idlest = udp_flows
+                                    .iter()
+                                    .min_by_key(|(_, served)| served.last_request)
+                                    .map(|(flow, _)| *flow);
+                                if let Some(idlest) = idlest {
+                                    debug!("Dropping the UDP flow {} of {}", idlest, node_id);
+                                    udp_flows.remove(&idlest);
+                                }
+                            }
+                            let task = tokio::spawn(send_udp_replies(
+                                flow,
+                                socket.clone(),
+                                connection.clone(),
+                            ));
+                            udp_flows.insert(
+                                flow,
+                                ServedUdpFlow {
+                                    socket: socket.clone(),
+                                    task,
+                                    last_request: Instant::now(),
+                                },
+                            );
+                            socket
+                        }
+                    };
+                    if let Err(error) = socket.send(payload).await {
+                        debug!("Couldn't forward UDP to {}: {:?}", target, error);
+                    }
+                }
+                None => debug!("Received a malformed datagram from {}", node_id),
+            }
+        }
+    }
+
+    /// Forwards the clients of `listener` to `target`, each on its own stream.
+    async fn forward_tcp(self, listener: TcpListener, node_id: NodeId, target: Target) {
+        loop {
+            let client = match listener.accept().await {
+                Ok((client, _)) => client,
+                Err(error) => {
+                    warn!("Couldn't accept a forwarded client. Reason: {:?}", error);
+                    continue;
+                }
+            };
+            let forwarding = self.clone();
+            let target = target.clone();
+            tokio::spawn(async move {
+                if let Err(error) = forwarding.open_stream(client, node_id, target).await {
+                    debug!("Forwarded connection failed: {:?}", error);
+                }
+            });
+        }
+    }
+
+    /// Carries one forwarded TCP connection on a new stream to the peer.
+    async fn open_stream(
+        self,
+        client: TcpStream,
+        node_id: NodeId,
+        target: Target,
+    ) -> io::Result<()> {
+        let (mut send, recv) = self
+            .connection(node_id)?
+            .open_bi()
+            .await
+            .map_err(io::Error::other)?;
+        write_line(&mut send, &ForwardRequest { target }).await?;
+        let mut recv = BufReader::new(recv);
+        match read_line::<ForwardResponse>(&mut recv).await? {
+            ForwardResponse::Ok(()) => {}
+            ForwardResponse::Error(error) => {
+                return Err(io::Error::new(io::ErrorKind::ConnectionRefused, error))
+            }
+        }
+        let (client_read, client_write) = client.into_split();
+        tokio::try_join!(splice(client_read, send), splice(recv, client_write))?;
+        Ok(())
+    }
+
+    /// Forwards the packets received on `socket` to `target`, in datagrams to the peer.
+    async fn forward_udp(self, socket: Arc<UdpSocket>, node_id: NodeId, target: Target) {
+        let target = target.to_string();
+        let mut flows = UdpFlows {
+            node_id,
+            socket: socket.clone(),
+            by_client: HashMap::new(),
+            udp_clients: self.udp_clients.clone(),
+        };
+        let mut prune = tokio::time::interval(UDP_FLOW_TIMEOUT);
+        let mut buffer = vec![0u8; MAX_UDP_PACKET];
+        loop {
+            let received = tokio::select! {
+                received = socket.recv_from(&mut buffer) => received,
+                _ = prune.tick() => {
+                    flows.prune();
+                    continue;
+                }
+            };
+            let (size, address) = match received {
+                Ok(received) => received,
+                Err(error) => {
+                    warn!("Couldn't receive a forwarded packet. Reason: {:?}", error);
+                    continue;
+                }
+            };
+            let flow = flows.flow(address, &self.next_flow);
+            let datagram = Datagram::Request {
+                flow,
+                target: &target,
+                payload: &buffer[..size],
+            };
+            let result = self.connection(node_id).and_then(|connection| {
+                connection
+                    .send_datagram(datagram.encode())
+                    .map_err(io::Error::other)
+            });
+            if let Err(error) = result {
+                debug!("Couldn't forward a packet from {}: {:?}", address, error);
+            }
+        }
+    }
+}
+
+/// The flows of a UDP forward, unregistered from the local clients when the forward stops.
+struct UdpFlows {
+    /// The peer the packets are forwarded to.
+    node_id: NodeId,
+    socket: Arc<UdpSocket>,
+    /// The flow of each client, with when the client last sent a packet.
+    by_client: HashMap<SocketAddr, (u32, Instant)>,
+    udp_clients: Arc<Mutex<HashMap<(NodeId, u32), UdpClient>>>,
+}
+
+impl UdpFlows {
+    /// Returns the flow of the client at `address`, registering a new one if needed.
+    fn flow(&mut self, address: SocketAddr, next_flow: &AtomicU32) -> u32 {
+        let now = Instant::now();
+        if let Some((flow, last_packet)) = self.by_client.get_mut(&address) {
+            *last_packet = now;
+            return *flow;
+        }
+        let flow = next_flow.fetch_add(1, Ordering::Relaxed);
+        self.udp_clients.lock().unwrap().insert(
+            (self.node_id, flow),
+            UdpClient {
+                socket: self.socket.clone(),
+                address,
+            },
+        );
+        self.by_client.insert(address, (flow, now));
+        flow
+    }
+
+    /// Removes the flows of the clients idle for longer than [UDP_FLOW_TIMEOUT].
+    fn prune(&mut self) {
+        let mut udp_clients = self.udp_clients.lock().unwrap();
+        self.by_client.retain(|_, (flow, last_packet)| {
+            let active = last_packet.elapsed() < UDP_FLOW_TIMEOUT;
+            if !active {
+                udp_clients.remove(&(self.node_id, *flow));
+            }
+            active
+        });
+    }
+}
+
+impl Drop for UdpFlows {
+    fn drop(&mut self) {
+        let mut udp_clients = self.udp_clients.lock().unwrap();
+        for (flow, _) in self.by_client.values() {
+            udp_clients.remove(&(self.node_id, *flow));
+        }
+    }
+}
+
+/// Checks the request on a forward stream, then connects it to its target.
+async fn serve_stream(
+    node_id: NodeId,
+    mut send: SendStream,
+    recv: RecvStream,
+    policy: &RwLock<ForwardPolicy>,
+) -> io::Result<()> {
+    let mut recv = BufReader::new(recv);
+    let request = read_line::<ForwardRequest>(&mut recv).await?;
+    let allowed = policy
+        .read()
+        .unwrap()
+        .allows(node_id, ForwardProtocol::Tcp, &request.target);
+    let connected = if allowed {
+        TcpStream::connect((request.target.host.as_str(), request.target.port))
+            .await
+            .map_err(|error| error.to_string())
+    } else {
+        Err("not allowed".to_string())
+    };
+    let target = match connected {
+        Ok(target) => {
+            write_line(&mut send, &ForwardResponse::Ok(())).await?;
+            target
+        }
+        Err(error) => {
+            debug!(
+                "Refused to forward {} to {}: {}",
+                node_id, request.target, error
+            );
+            write_line(&mut send, &ForwardResponse::Error(error)).await?;
+            return send.finish().await.map_err(io::Error::from);
+        }
+    };
+    let (target_read, target_write) = target.into_split();
+    tokio::try_join!(splice(recv, target_write), splice(target_read, send))?;
+    Ok(())
+}
+
+/// Checks a UDP flow requested by a peer, then opens a socket connected to its target.
+async fn open_udp_flow(
+    node_id: NodeId,
+    target: &str,
+    policy: &RwLock<ForwardPolicy>,
+) -> io::Result<UdpSocket> {
+    let target: Target = target
+        .parse()
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidInput, error))?;
+    if !policy
+        .read()
+        .unwrap()
+        .allows(node_id, ForwardProtocol::Udp, &target)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "not allowed",
+        ));
+    }
+    let address = tokio::net::lookup_host((target.host.as_str(), target.port))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no addresses"))?;
+    let unspecified: IpAddr = match address {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    socket.connect(address).await?;
+    Ok(socket)
+}
+
+/// Sends the packets received on the socket of a flow back to the peer,
+/// until none is received for [UDP_FLOW_TIMEOUT].
+async fn send_udp_replies(flow: u32, socket: Arc<UdpSocket>, connection: Connection) {
+    let mut buffer = vec![0u8; MAX_UDP_PACKET];
+    while let Ok(Ok(size)) = tokio::time::timeout(UDP_FLOW_TIMEOUT, socket.recv(&mut buffer)).await
+    {
+        let datagram = Datagram::Reply {
+            flow,
+            payload: &buffer[..size],
+        };
+        if let Err(error) = connection.send_datagram(datagram.encode()) {
+            debug!("Couldn't send a UDP reply: {:?}", error);
+        }
+    }
+}
+
+/// Copies `reader` into `writer` until the end, then shuts `writer` down.
+async fn splice(
+    mut reader: impl AsyncRead + Unpin,
+    mut writer: impl AsyncWrite + Unpin,
+) -> io::Result<()> {
+    tokio::io::copy(&mut reader, &mut writer).await?;
+    writer.shutdown().await
+}
+
+/// Reads a line of JSON, at most [MAX_HEADER] bytes long.
+async fn read_line<T: for<'de> Deserialize<'de>>(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<T> {
+    let mut line = String::new();
+    reader.take(MAX_HEADER).read_line(&mut line).await?;
+    Ok(serde_json::from_str(&line)?)
+}
+
+/// Writes `value` as one line of JSON.
+async fn write_line(
+    writer: &mut (impl AsyncWrite + Unpin),
+    value: &impl Serialize,
+) -> io::Result<()> {
+    let mut line = serde_json::to_vec(value)?;
+    line.push(b'\n');
+    writer.write_all(&line).await
+}
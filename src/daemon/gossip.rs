@@ -0,0 +1,298 @@
+//! Module for gossiping the addresses of the members of the network.
+//!
+//! Every node signs its own [NodeAddr], and sends it to its peers in its
+//! [Advertisement](super::advertisements::Advertisement), along with the signed addresses of
+//! the other members it knows. A node dials the members it learns about, so a node joining
+//! the network through one ticket connects to the rest of it.
+//!
+//! The addresses are signed by the node they belong to, so a peer can pass them on but not
+//! forge them. Only the members admitted by the [AdmissionPolicy] are gossiped, and signed
+//! addresses expire after [MEMBER_TTL].
+//!
+//! The known members are sent to the peers at most once per [GossipConfig::interval],
+//! and at most [GossipConfig::max_dials] of them are dialed per interval.
+//!
+//! The gossip is off by default. Without the members of the [AdmissionPolicy], every node
+//! is a member, so any peer could make this node dial the nodes it wants.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use iroh_net::{
+    key::{SecretKey, Signature},
+    NodeAddr, NodeId,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+use crate::daemon::{
+    actors::{peer_source::PeerSourceMessage, Addr},
+    admission::AdmissionPolicy,
+    advertisements::Advertisements,
+    events::{Event, Events},
+    rotation::Rotations,
+    store::unix_time,
+    DaemonError,
+};
+
+/// How long signed addresses are gossiped after they were signed.
+pub const MEMBER_TTL: Duration = Duration::from_secs(60 * 60);
+/// How often a node signs its addresses again, even if they didn't change.
+const REISSUE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How far in the future signed addresses may be, because of clock differences.
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// How long a member isn't dialed again after it was dialed.
+const DIAL_BACKOFF: Duration = Duration::from_secs(5 * 60);
+/// The most members known, so the advertisements stay small.
+pub const MAX_MEMBERS: usize = 128;
+
+/// Settings of the gossip.
+#[derive(Debug, Clone)]
+pub struct GossipConfig {
+    /// Whether the addresses of the members are gossiped, and the members learned are dialed.
+    /// Off by default.
+    pub enabled: bool,
+    /// How often the known members are sent to the peers, when they changed.
+    pub interval: Duration,
+    /// The most members dialed per interval.
+    pub max_dials: usize,
+}
+
+impl Default for GossipConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval: Duration::from_secs(30),
+            max_dials: 8,
+        }
+    }
+}
+
+/// The addresses of a node, signed by it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedNodeAddr {
+    pub node_addr: NodeAddr,
+    /// When the node signed its addresses, in seconds since the Unix epoch.
+    pub issued_at: u64,
+    /// The signature of the addresses and [SignedNodeAddr::issued_at] by the node.
+    pub signature: Vec<u8>,
+}
+
+impl SignedNodeAddr {
+    /// Signs the addresses of the node of `secret_key`.
+    pub fn sign(secret_key: &SecretKey, node_addr: NodeAddr, issued_at: u64) -> Self {
+        let signature = secret_key.sign(&Self::signed_data(&node_addr, issued_at));
+        Self {
+            node_addr,
+            issued_at,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    /// Checks whether the addresses are signed by their node.
+    pub fn verify(&self) -> bool {
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        self.node_addr
+            .node_id
+            .verify(
+                &Self::signed_data(&self.node_addr, self.issued_at),
+                &signature,
+            )
+            .is_ok()
+    }
+
+    /// Returns the data covered by the signature.
+    fn signed_data(node_addr: &NodeAddr, issued_at: u64) -> Vec<u8> {
+        serde_json::to_vec(&(node_addr, issued_at)).expect("node addresses are serializable")
+    }
+}
+
+/// Gossips the addresses of the members of the network with the peers, and dials the members
+/// this node isn't connected to.
+pub struct Gossip {
+    config: GossipConfig,
+    secret_key: SecretKey,
+    admission: AdmissionPolicy,
+    rotations: Rotations,
+    advertisements: Advertisements,
+    peer_source: Addr<PeerSourceMessage>,
+    /// The latest signed addresses of each known member, this node included.
+    members: HashMap<NodeId, SignedNodeAddr>,
+    /// When each member was last dialed.
+    dialed: HashMap<NodeId, Instant>,
+    /// Whether the known members changed since they were last sent to the peers.
+    changed: bool,
+    event_receiver: broadcast::Receiver<Event>,
+}
+
+impl Gossip {
+    /// Creates a new [Gossip] signing the addresses of this node with `secret_key`.
+    ///
+    /// The members are exchanged through `advertisements`, and dialed with `peer_source`.
+    /// The members of `admission` are followed to their new keys with `rotations`.
+    pub fn new(
+        config: GossipConfig,
+        secret_key: SecretKey,
+        admission: AdmissionPolicy,
+        rotations: Rotations,
+        advertisements: Advertisements,
+        peer_source: Addr<PeerSourceMessage>,
+        events: &Events,
+    ) -> Self {
+        Self {
+            config,
+            secret_key,
+            admission,
+            rotations,
+            advertisements,
+            peer_source,
+            members: HashMap::new(),
+            dialed: HashMap::new(),
+            changed: false,
+            event_receiver: events.subscribe(),
+        }
+    }
+
+    /// Signs the addresses of this node again when they changed or are getting old.
+    async fn refresh_own(&mut self) -> Result<(), DaemonError> {
+        let ticket = self.peer_source.ask(PeerSourceMessage::GetTicket).await??;
+        let node_addr = ticket.node_addr().clone();
+        let now = unix_time().as_secs();
+        let current = self.members.get(&node_addr.node_id);
+        let outdated = current.is_none_or(|current| {
+            current.node_addr != node_addr
+                || now.saturating_sub(current.issued_at) >= REISSUE_INTERVAL.as_secs()
+        });
+        if outdated {
+            let signed = SignedNodeAddr::sign(&self.secret_key, node_addr, now);
+            self.members.insert(signed.node_addr.node_id, signed);
+            self.changed = true;
+        }
+        Ok(())
+    }
+
+    /// Learns the members gossiped by the peer with the given [NodeId].
+    fn receive(&mut self, from: NodeId, members: Vec<SignedNodeAddr>) {
+        if !self.rotations.admits(&self.admission, from) {
+            return;
+        }
+        let now = unix_time().as_secs();
+        for member in members.into_iter().take(MAX_MEMBERS) {
+            let node_id = member.node_addr.node_id;
+            let newer = self
+                .members
+                .get(&node_id)
+                .is_none_or(|known| known.issued_at < member.issued_at);
+            if !newer
+                || node_id == self.secret_key.public()
+                || !self.rotations.admits(&self.admission, node_id)
+                || !is_fresh(member.issued_at, now)
+            {
+                continue;
+            }
+            if !self.members.contains_key(&node_id) && self.members.len() >= MAX_MEMBERS {
+                continue;
+            }
+            if !member.verify() {
+                debug!("Peer {} gossiped a forged address of {}", from, node_id);
+                continue;
+            }
+            if !self.members.contains_key(&node_id) {
+                info!("Learned about member {} from {}", node_id, from);
+            }
+            self.members.insert(node_id, member);
+            self.changed = true;
+        }
+    }
+
+    /// Forgets the members whose signed addresses expired.
+    fn expire(&mut self) {
+        let now = unix_time().as_secs();
+        let count = self.members.len();
+        self.members
+            .retain(|_, member| is_fresh(member.issued_at, now));
+        self.changed |= self.members.len() != count;
+    }
+
+    /// Sends the known members to the peers, if they changed.
+    fn publish(&mut self) {
+        if !self.changed {
+            return;
+        }
+        let mut members: Vec<SignedNodeAddr> = self.members.values().cloned().collect();
+        members.sort_by_key(|member| member.node_addr.node_id);
+        self.advertisements.update_own(|own| own.members = members);
+        self.changed = false;
+    }
+
+    /// Dials the members this node isn't connected to, up to [GossipConfig::max_dials].
+    async fn dial_members(&mut self) {
+        let own = self.secret_key.public();
+        self.dialed
+            .retain(|_, dialed| dialed.elapsed() < DIAL_BACKOFF);
+        let candidates: Vec<NodeAddr> = self
+            .members
+            .values()
+            .map(|member| &member.node_addr)
+            .filter(|node_addr| {
+                node_addr.node_id != own
+                    && !self.advertisements.is_connected(node_addr.node_id)
+                    && !self.dialed.contains_key(&node_addr.node_id)
+            })
+            .take(self.config.max_dials)
+            .cloned()
+            .collect();
+        for node_addr in candidates {
+            info!("Dialing member {}", node_addr.node_id);
+            self.dialed.insert(node_addr.node_id, Instant::now());
+            self.peer_source
+                .send_message(PeerSourceMessage::DialPeer(node_addr))
+                .await;
+        }
+    }
+
+    /// Runs the gossip, following the advertisements of the peers.
+    pub async fn run(mut self) {
+        let mut ticks = interval(self.config.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            select! {
+                _ = ticks.tick() => {
+                    if let Err(error) = self.refresh_own().await {
+                        warn!("Couldn't get the addresses of this node. Reason: {:?}", error);
+                    }
+                    self.expire();
+                    self.publish();
+                    self.dial_members().await;
+                }
+                event = self.event_receiver.recv() => match event {
+                    Ok(Event::Advertised { node_id, advertisement }) => {
+                        self.receive(node_id, advertisement.members);
+                    }
+                    Ok(_) => {}
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Gossip missed {} events", missed);
+                        for (node_id, advertisement) in self.advertisements.peers() {
+                            self.receive(node_id, advertisement.members);
+                        }
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+}
+
+/// Checks whether addresses signed at `issued_at` can still be gossiped at `now`.
+fn is_fresh(issued_at: u64, now: u64) -> bool {
+    issued_at <= now + CLOCK_SKEW.as_secs() && now.saturating_sub(issued_at) < MEMBER_TTL.as_secs()
+}
@@ -0,0 +1,480 @@
+//! Module for [DaemonHandle], used to control a running daemon.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{atomic::Ordering, Arc},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use iroh_net::{ticket::NodeTicket, NodeAddr, NodeId};
+use serde::Serialize;
+use tokio::{
+    sync::{broadcast, Mutex},
+    task::JoinHandle,
+};
+use tracing::info;
+
+use crate::daemon::{
+    actors::{
+        mesh_router::{MeshRouteInfo, MeshRouterMessage},
+        peer_collection::{GatewayGroupInfo, PeerCollectionMessage, PeerInfo},
+        peer_source::PeerSourceMessage,
+        route_manager::{RouteInfo, RouteManagerMessage},
+        subnet_router::{SubnetRouteInfo, SubnetRouterMessage},
+        Addr,
+    },
+    advertisements::Advertisements,
+    events::{Event, Events},
+    firewall::FirewallHandle,
+    forward::{ForwardGrant, ForwardInfo, ForwardProtocol, Forwarding, Target},
+    invite::{InviteInfo, Invites, SignedInvite},
+    packet::Packet,
+    pairing::{self, PairingCode, PairingConfig},
+    revocation::{Revocation, Revocations},
+    rotation::{Rotations, Succession},
+    shutdown::Shutdown,
+    state::{NetworkState, Record, RecordInfo, RecordKey},
+    supervisor::ExitReason,
+    wasm_filter::WasmFilterHandle,
+    DaemonError,
+};
+
+/// The task supervising the actors of a daemon.
+type SupervisorTask = JoinHandle<Result<(), DaemonError>>;
+
+/// Statistics of a running daemon.
+#[derive(Debug, Clone, Serialize)]
+pub struct DaemonStats {
+    /// The connected peers.
+    pub peers: Vec<PeerInfo>,
+    /// Packets dropped because the packet router's mailbox was full.
+    pub router_dropped_packets: u64,
+    /// Incoming packets let through by the firewall.
+    pub firewall_accepted_packets: u64,
+    /// Incoming packets dropped by the firewall.
+    pub firewall_dropped_packets: u64,
+    /// How many times the traffic to a prefix moved to another gateway.
+    pub failovers: u64,
+}
+
+/// A handle to a running daemon, returned by [DaemonBuilder::start](super::DaemonBuilder::start).
+///
+/// Clones of the handle control the same daemon.
+#[derive(Clone)]
+pub struct DaemonHandle {
+    pub(super) node_id: NodeId,
+    pub(super) peer_source: Addr<PeerSourceMessage>,
+    pub(super) peer_collection: Addr<PeerCollectionMessage>,
+    pub(super) packet_router: Addr<Packet>,
+    /// [None] when the TUN device is disabled.
+    pub(super) route_manager: Option<Addr<RouteManagerMessage>>,
+    pub(super) subnet_router: Addr<SubnetRouterMessage>,
+    /// [None] when the mesh is disabled.
+    pub(super) mesh_router: Option<Addr<MeshRouterMessage>>,
+    pub(super) firewall: Option<FirewallHandle>,
+    pub(super) wasm_filters: Vec<WasmFilterHandle>,
+    pub(super) forwarding: Forwarding,
+    pub(super) advertisements: Advertisements,
+    /// [None] when the replicated state isn't kept.
+    pub(super) state: Option<NetworkState>,
+    pub(super) invites: Invites,
+    pub(super) pairing: PairingConfig,
+    pub(super) revocations: Revocations,
+    pub(super) rotations: Rotations,
+    pub(super) events: Events,
+    pub(super) shutdown: Shutdown,
+    /// The task supervising the actors. [None] once its result has been returned.
+    pub(super) task: Arc<Mutex<Option<SupervisorTask>>>,
+}
+
+impl DaemonHandle {
+    /// Returns the [NodeId] of this node.
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// Returns a [NodeTicket] other nodes can use to connect to this node.
+    pub async fn ticket(&self) -> Result<NodeTicket, DaemonError> {
+        self.peer_source.ask(PeerSourceMessage::GetTicket).await?
+    }
+
+    /// Starts connecting to a peer.
+    pub async fn dial(&self, node_addr: NodeAddr) {
+        self.peer_source
+            .send_message(PeerSourceMessage::DialPeer(node_addr))
+            .await;
+    }
+
+    /// Starts connecting to the node with the given [NodeId], resolving its addresses through
+    /// the discovery.
+    pub async fn dial_node(&self, node_id: NodeId) {
+        self.peer_source
+            .send_message(PeerSourceMessage::DialNode(node_id))
+            .await;
+    }
+
+    /// Issues an invite to join the network through this node, valid for `validity`
+    /// and `max_uses` nodes, granting them `tags` and `address`.
+    pub async fn create_invite(
+        &self,
+        validity: Duration,
+        max_uses: u32,
+        tags: Vec<String>,
+        address: Option<IpAddr>,
+    ) -> Result<SignedInvite, DaemonError> {
+        let ticket = self.ticket().await?;
+        self.invites.issue(
+            ticket.node_addr().clone(),
+            validity,
+            max_uses,
+            tags,
+            address,
+        )
+    }
+
+    /// Returns the invites issued by this node that can still be used.
+    pub fn invites(&self) -> Vec<InviteInfo> {
+        self.invites.outstanding()
+    }
+
+    /// Revokes the invite `id` issued by this node.
+    pub fn revoke_invite(&self, id: u64) -> Result<(), DaemonError> {
+        self.invites.revoke(id)
+    }
+
+    /// Joins the network with `invite`, connecting to its issuer.
+    pub async fn join(&self, invite: SignedInvite) -> Result<(), DaemonError> {
+        let issuer = self.invites.join(invite)?;
+        self.dial(issuer).await;
+        Ok(())
+    }
+
+    /// Shows `code` to another node, and pairs with the node given it.
+    ///
+    /// Returns once the nodes are paired, after which the other node dials this one.
+    pub async fn pair(&self, code: &PairingCode) -> Result<NodeId, DaemonError> {
+        let ticket = self.ticket().await?;
+        let peer = pairing::offer(&self.pairing, code, ticket.node_addr().clone()).await?;
+        self.paired(peer.node_id)?;
+        Ok(peer.node_id)
+    }
+
+    /// Pairs with the node showing `code`, then connects to it.
+    pub async fn pair_with(&self, code: &PairingCode) -> Result<NodeId, DaemonError> {
+        let ticket = self.ticket().await?;
+        let peer = pairing::join(&self.pairing, code, ticket.node_addr().clone()).await?;
+        self.paired(peer.node_id)?;
+        let node_id = peer.node_id;
+        self.dial(peer).await;
+        Ok(node_id)
+    }
+
+    /// Admits the node with the given [NodeId], paired with this node.
+    fn paired(&self, node_id: NodeId) -> Result<(), DaemonError> {
+        self.invites.allow(node_id)?;
+        info!("Paired with {}", node_id);
+        self.events.emit(Event::Paired { node_id });
+        Ok(())
+    }
+
+    /// Revokes the key of the node with the given [NodeId] on every node of the mesh.
+    /// This node must be an authority of the state.
+    pub fn revoke(&self, node_id: NodeId) -> Result<(), DaemonError> {
+        self.revocations.revoke(node_id)
+    }
+
+    /// Returns the revocations of node keys in effect.
+    pub fn revocations(&self) -> Vec<Revocation> {
+        self.revocations.list()
+    }
+
+    /// Replaces the key of this node by a new one, and tells every node of the mesh to move
+    /// what was granted to this node to the new key. Returns the [NodeId] of the new key.
+    ///
+    /// The new key is written to the key file, and the node keeps using its current key until
+    /// it is restarted with it.
+    pub fn rotate_key(&self) -> Result<NodeId, DaemonError> {
+        self.rotations.rotate()
+    }
+
+    /// Returns the replacements of node keys known by this node.
+    pub fn successions(&self) -> Vec<Succession> {
+        self.rotations.list()
+    }
+
+    /// Returns the information about the connected peers.
+    pub async fn peers(&self) -> Result<Vec<PeerInfo>, DaemonError> {
+        Ok(self
+            .peer_collection
+            .ask(PeerCollectionMessage::ListPeers)
+            .await?)
+    }
+
+    /// Routes `prefix` into the TUN device, through the peer with the given [NodeId].
+    ///
+    /// The packets to `prefix` are sent only to that peer.
+    /// The route is removed when the peer disconnects.
+    pub async fn add_route(&self, prefix: IpNet, node_id: NodeId) -> Result<(), DaemonError> {
+        self.route_manager()?
+            .ask(|reply| RouteManagerMessage::AddRoute(prefix, node_id, reply))
+            .await??;
+        self.peer_collection
+            .send_message(PeerCollectionMessage::AddRoute(prefix, node_id))
+            .await;
+        Ok(())
+    }
+
+    /// Removes the route to `prefix`.
+    pub async fn remove_route(&self, prefix: IpNet) -> Result<(), DaemonError> {
+        self.route_manager()?
+            .ask(|reply| RouteManagerMessage::RemoveRoute(prefix, reply))
+            .await??;
+        self.peer_collection
+            .send_message(PeerCollectionMessage::RemoveRoute(prefix))
+            .await;
+        Ok(())
+    }
+
+    /// Returns the routes installed by the daemon.
+    pub async fn routes(&self) -> Result<Vec<RouteInfo>, DaemonError> {
+        Ok(self
+            .route_manager()?
+            .ask(RouteManagerMessage::ListRoutes)
+            .await?)
+    }
+
+    /// Returns the prefixes advertised by the connected peers, and whether they are routed.
+    pub async fn subnet_routes(&self) -> Result<Vec<SubnetRouteInfo>, DaemonError> {
+        Ok(self
+            .subnet_router
+            .ask(SubnetRouterMessage::ListRoutes)
+            .await?)
+    }
+
+    /// Returns the prefixes advertised by several peers, with the gateway used for each.
+    pub async fn gateways(&self) -> Result<Vec<GatewayGroupInfo>, DaemonError> {
+        Ok(self
+            .peer_collection
+            .ask(PeerCollectionMessage::ListGateways)
+            .await?)
+    }
+
+    /// Returns the routes selected to the nodes of the mesh.
+    pub async fn mesh_routes(&self) -> Result<Vec<MeshRouteInfo>, DaemonError> {
+        let mesh_router = self.mesh_router.as_ref().ok_or(DaemonError::MeshDisabled)?;
+        Ok(mesh_router.ask(MeshRouterMessage::ListRoutes).await?)
+    }
+
+    /// Returns the records of the state replicated between the nodes.
+    pub fn state_records(&self) -> Result<Vec<RecordInfo>, DaemonError> {
+        Ok(self.state()?.records())
+    }
+
+    /// Writes `record` to the replicated state, replacing the record of its key.
+    pub fn set_record(&self, record: Record) -> Result<(), DaemonError> {
+        self.state()?.set(record)
+    }
+
+    /// Removes the record of `key` from the replicated state.
+    pub fn remove_record(&self, key: RecordKey) -> Result<(), DaemonError> {
+        self.state()?.remove(key)
+    }
+
+    /// Returns the replicated state, which is only kept when configured.
+    fn state(&self) -> Result<&NetworkState, DaemonError> {
+        self.state.as_ref().ok_or(DaemonError::StateDisabled)
+    }
+
+    /// Returns the address of the route manager, which exists only with the TUN device.
+    fn route_manager(&self) -> Result<&Addr<RouteManagerMessage>, DaemonError> {
+        self.route_manager.as_ref().ok_or(DaemonError::TunDisabled)
+    }
+
+    /// Forwards the clients of the local address `listen` to `target`,
+    /// reached from the peer with the given [NodeId].
+    ///
+    /// The peer has to allow it in its [ForwardPolicy](super::forward::ForwardPolicy).
+    pub async fn forward(
+        &self,
+        protocol: ForwardProtocol,
+        listen: SocketAddr,
+        node_id: NodeId,
+        target: Target,
+    ) -> Result<ForwardInfo, DaemonError> {
+        self.forwarding
+            .forward(protocol, listen, node_id, target)
+            .await
+    }
+
+    /// Stops the forward with the given ID. Returns whether it existed.
+    pub fn remove_forward(&self, id: u64) -> bool {
+        self.forwarding.remove_forward(id)
+    }
+
+    /// Returns the forwards started with [DaemonHandle::forward].
+    pub fn forwards(&self) -> Vec<ForwardInfo> {
+        self.forwarding.forwards()
+    }
+
+    /// Allows peers to reach more targets through their forwards, until the daemon stops.
+    pub fn publish(&self, grant: ForwardGrant) {
+        self.forwarding.publish(grant);
+    }
+
+    /// Sends the traffic leaving the overlay only to the exit node with the given [NodeId],
+    /// or stops using an exit node when [None].
+    ///
+    /// With the TUN device, the default routes are moved to the TUN device too.
+    /// While the exit node is disconnected, the traffic leaving the overlay is dropped.
+    pub async fn set_exit(&self, node_id: Option<NodeId>) -> Result<(), DaemonError> {
+        if let Some(node_id) = node_id {
+            let is_exit_node = self
+                .advertisements
+                .peer(node_id)
+                .is_some_and(|advertisement| advertisement.exit_node);
+            if !is_exit_node {
+                return Err(DaemonError::NotAnExitNode(node_id));
+            }
+        }
+        let previous = self.exit().await?;
+        if let Some(node_id) = node_id {
+            // Restrict the traffic to the exit node before routing more traffic to the peers
+            self.peer_collection
+                .send_message(PeerCollectionMessage::SetExit(Some(node_id)))
+                .await;
+        }
+        if let Some(route_manager) = &self.route_manager {
+            let result = route_manager
+                .ask(|reply| RouteManagerMessage::SetExit(node_id.is_some(), reply))
+                .await
+                .map_err(DaemonError::from)
+                .and_then(|result| result);
+            if let Err(error) = result {
+                // The routes are left as they were, so is the exit node
+                self.peer_collection
+                    .send_message(PeerCollectionMessage::SetExit(previous))
+                    .await;
+                return Err(error);
+            }
+        }
+        if node_id.is_none() {
+            self.peer_collection
+                .send_message(PeerCollectionMessage::SetExit(None))
+                .await;
+        }
+        Ok(())
+    }
+
+    /// Returns the selected exit node.
+    pub async fn exit(&self) -> Result<Option<NodeId>, DaemonError> {
+        Ok(self
+            .peer_collection
+            .ask(PeerCollectionMessage::GetExit)
+            .await?)
+    }
+
+    /// Returns the connected peers that advertise themselves as exit nodes.
+    pub fn exit_nodes(&self) -> Vec<NodeId> {
+        self.advertisements
+            .peers()
+            .into_iter()
+            .filter(|(_, advertisement)| advertisement.exit_node)
+            .map(|(node_id, _)| node_id)
+            .collect()
+    }
+
+    /// Returns the statistics of the daemon.
+    pub async fn stats(&self) -> Result<DaemonStats, DaemonError> {
+        let (firewall_accepted_packets, firewall_dropped_packets) = match &self.firewall {
+            Some(firewall) => (
+                firewall.stats().accepted.load(Ordering::Relaxed),
+                firewall.stats().dropped.load(Ordering::Relaxed),
+            ),
+            None => (0, 0),
+        };
+        Ok(DaemonStats {
+            peers: self.peers().await?,
+            router_dropped_packets: self
+                .packet_router
+                .mailbox_stats()
+                .dropped
+                .load(Ordering::Relaxed),
+            firewall_accepted_packets,
+            firewall_dropped_packets,
+            failovers: self
+                .peer_collection
+                .ask(PeerCollectionMessage::GetFailovers)
+                .await?,
+        })
+    }
+
+    /// Reloads the firewall rules and the WebAssembly filters from their files.
+    ///
+    /// Stops at the first error. The firewall or filter that failed keeps its previous version.
+    pub fn reload(&self) -> Result<(), DaemonError> {
+        if let Some(firewall) = &self.firewall {
+            firewall.reload()?;
+            info!("Reloaded firewall rules");
+        }
+        for wasm_filter in &self.wasm_filters {
+            wasm_filter.reload()?;
+            info!("Reloaded WebAssembly filter {}", wasm_filter.name());
+        }
+        Ok(())
+    }
+
+    /// Swaps the module of the WebAssembly filter of the stage `name` with the module file at
+    /// `path`, or loads its own module file again when [None]. The other filters are untouched.
+    ///
+    /// On error, the filter keeps its previous module.
+    pub fn swap_wasm_filter(&self, name: &str, path: Option<&Path>) -> Result<(), DaemonError> {
+        let wasm_filter = self
+            .wasm_filters
+            .iter()
+            .find(|wasm_filter| wasm_filter.name() == name)
+            .ok_or_else(|| DaemonError::UnknownStage(name.to_string()))?;
+        match path {
+            Some(path) => wasm_filter.swap(&std::fs::read(path)?)?,
+            None => wasm_filter.reload()?,
+        }
+        info!("Swapped WebAssembly filter {}", name);
+        Ok(())
+    }
+
+    /// Subscribes to the [Event]s emitted by the daemon from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
+    }
+
+    /// Returns the [Shutdown] of the daemon, which can trigger its shutdown from anywhere.
+    pub fn shutdown_trigger(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Waits until the daemon stops, either because it was shut down or because an actor died.
+    ///
+    /// The result is returned to only one caller. The others get `Ok(())`, also when
+    /// the daemon has already stopped.
+    pub async fn wait(&self) -> Result<(), DaemonError> {
+        let mut task = self.task.lock().await;
+        let Some(join_handle) = task.as_mut() else {
+            return Ok(());
+        };
+        let result = join_handle.await.unwrap_or_else(|error| {
+            Err(DaemonError::ActorDied(
+                "supervisor".to_string(),
+                ExitReason::from_join_error(error),
+            ))
+        });
+        *task = None;
+        result
+    }
+
+    /// Shuts the daemon down and waits until it stops.
+    pub async fn shutdown(&self) -> Result<(), DaemonError> {
+        self.shutdown.trigger();
+        self.wait().await
+    }
+}
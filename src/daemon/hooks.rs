@@ -0,0 +1,322 @@
+//! Module for hook executables run when peers come and go.
+//!
+//! Hooks receive the details of the event as environment variables:
+//!
+//! - `P2PTUN_EVENT`: `peer_up`, `peer_down`, `daemon_start` or `daemon_stop`
+//! - `P2PTUN_NODE_ID`: the [NodeId] of this node
+//! - `P2PTUN_PEER_ID`, `P2PTUN_PEER_NAME`, `P2PTUN_PEER_ADDRESS`: the peer, for peer events
+//! - `P2PTUN_PEER_PATH`: `direct`, `relay`, `mixed` or `none`, for peer events
+//! - `P2PTUN_DISCONNECT_REASON`: why the peer was disconnected, for `peer_down`
+//!
+//! The name and the overlay address of a peer are taken from [HookConfig::peers], or else from
+//! the records of the [NetworkState] given to [Hooks::state].
+//!
+//! The hooks of the events of one peer run in the order of the events: the `peer_down` hooks
+//! of a peer start once its `peer_up` hooks have finished.
+
+use std::{
+    collections::HashMap, net::IpAddr, path::PathBuf, process::Stdio, sync::Arc, time::Duration,
+};
+
+use iroh_net::NodeId;
+use tokio::{
+    process::Command,
+    sync::{
+        broadcast::{error::RecvError, Receiver},
+        Semaphore,
+    },
+    task::{JoinHandle, JoinSet},
+};
+use tracing::{info, warn};
+
+use crate::daemon::{
+    events::{DisconnectReason, Event, Path},
+    state::{NetworkState, Record},
+};
+
+/// The hook executables and how they are run.
+#[derive(Debug, Clone)]
+pub struct HookConfig {
+    /// Run when a peer connects.
+    pub peer_up: Vec<PathBuf>,
+    /// Run when a peer disconnects.
+    pub peer_down: Vec<PathBuf>,
+    /// Run when the daemon has started.
+    pub daemon_start: Vec<PathBuf>,
+    /// Run when the daemon starts stopping. It is bounded by the shutdown deadline too.
+    pub daemon_stop: Vec<PathBuf>,
+    /// How long a hook may run before it is killed.
+    pub timeout: Duration,
+    /// How many hooks may run at once. Others wait for their turn.
+    pub max_concurrent: usize,
+    /// Names and overlay addresses of known peers.
+    pub peers: HashMap<NodeId, PeerDetails>,
+}
+
+impl HookConfig {
+    /// Returns whether no hook is configured.
+    pub fn is_empty(&self) -> bool {
+        self.peer_up.is_empty()
+            && self.peer_down.is_empty()
+            && self.daemon_start.is_empty()
+            && self.daemon_stop.is_empty()
+    }
+}
+
+impl Default for HookConfig {
+    fn default() -> Self {
+        Self {
+            peer_up: Vec::new(),
+            peer_down: Vec::new(),
+            daemon_start: Vec::new(),
+            daemon_stop: Vec::new(),
+            timeout: Duration::from_secs(10),
+            max_concurrent: 4,
+            peers: HashMap::new(),
+        }
+    }
+}
+
+/// The details of a peer passed to hooks.
+#[derive(Debug, Clone, Default)]
+pub struct PeerDetails {
+    pub name: Option<String>,
+    pub address: Option<IpAddr>,
+}
+
+/// Runs the hooks for the events of a daemon.
+pub struct Hooks {
+    config: Arc<HookConfig>,
+    node_id: NodeId,
+    semaphore: Arc<Semaphore>,
+    /// The last known path to each peer.
+    paths: HashMap<NodeId, Path>,
+    /// The hooks running for each peer, awaited by its next hooks.
+    peer_hooks: HashMap<NodeId, JoinHandle<()>>,
+    state: Option<NetworkState>,
+}
+
+impl Hooks {
+    /// Creates the hook runner of the node with the given [NodeId].
+    pub fn new(config: HookConfig, node_id: NodeId) -> Self {
+        let semaphore = Arc::new(Semaphore::new(config.max_concurrent.max(1)));
+        Self {
+            config: Arc::new(config),
+            node_id,
+            semaphore,
+            paths: HashMap::new(),
+            peer_hooks: HashMap::new(),
+            state: None,
+        }
+    }
+
+    /// Sets the state holding the names and addresses of the peers missing from the config.
+    pub fn state(mut self, state: NetworkState) -> Self {
+        self.state = Some(state);
+        self
+    }
+
+    /// Runs hooks for the events from `events` until the daemon starts stopping.
+    ///
+    /// Returns once the `daemon_stop` hooks have finished.
+    pub async fn run(mut self, mut events: Receiver<Event>) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Hooks missed {} events", missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            match event {
+                Event::DaemonStarted { .. } => {
+                    let environment = self.environment("daemon_start");
+                    self.spawn_all(&self.config.daemon_start, environment)
+                        .detach_all();
+                }
+                Event::DaemonStopping => {
+                    let environment = self.environment("daemon_stop");
+                    let mut hooks = self.spawn_all(&self.config.daemon_stop, environment);
+                    while hooks.join_next().await.is_some() {}
+                    return;
+                }
+                Event::PathChanged { node_id, path } => {
+                    self.paths.insert(node_id, path);
+                }
+                Event::PeerConnected { node_id } => {
+                    let environment = self.peer_environment("peer_up", node_id);
+                    self.spawn_peer_hooks(node_id, self.config.peer_up.clone(), environment);
+                }
+                Event::PeerDisconnected { node_id, reason } => {
+                    let mut environment = self.peer_environment("peer_down", node_id);
+                    environment.push(("P2PTUN_DISCONNECT_REASON", reason_name(&reason)));
+                    self.spawn_peer_hooks(node_id, self.config.peer_down.clone(), environment);
+                    self.paths.remove(&node_id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Returns the environment variables common to all hooks.
+    fn environment(&self, event: &str) -> Vec<(&'static str, String)> {
+        vec![
+            ("P2PTUN_EVENT", event.to_string()),
+            ("P2PTUN_NODE_ID", self.node_id.to_string()),
+        ]
+    }
+
+    /// Returns the environment variables of a hook for the peer with the given [NodeId].
+    fn peer_environment(&self, event: &str, node_id: NodeId) -> Vec<(&'static str, String)> {
+        let mut environment = self.environment(event);
+        environment.push(("P2PTUN_PEER_ID", node_id.to_string()));
+        let details = self.peer_details(node_id);
+        if let Some(name) = details.name {
+            environment.push(("P2PTUN_PEER_NAME", name));
+        }
+        if let Some(address) = details.address {
+            environment.push(("P2PTUN_PEER_ADDRESS", address.to_string()));
+        }
+        let path = match self.paths.get(&node_id) {
+            Some(Path::Direct { .. }) => "direct",
+            Some(Path::Relay { .. }) => "relay",
+            Some(Path::Mixed { .. }) => "mixed",
+            Some(Path::None) | None => "none",
+        };
+        environment.push(("P2PTUN_PEER_PATH", path.to_string()));
+        environment
+    }
+
+    /// Returns the name and the overlay address of the peer with the given [NodeId],
+    /// from the config or else from the records of the state.
+    fn peer_details(&self, node_id: NodeId) -> PeerDetails {
+        let mut details = self.config.peers.get(&node_id).cloned().unwrap_or_default();
+        let Some(state) = &self.state else {
+            return details;
+        };
+        for info in state.records() {
+            match info.record {
+                Record::Name {
+                    node_id: named,
+                    name,
+                } if named == node_id => {
+                    details.name.get_or_insert(name);
+                }
+                Record::Address {
+                    address,
+                    node_id: owner,
+                } if owner == node_id => {
+                    details.address.get_or_insert(address);
+                }
+                _ => {}
+            }
+        }
+        details
+    }
+
+    /// Runs every hook in `hooks` in the background, once the previous hooks of the peer with
+    /// the given [NodeId] have finished.
+    fn spawn_peer_hooks(
+        &mut self,
+        node_id: NodeId,
+        hooks: Vec<PathBuf>,
+        environment: Vec<(&'static str, String)>,
+    ) {
+        self.peer_hooks.retain(|_, task| !task.is_finished());
+        let previous = self.peer_hooks.remove(&node_id);
+        let (timeout, semaphore) = (self.config.timeout, self.semaphore.clone());
+        let task = tokio::spawn(async move {
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+            let mut tasks = JoinSet::new();
+            for hook in hooks {
+                tasks.spawn(run_hook(
+                    hook,
+                    environment.clone(),
+                    timeout,
+                    semaphore.clone(),
+                ));
+            }
+            while tasks.join_next().await.is_some() {}
+        });
+        self.peer_hooks.insert(node_id, task);
+    }
+
+    /// Runs every hook in `hooks` in the background.
+    fn spawn_all(
+        &self,
+        hooks: &[PathBuf],
+        environment: Vec<(&'static str, String)>,
+    ) -> JoinSet<()> {
+        let mut tasks = JoinSet::new();
+        for hook in hooks {
+            tasks.spawn(run_hook(
+                hook.clone(),
+                environment.clone(),
+                self.config.timeout,
+                self.semaphore.clone(),
+            ));
+        }
+        tasks
+    }
+}
+
+/// Returns the value of `P2PTUN_DISCONNECT_REASON` for `reason`.
+fn reason_name(reason: &DisconnectReason) -> String {
+    match reason {
+        DisconnectReason::PeerShutdown => "peer_shutdown".to_string(),
+        DisconnectReason::Closed => "closed".to_string(),
+        DisconnectReason::ConnectionLost { .. } => "connection_lost".to_string(),
+        DisconnectReason::Requested => "requested".to_string(),
+        DisconnectReason::Stopped => "stopped".to_string(),
+    }
+}
+
+/// Runs one hook, killing it after `timeout`, and logs its output.
+async fn run_hook(
+    hook: PathBuf,
+    environment: Vec<(&'static str, String)>,
+    timeout: Duration,
+    semaphore: Arc<Semaphore>,
+) {
+    // The semaphore is never closed
+    let Ok(_permit) = semaphore.acquire_owned().await else {
+        return;
+    };
+    let child = Command::new(&hook)
+        .envs(environment)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn();
+    let child = match child {
+        Ok(child) => child,
+        Err(error) => {
+            warn!("Couldn't run the hook {:?}. Reason: {:?}", hook, error);
+            return;
+        }
+    };
+    let output = match tokio::time::timeout(timeout, child.wait_with_output()).await {
+        Ok(Ok(output)) => output,
+        Ok(Err(error)) => {
+            warn!("Couldn't wait for the hook {:?}. Reason: {:?}", hook, error);
+            return;
+        }
+        Err(_) => {
+            warn!("The hook {:?} timed out and was killed", hook);
+            return;
+        }
+    };
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("Hook {:?}: {}", hook, line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("Hook {:?}: {}", hook, line);
+    }
+    if !output.status.success() {
+        warn!("The hook {:?} failed with {}", hook, output.status);
+    }
+}
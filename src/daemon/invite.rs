@@ -0,0 +1,508 @@
+//! Module for the invites to join the network.
+//!
+//! An [Invite] is issued and signed by an admin node, one of the authorities of the replicated
+//! state, see [StateConfig::authorities](super::state::StateConfig::authorities). It tells the
+//! node joining the network which node to dial, and when presented to the issuer in the
+//! handshake of the connection, admits the joining node even if the
+//! [AdmissionPolicy](super::admission::AdmissionPolicy) doesn't. The issuer writes the
+//! membership of the joining node, and its overlay address, to the replicated state, so the
+//! other members admit it too. The issuer also grants it the tags of the invite, used by its
+//! firewall.
+//!
+//! Nodes paired with this node with a short code, see [pairing](super::pairing), are admitted
+//! the same way. When a node replaces its key, see [rotation](super::rotation), what it was
+//! granted moves to its new key.
+//!
+//! An invite is only valid for the network it was issued for, until it expires, and for at
+//! most [Invite::max_uses] nodes. The issuer can revoke the invites it issued.
+//! The invites issued, and the nodes admitted through them, are persisted to
+//! [InviteConfig::path] when it is set.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt::Display,
+    net::IpAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use iroh_net::{
+    key::{SecretKey, Signature},
+    NodeAddr, NodeId,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::daemon::{
+    events::{Event, Events},
+    state::{NetworkState, Record, RecordKey},
+    store::{unix_time, StoreFile},
+    DaemonError,
+};
+
+/// The prefix of invites written as text.
+const INVITE_PREFIX: &str = "p2ptun-invite:";
+
+/// Settings of the invites.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InviteConfig {
+    /// The network the invites are issued for and accepted from.
+    pub network_id: String,
+    /// The file the invites are persisted to. They are only kept in memory when not set.
+    pub path: Option<PathBuf>,
+}
+
+impl Default for InviteConfig {
+    fn default() -> Self {
+        Self {
+            network_id: "p2ptun".to_string(),
+            path: None,
+        }
+    }
+}
+
+/// An invite to join the network, issued by an admin node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Invite {
+    pub id: u64,
+    pub network_id: String,
+    /// The addresses of the node that issued the invite, dialed to join the network.
+    pub issuer: NodeAddr,
+    /// When the invite expires, in seconds since the Unix epoch.
+    pub expires_at: u64,
+    /// How many nodes may join the network with the invite.
+    pub max_uses: u32,
+    /// The tags granted to the nodes joining with the invite.
+    pub tags: Vec<String>,
+    /// The overlay address assigned to the node joining with the invite.
+    pub address: Option<IpAddr>,
+}
+
+/// An [Invite] signed by its issuer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedInvite {
+    pub invite: Invite,
+    /// The signature of the invite by its issuer.
+    pub signature: Vec<u8>,
+}
+
+impl SignedInvite {
+    /// Signs `invite`, issued by the node of `secret_key`.
+    pub fn sign(secret_key: &SecretKey, invite: Invite) -> Self {
+        let signature = secret_key.sign(&Self::signed_data(&invite));
+        Self {
+            invite,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    /// Checks whether the invite is signed by its issuer.
+    pub fn verify(&self) -> bool {
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        self.invite
+            .issuer
+            .node_id
+            .verify(&Self::signed_data(&self.invite), &signature)
+            .is_ok()
+    }
+
+    /// Checks whether the invite is signed, for the network `network_id`, and not expired at `now`.
+    pub fn check(&self, network_id: &str, now: u64) -> Result<(), InviteError> {
+        if !self.verify() {
+            Err(InviteError::Forged)
+        } else if self.invite.network_id != network_id {
+            Err(InviteError::WrongNetwork)
+        } else if self.invite.expires_at <= now {
+            Err(InviteError::Expired)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Returns the data covered by the signature.
+    fn signed_data(invite: &Invite) -> Vec<u8> {
+        serde_json::to_vec(invite).expect("invites are serializable")
+    }
+}
+
+impl Display for SignedInvite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let data = serde_json::to_vec(self).map_err(|_| std::fmt::Error)?;
+        write!(f, "{}", INVITE_PREFIX)?;
+        for byte in data {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SignedInvite {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hex = s
+            .strip_prefix(INVITE_PREFIX)
+            .ok_or_else(|| "not an invite".to_string())?;
+        if hex.len() % 2 != 0 {
+            return Err("truncated invite".to_string());
+        }
+        let data = (0..hex.len())
+            .step_by(2)
+            .map(|index| u8::from_str_radix(&hex[index..index + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|error| error.to_string())?;
+        serde_json::from_slice(&data).map_err(|error| error.to_string())
+    }
+}
+
+/// Why an invite isn't accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InviteError {
+    /// It isn't signed by its issuer.
+    Forged,
+    /// It was issued for another network.
+    WrongNetwork,
+    Expired,
+    /// It was issued by another node, or isn't known by its issuer anymore.
+    Unknown,
+    Revoked,
+    /// As many nodes as allowed already joined with it.
+    UsedUp,
+}
+
+/// Information about an invite issued by this node.
+#[derive(Debug, Clone, Serialize)]
+pub struct InviteInfo {
+    pub id: u64,
+    pub expires_at: u64,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub tags: Vec<String>,
+    pub address: Option<IpAddr>,
+    /// The nodes that joined with the invite.
+    pub nodes: Vec<NodeId>,
+}
+
+/// What a node admitted through an invite was granted.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Grant {
+    invite_id: u64,
+    tags: Vec<String>,
+    address: Option<IpAddr>,
+}
+
+/// An invite issued by this node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Issued {
+    invite: SignedInvite,
+    revoked: bool,
+}
+
+/// The persisted state of the invites.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct InvitesState {
+    issued: BTreeMap<u64, Issued>,
+    /// The nodes admitted through an invite issued by this node.
+    admitted: HashMap<NodeId, Grant>,
+    /// The invites this node joined with, by issuer.
+    joined: HashMap<NodeId, SignedInvite>,
+    /// The nodes paired with this node.
+    #[serde(default)]
+    paired: HashSet<NodeId>,
+    /// The version of the invites, numbering their changes.
+    #[serde(skip)]
+    version: u64,
+}
+
+impl InvitesState {
+    /// Returns how many nodes joined with the invite `id`.
+    fn uses(&self, id: u64) -> u32 {
+        self.admitted
+            .values()
+            .filter(|grant| grant.invite_id == id)
+            .count() as u32
+    }
+}
+
+/// Issues invites, and admits the nodes presenting them.
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct Invites {
+    config: Arc<InviteConfig>,
+    file: Arc<StoreFile>,
+    secret_key: SecretKey,
+    inner: Arc<Mutex<InvitesState>>,
+    state: Option<NetworkState>,
+    events: Events,
+}
+
+impl Invites {
+    /// Creates a new [Invites] signing invites with `secret_key`,
+    /// and loads the persisted invites from [InviteConfig::path].
+    ///
+    /// The addresses granted are written to `state`, and the nodes admitted reported to `events`.
+    pub fn new(
+        config: InviteConfig,
+        secret_key: SecretKey,
+        state: Option<NetworkState>,
+        events: Events,
+    ) -> Result<Self, DaemonError> {
+        let file = StoreFile::new(config.path.clone());
+        let inner = match file.read()? {
+            Some(data) => serde_json::from_slice(&data).map_err(anyhow::Error::from)?,
+            None => InvitesState::default(),
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            file: Arc::new(file),
+            secret_key,
+            inner: Arc::new(Mutex::new(inner)),
+            state,
+            events,
+        })
+    }
+
+    /// Returns the ID of the network the invites are issued for.
+    pub fn network_id(&self) -> &str {
+        &self.config.network_id
+    }
+
+    /// Issues an invite to join through the node at `node_addr`, this node,
+    /// valid for `validity` and `max_uses` nodes.
+    pub fn issue(
+        &self,
+        node_addr: NodeAddr,
+        validity: Duration,
+        max_uses: u32,
+        tags: Vec<String>,
+        address: Option<IpAddr>,
+    ) -> Result<SignedInvite, DaemonError> {
+        let is_admin = self
+            .state
+            .as_ref()
+            .is_some_and(|state| state.is_authority(self.secret_key.public()));
+        if !is_admin {
+            return Err(DaemonError::NotAnAdmin);
+        }
+        let invite = SignedInvite::sign(
+            &self.secret_key,
+            Invite {
+                id: rand::random(),
+                network_id: self.config.network_id.clone(),
+                issuer: node_addr,
+                expires_at: unix_time().as_secs() + validity.as_secs(),
+                max_uses,
+                tags,
+                address,
+            },
+        );
+        let mut inner = self.inner.lock().unwrap();
+        inner.issued.insert(
+            invite.invite.id,
+            Issued {
+                invite: invite.clone(),
+                revoked: false,
+            },
+        );
+        self.persist(inner)?;
+        info!("Issued invite {}", invite.invite.id);
+        Ok(invite)
+    }
+
+    /// Returns the invites issued by this node that can still be used.
+    pub fn outstanding(&self) -> Vec<InviteInfo> {
+        let now = unix_time().as_secs();
+        let inner = self.inner.lock().unwrap();
+        inner
+            .issued
+            .values()
+            .map(|issued| &issued.invite.invite)
+            .filter(|invite| {
+                !inner.issued[&invite.id].revoked
+                    && invite.expires_at > now
+                    && inner.uses(invite.id) < invite.max_uses
+            })
+            .map(|invite| InviteInfo {
+                id: invite.id,
+                expires_at: invite.expires_at,
+                max_uses: invite.max_uses,
+                uses: inner.uses(invite.id),
+                tags: invite.tags.clone(),
+                address: invite.address,
+                nodes: inner
+                    .admitted
+                    .iter()
+                    .filter(|(_, grant)| grant.invite_id == invite.id)
+                    .map(|(node_id, _)| *node_id)
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Revokes the invite `id`, so no more nodes join with it.
+    ///
+    /// The nodes that already joined with it stay admitted.
+    pub fn revoke(&self, id: u64) -> Result<(), DaemonError> {
+        let mut inner = self.inner.lock().unwrap();
+        let issued = inner
+            .issued
+            .get_mut(&id)
+            .ok_or(DaemonError::InvalidInvite(InviteError::Unknown))?;
+        issued.revoked = true;
+        self.persist(inner)?;
+        info!("Revoked invite {}", id);
+        Ok(())
+    }
+
+    /// Accepts `invite` to join the network, returning the addresses of its issuer to dial.
+    ///
+    /// The invite is presented to the issuer whenever this node connects to it.
+    pub fn join(&self, invite: SignedInvite) -> Result<NodeAddr, DaemonError> {
+        invite
+            .check(&self.config.network_id, unix_time().as_secs())
+            .map_err(DaemonError::InvalidInvite)?;
+        let issuer = invite.invite.issuer.clone();
+        let mut inner = self.inner.lock().unwrap();
+        inner.joined.insert(issuer.node_id, invite);
+        self.persist(inner)?;
+        Ok(issuer)
+    }
+
+    /// Returns the invite to present to the node with the given [NodeId], if it issued one
+    /// this node joined with.
+    pub fn invite_for(&self, node_id: NodeId) -> Option<SignedInvite> {
+        self.inner.lock().unwrap().joined.get(&node_id).cloned()
+    }
+
+    /// Checks whether the node with the given [NodeId] joined with an invite issued by this node,
+    /// is a member in the replicated state, issued an invite this node joined with,
+    /// or is paired with this node.
+    pub fn admits(&self, node_id: NodeId) -> bool {
+        {
+            let inner = self.inner.lock().unwrap();
+            if inner.admitted.contains_key(&node_id)
+                || inner.joined.contains_key(&node_id)
+                || inner.paired.contains(&node_id)
+            {
+                return true;
+            }
+        }
+        self.state
+            .as_ref()
+            .is_some_and(|state| state.is_member(node_id))
+    }
+
+    /// Admits the node with the given [NodeId], paired with this node.
+    pub fn allow(&self, node_id: NodeId) -> Result<(), DaemonError> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.paired.insert(node_id) {
+            self.persist(inner)?;
+        }
+        Ok(())
+    }
+
+    /// Moves what was granted to the node `predecessor`, the invite it joined with or issued and
+    /// its pairing, to the node `successor` that replaced its key.
+    pub fn transfer(&self, predecessor: NodeId, successor: NodeId) -> Result<(), DaemonError> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut moved = false;
+        if let Some(grant) = inner.admitted.remove(&predecessor) {
+            inner.admitted.entry(successor).or_insert(grant);
+            moved = true;
+        }
+        if let Some(invite) = inner.joined.remove(&predecessor) {
+            inner.joined.entry(successor).or_insert(invite);
+            moved = true;
+        }
+        if inner.paired.remove(&predecessor) {
+            inner.paired.insert(successor);
+            moved = true;
+        }
+        if moved {
+            self.persist(inner)?;
+            info!("Moved the invites of {} to {}", predecessor, successor);
+        }
+        Ok(())
+    }
+
+    /// Returns the tags granted to the node with the given [NodeId] by an invite.
+    pub fn tags(&self, node_id: NodeId) -> Vec<String> {
+        self.inner
+            .lock()
+            .unwrap()
+            .admitted
+            .get(&node_id)
+            .map(|grant| grant.tags.clone())
+            .unwrap_or_default()
+    }
+
+    /// Admits the node with the given [NodeId] presenting `invite`,
+    /// if it is an invite issued by this node that can still be used.
+    ///
+    /// The node isn't admitted if its membership can't be written to the state,
+    /// or the invites can't be persisted.
+    pub fn redeem(&self, node_id: NodeId, invite: &SignedInvite) -> Result<(), DaemonError> {
+        invite
+            .check(&self.config.network_id, unix_time().as_secs())
+            .map_err(DaemonError::InvalidInvite)?;
+        let id = invite.invite.id;
+        let mut inner = self.inner.lock().unwrap();
+        let issued = inner
+            .issued
+            .get(&id)
+            .filter(|issued| issued.invite == *invite)
+            .ok_or(DaemonError::InvalidInvite(InviteError::Unknown))?;
+        if issued.revoked {
+            return Err(DaemonError::InvalidInvite(InviteError::Revoked));
+        }
+        if inner.uses(id) >= invite.invite.max_uses {
+            return Err(DaemonError::InvalidInvite(InviteError::UsedUp));
+        }
+        let state = self.state.as_ref().ok_or(DaemonError::StateDisabled)?;
+        state.set(Record::Member { node_id })?;
+        if let Some(address) = invite.invite.address {
+            if let Err(error) = state.set(Record::Address { address, node_id }) {
+                warn!("Couldn't assign {} to {}: {:?}", address, node_id, error);
+            }
+        }
+        inner.admitted.insert(
+            node_id,
+            Grant {
+                invite_id: id,
+                tags: invite.invite.tags.clone(),
+                address: invite.invite.address,
+            },
+        );
+        if let Err(error) = self.persist(inner) {
+            self.inner.lock().unwrap().admitted.remove(&node_id);
+            let _ = state.remove(RecordKey::Member { node_id });
+            return Err(error);
+        }
+        info!("Admitted {} with invite {}", node_id, id);
+        self.events.emit(Event::InviteRedeemed {
+            node_id,
+            invite_id: id,
+        });
+        Ok(())
+    }
+
+    /// Writes the invites to [InviteConfig::path], once `inner` is released.
+    fn persist(&self, mut inner: MutexGuard<InvitesState>) -> Result<(), DaemonError> {
+        if self.file.path().is_none() {
+            return Ok(());
+        }
+        inner.version += 1;
+        let version = inner.version;
+        let data = serde_json::to_vec(&*inner).map_err(anyhow::Error::from)?;
+        drop(inner);
+        self.file.write(version, &data)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,427 @@
+//! Module for the discovery of the nodes on the local network with mDNS.
+//!
+//! Every node announces itself as a DNS-SD service of type [SERVICE], in multicast DNS
+//! responses sent to [MdnsConfig::group] every [MdnsConfig::interval] and whenever another node
+//! browses the service. The TXT record of the service carries the network ID of the node, its
+//! [NodeId] and its direct addresses, one `addr` entry each, see [Announcement].
+//!
+//! The announced nodes of the same network that are admitted, by the [AdmissionPolicy] or the
+//! [Invites], are dialed with their direct addresses, so two nodes on the same local network
+//! connect without exchanging tickets and without going through a relay first.
+//!
+//! Announcements aren't signed: a node claiming another [NodeId] only makes the dial fail,
+//! since the key of the node is checked when connecting. After a failed dial, the node isn't
+//! dialed again at the same addresses for a minute, so an announcement with the addresses
+//! of the genuine node is still dialed. At most [MdnsConfig::max_dials] nodes are dialed per
+//! [MdnsConfig::interval].
+
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+
+use hickory_proto::{
+    op::{Message, MessageType, OpCode, Query},
+    rr::{
+        rdata::{A, AAAA, PTR, SRV, TXT},
+        Name, RData, Record, RecordType,
+    },
+};
+use iroh_net::{NodeAddr, NodeId};
+use serde::Deserialize;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::{
+    net::UdpSocket,
+    select,
+    sync::broadcast::{self, error::RecvError},
+    time::{interval, MissedTickBehavior},
+};
+use tracing::{debug, info, warn};
+
+use crate::daemon::{
+    actors::{peer_source::PeerSourceMessage, Addr},
+    admission::AdmissionPolicy,
+    advertisements::Advertisements,
+    events::{Event, Events},
+    invite::Invites,
+    rotation::Rotations,
+    DaemonError,
+};
+
+/// The DNS-SD service type of the nodes.
+pub const SERVICE: &str = "_p2ptun._udp.local.";
+/// How long the announced records are valid, in seconds.
+const TTL: u32 = 120;
+/// How long a node isn't dialed again at the same addresses after dialing it failed.
+const DIAL_BACKOFF: Duration = Duration::from_secs(60);
+/// How long a dial is waited for before the node is dialed again.
+const DIAL_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long this node waits before answering another query.
+const ANSWER_INTERVAL: Duration = Duration::from_secs(1);
+/// The longest mDNS message received.
+const MAX_MESSAGE_SIZE: usize = 9000;
+
+/// Settings of the discovery on the local network.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MdnsConfig {
+    /// Whether this node is announced on the local network, and the nodes announced dialed.
+    pub enabled: bool,
+    /// The multicast group and port the announcements are sent to.
+    pub group: SocketAddrV4,
+    /// The address of the interface the announcements are sent and received on.
+    /// The interface is chosen by the system when unspecified.
+    pub interface: Ipv4Addr,
+    /// How often this node is announced.
+    pub interval: Duration,
+    /// The most nodes dialed per interval.
+    pub max_dials: usize,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            group: SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353),
+            interface: Ipv4Addr::UNSPECIFIED,
+            interval: Duration::from_secs(30),
+            max_dials: 8,
+        }
+    }
+}
+
+/// What a node tells the other nodes of the local network about itself.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Announcement {
+    /// The network the node is a member of.
+    pub network_id: String,
+    /// The [NodeId] and direct addresses of the node.
+    pub node_addr: NodeAddr,
+}
+
+impl Announcement {
+    /// Encodes the announcement as an mDNS response, answering the browsing of [SERVICE].
+    pub fn to_message(&self) -> Result<Vec<u8>, DaemonError> {
+        let service = service_name();
+        let node_id = self.node_addr.node_id;
+        let instance =
+            Name::from_ascii(format!("{}.{}", node_id, SERVICE)).map_err(anyhow::Error::from)?;
+        let host = Name::from_ascii(format!("{}.local.", node_id)).map_err(anyhow::Error::from)?;
+        let addresses: Vec<SocketAddr> = self.node_addr.direct_addresses().copied().collect();
+        let port = addresses.first().map_or(0, SocketAddr::port);
+        let mut txt = vec![
+            format!("network={}", self.network_id),
+            format!("node={}", node_id),
+        ];
+        txt.extend(addresses.iter().map(|address| format!("addr={}", address)));
+
+        let mut message = Message::new();
+        message
+            .set_message_type(MessageType::Response)
+            .set_op_code(OpCode::Query)
+            .set_authoritative(true);
+        message.add_answer(Record::from_rdata(
+            service,
+            TTL,
+            RData::PTR(PTR(instance.clone())),
+        ));
+        message.add_answer(Record::from_rdata(
+            instance.clone(),
+            TTL,
+            RData::SRV(SRV::new(0, 0, port, host.clone())),
+        ));
+        message.add_answer(Record::from_rdata(instance, TTL, RData::TXT(TXT::new(txt))));
+        for address in &addresses {
+            let rdata = match address {
+                SocketAddr::V4(address) => RData::A(A(*address.ip())),
+                SocketAddr::V6(address) => RData::AAAA(AAAA(*address.ip())),
+            };
+            message.add_additional(Record::from_rdata(host.clone(), TTL, rdata));
+        }
+        Ok(message.to_vec().map_err(anyhow::Error::from)?)
+    }
+
+    /// Decodes the announcements of the nodes in an mDNS response.
+    ///
+    /// Records of other services, and the ones this node can't read, are skipped.
+    pub fn from_message(data: &[u8]) -> Vec<Self> {
+        Message::from_vec(data)
+            .map(|message| Self::from_records(&message))
+            .unwrap_or_default()
+    }
+
+    /// Reads the announcements of the nodes in the records of `message`.
+    fn from_records(message: &Message) -> Vec<Self> {
+        let service = service_name();
+        message
+            .answers()
+            .iter()
+            .chain(message.additionals())
+            .filter(|record| record.name().base_name() == service)
+            .filter_map(|record| match record.data() {
+                Some(RData::TXT(txt)) => Self::from_txt(txt),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Reads an announcement from the TXT record of a node.
+    fn from_txt(txt: &TXT) -> Option<Self> {
+        let entries: Vec<(&str, &str)> = txt
+            .txt_data()
+            .iter()
+            .filter_map(|entry| std::str::from_utf8(entry).ok()?.split_once('='))
+            .collect();
+        let value = |key: &str| {
+            entries
+                .iter()
+                .find(|(entry_key, _)| *entry_key == key)
+                .map(|(_, value)| *value)
+        };
+        let node_id: NodeId = value("node")?.parse().ok()?;
+        let addresses: Vec<SocketAddr> = entries
+            .iter()
+            .filter(|(key, _)| *key == "addr")
+            .filter_map(|(_, address)| address.parse().ok())
+            .collect();
+        Some(Self {
+            network_id: value("network")?.to_string(),
+            node_addr: NodeAddr::new(node_id).with_direct_addresses(addresses),
+        })
+    }
+}
+
+/// Returns the name of the [SERVICE].
+fn service_name() -> Name {
+    Name::from_ascii(SERVICE).expect("the service name is valid")
+}
+
+/// Returns an mDNS query browsing [SERVICE].
+fn browse_message() -> Result<Vec<u8>, DaemonError> {
+    let mut message = Message::new();
+    message
+        .set_message_type(MessageType::Query)
+        .set_op_code(OpCode::Query);
+    message.add_query(Query::query(service_name(), RecordType::PTR));
+    Ok(message.to_vec().map_err(anyhow::Error::from)?)
+}
+
+/// Binds a socket to the port of `group`, shared with the other mDNS responders of the host,
+/// and joins `group` on `interface`.
+fn bind(group: SocketAddrV4, interface: Ipv4Addr) -> Result<UdpSocket, DaemonError> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port()).into())?;
+    socket.join_multicast_v4(group.ip(), &interface)?;
+    socket.set_multicast_if_v4(&interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Announces this node on the local network, and dials the nodes of the network announced.
+pub struct Mdns {
+    config: MdnsConfig,
+    node_id: NodeId,
+    socket: UdpSocket,
+    admission: AdmissionPolicy,
+    invites: Invites,
+    rotations: Rotations,
+    peer_source: Addr<PeerSourceMessage>,
+    /// When this node last answered a query.
+    answered: Option<Instant>,
+    advertisements: Advertisements,
+    /// The nodes being dialed, with when and the addresses dialed.
+    dialing: HashMap<NodeId, (Instant, NodeAddr)>,
+    /// The nodes that couldn't be dialed, with when and the addresses dialed.
+    failed: HashMap<NodeId, (Instant, NodeAddr)>,
+    /// How many nodes were dialed since this node was last announced.
+    dials: usize,
+    event_receiver: broadcast::Receiver<Event>,
+}
+
+impl Mdns {
+    /// Creates a new [Mdns] announcing the node with the given [NodeId], joining the multicast
+    /// group of `config`.
+    ///
+    /// The announced nodes are dialed with `peer_source` when admitted by `admission`, followed
+    /// to their new keys with `rotations`, or by `invites`, whose network ID is announced.
+    /// The nodes connected according to `advertisements` aren't dialed, and the failed dials
+    /// are followed through `events`.
+    pub fn new(
+        config: MdnsConfig,
+        node_id: NodeId,
+        admission: AdmissionPolicy,
+        invites: Invites,
+        rotations: Rotations,
+        advertisements: Advertisements,
+        peer_source: Addr<PeerSourceMessage>,
+        events: &Events,
+    ) -> Result<Self, DaemonError> {
+        let socket = bind(config.group, config.interface)?;
+        Ok(Self {
+            config,
+            node_id,
+            socket,
+            admission,
+            invites,
+            rotations,
+            peer_source,
+            answered: None,
+            advertisements,
+            dialing: HashMap::new(),
+            failed: HashMap::new(),
+            dials: 0,
+            event_receiver: events.subscribe(),
+        })
+    }
+
+    /// Gets the current direct addresses of this node, and announces them.
+    async fn announce(&self) -> Result<(), DaemonError> {
+        let ticket = self.peer_source.ask(PeerSourceMessage::GetTicket).await??;
+        let announcement = Announcement {
+            network_id: self.invites.network_id().to_string(),
+            node_addr: NodeAddr::new(self.node_id)
+                .with_direct_addresses(ticket.node_addr().direct_addresses().copied()),
+        };
+        self.socket
+            .send_to(&announcement.to_message()?, self.config.group)
+            .await?;
+        Ok(())
+    }
+
+    /// Browses the nodes of the local network, which answer with their announcements.
+    async fn browse(&self) -> Result<(), DaemonError> {
+        self.socket
+            .send_to(&browse_message()?, self.config.group)
+            .await?;
+        Ok(())
+    }
+
+    /// Handles an mDNS message received from `from`.
+    async fn receive(&mut self, data: &[u8], from: SocketAddr) {
+        let message = match Message::from_vec(data) {
+            Ok(message) => message,
+            Err(error) => {
+                debug!("Invalid mDNS message from {}: {:?}", from, error);
+                return;
+            }
+        };
+        match message.message_type() {
+            MessageType::Query => {
+                let service = service_name();
+                let browsed = message.queries().iter().any(|query| {
+                    query.name() == &service
+                        && matches!(query.query_type(), RecordType::PTR | RecordType::ANY)
+                });
+                if browsed
+                    && self
+                        .answered
+                        .is_none_or(|at| at.elapsed() >= ANSWER_INTERVAL)
+                {
+                    self.answered = Some(Instant::now());
+                    if let Err(error) = self.announce().await {
+                        warn!("Couldn't answer the query of {}. Reason: {:?}", from, error);
+                    }
+                }
+            }
+            MessageType::Response => {
+                for announcement in Announcement::from_records(&message) {
+                    self.discovered(announcement).await;
+                }
+            }
+        }
+    }
+
+    /// Dials the node of `announcement` if it is an admitted member of the network this node
+    /// isn't connected to.
+    async fn discovered(&mut self, announcement: Announcement) {
+        let node_id = announcement.node_addr.node_id;
+        if node_id == self.node_id
+            || announcement.network_id != self.invites.network_id()
+            || self.advertisements.is_connected(node_id)
+            || self
+                .dialing
+                .get(&node_id)
+                .is_some_and(|(dialed, _)| dialed.elapsed() < DIAL_TIMEOUT)
+            || self
+                .failed
+                .get(&node_id)
+                .is_some_and(|(failed, node_addr)| {
+                    failed.elapsed() < DIAL_BACKOFF && *node_addr == announcement.node_addr
+                })
+        {
+            return;
+        }
+        if !self.rotations.admits(&self.admission, node_id) && !self.invites.admits(node_id) {
+            debug!("Ignoring {} on the local network, not a member", node_id);
+            return;
+        }
+        if self.dials >= self.config.max_dials {
+            debug!(
+                "Not dialing {} on the local network yet, too many dials",
+                node_id
+            );
+            return;
+        }
+        info!("Discovered {} on the local network, dialing it", node_id);
+        self.dials += 1;
+        self.dialing
+            .insert(node_id, (Instant::now(), announcement.node_addr.clone()));
+        self.peer_source
+            .send_message(PeerSourceMessage::DialPeer(announcement.node_addr))
+            .await;
+    }
+
+    /// Forgets the dials waited for and failed long enough ago.
+    fn expire_dials(&mut self) {
+        self.dials = 0;
+        self.dialing
+            .retain(|_, (dialed, _)| dialed.elapsed() < DIAL_TIMEOUT);
+        self.failed
+            .retain(|_, (failed, _)| failed.elapsed() < DIAL_BACKOFF);
+    }
+
+    /// Runs the discovery, following the dials of the nodes.
+    pub async fn run(mut self) {
+        if let Err(error) = self.browse().await {
+            warn!("Couldn't browse the local network. Reason: {:?}", error);
+        }
+        let mut ticks = interval(self.config.interval);
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+        loop {
+            select! {
+                _ = ticks.tick() => {
+                    self.expire_dials();
+                    if let Err(error) = self.announce().await {
+                        warn!("Couldn't announce this node. Reason: {:?}", error);
+                    }
+                }
+                received = self.socket.recv_from(&mut buffer) => match received {
+                    Ok((length, from)) => self.receive(&buffer[..length], from).await,
+                    Err(error) => warn!("Couldn't receive an mDNS message. Reason: {:?}", error),
+                },
+                event = self.event_receiver.recv() => match event {
+                    Ok(Event::DialFailed { node_id, .. }) => {
+                        if let Some((_, node_addr)) = self.dialing.remove(&node_id) {
+                            self.failed.insert(node_id, (Instant::now(), node_addr));
+                        }
+                    }
+                    Ok(Event::PeerConnected { node_id }) => {
+                        self.dialing.remove(&node_id);
+                        self.failed.remove(&node_id);
+                    }
+                    Ok(_) => {}
+                    // The dials whose end was missed time out
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("mDNS missed {} events", missed);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+            }
+        }
+    }
+}
@@ -0,0 +1,216 @@
+//! Module for configuring network interfaces of the host through rtnetlink.
+
+use std::{io, ops::Range};
+
+use futures::TryStreamExt;
+use ipnet::IpNet;
+use netlink_packet_route::{
+    nlas::rule::Nla, RouteMessage, RuleMessage, FR_ACT_TO_TBL, RT_SCOPE_LINK, RT_TABLE_MAIN,
+};
+use rtnetlink::{Handle, IpVersion};
+
+/// A connection to the rtnetlink of the host.
+#[derive(Clone)]
+pub struct Netlink {
+    handle: Handle,
+}
+
+impl Netlink {
+    /// Opens a connection, served by a background task.
+    pub fn connect() -> io::Result<Self> {
+        let (connection, handle, _) = rtnetlink::new_connection()?;
+        tokio::spawn(connection);
+        Ok(Self { handle })
+    }
+
+    /// Returns the index of the interface named `name`.
+    pub async fn link_index(&self, name: &str) -> Result<u32, rtnetlink::Error> {
+        let mut links = self
+            .handle
+            .link()
+            .get()
+            .match_name(name.to_string())
+            .execute();
+        match links.try_next().await? {
+            Some(link) => Ok(link.header.index),
+            None => Err(rtnetlink::Error::RequestFailed),
+        }
+    }
+
+    /// Assigns `address` to the interface with the given index.
+    pub async fn add_address(&self, index: u32, address: IpNet) -> Result<(), rtnetlink::Error> {
+        self.handle
+            .address()
+            .add(index, address.addr(), address.prefix_len())
+            .execute()
+            .await
+    }
+
+    /// Routes `prefix` to the interface with the given index, marking the route with `protocol`.
+    ///
+    /// Replaces an existing route to the same prefix.
+    pub async fn add_route(
+        &self,
+        index: u32,
+        prefix: IpNet,
+        protocol: u8,
+    ) -> Result<(), rtnetlink::Error> {
+        self.add_table_route(index, prefix, protocol, RT_TABLE_MAIN)
+            .await
+    }
+
+    /// Like [Netlink::add_route], but adds the route to the routing table `table`.
+    pub async fn add_table_route(
+        &self,
+        index: u32,
+        prefix: IpNet,
+        protocol: u8,
+        table: u8,
+    ) -> Result<(), rtnetlink::Error> {
+        let request = self
+            .handle
+            .route()
+            .add()
+            .table_id(table.into())
+            .output_interface(index)
+            .protocol(protocol)
+            .scope(RT_SCOPE_LINK)
+            .replace();
+        match prefix {
+            IpNet::V4(prefix) => {
+                request
+                    .v4()
+                    .destination_prefix(prefix.addr(), prefix.prefix_len())
+                    .execute()
+                    .await
+            }
+            IpNet::V6(prefix) => {
+                request
+                    .v6()
+                    .destination_prefix(prefix.addr(), prefix.prefix_len())
+                    .execute()
+                    .await
+            }
+        }
+    }
+
+    /// Returns the IPv4 and IPv6 routes to the interface with the given index marked with
+    /// `protocol`.
+    ///
+    /// The routes of other interfaces are left out, since other processes may use the same
+    /// protocol.
+    pub async fn routes(
+        &self,
+        index: u32,
+        protocol: u8,
+    ) -> Result<Vec<RouteMessage>, rtnetlink::Error> {
+        let mut routes = Vec::new();
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let found: Vec<RouteMessage> = self
+                .handle
+                .route()
+                .get(ip_version)
+                .execute()
+                .try_collect()
+                .await?;
+            routes.extend(found.into_iter().filter(|route| {
+                route.header.protocol == protocol && route.output_interface() == Some(index)
+            }));
+        }
+        Ok(routes)
+    }
+
+    /// Removes the route of the main table to `prefix` through the interface with the given
+    /// index, marked with `protocol`, if there is one.
+    pub async fn remove_route(
+        &self,
+        index: u32,
+        prefix: IpNet,
+        protocol: u8,
+    ) -> Result<(), rtnetlink::Error> {
+        for route in self.routes(index, protocol).await? {
+            if route.header.table == RT_TABLE_MAIN
+                && route.destination_prefix() == Some((prefix.addr(), prefix.prefix_len()))
+            {
+                self.handle.route().del(route).execute().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes all routes to the interface with the given index marked with `protocol`.
+    /// Returns how many were removed.
+    pub async fn remove_routes(&self, index: u32, protocol: u8) -> Result<usize, rtnetlink::Error> {
+        let routes = self.routes(index, protocol).await?;
+        let count = routes.len();
+        for route in routes {
+            self.handle.route().del(route).execute().await?;
+        }
+        Ok(count)
+    }
+
+    /// Removes the routes of the routing table `table` to the interface with the given index
+    /// marked with `protocol`.
+    pub async fn remove_table_routes(
+        &self,
+        index: u32,
+        table: u8,
+        protocol: u8,
+    ) -> Result<(), rtnetlink::Error> {
+        for route in self.routes(index, protocol).await? {
+            if route.header.table == table {
+                self.handle.route().del(route).execute().await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds a rule looking up the routing table `table` for IPv4 or IPv6 packets.
+    ///
+    /// `conditions` restrict the packets the rule applies to, such as a range of user IDs.
+    pub async fn add_rule(
+        &self,
+        ip_version: IpVersion,
+        priority: u32,
+        table: u8,
+        conditions: Vec<Nla>,
+    ) -> Result<(), rtnetlink::Error> {
+        let mut request = self
+            .handle
+            .rule()
+            .add()
+            .priority(priority)
+            .table_id(table.into())
+            .action(FR_ACT_TO_TBL);
+        request.message_mut().nlas.extend(conditions);
+        match ip_version {
+            IpVersion::V4 => request.v4().execute().await,
+            IpVersion::V6 => request.v6().execute().await,
+        }
+    }
+
+    /// Removes the IPv4 and IPv6 rules with priorities in `priorities`. Returns how many were removed.
+    pub async fn remove_rules(&self, priorities: Range<u32>) -> Result<usize, rtnetlink::Error> {
+        let mut count = 0;
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let rules: Vec<RuleMessage> = self
+                .handle
+                .rule()
+                .get(ip_version)
+                .execute()
+                .try_collect()
+                .await?;
+            for rule in rules {
+                let in_range = rule.nlas.iter().any(|nla| match nla {
+                    Nla::Priority(priority) => priorities.contains(priority),
+                    _ => false,
+                });
+                if in_range {
+                    self.handle.rule().del(rule).execute().await?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+}
@@ -6,23 +6,71 @@
 //! The [Packet] enum is designed to facilitate packet handling and routing within the VPN
 //! tunnel, providing a standardized representation for network traffic.
 
+pub mod ip;
+
 use std::{fmt::Debug, sync::Arc};
 
+use iroh_net::NodeId;
+
 /// Represents a network packet used in the VPN tunnel.
 #[derive(Clone)]
 pub enum Packet {
     /// Outgoing packet containing data to be transmitted.
     Outgoing(Arc<[u8]>),
 
-    /// Incoming packet containing received data.
-    Incoming(Arc<[u8]>),
+    /// Incoming packet containing data received from the peer with the given [NodeId].
+    Incoming(NodeId, Arc<[u8]>),
+
+    /// Outgoing packet containing data to be transmitted only to the peer with the given [NodeId].
+    Redirected(NodeId, Arc<[u8]>),
+
+    /// Packet received from the peer with the first [NodeId], forwarded to the peer with the
+    /// second one on the path to its destination.
+    Transit(NodeId, NodeId, Arc<[u8]>),
+}
+
+impl Packet {
+    /// Returns the data of the packet.
+    pub fn data(&self) -> &Arc<[u8]> {
+        match self {
+            Self::Outgoing(data)
+            | Self::Incoming(_, data)
+            | Self::Redirected(_, data)
+            | Self::Transit(_, _, data) => data,
+        }
+    }
+
+    /// Returns the same kind of packet carrying different data.
+    pub fn with_data(self, data: Arc<[u8]>) -> Self {
+        match self {
+            Self::Outgoing(_) => Self::Outgoing(data),
+            Self::Incoming(node_id, _) => Self::Incoming(node_id, data),
+            Self::Redirected(node_id, _) => Self::Redirected(node_id, data),
+            Self::Transit(from, to, _) => Self::Transit(from, to, data),
+        }
+    }
 }
 
 impl Debug for Packet {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Outgoing(arg0) => f.debug_tuple("Outgoing").field(&arg0.len()).finish(),
-            Self::Incoming(arg0) => f.debug_tuple("Incoming").field(&arg0.len()).finish(),
+            Self::Incoming(arg0, arg1) => f
+                .debug_tuple("Incoming")
+                .field(&arg0.fmt_short())
+                .field(&arg1.len())
+                .finish(),
+            Self::Redirected(arg0, arg1) => f
+                .debug_tuple("Redirected")
+                .field(&arg0.fmt_short())
+                .field(&arg1.len())
+                .finish(),
+            Self::Transit(arg0, arg1, arg2) => f
+                .debug_tuple("Transit")
+                .field(&arg0.fmt_short())
+                .field(&arg1.fmt_short())
+                .field(&arg2.len())
+                .finish(),
         }
     }
 }
@@ -0,0 +1,154 @@
+//! Module for inspecting IP headers of packets.
+//!
+//! It extracts the fields needed to classify a packet into a [Flow], without copying the packet.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use serde::Serialize;
+
+/// IANA protocol number of ICMP.
+pub const PROTOCOL_ICMP: u8 = 1;
+/// IANA protocol number of TCP.
+pub const PROTOCOL_TCP: u8 = 6;
+/// IANA protocol number of UDP.
+pub const PROTOCOL_UDP: u8 = 17;
+/// IANA protocol number of ICMPv6.
+pub const PROTOCOL_ICMPV6: u8 = 58;
+
+/// The 5-tuple identifying a single flow of packets.
+///
+/// Ports are set to `0` for protocols that have no ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct Flow {
+    pub protocol: u8,
+    pub source: IpAddr,
+    pub destination: IpAddr,
+    pub source_port: u16,
+    pub destination_port: u16,
+}
+
+impl Flow {
+    /// Parses the flow of an IPv4 or IPv6 packet.
+    ///
+    /// Returns [None] if the packet is truncated or is not an IP packet.
+    pub fn parse(packet: &[u8]) -> Option<Self> {
+        match packet.first()? >> 4 {
+            4 => Self::parse_ipv4(packet),
+            6 => Self::parse_ipv6(packet),
+            _ => None,
+        }
+    }
+
+    /// Returns the flow of packets going in the opposite direction.
+    pub fn reversed(&self) -> Self {
+        Self {
+            protocol: self.protocol,
+            source: self.destination,
+            destination: self.source,
+            source_port: self.destination_port,
+            destination_port: self.source_port,
+        }
+    }
+
+    fn parse_ipv4(packet: &[u8]) -> Option<Self> {
+        let header_length = usize::from(packet.first()? & 0x0f) * 4;
+        if header_length < 20 || packet.len() < header_length {
+            return None;
+        }
+        let protocol = packet[9];
+        let source = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[12..16]).ok()?);
+        let destination = Ipv4Addr::from(<[u8; 4]>::try_from(&packet[16..20]).ok()?);
+        // Only the first fragment carries the transport header
+        let fragment_offset = u16::from_be_bytes([packet[6], packet[7]]) & 0x1fff;
+        let (source_port, destination_port) = if fragment_offset == 0 {
+            Self::parse_ports(protocol, &packet[header_length..])
+        } else {
+            (0, 0)
+        };
+        Some(Self {
+            protocol,
+            source: source.into(),
+            destination: destination.into(),
+            source_port,
+            destination_port,
+        })
+    }
+
+    fn parse_ipv6(packet: &[u8]) -> Option<Self> {
+        if packet.len() < 40 {
+            return None;
+        }
+        let source = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[8..24]).ok()?);
+        let destination = Ipv6Addr::from(<[u8; 16]>::try_from(&packet[24..40]).ok()?);
+        // Skip the extension headers to find the transport protocol
+        let mut protocol = packet[6];
+        let mut offset = 40;
+        let mut has_ports = true;
+        loop {
+            match protocol {
+                // Hop-by-hop, routing and destination options
+                0 | 43 | 60 => {
+                    let header = packet.get(offset..offset + 2)?;
+                    protocol = header[0];
+                    offset += (usize::from(header[1]) + 1) * 8;
+                }
+                // Fragment
+                44 => {
+                    let header = packet.get(offset..offset + 8)?;
+                    protocol = header[0];
+                    has_ports &= u16::from_be_bytes([header[2], header[3]]) >> 3 == 0;
+                    offset += 8;
+                }
+                _ => break,
+            }
+        }
+        let (source_port, destination_port) = match packet.get(offset..) {
+            Some(payload) if has_ports => Self::parse_ports(protocol, payload),
+            _ => (0, 0),
+        };
+        Some(Self {
+            protocol,
+            source: source.into(),
+            destination: destination.into(),
+            source_port,
+            destination_port,
+        })
+    }
+
+    /// Reads the source and destination ports of a TCP or UDP header.
+    fn parse_ports(protocol: u8, payload: &[u8]) -> (u16, u16) {
+        match (protocol, payload) {
+            (PROTOCOL_TCP | PROTOCOL_UDP, [a, b, c, d, ..]) => {
+                (u16::from_be_bytes([*a, *b]), u16::from_be_bytes([*c, *d]))
+            }
+            _ => (0, 0),
+        }
+    }
+}
+
+/// Decrements the TTL of an IPv4 packet or the hop limit of an IPv6 packet, in place.
+///
+/// Returns `false` if the packet can't be forwarded anymore, or is not an IP packet.
+pub fn decrement_hop_limit(packet: &mut [u8]) -> bool {
+    match packet.first().map(|byte| byte >> 4) {
+        Some(4) if packet.len() >= 20 => {
+            if packet[8] <= 1 {
+                return false;
+            }
+            packet[8] -= 1;
+            // The TTL is the high byte of its header word, see RFC 1141
+            let sum = u32::from(u16::from_be_bytes([packet[10], packet[11]])) + 0x0100;
+            let checksum = (sum + (sum >> 16)) as u16;
+            packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+            true
+        }
+        Some(6) if packet.len() >= 40 => {
+            if packet[7] <= 1 {
+                return false;
+            }
+            packet[7] -= 1;
+            true
+        }
+        _ => false,
+    }
+}
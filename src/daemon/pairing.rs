@@ -0,0 +1,473 @@
+//! Module for pairing two nodes with a short code.
+//!
+//! One node shows a [PairingCode] like `7-purple-sausages`, and the other one is given it.
+//! Both nodes then run SPAKE2, a password-authenticated key exchange, with the code as the
+//! password over a rendezvous channel: UDP datagrams broadcast on the local network. The keys
+//! they agree on only match if they used the same code, and are used to authenticate the
+//! [NodeAddr]s the nodes exchange afterwards. Someone eavesdropping learns nothing about the
+//! code, and someone meddling gets a single guess, failing the pairing when wrong. The node
+//! showing the code only gives up after [MAX_FAILURES] wrong guesses.
+//!
+//! The number of the code, its nameplate, tells apart the pairings running on the same network.
+//! The node showing the code listens on [PairingConfig::listen], and the node given it sends its
+//! messages to [PairingConfig::rendezvous] until it gets an answer. Each side sends its messages
+//! again every [RESEND_INTERVAL] until the other one answers, since datagrams may be lost.
+//! The node showing the code runs SPAKE2 with every node sending it a message for the nameplate,
+//! so another host on the network can't hold up the pairing by sending first.
+//!
+//! The rendezvous is an IPv4 broadcast by default, so both nodes must be on the same IPv4
+//! network. There is no rendezvous through a relay: nodes on different networks pair by setting
+//! [PairingConfig::rendezvous] to the address of the node showing the code.
+
+use std::{
+    fmt::Display,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::Duration,
+};
+
+use hmac::{Hmac, Mac};
+use iroh_net::NodeAddr;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use tokio::{net::UdpSocket, select, time::interval};
+use tracing::{debug, warn};
+
+/// The port of the rendezvous channel, by default.
+pub const PAIRING_PORT: u16 = 41414;
+/// How often the messages are sent again until the other side answers.
+pub const RESEND_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the node given the code keeps answering the other side after it is paired,
+/// in case its last message was lost.
+const LINGER: Duration = Duration::from_secs(2);
+/// The longest message accepted.
+const MAX_MESSAGE_SIZE: usize = 8 * 1024;
+/// How many nodes may fail to authenticate before the node showing the code gives up.
+pub const MAX_FAILURES: u32 = 3;
+/// How many nodes the node showing the code runs SPAKE2 with at once.
+const MAX_EXCHANGES: usize = 8;
+/// The identity both sides use in SPAKE2.
+const IDENTITY: &[u8] = b"p2ptun-pair";
+
+/// The words of the codes. There are 256 of them, so each word is a byte of the code.
+const WORDS: [&str; 256] = [
+    "acorn", "actor", "amber", "anchor", "apple", "arrow", "autumn", "bamboo", "banana", "band",
+    "banjo", "barn", "basil", "basket", "beach", "beard", "beaver", "bell", "berry", "bison",
+    "blade", "blanket", "blossom", "boat", "bonnet", "border", "bottle", "boulder", "bowl",
+    "brain", "branch", "bread", "breeze", "brick", "bridge", "bronze", "brook", "broom", "bubble",
+    "bucket", "buffalo", "bugle", "butter", "button", "cabin", "cable", "cactus", "camel",
+    "camera", "canal", "candle", "canoe", "canyon", "captain", "carpet", "carrot", "castle",
+    "cave", "cedar", "cello", "cereal", "chair", "chalk", "cherry", "chess", "chicken", "chimney",
+    "cider", "cinema", "circle", "citrus", "clock", "cloud", "clover", "coast", "cobalt", "cocoa",
+    "coffee", "comet", "compass", "copper", "coral", "cotton", "cowboy", "crab", "crayon",
+    "cricket", "crystal", "cupcake", "curtain", "cushion", "daisy", "dancer", "desert", "diamond",
+    "dinner", "doctor", "dolphin", "donkey", "dragon", "drum", "eagle", "echo", "elbow", "ember",
+    "engine", "falcon", "feather", "fern", "fiddle", "finch", "flame", "flute", "forest", "fossil",
+    "fountain", "fox", "galaxy", "garden", "garlic", "gecko", "geyser", "ginger", "giraffe",
+    "glacier", "globe", "goblin", "gravel", "guitar", "hammer", "harbor", "harp", "hazel",
+    "helmet", "hermit", "honey", "hornet", "igloo", "island", "ivory", "jacket", "jaguar", "jelly",
+    "jigsaw", "jungle", "kayak", "kettle", "kitten", "koala", "ladder", "lagoon", "lantern",
+    "lemon", "leopard", "lettuce", "lily", "lizard", "lobster", "locket", "lotus", "magnet",
+    "mango", "maple", "marble", "meadow", "melon", "meteor", "mirror", "mitten", "monkey", "moose",
+    "mosaic", "muffin", "mustard", "napkin", "nebula", "needle", "nickel", "noodle", "nutmeg",
+    "oasis", "ocean", "olive", "onion", "orbit", "orchid", "otter", "oyster", "paddle", "panda",
+    "panther", "parrot", "peach", "peanut", "pebble", "pepper", "piano", "pickle", "pigeon",
+    "pillow", "pirate", "planet", "plum", "pocket", "pony", "potato", "pretzel", "puffin",
+    "pumpkin", "purple", "puzzle", "quartz", "quill", "rabbit", "radish", "raven", "ribbon",
+    "river", "robin", "rocket", "saddle", "salmon", "sausages", "scarf", "shadow", "shovel",
+    "silver", "sketch", "sparrow", "spider", "sponge", "squirrel", "stamp", "sugar", "summit",
+    "sunset", "swan", "tiger", "tomato", "tulip", "turnip", "turtle", "umbrella", "valley",
+    "velvet", "violin", "walnut", "walrus", "whale", "willow", "window", "wizard", "wolf",
+    "yogurt", "zebra", "zipper",
+];
+
+/// Settings of the pairing.
+#[derive(Debug, Clone)]
+pub struct PairingConfig {
+    /// The address the node showing the code listens on.
+    pub listen: SocketAddr,
+    /// The address the node given the code sends its messages to, until it gets an answer.
+    pub rendezvous: SocketAddr,
+    /// How long a node waits for the other side before giving up.
+    pub timeout: Duration,
+}
+
+impl Default for PairingConfig {
+    fn default() -> Self {
+        Self {
+            listen: (Ipv4Addr::UNSPECIFIED, PAIRING_PORT).into(),
+            rendezvous: (Ipv4Addr::BROADCAST, PAIRING_PORT).into(),
+            timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// A short code pairing two nodes, like `7-purple-sausages`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PairingCode {
+    /// Tells apart the pairings running on the same network.
+    pub nameplate: u16,
+    /// Indexes into the words of the codes.
+    pub words: [u8; 2],
+}
+
+impl PairingCode {
+    /// Generates a random code.
+    pub fn generate() -> Self {
+        let mut rng = rand::thread_rng();
+        Self {
+            nameplate: rng.gen_range(1..100),
+            words: rng.gen(),
+        }
+    }
+}
+
+impl Display for PairingCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}-{}-{}",
+            self.nameplate, WORDS[self.words[0] as usize], WORDS[self.words[1] as usize]
+        )
+    }
+}
+
+impl FromStr for PairingCode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.trim().split('-').collect();
+        let [nameplate, first, second] = parts.as_slice() else {
+            return Err(format!("expected a code like 7-purple-sausages, got {}", s));
+        };
+        let word = |word: &str| {
+            WORDS
+                .iter()
+                .position(|known| known.eq_ignore_ascii_case(word))
+                .map(|index| index as u8)
+                .ok_or_else(|| format!("unknown word in the code: {}", word))
+        };
+        Ok(Self {
+            nameplate: nameplate.parse().map_err(|_| "invalid code number")?,
+            words: [word(first)?, word(second)?],
+        })
+    }
+}
+
+/// Errors that can happen while pairing.
+#[derive(Debug)]
+pub enum PairingError {
+    /// The other side used another code, or someone meddled with the pairing.
+    WrongCode,
+    /// The other side didn't answer in time.
+    TimedOut,
+    IoError(io::Error),
+}
+
+impl From<io::Error> for PairingError {
+    fn from(error: io::Error) -> Self {
+        Self::IoError(error)
+    }
+}
+
+/// Which side of the pairing a node is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Side {
+    /// The node showing the code.
+    Offer,
+    /// The node given the code.
+    Join,
+}
+
+/// A message of the rendezvous channel, sent as JSON.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Message {
+    /// The SPAKE2 message of a side.
+    Pake { nameplate: u16, message: Vec<u8> },
+    /// The addresses of a side, authenticated with the key agreed on.
+    Confirm {
+        nameplate: u16,
+        side: Side,
+        node_addr: NodeAddr,
+        mac: Vec<u8>,
+    },
+}
+
+/// Shows `code` to the other side: waits for the node given it, and exchanges the addresses
+/// of this node, `node_addr`, with its addresses.
+pub async fn offer(
+    config: &PairingConfig,
+    code: &PairingCode,
+    node_addr: NodeAddr,
+) -> Result<NodeAddr, PairingError> {
+    let socket = UdpSocket::bind(config.listen).await?;
+    let session = Session::new(Side::Offer, code, node_addr, None);
+    tokio::time::timeout(config.timeout, session.run(&socket))
+        .await
+        .map_err(|_| PairingError::TimedOut)?
+}
+
+/// Joins the node showing `code`, exchanging the addresses of this node, `node_addr`,
+/// with its addresses.
+pub async fn join(
+    config: &PairingConfig,
+    code: &PairingCode,
+    node_addr: NodeAddr,
+) -> Result<NodeAddr, PairingError> {
+    let unspecified: SocketAddr = match config.rendezvous {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(unspecified).await?;
+    socket.set_broadcast(true)?;
+    let session = Session::new(Side::Join, code, node_addr, Some(config.rendezvous));
+    tokio::time::timeout(config.timeout, session.run(&socket))
+        .await
+        .map_err(|_| PairingError::TimedOut)?
+}
+
+/// A SPAKE2 exchange with one node.
+struct Exchange {
+    /// Where the messages of the exchange are sent.
+    address: SocketAddr,
+    /// The SPAKE2 state, until the message of the other side is received.
+    spake: Option<Spake2<Ed25519Group>>,
+    pake_message: Vec<u8>,
+    /// The SPAKE2 message of the other side, once received.
+    their_message: Option<Vec<u8>>,
+    /// The key agreed on, once the message of the other side is received.
+    key: Option<Vec<u8>>,
+}
+
+impl Exchange {
+    fn start(code: &PairingCode, address: SocketAddr) -> Self {
+        let (spake, pake_message) = Spake2::<Ed25519Group>::start_symmetric(
+            &Password::new(code.to_string().as_bytes()),
+            &Identity::new(IDENTITY),
+        );
+        Self {
+            address,
+            spake: Some(spake),
+            pake_message,
+            their_message: None,
+            key: None,
+        }
+    }
+
+    /// Agrees on the key with the SPAKE2 message of the other side.
+    fn finish(&mut self, message: Vec<u8>) -> Result<(), spake2::Error> {
+        let Some(spake) = self.spake.take() else {
+            return Ok(());
+        };
+        self.key = Some(spake.finish(&message)?);
+        self.their_message = Some(message);
+        Ok(())
+    }
+}
+
+/// The state of one side of a pairing.
+struct Session {
+    side: Side,
+    code: PairingCode,
+    node_addr: NodeAddr,
+    /// The exchanges with the nodes that sent a message, oldest first. The node given the code
+    /// runs a single one, with the rendezvous until the other side answers.
+    exchanges: Vec<Exchange>,
+    /// How many nodes failed to authenticate.
+    failures: u32,
+}
+
+impl Session {
+    fn new(
+        side: Side,
+        code: &PairingCode,
+        node_addr: NodeAddr,
+        rendezvous: Option<SocketAddr>,
+    ) -> Self {
+        Self {
+            side,
+            code: code.clone(),
+            node_addr,
+            exchanges: rendezvous
+                .map(|rendezvous| Exchange::start(code, rendezvous))
+                .into_iter()
+                .collect(),
+            failures: 0,
+        }
+    }
+
+    /// Exchanges the messages until the other side is authenticated,
+    /// returning the addresses it sent.
+    async fn run(mut self, socket: &UdpSocket) -> Result<NodeAddr, PairingError> {
+        let mut resend = interval(RESEND_INTERVAL);
+        let mut buffer = vec![0u8; MAX_MESSAGE_SIZE];
+        let paired = loop {
+            select! {
+                _ = resend.tick() => {
+                    for exchange in &self.exchanges {
+                        self.send(socket, exchange).await;
+                    }
+                }
+                received = socket.recv_from(&mut buffer) => {
+                    let (size, from) = received?;
+                    if let Some(node_addr) = self.receive(socket, &buffer[..size], from).await? {
+                        break node_addr;
+                    }
+                }
+            }
+        };
+        // The other side may still be waiting for the addresses of this node
+        if self.side == Side::Join {
+            let linger = async {
+                loop {
+                    let Ok((size, from)) = socket.recv_from(&mut buffer).await else {
+                        return;
+                    };
+                    let _ = self.receive(socket, &buffer[..size], from).await;
+                }
+            };
+            let _ = tokio::time::timeout(LINGER, linger).await;
+        }
+        Ok(paired)
+    }
+
+    /// Handles a datagram from `from`, returning the addresses of the other side
+    /// once they are authenticated.
+    async fn receive(
+        &mut self,
+        socket: &UdpSocket,
+        data: &[u8],
+        from: SocketAddr,
+    ) -> Result<Option<NodeAddr>, PairingError> {
+        let message = match serde_json::from_slice::<Message>(data) {
+            Ok(message) => message,
+            Err(error) => {
+                debug!("Invalid pairing message from {}: {:?}", from, error);
+                return Ok(None);
+            }
+        };
+        match message {
+            Message::Pake { nameplate, message } if nameplate == self.code.nameplate => {
+                let Some(index) = self.exchange_for_pake(from, &message) else {
+                    return Ok(None);
+                };
+                let exchange = &mut self.exchanges[index];
+                exchange.address = from;
+                if let Err(error) = exchange.finish(message) {
+                    if self.side == Side::Join {
+                        return Err(PairingError::WrongCode);
+                    }
+                    debug!("Invalid SPAKE2 message from {}: {:?}", from, error);
+                    self.exchanges.remove(index);
+                    return Ok(None);
+                }
+                // Answers right away, and again when the other side sends its message again
+                self.send(socket, &self.exchanges[index]).await;
+                Ok(None)
+            }
+            Message::Confirm {
+                nameplate,
+                side,
+                node_addr,
+                mac,
+            } if nameplate == self.code.nameplate && side != self.side => {
+                let Some(index) = self
+                    .exchanges
+                    .iter()
+                    .position(|exchange| exchange.address == from && exchange.key.is_some())
+                else {
+                    return Ok(None);
+                };
+                let key = self.exchanges[index].key.as_ref().expect("checked above");
+                if authenticator(key, side, &node_addr)
+                    .verify_slice(&mac)
+                    .is_err()
+                {
+                    self.failures += 1;
+                    if self.side == Side::Join || self.failures >= MAX_FAILURES {
+                        return Err(PairingError::WrongCode);
+                    }
+                    warn!("Pairing with {} failed, it used another code", from);
+                    self.exchanges.remove(index);
+                    return Ok(None);
+                }
+                self.send(socket, &self.exchanges[index]).await;
+                Ok(Some(node_addr))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the exchange the SPAKE2 `message` from `from` is for, starting one if needed.
+    fn exchange_for_pake(&mut self, from: SocketAddr, message: &[u8]) -> Option<usize> {
+        if self.side == Side::Join {
+            // Locked onto the first node answering
+            let exchange = self.exchanges.first()?;
+            return (exchange.key.is_none() || exchange.address == from).then_some(0);
+        }
+        match self
+            .exchanges
+            .iter()
+            .position(|exchange| exchange.address == from)
+        {
+            // The other side sent its message again, or started over
+            Some(index) if self.exchanges[index].their_message.as_deref() == Some(message) => {
+                Some(index)
+            }
+            Some(index) => {
+                self.exchanges[index] = Exchange::start(&self.code, from);
+                Some(index)
+            }
+            None => {
+                if self.exchanges.len() >= MAX_EXCHANGES {
+                    self.exchanges.remove(0);
+                }
+                self.exchanges.push(Exchange::start(&self.code, from));
+                Some(self.exchanges.len() - 1)
+            }
+        }
+    }
+
+    /// Sends the SPAKE2 message of this side in `exchange`, and its addresses once the key is
+    /// agreed on.
+    async fn send(&self, socket: &UdpSocket, exchange: &Exchange) {
+        let mut messages = vec![Message::Pake {
+            nameplate: self.code.nameplate,
+            message: exchange.pake_message.clone(),
+        }];
+        if let Some(key) = &exchange.key {
+            let mac = authenticator(key, self.side, &self.node_addr)
+                .finalize()
+                .into_bytes()
+                .to_vec();
+            messages.push(Message::Confirm {
+                nameplate: self.code.nameplate,
+                side: self.side,
+                node_addr: self.node_addr.clone(),
+                mac,
+            });
+        }
+        for message in messages {
+            let data = serde_json::to_vec(&message).expect("pairing messages are serializable");
+            if let Err(error) = socket.send_to(&data, exchange.address).await {
+                debug!(
+                    "Couldn't send a pairing message to {}: {:?}",
+                    exchange.address, error
+                );
+            }
+        }
+    }
+}
+
+/// Returns the MAC of the addresses of `side` under `key`, before it is finalized.
+fn authenticator(key: &[u8], side: Side, node_addr: &NodeAddr) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(IDENTITY);
+    mac.update(&serde_json::to_vec(&(side, node_addr)).expect("node addresses are serializable"));
+    mac
+}
@@ -0,0 +1,137 @@
+//! Module for the packet processing pipeline.
+//!
+//! The [Pipeline] runs every packet going through the packet router through an ordered list
+//! of [PacketProcessor] stages. Each stage returns a [Verdict],
+//! which can pass the packet to the next stage, drop it, modify it or redirect it to a peer.
+//!
+//! The order of stages is configured separately for each [Direction].
+//! A stage left out of the order of a direction doesn't see the packets going in that direction.
+
+use std::sync::Arc;
+
+use iroh_net::NodeId;
+
+use crate::daemon::{packet::Packet, DaemonError};
+
+/// The direction of packets handled by a stage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Packets received from peers.
+    Incoming,
+    /// Packets to be sent to peers.
+    Outgoing,
+}
+
+/// The decision of a [PacketProcessor] about a packet.
+#[derive(Debug, Clone)]
+pub enum Verdict {
+    /// Passes the packet unchanged to the next stage.
+    Pass,
+    /// Drops the packet.
+    Drop,
+    /// Replaces the data of the packet and passes it to the next stage.
+    Modify(Arc<[u8]>),
+    /// Sends the packet to the peer with the given [NodeId], skipping the remaining stages.
+    Redirect(NodeId),
+}
+
+/// A stage of the [Pipeline].
+pub trait PacketProcessor: Send + Sync {
+    /// Returns the name of the stage, used to configure the order of stages.
+    fn name(&self) -> &str;
+
+    /// Processes a single packet.
+    fn process(&mut self, packet: &Packet) -> Verdict;
+}
+
+/// The result of running a packet through the [Pipeline].
+#[derive(Debug)]
+pub enum Outcome {
+    /// The packet should be delivered as usual.
+    Deliver(Packet),
+    /// The packet was dropped.
+    Drop,
+    /// The packet should be sent only to the peer with the given [NodeId].
+    Redirect(NodeId, Arc<[u8]>),
+}
+
+/// An ordered list of [PacketProcessor] stages for each [Direction].
+#[derive(Default)]
+pub struct Pipeline {
+    /// All registered stages.
+    stages: Vec<Box<dyn PacketProcessor>>,
+    /// Indices of stages run on incoming packets, in order.
+    incoming_order: Vec<usize>,
+    /// Indices of stages run on outgoing packets, in order.
+    outgoing_order: Vec<usize>,
+}
+
+impl Pipeline {
+    /// Creates an empty [Pipeline].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a stage at the end of the pipeline for both directions.
+    pub fn add_stage(&mut self, stage: Box<dyn PacketProcessor>) {
+        let index = self.stages.len();
+        self.stages.push(stage);
+        self.incoming_order.push(index);
+        self.outgoing_order.push(index);
+    }
+
+    /// Sets the order of stages for a direction, by their names.
+    ///
+    /// Stages not listed in `names` are skipped for that direction.
+    pub fn set_order(&mut self, direction: Direction, names: &[String]) -> Result<(), DaemonError> {
+        let order = names
+            .iter()
+            .map(|name| {
+                self.stages
+                    .iter()
+                    .position(|stage| stage.name() == name)
+                    .ok_or_else(|| DaemonError::UnknownStage(name.clone()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match direction {
+            Direction::Incoming => self.incoming_order = order,
+            Direction::Outgoing => self.outgoing_order = order,
+        }
+        Ok(())
+    }
+
+    /// Runs a packet through the stages of its direction.
+    ///
+    /// A [Packet::Transit] runs through the incoming stages as received from its first peer,
+    /// then through the outgoing stages as [Packet::Redirected] to the next one.
+    pub fn process(&mut self, packet: Packet) -> Outcome {
+        let Packet::Transit(from, next_hop, data) = packet else {
+            return self.run(packet);
+        };
+        match self.run(Packet::Incoming(from, data)) {
+            Outcome::Deliver(packet) => {
+                self.run(Packet::Redirected(next_hop, packet.data().clone()))
+            }
+            outcome => outcome,
+        }
+    }
+
+    /// Runs a packet through the stages of its direction.
+    fn run(&mut self, mut packet: Packet) -> Outcome {
+        let order = match packet {
+            Packet::Incoming(..) | Packet::Transit(..) => &self.incoming_order,
+            Packet::Outgoing(_) | Packet::Redirected(..) => &self.outgoing_order,
+        };
+        for &index in order {
+            match self.stages[index].process(&packet) {
+                Verdict::Pass => {}
+                Verdict::Drop => return Outcome::Drop,
+                Verdict::Modify(data) => packet = packet.with_data(data),
+                Verdict::Redirect(node_id) => {
+                    return Outcome::Redirect(node_id, packet.data().clone())
+                }
+            }
+        }
+        Outcome::Deliver(packet)
+    }
+}
@@ -0,0 +1,215 @@
+//! Module for the local proxies into the overlay, used with the userspace network stack.
+//!
+//! Both proxies only open TCP connections to IP addresses, since overlay hosts have no names:
+//!
+//! - SOCKS5 without authentication, with the `CONNECT` command and IPv4 or IPv6 addresses.
+//! - HTTP with the `CONNECT` method, such as `CONNECT 10.0.0.5:443 HTTP/1.1`.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, warn};
+
+use crate::daemon::actors::{
+    netstack::{NetStackMessage, StackStream},
+    Addr,
+};
+
+const SOCKS_VERSION: u8 = 5;
+const SOCKS_NO_AUTHENTICATION: u8 = 0;
+const SOCKS_NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const SOCKS_COMMAND_CONNECT: u8 = 1;
+const SOCKS_ADDRESS_IPV4: u8 = 1;
+const SOCKS_ADDRESS_DOMAIN: u8 = 3;
+const SOCKS_ADDRESS_IPV6: u8 = 4;
+const SOCKS_SUCCEEDED: u8 = 0;
+const SOCKS_CONNECTION_REFUSED: u8 = 5;
+const SOCKS_COMMAND_NOT_SUPPORTED: u8 = 7;
+const SOCKS_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// The longest HTTP request head accepted.
+const MAX_HTTP_HEAD: usize = 8192;
+
+/// The kind of a proxy.
+#[derive(Debug, Clone, Copy)]
+pub enum ProxyKind {
+    Socks5,
+    Http,
+}
+
+/// Accepts proxy clients on `listener` and connects them to the overlay through `net_stack`.
+pub async fn serve(listener: TcpListener, kind: ProxyKind, net_stack: Addr<NetStackMessage>) {
+    loop {
+        let client = match listener.accept().await {
+            Ok((client, _)) => client,
+            Err(error) => {
+                warn!("Couldn't accept a proxy client. Reason: {:?}", error);
+                continue;
+            }
+        };
+        let net_stack = net_stack.clone();
+        tokio::spawn(async move {
+            let result = match kind {
+                ProxyKind::Socks5 => serve_socks5(client, &net_stack).await,
+                ProxyKind::Http => serve_http(client, &net_stack).await,
+            };
+            if let Err(error) = result {
+                debug!("Proxy client failed: {:?}", error);
+            }
+        });
+    }
+}
+
+/// Opens a connection to `remote` through the userspace stack.
+async fn connect(net_stack: &Addr<NetStackMessage>, remote: SocketAddr) -> io::Result<StackStream> {
+    net_stack
+        .ask(|reply| NetStackMessage::Connect(remote, reply))
+        .await
+        .map_err(|error| io::Error::new(io::ErrorKind::TimedOut, format!("{:?}", error)))?
+}
+
+/// Serves one SOCKS5 client.
+async fn serve_socks5(mut client: TcpStream, net_stack: &Addr<NetStackMessage>) -> io::Result<()> {
+    // Method selection
+    let mut header = [0u8; 2];
+    client.read_exact(&mut header).await?;
+    let mut methods = vec![0u8; header[1].into()];
+    client.read_exact(&mut methods).await?;
+    if header[0] != SOCKS_VERSION || !methods.contains(&SOCKS_NO_AUTHENTICATION) {
+        client
+            .write_all(&[SOCKS_VERSION, SOCKS_NO_ACCEPTABLE_METHODS])
+            .await?;
+        return Ok(());
+    }
+    client
+        .write_all(&[SOCKS_VERSION, SOCKS_NO_AUTHENTICATION])
+        .await?;
+
+    // Request
+    let mut request = [0u8; 4];
+    client.read_exact(&mut request).await?;
+    let address = match request[3] {
+        SOCKS_ADDRESS_IPV4 => {
+            let mut octets = [0u8; 4];
+            client.read_exact(&mut octets).await?;
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        SOCKS_ADDRESS_IPV6 => {
+            let mut octets = [0u8; 16];
+            client.read_exact(&mut octets).await?;
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        SOCKS_ADDRESS_DOMAIN => {
+            return socks5_reply(&mut client, SOCKS_ADDRESS_TYPE_NOT_SUPPORTED).await;
+        }
+        _ => return socks5_reply(&mut client, SOCKS_ADDRESS_TYPE_NOT_SUPPORTED).await,
+    };
+    let port = client.read_u16().await?;
+    if request[1] != SOCKS_COMMAND_CONNECT {
+        return socks5_reply(&mut client, SOCKS_COMMAND_NOT_SUPPORTED).await;
+    }
+
+    let remote = SocketAddr::new(address, port);
+    match connect(net_stack, remote).await {
+        Ok(stream) => {
+            socks5_reply(&mut client, SOCKS_SUCCEEDED).await?;
+            stream.pipe(client).await
+        }
+        Err(error) => {
+            debug!("Couldn't connect to {}. Reason: {:?}", remote, error);
+            socks5_reply(&mut client, SOCKS_CONNECTION_REFUSED).await
+        }
+    }
+}
+
+/// Sends a SOCKS5 reply with the given status and an unspecified bound address.
+async fn socks5_reply(client: &mut TcpStream, status: u8) -> io::Result<()> {
+    client
+        .write_all(&[
+            SOCKS_VERSION,
+            status,
+            0,
+            SOCKS_ADDRESS_IPV4,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])
+        .await
+}
+
+/// Serves one HTTP proxy client.
+async fn serve_http(client: TcpStream, net_stack: &Addr<NetStackMessage>) -> io::Result<()> {
+    let mut reader = BufReader::new(client);
+    let mut request_line = String::new();
+    let mut head_size = 0;
+    if !read_head_line(&mut reader, &mut request_line, &mut head_size).await? {
+        return http_reply(reader.get_mut(), "431 Request Header Fields Too Large").await;
+    }
+    // Skip the headers
+    loop {
+        let mut line = String::new();
+        if !read_head_line(&mut reader, &mut line, &mut head_size).await? {
+            return http_reply(reader.get_mut(), "431 Request Header Fields Too Large").await;
+        }
+        if line.is_empty() || line == "\r\n" || line == "\n" {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return http_reply(reader.get_mut(), "400 Bad Request").await;
+    };
+    if method != "CONNECT" {
+        return http_reply(reader.get_mut(), "405 Method Not Allowed").await;
+    }
+    let Ok(remote) = target.parse::<SocketAddr>() else {
+        return http_reply(reader.get_mut(), "400 Bad Request").await;
+    };
+
+    match connect(net_stack, remote).await {
+        Ok(stream) => {
+            http_reply(reader.get_mut(), "200 Connection Established").await?;
+            // Data sent by the client right after its request is already buffered
+            if !reader.buffer().is_empty() {
+                stream.send(reader.buffer()).await?;
+            }
+            stream.pipe(reader.into_inner()).await
+        }
+        Err(error) => {
+            debug!("Couldn't connect to {}. Reason: {:?}", remote, error);
+            http_reply(reader.get_mut(), "502 Bad Gateway").await
+        }
+    }
+}
+
+/// Reads a line of the request head into `line`, adding its size to `head_size`.
+///
+/// Returns false if the head gets longer than [MAX_HTTP_HEAD]. `line` is empty at the end of
+/// the stream.
+async fn read_head_line(
+    reader: &mut BufReader<TcpStream>,
+    line: &mut String,
+    head_size: &mut usize,
+) -> io::Result<bool> {
+    let remaining = MAX_HTTP_HEAD.saturating_sub(*head_size);
+    let size = reader.take(remaining as u64 + 1).read_line(line).await?;
+    *head_size += size;
+    Ok(*head_size <= MAX_HTTP_HEAD)
+}
+
+/// Sends an HTTP response with the given status and no body.
+async fn http_reply(client: &mut TcpStream, status: &str) -> io::Result<()> {
+    client
+        .write_all(format!("HTTP/1.1 {}\r\n\r\n", status).as_bytes())
+        .await
+}
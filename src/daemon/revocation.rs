@@ -0,0 +1,92 @@
+//! Module for the revocation of node keys.
+//!
+//! An admin node, one of the authorities of the replicated state, revokes the key of a node, for
+//! example when the machine holding it is stolen, by writing a
+//! [Record::Revocation](super::state::Record::Revocation) to the [NetworkState]. The revocations
+//! spread across the mesh with the rest of the state, and are persisted with it.
+//!
+//! Revocations can't be undone, and the entries signed by a revoked node are refused. The
+//! revocations of other nodes signed by a revoked admin are ignored, whenever they were written,
+//! since a stolen admin key can backdate them. Two admins revoking each other are both revoked.
+//!
+//! The keys that replaced a revoked key are revoked too, see [rotation](super::rotation).
+//!
+//! Nodes disconnect from the revoked nodes as soon as they learn about their revocation,
+//! and refuse to connect to them afterwards.
+
+use iroh_net::NodeId;
+use serde::Serialize;
+
+use crate::daemon::{
+    state::{NetworkState, Record},
+    DaemonError,
+};
+
+/// The revocation of the key of a node.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Revocation {
+    /// The revoked node.
+    pub node_id: NodeId,
+    /// When the key was revoked, in milliseconds since the Unix epoch.
+    pub revoked_at: u64,
+    /// The admin that revoked the key.
+    pub issuer: NodeId,
+}
+
+/// Revokes node keys, and follows their revocations in the replicated state.
+///
+/// Nothing is revoked without the state. Clones share the same state.
+#[derive(Clone)]
+pub struct Revocations {
+    state: Option<NetworkState>,
+}
+
+impl Revocations {
+    /// Creates a new [Revocations] following the revocations of `state`.
+    pub fn new(state: Option<NetworkState>) -> Self {
+        Self { state }
+    }
+
+    /// Revokes the key of the node with the given [NodeId]. This node must be an admin.
+    pub fn revoke(&self, node_id: NodeId) -> Result<(), DaemonError> {
+        let state = self.state.as_ref().ok_or(DaemonError::StateDisabled)?;
+        match state.set(Record::Revocation { node_id }) {
+            Err(DaemonError::Unauthorized(_)) => Err(DaemonError::NotAnAdmin),
+            result => result,
+        }
+    }
+
+    /// Checks whether the key of the node with the given [NodeId] is revoked.
+    pub fn is_revoked(&self, node_id: NodeId) -> bool {
+        self.state
+            .as_ref()
+            .is_some_and(|state| state.is_revoked(node_id))
+    }
+
+    /// Returns the revocations in effect.
+    pub fn list(&self) -> Vec<Revocation> {
+        let Some(state) = &self.state else {
+            return Vec::new();
+        };
+        state
+            .records()
+            .into_iter()
+            .filter_map(|info| match info.record {
+                Record::Revocation { node_id } => Some(Revocation {
+                    node_id,
+                    revoked_at: info.timestamp,
+                    issuer: info.signer,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Waits until the key of the node with the given [NodeId] is revoked.
+    pub async fn revoked(&self, node_id: NodeId) {
+        match &self.state {
+            Some(state) => state.revoked(node_id).await,
+            None => std::future::pending().await,
+        }
+    }
+}
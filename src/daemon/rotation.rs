@@ -0,0 +1,228 @@
+//! Module for the rotation of node keys.
+//!
+//! A node replaces its key by writing a [Record::Succession] to the [NetworkState], signed with
+//! its current key and countersigned by the new one. The successions spread across the mesh
+//! with the rest of the state, and are persisted with it.
+//!
+//! When a node learns about a succession, what was granted to the previous key moves to the new
+//! one: the invites it joined with and the pairing, see [Invites], and the membership, name and
+//! overlay addresses of the replicated state, as far as this node may write them. The members of
+//! the [AdmissionPolicy] and the tags of the firewall rules apply to the successors of the keys
+//! they name. The previous key is retired: new connections with it are refused.
+//!
+//! A key is replaced once, and a revoked key can't be replaced. The new key is written to
+//! [RotationConfig::key_path] before the succession is published, and the node keeps using its
+//! previous key until it is restarted with the new one.
+
+use std::{
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use iroh_net::{key::SecretKey, NodeId};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::warn;
+
+use crate::daemon::{
+    admission::AdmissionPolicy,
+    events::Event,
+    invite::Invites,
+    state::{NetworkState, Record},
+    DaemonError,
+};
+
+/// Settings of the key rotations.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RotationConfig {
+    /// The file the new key is written to. The key of this node can't be replaced when not set.
+    pub key_path: Option<PathBuf>,
+}
+
+/// The replacement of the key of a node by a new one.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Succession {
+    /// The previous key of the node.
+    pub predecessor: NodeId,
+    /// The new key of the node.
+    pub successor: NodeId,
+    /// When the key was replaced, in milliseconds since the Unix epoch.
+    pub rotated_at: u64,
+}
+
+/// Replaces the key of this node, and follows the successions of the replicated state.
+///
+/// No key is replaced without the state. Clones share the same state.
+#[derive(Clone)]
+pub struct Rotations {
+    config: Arc<RotationConfig>,
+    secret_key: SecretKey,
+    invites: Invites,
+    state: Option<NetworkState>,
+    /// Held while the key of this node is replaced, so it is replaced once.
+    rotating: Arc<Mutex<()>>,
+}
+
+impl Rotations {
+    /// Creates a new [Rotations] replacing the key `secret_key`, following the successions of
+    /// `state`.
+    ///
+    /// The grants of the replaced keys are moved in `invites` and `state`.
+    pub fn new(
+        config: RotationConfig,
+        secret_key: SecretKey,
+        invites: Invites,
+        state: Option<NetworkState>,
+    ) -> Self {
+        Self {
+            config: Arc::new(config),
+            secret_key,
+            invites,
+            state,
+            rotating: Default::default(),
+        }
+    }
+
+    /// Replaces the key of this node by a new one, written to [RotationConfig::key_path],
+    /// and returns the [NodeId] of the new key.
+    ///
+    /// The node keeps using its current key until it is restarted.
+    pub fn rotate(&self) -> Result<NodeId, DaemonError> {
+        let state = self.state.as_ref().ok_or(DaemonError::StateDisabled)?;
+        let path = self
+            .config
+            .key_path
+            .as_ref()
+            .ok_or(DaemonError::NoKeyFile)?;
+        let _rotating = self.rotating.lock().unwrap();
+        let node_id = self.secret_key.public();
+        if state.successor(node_id).is_some() {
+            return Err(DaemonError::KeyReplaced);
+        }
+        let successor = SecretKey::generate();
+        write_key(path, &successor)?;
+        if let Err(error) = state.set(Record::succession(node_id, &successor)) {
+            // The node keeps its current key when it is restarted
+            write_key(path, &self.secret_key)?;
+            return Err(error);
+        }
+        Ok(successor.public())
+    }
+
+    /// Returns the node that replaced the key of the node with the given [NodeId], if any.
+    pub fn successor(&self, node_id: NodeId) -> Option<NodeId> {
+        self.state.as_ref()?.successor(node_id)
+    }
+
+    /// Returns the node with the given [NodeId] followed by the keys it replaced,
+    /// from the latest to the first.
+    pub fn lineage(&self, node_id: NodeId) -> Vec<NodeId> {
+        match &self.state {
+            Some(state) => state.lineage(node_id),
+            None => vec![node_id],
+        }
+    }
+
+    /// Checks whether `admission` admits the node with the given [NodeId] or a key it replaced.
+    /// Replaced keys aren't admitted anymore.
+    pub fn admits(&self, admission: &AdmissionPolicy, node_id: NodeId) -> bool {
+        self.successor(node_id).is_none()
+            && self
+                .lineage(node_id)
+                .into_iter()
+                .any(|node_id| admission.admits(node_id))
+    }
+
+    /// Returns the successions in effect.
+    pub fn list(&self) -> Vec<Succession> {
+        let Some(state) = &self.state else {
+            return Vec::new();
+        };
+        state
+            .records()
+            .into_iter()
+            .filter_map(|info| match info.record {
+                Record::Succession {
+                    predecessor,
+                    successor,
+                    ..
+                } => Some(Succession {
+                    predecessor,
+                    successor,
+                    rotated_at: info.timestamp,
+                }),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Moves the grants of `predecessor` to `successor`.
+    fn transfer(&self, predecessor: NodeId, successor: NodeId) {
+        if let Err(error) = self.invites.transfer(predecessor, successor) {
+            warn!("Couldn't move the invites of {}: {:?}", predecessor, error);
+        }
+        if let Some(state) = &self.state {
+            if let Err(error) = state.transfer(predecessor, successor) {
+                warn!("Couldn't move the records of {}: {:?}", predecessor, error);
+            }
+        }
+    }
+
+    /// Moves the grants of every replaced key.
+    fn transfer_all(&self) {
+        for succession in self.list() {
+            self.transfer(succession.predecessor, succession.successor);
+        }
+    }
+
+    /// Moves the grants of the keys replaced by the known successions, in case this node is
+    /// restarted with the new key, then the ones of the keys replaced afterwards.
+    pub async fn run(self, mut event_receiver: broadcast::Receiver<Event>) {
+        self.transfer_all();
+        loop {
+            match event_receiver.recv().await {
+                Ok(Event::KeyRotated {
+                    predecessor,
+                    successor,
+                }) => self.transfer(predecessor, successor),
+                Ok(_) => {}
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Rotations missed {} events", missed);
+                    self.transfer_all();
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+}
+
+/// Reads the secret key written to `path`, [None] when the file doesn't exist.
+pub fn read_key(path: &Path) -> Result<Option<SecretKey>, DaemonError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let secret_key = std::fs::read_to_string(path)?
+        .trim()
+        .parse::<SecretKey>()
+        .map_err(anyhow::Error::from)?;
+    Ok(Some(secret_key))
+}
+
+/// Writes `secret_key` to `path`, only readable by the user running the daemon.
+///
+/// The file is replaced at once, so it is never left half written.
+pub fn write_key(path: &Path, secret_key: &SecretKey) -> std::io::Result<()> {
+    let temporary = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temporary)?;
+    file.write_all(secret_key.to_string().as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&temporary, path)
+}
@@ -0,0 +1,81 @@
+//! Module for coordinating the shutdown of the daemon.
+//!
+//! A [Shutdown] is triggered once, by a signal or by a library user, and every
+//! [ShutdownListener] is then woken up, so actors can clean up before they stop.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use quinn::VarInt;
+use tokio::sync::watch;
+
+/// The QUIC application error code used when closing connections because the node shuts down.
+///
+/// It lets peers tell a shutdown apart from a crash.
+pub const SHUTDOWN_ERROR_CODE: VarInt = VarInt::from_u32(1);
+
+/// The reason sent along with [SHUTDOWN_ERROR_CODE].
+pub const SHUTDOWN_REASON: &[u8] = b"shutting down";
+
+/// How long actors have to clean up after the shutdown is triggered.
+pub const SHUTDOWN_DEADLINE: Duration = Duration::from_secs(5);
+
+/// Triggers the shutdown of the daemon.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    /// Creates a new, not yet triggered, [Shutdown].
+    pub fn new() -> Self {
+        let (sender, _) = watch::channel(false);
+        Self {
+            sender: Arc::new(sender),
+        }
+    }
+
+    /// Triggers the shutdown. Triggering it again has no effect.
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// Returns whether the shutdown has been triggered.
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Creates a listener woken up when the shutdown is triggered.
+    pub fn listener(&self) -> ShutdownListener {
+        ShutdownListener {
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits for a [Shutdown] to be triggered.
+#[derive(Debug, Clone)]
+pub struct ShutdownListener {
+    receiver: watch::Receiver<bool>,
+}
+
+impl ShutdownListener {
+    /// Waits until the shutdown is triggered.
+    pub async fn wait(&mut self) {
+        // An error means the shutdown can't be triggered anymore, which is treated the same
+        let _ = self.receiver.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Runs `future` until it completes or the shutdown is triggered.
+    pub async fn run_until<F: Future<Output = ()>>(mut self, future: F) {
+        tokio::select! {
+            _ = future => {}
+            _ = self.wait() => {}
+        }
+    }
+}
@@ -0,0 +1,896 @@
+//! Module for the state of the network replicated between its nodes.
+//!
+//! The [NetworkState] holds what the nodes agree on without a coordinator: the members of the
+//! network, their names, the overlay addresses assigned to them, the routes they advertise, and
+//! the revoked node keys. Every node keeps a replica, persisted to a file, and sends the entries
+//! it learns to its peers.
+//!
+//! The state keeps the last [SignedEntry] each node wrote for each [RecordKey]. Of the entries of
+//! a key, the one with the later timestamp takes effect, then the one of the greater signer.
+//! Removals are kept as entries too. What takes effect is decided from all the entries kept when
+//! the state is read, so it doesn't depend on the order the entries were merged in, and the
+//! replicas converge once the peers are connected again after a partition.
+//!
+//! A node replaces its key by writing a [Record::Succession] signed with its current key and
+//! countersigned by the new one. A key is replaced once: of two successions of a key, the
+//! earlier one wins, and a succession naming a key that already replaced or was replaced by
+//! another is ignored. The records of the node then move to the new key, see
+//! [NetworkState::transfer]. The keys that replaced a revoked key are revoked too.
+//!
+//! Every entry is signed by the node that wrote it. The authorities of
+//! [StateConfig::authorities] may write any record but the successions, other nodes may only
+//! write the names, addresses, routes and successions of their own. The members claim the
+//! addresses the authorities didn't assign: of the claims of an address, the earliest one of a
+//! member takes effect, even if it was received before the membership of its signer.
+//! Revocations can't be removed. Entries signed
+//! by a revoked node are refused and its records ignored, but for its revocations of
+//! authorities, see [revocation](super::revocation).
+//!
+//! Entries are sent on the unidirectional streams of the
+//! [Advertisements](super::advertisements::Advertisements), starting with [STATE_STREAM]
+//! and followed by the entries as JSON. All entries are sent when a connection is established,
+//! then the ones changed since.
+
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use iroh_net::{
+    key::{SecretKey, Signature},
+    NodeId,
+};
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::{select, sync::watch};
+use tracing::{debug, info, warn};
+
+use crate::daemon::{
+    events::{Event, Events},
+    store::{unix_time, StoreFile},
+    DaemonError,
+};
+
+/// The first byte of the streams carrying entries, which JSON advertisements never start with.
+pub const STATE_STREAM: u8 = 0;
+/// The longest list of entries accepted from a peer.
+pub const MAX_STATE_SIZE: usize = 4 * 1024 * 1024;
+/// How far in the future entries may be, because of clock differences.
+const CLOCK_SKEW: Duration = Duration::from_secs(5 * 60);
+/// How long the changes are collected before they are sent to a peer.
+const SYNC_DELAY: Duration = Duration::from_secs(1);
+
+/// Settings of the replicated state.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StateConfig {
+    /// The file the state is persisted to. The state is only kept in memory when not set.
+    pub path: Option<PathBuf>,
+    /// The nodes allowed to write any record.
+    #[serde(default)]
+    pub authorities: HashSet<NodeId>,
+}
+
+/// A record of the state of the network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Record {
+    /// The node is a member of the network.
+    Member { node_id: NodeId },
+    /// The node is named `name`.
+    Name { node_id: NodeId, name: String },
+    /// The overlay address is assigned to the node.
+    Address { address: IpAddr, node_id: NodeId },
+    /// The node routes `prefix`.
+    Route { node_id: NodeId, prefix: IpNet },
+    /// The key of the node is revoked.
+    Revocation { node_id: NodeId },
+    /// The key of the node `predecessor` is replaced by the key of `successor`.
+    Succession {
+        predecessor: NodeId,
+        successor: NodeId,
+        /// The signature of the replacement by the successor.
+        countersignature: Vec<u8>,
+    },
+}
+
+/// What a [Record] is about. Each key has at most one record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordKey {
+    Member {
+        node_id: NodeId,
+    },
+    Name {
+        node_id: NodeId,
+    },
+    /// An address is assigned to one node at most.
+    Address {
+        address: IpAddr,
+    },
+    Route {
+        node_id: NodeId,
+        prefix: IpNet,
+    },
+    Revocation {
+        node_id: NodeId,
+    },
+    Succession {
+        predecessor: NodeId,
+    },
+}
+
+impl Record {
+    /// Returns the key of the record.
+    pub fn key(&self) -> RecordKey {
+        match self {
+            Record::Member { node_id } => RecordKey::Member { node_id: *node_id },
+            Record::Name { node_id, .. } => RecordKey::Name { node_id: *node_id },
+            Record::Address { address, .. } => RecordKey::Address { address: *address },
+            Record::Route { node_id, prefix } => RecordKey::Route {
+                node_id: *node_id,
+                prefix: *prefix,
+            },
+            Record::Revocation { node_id } => RecordKey::Revocation { node_id: *node_id },
+            Record::Succession { predecessor, .. } => RecordKey::Succession {
+                predecessor: *predecessor,
+            },
+        }
+    }
+
+    /// Returns the node the record is about.
+    pub fn node_id(&self) -> NodeId {
+        match self {
+            Record::Member { node_id }
+            | Record::Name { node_id, .. }
+            | Record::Address { node_id, .. }
+            | Record::Route { node_id, .. }
+            | Record::Revocation { node_id } => *node_id,
+            Record::Succession { predecessor, .. } => *predecessor,
+        }
+    }
+
+    /// Returns the replacement of the key of the node `predecessor` by `successor`,
+    /// countersigned by the successor.
+    pub fn succession(predecessor: NodeId, successor: &SecretKey) -> Self {
+        let countersignature =
+            successor.sign(&Self::succession_data(predecessor, successor.public()));
+        Record::Succession {
+            predecessor,
+            successor: successor.public(),
+            countersignature: countersignature.to_bytes().to_vec(),
+        }
+    }
+
+    /// Checks whether a succession replaces a key by another one, and is countersigned by
+    /// its successor. The other records are always valid.
+    fn is_countersigned(&self) -> bool {
+        let Record::Succession {
+            predecessor,
+            successor,
+            countersignature,
+        } = self
+        else {
+            return true;
+        };
+        let Ok(signature) = Signature::from_slice(countersignature) else {
+            return false;
+        };
+        predecessor != successor
+            && successor
+                .verify(&Self::succession_data(*predecessor, *successor), &signature)
+                .is_ok()
+    }
+
+    /// Returns the data covered by the countersignature of a succession.
+    fn succession_data(predecessor: NodeId, successor: NodeId) -> Vec<u8> {
+        serde_json::to_vec(&(predecessor, successor)).expect("successions are serializable")
+    }
+}
+
+/// A write to the state, signed by the node that made it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedEntry {
+    pub key: RecordKey,
+    /// The written record, [None] when the record of the key is removed.
+    pub record: Option<Record>,
+    /// When the entry was written, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub signer: NodeId,
+    /// The signature of the other fields by the signer.
+    pub signature: Vec<u8>,
+}
+
+impl SignedEntry {
+    /// Signs the write of `record` to `key`, or its removal when `record` is [None].
+    pub fn sign(
+        secret_key: &SecretKey,
+        key: RecordKey,
+        record: Option<Record>,
+        timestamp: u64,
+    ) -> Self {
+        let signer = secret_key.public();
+        let signature = secret_key.sign(&Self::signed_data(&key, &record, timestamp, &signer));
+        Self {
+            key,
+            record,
+            timestamp,
+            signer,
+            signature: signature.to_bytes().to_vec(),
+        }
+    }
+
+    /// Checks whether the entry is signed by its signer, and its record matches its key.
+    pub fn verify(&self) -> bool {
+        if self
+            .record
+            .as_ref()
+            .is_some_and(|record| record.key() != self.key || !record.is_countersigned())
+        {
+            return false;
+        }
+        let Ok(signature) = Signature::from_slice(&self.signature) else {
+            return false;
+        };
+        let data = Self::signed_data(&self.key, &self.record, self.timestamp, &self.signer);
+        self.signer.verify(&data, &signature).is_ok()
+    }
+
+    /// Checks whether the signer may write the entry, given the authorities of the network.
+    pub fn is_authorized(&self, authorities: &HashSet<NodeId>) -> bool {
+        match self.key {
+            // Only the node itself replaces its key, and the replacement can't be undone
+            RecordKey::Succession { predecessor } => {
+                return predecessor == self.signer && self.record.is_some();
+            }
+            // Nor can a revocation, even by the authorities
+            RecordKey::Revocation { .. } if self.record.is_none() => return false,
+            _ => {}
+        }
+        if authorities.contains(&self.signer) {
+            return true;
+        }
+        match self.key {
+            RecordKey::Name { node_id } | RecordKey::Route { node_id, .. } => {
+                node_id == self.signer
+            }
+            // A node may claim an address for itself, see [Entries::current], but only the
+            // authorities assign addresses to other nodes or release them
+            RecordKey::Address { .. } => self
+                .record
+                .as_ref()
+                .is_some_and(|record| record.node_id() == self.signer),
+            RecordKey::Member { .. }
+            | RecordKey::Revocation { .. }
+            | RecordKey::Succession { .. } => false,
+        }
+    }
+
+    /// Orders the entries of a key: the greatest one wins, but the least one of successions.
+    pub fn precedence(&self, other: &SignedEntry) -> Ordering {
+        (self.timestamp, self.signer, &self.signature).cmp(&(
+            other.timestamp,
+            other.signer,
+            &other.signature,
+        ))
+    }
+
+    /// Returns the data covered by the signature.
+    fn signed_data(
+        key: &RecordKey,
+        record: &Option<Record>,
+        timestamp: u64,
+        signer: &NodeId,
+    ) -> Vec<u8> {
+        serde_json::to_vec(&(key, record, timestamp, signer)).expect("entries are serializable")
+    }
+}
+
+/// Information about a record of the state.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordInfo {
+    pub record: Record,
+    pub signer: NodeId,
+    /// When the record was written, in milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// The entries of the state, in the order they were merged.
+#[derive(Default)]
+struct Entries {
+    /// The last entry of each signer for each key, with the sequence number it was merged with.
+    entries: HashMap<(RecordKey, NodeId), (SignedEntry, u64)>,
+    sequence: u64,
+}
+
+impl Entries {
+    /// Merges `entry`, returning whether it won over the entry of its signer for its key.
+    fn merge(&mut self, entry: SignedEntry) -> bool {
+        let slot = (entry.key, entry.signer);
+        let wins = match self.entries.get(&slot) {
+            None => true,
+            // A key is replaced once, by the earliest succession
+            Some((current, _)) if matches!(entry.key, RecordKey::Succession { .. }) => {
+                entry.precedence(current) == Ordering::Less
+            }
+            Some((current, _)) => entry.precedence(current) == Ordering::Greater,
+        };
+        if wins {
+            self.sequence += 1;
+            self.entries.insert(slot, (entry, self.sequence));
+        }
+        wins
+    }
+
+    /// Returns the keys replaced, with the keys that replaced them.
+    ///
+    /// The successions apply from the earliest one, ignoring the ones naming a key that already
+    /// replaced or was replaced by another one.
+    fn successions(&self) -> HashMap<NodeId, NodeId> {
+        let mut entries: Vec<&SignedEntry> = self
+            .entries
+            .values()
+            .map(|(entry, _)| entry)
+            .filter(|entry| matches!(entry.key, RecordKey::Succession { .. }))
+            .collect();
+        entries.sort_by(|first, second| first.precedence(second));
+        let mut successions = HashMap::new();
+        let mut successors = HashSet::new();
+        for entry in entries {
+            let Some(Record::Succession {
+                predecessor,
+                successor,
+                ..
+            }) = entry.record
+            else {
+                continue;
+            };
+            if !successors.contains(&successor) && !successions.contains_key(&successor) {
+                successions.insert(predecessor, successor);
+                successors.insert(successor);
+            }
+        }
+        successions
+    }
+
+    /// Returns the successions of the keys that aren't revoked.
+    fn replaced(&self, authorities: &HashSet<NodeId>) -> HashMap<NodeId, NodeId> {
+        let revoked = self.revoked(authorities);
+        self.successions()
+            .into_iter()
+            .filter(|(predecessor, _)| !revoked.contains_key(predecessor))
+            .collect()
+    }
+
+    /// Returns the nodes whose keys are revoked, with the authorities that revoked them.
+    ///
+    /// An authority revoked by another one is revoked whoever signed the revocation, so two
+    /// authorities revoking each other are both revoked, whatever the timestamps they chose. The
+    /// revocations of the other nodes signed by a revoked authority are ignored. The keys that
+    /// replaced a revoked key are revoked too. Of the authorities that revoked a node, the least
+    /// one is returned.
+    fn revoked(&self, authorities: &HashSet<NodeId>) -> HashMap<NodeId, NodeId> {
+        let revocations: Vec<(NodeId, NodeId)> = self
+            .entries
+            .values()
+            .filter_map(|(entry, _)| match entry.record {
+                Some(Record::Revocation { node_id }) => Some((node_id, entry.signer)),
+                _ => None,
+            })
+            .collect();
+        let (of_authorities, of_nodes): (Vec<_>, Vec<_>) = revocations
+            .into_iter()
+            .partition(|(node_id, _)| authorities.contains(node_id));
+        let mut revoked: HashMap<NodeId, NodeId> = HashMap::new();
+        let revoke = |revoked: &mut HashMap<NodeId, NodeId>, node_id, issuer| {
+            let current = revoked.entry(node_id).or_insert(issuer);
+            *current = (*current).min(issuer);
+        };
+        for (node_id, issuer) in of_authorities {
+            revoke(&mut revoked, node_id, issuer);
+        }
+        let revoked_authorities: HashSet<NodeId> = revoked.keys().copied().collect();
+        for (node_id, issuer) in of_nodes {
+            if !revoked_authorities.contains(&issuer) {
+                revoke(&mut revoked, node_id, issuer);
+            }
+        }
+        let successions = self.successions();
+        let mut pending: Vec<(NodeId, NodeId)> = revoked.clone().into_iter().collect();
+        while let Some((node_id, issuer)) = pending.pop() {
+            if let Some(successor) = successions.get(&node_id) {
+                if !revoked.contains_key(successor) {
+                    revoked.insert(*successor, issuer);
+                    pending.push((*successor, issuer));
+                }
+            }
+        }
+        revoked
+    }
+
+    /// Returns the entries in effect, without the removals and the entries of revoked nodes.
+    ///
+    /// Of the entries of a key, the greatest one wins. The authorities assign any address, the
+    /// members claim the addresses the authorities didn't assign since they last released them:
+    /// of their claims, the earliest one wins. Since the result only depends on the entries
+    /// merged, replicas with the same entries agree, whatever the order they merged them in.
+    fn current(&self, authorities: &HashSet<NodeId>) -> Vec<&SignedEntry> {
+        let revoked = self.revoked(authorities);
+        let successions = self.successions();
+        let mut keys: HashMap<RecordKey, Vec<&SignedEntry>> = HashMap::new();
+        for (entry, _) in self.entries.values() {
+            keys.entry(entry.key).or_default().push(entry);
+        }
+        let mut current = Vec::new();
+        let mut addresses = Vec::new();
+        for (key, entries) in keys {
+            match key {
+                // The revocations of authorities count whoever signed them
+                RecordKey::Revocation { node_id } => current.extend(
+                    entries
+                        .into_iter()
+                        .find(|entry| revoked.get(&node_id) == Some(&entry.signer)),
+                ),
+                RecordKey::Succession { predecessor } if !revoked.contains_key(&predecessor) => {
+                    current.extend(entries.into_iter().find(|entry| {
+                        matches!(
+                            entry.record,
+                            Some(Record::Succession { successor, .. })
+                                if successions.get(&predecessor) == Some(&successor)
+                        )
+                    }))
+                }
+                RecordKey::Succession { .. } => {}
+                RecordKey::Address { .. } => addresses.push(entries),
+                _ => current.extend(
+                    entries
+                        .into_iter()
+                        .filter(|entry| !revoked.contains_key(&entry.signer))
+                        .max_by(|first, second| first.precedence(second))
+                        .filter(|entry| entry.record.is_some()),
+                ),
+            }
+        }
+        let members: HashSet<NodeId> = current
+            .iter()
+            .filter_map(|entry| match entry.record {
+                Some(Record::Member { node_id }) => Some(node_id),
+                _ => None,
+            })
+            .collect();
+        for entries in addresses {
+            let (assigned, claimed): (Vec<&SignedEntry>, Vec<&SignedEntry>) = entries
+                .into_iter()
+                .filter(|entry| !revoked.contains_key(&entry.signer))
+                .partition(|entry| authorities.contains(&entry.signer));
+            let assigned = assigned
+                .into_iter()
+                .max_by(|first, second| first.precedence(second));
+            if assigned.is_some_and(|entry| entry.record.is_some()) {
+                current.extend(assigned);
+                continue;
+            }
+            let released = assigned.map(|entry| entry.timestamp);
+            current.extend(
+                claimed
+                    .into_iter()
+                    .filter(|entry| {
+                        members.contains(&entry.signer)
+                            && released.is_none_or(|released| entry.timestamp > released)
+                    })
+                    .min_by(|first, second| first.precedence(second)),
+            );
+        }
+        current
+    }
+
+    /// Checks whether `entry`, written by this node, takes effect given the current entries.
+    ///
+    /// The members only claim the addresses that aren't assigned to another node. A key that
+    /// isn't revoked is replaced by a key that didn't replace and wasn't replaced by another one.
+    fn allows(&self, entry: &SignedEntry, authorities: &HashSet<NodeId>) -> bool {
+        match entry.record {
+            Some(Record::Succession {
+                predecessor,
+                successor,
+                ..
+            }) => {
+                let known = self.successions().into_iter().any(|(other, known)| {
+                    other != predecessor && (known == successor || other == successor)
+                });
+                !known && !self.revoked(authorities).contains_key(&predecessor)
+            }
+            Some(Record::Address { address, .. }) if !authorities.contains(&entry.signer) => {
+                let current = self.current(authorities);
+                let is_member = current.iter().any(|current| {
+                    current.record
+                        == Some(Record::Member {
+                            node_id: entry.signer,
+                        })
+                });
+                let owner = current.iter().find_map(|current| match current.record {
+                    Some(Record::Address {
+                        address: assigned,
+                        node_id,
+                    }) if assigned == address => Some(node_id),
+                    _ => None,
+                });
+                is_member && owner.is_none_or(|owner| owner == entry.signer)
+            }
+            _ => true,
+        }
+    }
+}
+
+/// The replica of the state of the network kept by this node.
+///
+/// Clones share the same state.
+#[derive(Clone)]
+pub struct NetworkState {
+    config: Arc<StateConfig>,
+    file: Arc<StoreFile>,
+    secret_key: SecretKey,
+    entries: Arc<Mutex<Entries>>,
+    /// The sequence number of the last merged entry, followed by the peers to send the changes.
+    sequence: Arc<watch::Sender<u64>>,
+    events: Events,
+}
+
+impl NetworkState {
+    /// Creates a new [NetworkState] writing entries signed with `secret_key`,
+    /// and loads the persisted entries from [StateConfig::path].
+    ///
+    /// Entries merged are reported to `events`.
+    pub fn new(
+        config: StateConfig,
+        secret_key: SecretKey,
+        events: Events,
+    ) -> Result<Self, DaemonError> {
+        let mut entries = Entries::default();
+        let file = StoreFile::new(config.path.clone());
+        if let Some(data) = file.read()? {
+            let persisted: Vec<SignedEntry> =
+                serde_json::from_slice(&data).map_err(anyhow::Error::from)?;
+            for entry in persisted {
+                if entry.verify() && entry.is_authorized(&config.authorities) {
+                    entries.merge(entry);
+                } else {
+                    warn!("Ignoring an invalid persisted entry: {:?}", entry.key);
+                }
+            }
+            info!("Loaded {} state entries", entries.entries.len());
+        }
+        let (sequence, _) = watch::channel(entries.sequence);
+        Ok(Self {
+            config: Arc::new(config),
+            file: Arc::new(file),
+            secret_key,
+            entries: Arc::new(Mutex::new(entries)),
+            sequence: Arc::new(sequence),
+            events,
+        })
+    }
+
+    /// Writes `record`, replacing the record of its key.
+    pub fn set(&self, record: Record) -> Result<(), DaemonError> {
+        self.write(record.key(), Some(record))
+    }
+
+    /// Removes the record of `key`.
+    pub fn remove(&self, key: RecordKey) -> Result<(), DaemonError> {
+        self.write(key, None)
+    }
+
+    /// Signs and merges a write to `key`.
+    fn write(&self, key: RecordKey, record: Option<Record>) -> Result<(), DaemonError> {
+        // Later than the current entry even if the clock of its signer is ahead
+        let timestamp = self
+            .entries
+            .lock()
+            .unwrap()
+            .entries
+            .values()
+            .filter(|(current, _)| current.key == key)
+            .map(|(current, _)| current.timestamp + 1)
+            .fold(unix_time().as_millis() as u64, u64::max);
+        let entry = SignedEntry::sign(&self.secret_key, key, record, timestamp);
+        let allowed = entry.is_authorized(&self.config.authorities)
+            && self
+                .entries
+                .lock()
+                .unwrap()
+                .allows(&entry, &self.config.authorities);
+        if !allowed {
+            return Err(DaemonError::Unauthorized(key));
+        }
+        self.merge(None, vec![entry])
+    }
+
+    /// Merges the valid entries of `entries`, received from the peer with the given [NodeId],
+    /// or written by this node when [None].
+    ///
+    /// Every valid entry is kept, even if it doesn't take effect yet, see [Entries::current].
+    fn merge(&self, from: Option<NodeId>, entries: Vec<SignedEntry>) -> Result<(), DaemonError> {
+        let authorities = &self.config.authorities;
+        let latest = unix_time().as_millis() as u64 + CLOCK_SKEW.as_millis() as u64;
+        let mut merged = 0;
+        let mut state = self.entries.lock().unwrap();
+        let revoked_before = state.revoked(authorities);
+        let replaced_before = state.replaced(authorities);
+        let mut revoked_now = revoked_before.clone();
+        for entry in entries {
+            // The revocations of authorities count whoever signed them, and the successions of
+            // a revoked key revoke the keys that replaced it
+            let kept = match entry.record {
+                Some(Record::Revocation { node_id }) => authorities.contains(&node_id),
+                Some(Record::Succession { .. }) => true,
+                _ => false,
+            };
+            if entry.timestamp > latest
+                || !entry.verify()
+                || !entry.is_authorized(authorities)
+                || (revoked_now.contains_key(&entry.signer) && !kept)
+            {
+                debug!("Ignoring an invalid entry from {:?}: {:?}", from, entry.key);
+                continue;
+            }
+            let revokes = matches!(
+                entry.key,
+                RecordKey::Revocation { .. } | RecordKey::Succession { .. }
+            );
+            if state.merge(entry) {
+                merged += 1;
+                if revokes {
+                    revoked_now = state.revoked(authorities);
+                }
+            }
+        }
+        if merged == 0 {
+            return Ok(());
+        }
+        let rotated: Vec<(NodeId, NodeId)> = state
+            .replaced(authorities)
+            .into_iter()
+            .filter(|(predecessor, _)| !replaced_before.contains_key(predecessor))
+            .collect();
+        let newly_revoked: Vec<(NodeId, NodeId)> = revoked_now
+            .into_iter()
+            .filter(|(node_id, _)| !revoked_before.contains_key(node_id))
+            .collect();
+        let sequence = state.sequence;
+        let data = self.file.path().map(|_| {
+            let entries: Vec<&SignedEntry> =
+                state.entries.values().map(|(entry, _)| entry).collect();
+            serde_json::to_vec(&entries)
+        });
+        drop(state);
+        self.sequence.send_replace(sequence);
+        self.events.emit(Event::StateChanged {
+            node_id: from,
+            entries: merged,
+        });
+        for (predecessor, successor) in rotated {
+            info!("The key of {} was replaced by {}", predecessor, successor);
+            self.events.emit(Event::KeyRotated {
+                predecessor,
+                successor,
+            });
+        }
+        for (node_id, issuer) in newly_revoked {
+            warn!("The key of {} was revoked by {}", node_id, issuer);
+            self.events.emit(Event::Revoked { node_id, issuer });
+        }
+        if let Some(data) = data {
+            let data = data.map_err(anyhow::Error::from)?;
+            self.file.write(sequence, &data)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the records in effect, without the ones signed by revoked nodes.
+    pub fn records(&self) -> Vec<RecordInfo> {
+        let state = self.entries.lock().unwrap();
+        let mut records: Vec<RecordInfo> = state
+            .current(&self.config.authorities)
+            .into_iter()
+            .filter_map(|entry| {
+                Some(RecordInfo {
+                    record: entry.record.clone()?,
+                    signer: entry.signer,
+                    timestamp: entry.timestamp,
+                })
+            })
+            .collect();
+        records.sort_by_key(|info| info.timestamp);
+        records
+    }
+
+    /// Moves the membership, name and addresses of the node `predecessor` to the node
+    /// `successor` that replaced its key, as far as this node may write them.
+    ///
+    /// The records this node may not write are left to the authorities and the successor.
+    pub fn transfer(&self, predecessor: NodeId, successor: NodeId) -> Result<(), DaemonError> {
+        let current: Vec<Record> = self.records().into_iter().map(|info| info.record).collect();
+        for record in &current {
+            let moved = match record.clone() {
+                Record::Member { node_id } if node_id == predecessor => {
+                    Record::Member { node_id: successor }
+                }
+                Record::Name { node_id, name } if node_id == predecessor => Record::Name {
+                    node_id: successor,
+                    name,
+                },
+                Record::Address { address, node_id } if node_id == predecessor => Record::Address {
+                    address,
+                    node_id: successor,
+                },
+                _ => continue,
+            };
+            if !current.contains(&moved) {
+                match self.set(moved.clone()) {
+                    Err(DaemonError::Unauthorized(_)) => continue,
+                    result => result?,
+                }
+            }
+            if moved.key() != record.key() {
+                match self.remove(record.key()) {
+                    Err(DaemonError::Unauthorized(_)) => {}
+                    result => result?,
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the node the overlay address is assigned to, if any.
+    pub fn address_owner(&self, address: IpAddr) -> Option<NodeId> {
+        self.records()
+            .into_iter()
+            .find_map(|info| match info.record {
+                Record::Address {
+                    address: assigned,
+                    node_id,
+                } if assigned == address => Some(node_id),
+                _ => None,
+            })
+    }
+
+    /// Checks whether the node with the given [NodeId] is one of the authorities.
+    pub fn is_authority(&self, node_id: NodeId) -> bool {
+        self.config.authorities.contains(&node_id)
+    }
+
+    /// Checks whether the node with the given [NodeId] is a member of the network,
+    /// and its key isn't revoked.
+    pub fn is_member(&self, node_id: NodeId) -> bool {
+        !self.is_revoked(node_id)
+            && self
+                .records()
+                .iter()
+                .any(|info| info.record == Record::Member { node_id })
+    }
+
+    /// Checks whether the key of the node with the given [NodeId], or a key it replaced,
+    /// is revoked.
+    pub fn is_revoked(&self, node_id: NodeId) -> bool {
+        self.entries
+            .lock()
+            .unwrap()
+            .revoked(&self.config.authorities)
+            .contains_key(&node_id)
+    }
+
+    /// Returns the node that replaced the key of the node with the given [NodeId], if any.
+    /// Revoked keys aren't replaced.
+    pub fn successor(&self, node_id: NodeId) -> Option<NodeId> {
+        let state = self.entries.lock().unwrap();
+        state
+            .replaced(&self.config.authorities)
+            .get(&node_id)
+            .copied()
+    }
+
+    /// Returns the node with the given [NodeId] followed by the keys it replaced,
+    /// from the latest to the first.
+    pub fn lineage(&self, node_id: NodeId) -> Vec<NodeId> {
+        let predecessors: HashMap<NodeId, NodeId> = self
+            .entries
+            .lock()
+            .unwrap()
+            .successions()
+            .into_iter()
+            .map(|(predecessor, successor)| (successor, predecessor))
+            .collect();
+        let mut lineage = vec![node_id];
+        while let Some(predecessor) = predecessors.get(lineage.last().unwrap()) {
+            if lineage.contains(predecessor) {
+                break;
+            }
+            lineage.push(*predecessor);
+        }
+        lineage
+    }
+
+    /// Waits until the key of the node with the given [NodeId] is revoked.
+    pub async fn revoked(&self, node_id: NodeId) {
+        let mut sequence = self.sequence.subscribe();
+        while !self.is_revoked(node_id) {
+            if sequence.changed().await.is_err() {
+                std::future::pending::<()>().await;
+            }
+        }
+    }
+
+    /// Returns the entries merged after the sequence number `since`,
+    /// with the sequence number of the last one.
+    fn entries_since(&self, since: u64) -> (Vec<SignedEntry>, u64) {
+        let state = self.entries.lock().unwrap();
+        let mut entries: Vec<&(SignedEntry, u64)> = state
+            .entries
+            .values()
+            .filter(|(_, sequence)| *sequence > since)
+            .collect();
+        entries.sort_by_key(|(_, sequence)| *sequence);
+        let entries = entries
+            .into_iter()
+            .map(|(entry, _)| entry.clone())
+            .collect();
+        (entries, state.sequence)
+    }
+
+    /// Sends the entries to the peer on `connection` until the connection is closed.
+    pub fn attach(&self, node_id: NodeId, connection: Connection) {
+        tokio::spawn(self.clone().sync(node_id, connection));
+    }
+
+    /// Merges the entries received from the peer with the given [NodeId] as JSON.
+    pub fn receive(&self, node_id: NodeId, data: &[u8]) {
+        match serde_json::from_slice::<Vec<SignedEntry>>(data) {
+            Ok(entries) => {
+                if let Err(error) = self.merge(Some(node_id), entries) {
+                    warn!("Couldn't persist the state. Reason: {:?}", error);
+                }
+            }
+            Err(error) => debug!("Invalid state entries from {}: {:?}", node_id, error),
+        }
+    }
+
+    /// Sends all entries to the peer, then the changed ones, until the connection is closed.
+    async fn sync(self, node_id: NodeId, connection: Connection) {
+        let mut sequence = self.sequence.subscribe();
+        let mut sent = 0;
+        loop {
+            let (entries, latest) = self.entries_since(sent);
+            if !entries.is_empty() {
+                if let Err(error) = send(&connection, &entries).await {
+                    debug!("Couldn't send the state to {}: {:?}", node_id, error);
+                    return;
+                }
+            }
+            sent = latest;
+            select! {
+                changed = sequence.changed() => if changed.is_err() {
+                    return;
+                },
+                _ = connection.closed() => return,
+            }
+            // Sends the changes made meanwhile together
+            tokio::time::sleep(SYNC_DELAY).await;
+        }
+    }
+}
+
+/// Sends `entries` on a new stream of `connection`.
+async fn send(connection: &Connection, entries: &[SignedEntry]) -> anyhow::Result<()> {
+    let mut data = vec![STATE_STREAM];
+    serde_json::to_writer(&mut data, entries)?;
+    let mut send = connection.open_uni().await?;
+    send.write_all(&data).await?;
+    send.finish().await?;
+    Ok(())
+}
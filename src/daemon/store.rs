@@ -0,0 +1,64 @@
+//! Module for keeping what the daemon learns across restarts.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// A file replaced at once with the latest version of its data, so it is never left half written.
+///
+/// The data is serialized under the lock of its owner, then written once the lock is released.
+/// Versions are numbered, so a version written late doesn't replace a newer one.
+#[derive(Debug)]
+pub struct StoreFile {
+    path: Option<PathBuf>,
+    /// The version of the data in the file.
+    written: Mutex<u64>,
+}
+
+impl StoreFile {
+    /// Creates a new [StoreFile] at `path`. Nothing is written when it is [None].
+    pub fn new(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            written: Mutex::new(0),
+        }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Reads the file, returning [None] when it doesn't exist.
+    pub fn read(&self) -> io::Result<Option<Vec<u8>>> {
+        match &self.path {
+            Some(path) if path.exists() => std::fs::read(path).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    /// Writes `data`, the version `version` of the data, unless a later version was written.
+    pub fn write(&self, version: u64, data: &[u8]) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let mut written = self.written.lock().unwrap();
+        if version <= *written {
+            return Ok(());
+        }
+        let temporary = path.with_extension("tmp");
+        std::fs::write(&temporary, data)?;
+        std::fs::rename(&temporary, path)?;
+        *written = version;
+        Ok(())
+    }
+}
+
+/// Returns the time elapsed since the Unix epoch.
+pub fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
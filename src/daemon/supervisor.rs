@@ -0,0 +1,269 @@
+//! Module for supervising the actors of the daemon.
+//!
+//! Every actor runs under a name and a [RestartPolicy]. When an actor stops or panics before the
+//! shutdown, the [Supervisor] reports why with [Event::ActorExited], then either starts it again
+//! after a growing backoff, or stops the daemon with [DaemonError::ActorDied].
+//!
+//! Actors keep their [Addr](super::actors::Addr)s across restarts when they are started again on
+//! the same mailbox, see [MailboxKeeper](super::actors::mailbox::MailboxKeeper).
+
+use std::{
+    any::Any,
+    fmt::Display,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::{
+    select,
+    task::{AbortHandle, JoinError, JoinSet},
+};
+use tracing::{info, warn};
+
+use crate::daemon::{
+    events::{Event, Events},
+    shutdown::Shutdown,
+    DaemonError,
+};
+
+/// How long an actor must run for its backoff to start over.
+const STABLE_AFTER: Duration = Duration::from_secs(60);
+
+/// What happens to an actor that stops before the shutdown.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Starts the actor again after `initial_backoff`, doubled after each restart up to
+    /// `max_backoff`. After `max_restarts` restarts in a row, the daemon is stopped.
+    Restart {
+        initial_backoff: Duration,
+        max_backoff: Duration,
+        max_restarts: u32,
+    },
+    /// Stops the daemon.
+    Escalate,
+}
+
+impl RestartPolicy {
+    /// Restarts after 1 second at first and at most 1 minute, up to 10 times in a row.
+    pub const fn restart() -> Self {
+        Self::Restart {
+            initial_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_restarts: 10,
+        }
+    }
+}
+
+/// Why an actor stopped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum ExitReason {
+    /// The actor returned.
+    Returned,
+    /// The actor panicked with `message`.
+    Panicked { message: String },
+    /// The actor was cancelled.
+    Cancelled,
+}
+
+impl ExitReason {
+    /// Returns why the task that failed with `error` stopped.
+    pub fn from_join_error(error: JoinError) -> Self {
+        match error.try_into_panic() {
+            Ok(panic) => Self::Panicked {
+                message: panic_message(panic),
+            },
+            Err(_) => Self::Cancelled,
+        }
+    }
+}
+
+impl Display for ExitReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Returned => write!(f, "returned"),
+            Self::Panicked { message } => write!(f, "panicked: {}", message),
+            Self::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Returns the message of a panic payload.
+fn panic_message(panic: Box<dyn Any + Send>) -> String {
+    match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast::<&'static str>() {
+            Ok(message) => message.to_string(),
+            Err(_) => "unknown panic".to_string(),
+        },
+    }
+}
+
+/// Aborts a task when dropped, so an actor doesn't outlive its supervision.
+struct AbortOnDrop(AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Runs the actors, restarting them according to their [RestartPolicy].
+pub struct Supervisor {
+    tasks: JoinSet<Result<(), DaemonError>>,
+    shutdown: Shutdown,
+    events: Events,
+    /// Set once [Supervisor::wait] returned, so actors stopping from then on aren't restarted.
+    stopping: Arc<AtomicBool>,
+}
+
+impl Supervisor {
+    /// Creates a [Supervisor] without actors.
+    ///
+    /// Actors are stopped by `shutdown`, and their exits are reported to `events`.
+    pub fn new(shutdown: Shutdown, events: Events) -> Self {
+        Self {
+            tasks: JoinSet::new(),
+            shutdown,
+            events,
+            stopping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Runs the actor named `name`, started by `start` and again by `start` on each restart.
+    pub fn spawn<F, Fut>(&mut self, name: &str, policy: RestartPolicy, start: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let supervised = Self::supervise(
+            name.to_string(),
+            policy,
+            start,
+            self.shutdown.clone(),
+            self.events.clone(),
+            self.stopping.clone(),
+        );
+        self.tasks.spawn(supervised);
+    }
+
+    /// Runs the actor named `name`, which can't be started again, with [RestartPolicy::Escalate].
+    pub fn spawn_once<Fut>(&mut self, name: &str, future: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut future = Some(future);
+        self.spawn(name, RestartPolicy::Escalate, move || {
+            future
+                .take()
+                .expect("escalated actors are not started again")
+        });
+    }
+
+    /// Runs an actor until the shutdown, or until it stops and its policy gives up.
+    async fn supervise<F, Fut>(
+        name: String,
+        policy: RestartPolicy,
+        mut start: F,
+        shutdown: Shutdown,
+        events: Events,
+        stopping: Arc<AtomicBool>,
+    ) -> Result<(), DaemonError>
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let mut restarts = 0;
+        loop {
+            let started = Instant::now();
+            let task = tokio::spawn(start());
+            let _abort = AbortOnDrop(task.abort_handle());
+            let reason = match task.await {
+                Ok(()) => ExitReason::Returned,
+                Err(error) => ExitReason::from_join_error(error),
+            };
+            if shutdown.is_triggered() || stopping.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            if started.elapsed() >= STABLE_AFTER {
+                restarts = 0;
+            }
+            let backoff = match policy {
+                RestartPolicy::Restart {
+                    initial_backoff,
+                    max_backoff,
+                    max_restarts,
+                } if restarts < max_restarts => Some(
+                    initial_backoff
+                        .saturating_mul(2u32.saturating_pow(restarts))
+                        .min(max_backoff),
+                ),
+                _ => None,
+            };
+            events.emit(Event::ActorExited {
+                name: name.clone(),
+                reason: reason.clone(),
+                restarting: backoff.is_some(),
+            });
+            let Some(backoff) = backoff else {
+                warn!("Actor {} {}, stopping the daemon", name, reason);
+                return Err(DaemonError::ActorDied(name, reason));
+            };
+            warn!("Actor {} {}, restarting it in {:?}", name, reason, backoff);
+            let mut listener = shutdown.listener();
+            select! {
+                _ = tokio::time::sleep(backoff) => {}
+                _ = listener.wait() => return Ok(()),
+            }
+            restarts += 1;
+            info!("Restarting actor {}", name);
+        }
+    }
+
+    /// Waits until the shutdown is triggered or an actor stops the daemon.
+    ///
+    /// Actors stopping after it returned are not restarted.
+    pub async fn wait(&mut self) -> Result<(), DaemonError> {
+        let result = self.wait_for_stop().await;
+        self.stopping.store(true, Ordering::Release);
+        result
+    }
+
+    async fn wait_for_stop(&mut self) -> Result<(), DaemonError> {
+        let mut listener = self.shutdown.listener();
+        loop {
+            select! {
+                joined = self.tasks.join_next() => match joined {
+                    // Stopped by the shutdown
+                    Some(Ok(Ok(()))) => {}
+                    Some(Ok(Err(error))) => return Err(error),
+                    Some(Err(error)) => {
+                        let reason = ExitReason::from_join_error(error);
+                        return Err(DaemonError::ActorDied("supervisor".to_string(), reason));
+                    }
+                    None => {
+                        listener.wait().await;
+                        return Ok(());
+                    }
+                },
+                _ = listener.wait() => return Ok(()),
+            }
+        }
+    }
+
+    /// Triggers the shutdown and waits up to `deadline` for the actors to stop,
+    /// aborting the remaining ones.
+    pub async fn stop(mut self, deadline: Duration) {
+        self.shutdown.trigger();
+        let drain = async { while self.tasks.join_next().await.is_some() {} };
+        if tokio::time::timeout(deadline, drain).await.is_err() {
+            warn!("Shutdown deadline exceeded, stopping the remaining actors");
+            self.tasks.abort_all();
+        }
+    }
+}
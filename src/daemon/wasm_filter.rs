@@ -0,0 +1,252 @@
+//! Module for packet filters implemented as WebAssembly modules.
+//!
+//! A [WasmFilter] is a pipeline stage that passes each packet to a sandboxed WebAssembly module,
+//! which decides whether the packet passes or is dropped.
+//! The module of a running filter can be swapped with [WasmFilterHandle::reload] or
+//! [WasmFilterHandle::swap], without touching the other filters.
+//!
+//! # ABI
+//!
+//! The module must export:
+//! - `memory`: the linear memory of the module.
+//! - `p2ptun_alloc(size: i32) -> i32`: returns a pointer to `size` bytes of memory owned by
+//!   the host. It is called once, when the module is loaded, with [BUFFER_SIZE].
+//!   A module whose buffer doesn't fit in its memory is rejected.
+//! - `p2ptun_filter(direction: i32, length: i32) -> i32`: decides about the packet written
+//!   to the buffer.
+//!
+//! Before `p2ptun_filter` is called, the host writes to the buffer the [NodeId] of the peer
+//! that sent the packet (32 bytes, zeroed for outgoing packets), followed by `length` bytes
+//! of the packet. `direction` is `0` for incoming and `1` for outgoing packets.
+//!
+//! `p2ptun_filter` returns `0` to pass the packet and any other value to drop it.
+//!
+//! The module can't import anything. Each call is limited to [FUEL_PER_PACKET] units of fuel,
+//! and should return within [TIME_PER_PACKET]. A module that traps, runs out of fuel or returns
+//! too late drops the packet.
+//!
+//! The time is only checked once the call returns: a call isn't interrupted when its time is
+//! up, so only the fuel bounds how long it blocks the pipeline.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use iroh_net::NodeId;
+use tracing::warn;
+use wasmi::{Config, Engine, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::daemon::{
+    packet::Packet,
+    pipeline::{PacketProcessor, Verdict},
+    DaemonError,
+};
+
+/// The size of the buffer shared with the module: a [NodeId] followed by the largest packet.
+pub const BUFFER_SIZE: i32 = 32 + 65535;
+
+/// The fuel available to the module for filtering a single packet.
+pub const FUEL_PER_PACKET: u64 = 1_000_000;
+
+/// The time available to the module for filtering a single packet.
+pub const TIME_PER_PACKET: Duration = Duration::from_millis(5);
+
+/// An instantiated filter module.
+struct Plugin {
+    store: Store<()>,
+    memory: Memory,
+    filter: TypedFunc<(i32, i32), i32>,
+    buffer: usize,
+    /// The total fuel added to the store.
+    fuel_added: u64,
+}
+
+impl Plugin {
+    /// Compiles and instantiates a module.
+    fn new(wasm: &[u8]) -> Result<Self, DaemonError> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)?;
+        let mut store = Store::new(&engine, ());
+        let mut fuel_added = 0;
+        refuel(&mut store, &mut fuel_added)?;
+        let instance = Linker::<()>::new(&engine)
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or(DaemonError::MissingWasmExport("memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "p2ptun_alloc")?;
+        let filter = instance.get_typed_func::<(i32, i32), i32>(&store, "p2ptun_filter")?;
+        let buffer = alloc
+            .call(&mut store, BUFFER_SIZE)
+            .map_err(wasmi::Error::from)? as u32 as usize;
+        // Memory never shrinks, so the buffer stays in bounds once it is
+        if buffer + BUFFER_SIZE as usize > memory.data(&store).len() {
+            return Err(DaemonError::WasmBufferOutOfBounds(buffer));
+        }
+        Ok(Self {
+            store,
+            memory,
+            filter,
+            buffer,
+            fuel_added,
+        })
+    }
+
+    /// Runs the filter function of the module on a packet, dropping it if the module doesn't
+    /// return within `time_limit`.
+    fn filter(
+        &mut self,
+        peer: Option<&NodeId>,
+        direction: i32,
+        data: &[u8],
+        time_limit: Duration,
+    ) -> Verdict {
+        let started = Instant::now();
+        let peer = peer.map_or([0; 32], |peer| *peer.as_bytes());
+        let result = self
+            .memory
+            .write(&mut self.store, self.buffer, &peer)
+            .and_then(|()| {
+                self.memory
+                    .write(&mut self.store, self.buffer + peer.len(), data)
+            })
+            .map_err(wasmi::Error::from)
+            .and_then(|()| self.call_with_fuel(direction, data.len() as i32));
+        let elapsed = started.elapsed();
+        if elapsed > time_limit {
+            warn!("WebAssembly filter took {:?}, dropping the packet", elapsed);
+            return Verdict::Drop;
+        }
+        match result {
+            Ok(0) => Verdict::Pass,
+            Ok(_) => Verdict::Drop,
+            Err(error) => {
+                warn!("WebAssembly filter failed. Reason: {}", error);
+                Verdict::Drop
+            }
+        }
+    }
+
+    /// Calls the filter function with [FUEL_PER_PACKET] units of fuel.
+    fn call_with_fuel(&mut self, direction: i32, length: i32) -> Result<i32, wasmi::Error> {
+        refuel(&mut self.store, &mut self.fuel_added)?;
+        Ok(self.filter.call(&mut self.store, (direction, length))?)
+    }
+}
+
+/// Tops up the fuel of the store to [FUEL_PER_PACKET].
+fn refuel(store: &mut Store<()>, fuel_added: &mut u64) -> Result<(), wasmi::Error> {
+    let remaining = *fuel_added - store.fuel_consumed().unwrap_or_default();
+    store.add_fuel(FUEL_PER_PACKET - remaining)?;
+    *fuel_added += FUEL_PER_PACKET - remaining;
+    Ok(())
+}
+
+/// A handle that replaces the module of a running [WasmFilter].
+#[derive(Clone)]
+pub struct WasmFilterHandle {
+    name: String,
+    path: PathBuf,
+    plugin: Arc<Mutex<Plugin>>,
+}
+
+impl WasmFilterHandle {
+    /// Returns the stage name of the filter.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Loads the module file again and makes the filter use the new module.
+    ///
+    /// On error, the filter keeps using the previous module.
+    pub fn reload(&self) -> Result<(), DaemonError> {
+        self.swap(&std::fs::read(&self.path)?)
+    }
+
+    /// Makes the filter use the module `wasm` instead of the module file.
+    ///
+    /// The module file is loaded again on the next [WasmFilterHandle::reload].
+    /// On error, the filter keeps using the previous module.
+    pub fn swap(&self, wasm: &[u8]) -> Result<(), DaemonError> {
+        let plugin = Plugin::new(wasm)?;
+        *self.plugin.lock().unwrap() = plugin;
+        Ok(())
+    }
+}
+
+/// A pipeline stage running a WebAssembly filter module.
+pub struct WasmFilter {
+    name: String,
+    path: PathBuf,
+    plugin: Arc<Mutex<Plugin>>,
+    time_limit: Duration,
+}
+
+impl WasmFilter {
+    /// Loads a filter module from the file at `path`.
+    ///
+    /// The stage is named `wasm:` followed by the file name without extension.
+    pub fn load(path: PathBuf) -> Result<Self, DaemonError> {
+        let name = Self::stage_name(&path);
+        let plugin = Plugin::new(&std::fs::read(&path)?)?;
+        Ok(Self {
+            name,
+            path,
+            plugin: Arc::new(Mutex::new(plugin)),
+            time_limit: TIME_PER_PACKET,
+        })
+    }
+
+    /// Creates a filter from the bytes of a module.
+    pub fn from_bytes(name: &str, wasm: &[u8]) -> Result<Self, DaemonError> {
+        Ok(Self {
+            name: format!("wasm:{}", name),
+            path: PathBuf::new(),
+            plugin: Arc::new(Mutex::new(Plugin::new(wasm)?)),
+            time_limit: TIME_PER_PACKET,
+        })
+    }
+
+    /// Sets the time the module has for filtering a single packet, [TIME_PER_PACKET] by default.
+    pub fn time_limit(mut self, time_limit: Duration) -> Self {
+        self.time_limit = time_limit;
+        self
+    }
+
+    /// Returns a handle used to replace the module while the filter is running.
+    pub fn handle(&self) -> WasmFilterHandle {
+        WasmFilterHandle {
+            name: self.name.clone(),
+            path: self.path.clone(),
+            plugin: self.plugin.clone(),
+        }
+    }
+
+    fn stage_name(path: &Path) -> String {
+        let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+        format!("wasm:{}", stem)
+    }
+}
+
+impl PacketProcessor for WasmFilter {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn process(&mut self, packet: &Packet) -> Verdict {
+        let mut plugin = self.plugin.lock().unwrap();
+        match packet {
+            Packet::Incoming(peer, data) | Packet::Transit(peer, _, data) => {
+                plugin.filter(Some(peer), 0, data, self.time_limit)
+            }
+            Packet::Outgoing(data) | Packet::Redirected(_, data) => {
+                plugin.filter(None, 1, data, self.time_limit)
+            }
+        }
+    }
+}
@@ -1,6 +1,315 @@
-use p2ptun::daemon::{run_daemon, DaemonConfig};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use iroh_net::{key::SecretKey, NodeId};
+
+use p2ptun::daemon::{
+    control::{self, Request, Response},
+    discovery::DiscoveryConfig,
+    handle::DaemonHandle,
+    pairing::PairingCode,
+    rotation::{self, RotationConfig},
+    DaemonBuilder, DaemonError,
+};
+use tokio::{
+    select,
+    signal::unix::{signal, SignalKind},
+};
+use tracing::warn;
+
+const USAGE: &str = "\
+Usage:
+  p2ptun                                                  Run the daemon
+  p2ptun dial <node id>                                   Dial a node found by the discovery
+  p2ptun forward <tcp|udp> <listen address> <node id> <host:port>
+  p2ptun forward list
+  p2ptun forward remove <id>
+  p2ptun forward publish <tcp|udp> <host:port> [<node id>]
+  p2ptun exit list
+  p2ptun exit use <node id>
+  p2ptun exit none
+  p2ptun filter swap <name> [<module file>]                Replace the module of a filter
+  p2ptun invite create <seconds valid> <max uses> [<tag>...]
+  p2ptun invite list
+  p2ptun invite revoke <id>
+  p2ptun join <invite>
+  p2ptun pair [<code>]                                    Pair with another node
+  p2ptun revoke <node id>
+  p2ptun revoke list
+  p2ptun rotate                                           Replace the key of this node
+  p2ptun rotate list
+
+The control socket is at $P2PTUN_CONTROL_SOCKET when it is set.
+The daemon uses the secret key in the file $P2PTUN_KEY_FILE when it is set, writing a new one
+there if the file doesn't exist, then the secret key in $P2PTUN_SECRET_KEY, and a new one
+otherwise. `p2ptun rotate` writes the new key to $P2PTUN_KEY_FILE, used once the daemon is
+restarted.
+It publishes its addresses to the discovery server at $P2PTUN_DISCOVERY_SERVER, and resolves
+the addresses of other nodes from the DNS zone $P2PTUN_DISCOVERY_ZONE, or from the server.";
 
 #[tokio::main]
-async fn main() {
-    run_daemon(DaemonConfig::default()).await.unwrap();
+async fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+    let control_socket = std::env::var_os("P2PTUN_CONTROL_SOCKET")
+        .map(PathBuf::from)
+        .unwrap_or_else(control::default_socket_path);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        None => {
+            let mut builder = DaemonBuilder::new().control_socket(control_socket);
+            let key_path = std::env::var_os("P2PTUN_KEY_FILE").map(PathBuf::from);
+            match secret_key(key_path.as_deref()) {
+                Ok(Some(secret_key)) => builder = builder.secret_key(secret_key),
+                Ok(None) => {}
+                Err(error) => {
+                    eprintln!("Couldn't get the secret key: {:?}", error);
+                    return ExitCode::FAILURE;
+                }
+            }
+            builder = builder.rotations(RotationConfig { key_path });
+            let server = std::env::var("P2PTUN_DISCOVERY_SERVER")
+                .ok()
+                .map(|server| server.parse())
+                .transpose();
+            let server = match server {
+                Ok(server) => server,
+                Err(error) => {
+                    eprintln!("Invalid discovery server: {}", error);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let discovery = DiscoveryConfig {
+                server,
+                zone: std::env::var("P2PTUN_DISCOVERY_ZONE").ok(),
+                ..DiscoveryConfig::default()
+            };
+            builder = builder.discovery(discovery);
+            let daemon = builder.start().await.unwrap();
+            run(daemon).await.unwrap();
+            ExitCode::SUCCESS
+        }
+        Some("forward") => match forward_request(&args[1..]) {
+            Ok(request) => send(control_socket, request).await,
+            Err(error) => {
+                eprintln!("{}\n\n{}", error, USAGE);
+                ExitCode::FAILURE
+            }
+        },
+        Some("exit") => match exit_request(&args[1..]) {
+            Ok(request) => send(control_socket, request).await,
+            Err(error) => {
+                eprintln!("{}\n\n{}", error, USAGE);
+                ExitCode::FAILURE
+            }
+        },
+        Some("invite") => match invite_request(&args[1..]) {
+            Ok(request) => send(control_socket, request).await,
+            Err(error) => {
+                eprintln!("{}\n\n{}", error, USAGE);
+                ExitCode::FAILURE
+            }
+        },
+        Some("dial") if args.len() == 2 => match args[1].parse::<NodeId>() {
+            Ok(node_id) => send(control_socket, Request::DialNode { node_id }).await,
+            Err(error) => {
+                eprintln!("{}\n\n{}", error, USAGE);
+                ExitCode::FAILURE
+            }
+        },
+        Some("filter") if (3..=4).contains(&args.len()) && args[1] == "swap" => {
+            let path = match args.get(3).map(std::fs::canonicalize).transpose() {
+                Ok(path) => path,
+                Err(error) => {
+                    eprintln!("{}\n\n{}", error, USAGE);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let request = Request::SwapFilter {
+                name: args[2].clone(),
+                path,
+            };
+            send(control_socket, request).await
+        }
+        Some("join") if args.len() == 2 => {
+            let request = Request::Join {
+                invite: args[1].clone(),
+            };
+            send(control_socket, request).await
+        }
+        Some("pair") if args.len() == 1 => {
+            let code = PairingCode::generate();
+            println!("Enter this code on the other node: {}", code);
+            let request = Request::Pair {
+                code: code.to_string(),
+            };
+            send(control_socket, request).await
+        }
+        Some("pair") if args.len() == 2 => {
+            let request = Request::PairWith {
+                code: args[1].clone(),
+            };
+            send(control_socket, request).await
+        }
+        Some("revoke") if args.len() == 2 => {
+            let request = match args[1].as_str() {
+                "list" => Request::Revocations,
+                node_id => match node_id.parse::<NodeId>() {
+                    Ok(node_id) => Request::Revoke { node_id },
+                    Err(error) => {
+                        eprintln!("{}\n\n{}", error, USAGE);
+                        return ExitCode::FAILURE;
+                    }
+                },
+            };
+            send(control_socket, request).await
+        }
+        Some("rotate") if args.len() == 1 => send(control_socket, Request::RotateKey).await,
+        Some("rotate") if args.len() == 2 && args[1] == "list" => {
+            send(control_socket, Request::Successions).await
+        }
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the secret key in the file at `key_path`, writing a new one there if the file doesn't
+/// exist, then the one in $P2PTUN_SECRET_KEY.
+fn secret_key(key_path: Option<&Path>) -> Result<Option<SecretKey>, DaemonError> {
+    if let Some(path) = key_path {
+        if let Some(secret_key) = rotation::read_key(path)? {
+            return Ok(Some(secret_key));
+        }
+        let secret_key = SecretKey::generate();
+        rotation::write_key(path, &secret_key)?;
+        return Ok(Some(secret_key));
+    }
+    match std::env::var("P2PTUN_SECRET_KEY") {
+        Ok(secret_key) => Ok(Some(
+            secret_key
+                .parse::<SecretKey>()
+                .map_err(anyhow::Error::from)?,
+        )),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Runs the daemon until SIGINT or SIGTERM, reloading it on SIGHUP.
+async fn run(daemon: DaemonHandle) -> Result<(), DaemonError> {
+    println!("Node ID: {}", daemon.node_id());
+    println!("Node ticket: {}", daemon.ticket().await?);
+    let mut hangups = signal(SignalKind::hangup())?;
+    let mut terminations = signal(SignalKind::terminate())?;
+    loop {
+        select! {
+            result = daemon.wait() => return result,
+            _ = hangups.recv() => {
+                if let Err(error) = daemon.reload() {
+                    warn!("Couldn't reload. Reason: {:?}", error);
+                }
+            }
+            _ = tokio::signal::ctrl_c() => break,
+            _ = terminations.recv() => break,
+        }
+    }
+    daemon.shutdown().await
+}
+
+/// Parses the arguments of `p2ptun forward` into a control socket request.
+fn forward_request(args: &[String]) -> Result<Request, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["list"] => Ok(Request::Forwards),
+        ["remove", id] => Ok(Request::RemoveForward {
+            id: id.parse::<u64>().map_err(|error| error.to_string())?,
+        }),
+        ["publish", protocol, target, node_id @ ..] if node_id.len() <= 1 => Ok(Request::Publish {
+            protocol: Some(protocol.parse()?),
+            target: target.parse()?,
+            node_id: match node_id.first() {
+                Some(node_id) => Some(
+                    node_id
+                        .parse::<NodeId>()
+                        .map_err(|error| error.to_string())?,
+                ),
+                None => None,
+            },
+        }),
+        [protocol, listen, node_id, target] => Ok(Request::Forward {
+            protocol: protocol.parse()?,
+            listen: listen
+                .parse::<SocketAddr>()
+                .map_err(|error| error.to_string())?,
+            node_id: node_id
+                .parse::<NodeId>()
+                .map_err(|error| error.to_string())?,
+            target: target.parse()?,
+        }),
+        _ => Err("invalid arguments".to_string()),
+    }
+}
+
+/// Parses the arguments of `p2ptun exit` into a control socket request.
+fn exit_request(args: &[String]) -> Result<Request, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["list"] => Ok(Request::ExitNodes),
+        ["use", node_id] => Ok(Request::SetExit {
+            node_id: Some(
+                node_id
+                    .parse::<NodeId>()
+                    .map_err(|error| error.to_string())?,
+            ),
+        }),
+        ["none"] => Ok(Request::SetExit { node_id: None }),
+        _ => Err("invalid arguments".to_string()),
+    }
+}
+
+/// Parses the arguments of `p2ptun invite` into a control socket request.
+fn invite_request(args: &[String]) -> Result<Request, String> {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["list"] => Ok(Request::Invites),
+        ["revoke", id] => Ok(Request::RevokeInvite {
+            id: id.parse::<u64>().map_err(|error| error.to_string())?,
+        }),
+        ["create", valid_for, max_uses, tags @ ..] => Ok(Request::Invite {
+            valid_for: valid_for
+                .parse::<u64>()
+                .map_err(|error| error.to_string())?,
+            max_uses: max_uses.parse::<u32>().map_err(|error| error.to_string())?,
+            tags: tags.iter().map(|tag| tag.to_string()).collect(),
+            address: None,
+        }),
+        _ => Err("invalid arguments".to_string()),
+    }
+}
+
+/// Sends `request` to the daemon and prints the response.
+async fn send(control_socket: PathBuf, request: Request) -> ExitCode {
+    match control::request(&control_socket, &request).await {
+        Ok(Response::Ok(value)) => {
+            if !value.is_null() {
+                println!("{:#}", value);
+            }
+            ExitCode::SUCCESS
+        }
+        Ok(Response::Error(error)) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+        Err(error) => {
+            eprintln!(
+                "Couldn't reach the daemon at {}: {}",
+                control_socket.display(),
+                error
+            );
+            ExitCode::FAILURE
+        }
+    }
 }
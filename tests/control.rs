@@ -0,0 +1,41 @@
+use std::{io, os::unix::fs::PermissionsExt, path::PathBuf};
+
+use p2ptun::daemon::control::ControlServer;
+
+fn socket_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("p2ptun-{}-{}.sock", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+#[tokio::test]
+async fn socket_is_private() {
+    let path = socket_path("private");
+    let _server = ControlServer::bind(path.clone()).unwrap();
+    let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn only_stale_sockets_are_replaced() {
+    let path = socket_path("stale");
+    let server = ControlServer::bind(path.clone()).unwrap();
+    let error = ControlServer::bind(path.clone()).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AddrInUse);
+
+    // The socket is left behind, but nothing listens on it anymore
+    drop(server);
+    let _server = ControlServer::bind(path.clone()).unwrap();
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn other_files_are_not_replaced() {
+    let path = socket_path("file");
+    std::fs::write(&path, b"not a socket").unwrap();
+    let error = ControlServer::bind(path.clone()).err().unwrap();
+    assert_eq!(error.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+    std::fs::remove_file(path).unwrap();
+}
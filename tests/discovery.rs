@@ -0,0 +1,195 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hickory_proto::rr::rdata::TXT;
+use iroh_net::{key::SecretKey, NodeAddr};
+use p2ptun::daemon::{
+    discovery::{Discovery, DiscoveryConfig, DiscoveryServer, SignedPacket, MAX_PACKET_AGE},
+    DaemonError,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// Starts a stand-in discovery server on loopback.
+async fn start_server() -> DiscoveryServer {
+    DiscoveryServer::bind(
+        "127.0.0.1:0".parse().unwrap(),
+        "127.0.0.1:0".parse().unwrap(),
+    )
+    .await
+    .unwrap()
+}
+
+fn node_addr(secret_key: &SecretKey) -> NodeAddr {
+    NodeAddr::new(secret_key.public())
+        .with_relay_url("https://relay.example.com".parse().unwrap())
+        .with_direct_addresses([
+            "192.168.1.20:41641".parse().unwrap(),
+            "[2001:db8::20]:41641".parse().unwrap(),
+        ])
+}
+
+#[test]
+fn only_packets_signed_by_the_node_are_valid() {
+    let secret_key = SecretKey::generate();
+    let node_addr = node_addr(&secret_key);
+    let packet = SignedPacket::sign(&secret_key, &node_addr, 1_700_000_000_000_000).unwrap();
+    assert!(packet.verify());
+    assert_eq!(packet.node_addr().unwrap(), node_addr);
+    let decoded = SignedPacket::from_bytes(secret_key.public(), &packet.to_bytes()).unwrap();
+    assert_eq!(decoded, packet);
+
+    let mut forged = packet.clone();
+    forged.timestamp += 1;
+    assert!(!forged.verify());
+    let forged = SignedPacket::from_bytes(SecretKey::generate().public(), &packet.to_bytes());
+    assert!(!forged.unwrap().verify());
+}
+
+/// Returns the timestamp of a packet signed `age` ago.
+fn signed_ago(age: Duration) -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    (now - age).as_micros() as u64
+}
+
+#[test]
+fn packets_in_the_dns_zone_are_signed() {
+    let secret_key = SecretKey::generate();
+    let node_addr = node_addr(&secret_key);
+    let packet = SignedPacket::sign(&secret_key, &node_addr, signed_ago(Duration::ZERO)).unwrap();
+    let txt = packet.to_txt();
+    assert!(txt.txt_data().iter().all(|string| string.len() <= 255));
+    assert_eq!(
+        SignedPacket::from_txt(secret_key.public(), &txt).unwrap(),
+        packet
+    );
+
+    let forged = SignedPacket::from_txt(SecretKey::generate().public(), &txt);
+    assert!(!forged.unwrap().verify());
+    let unsigned = TXT::new(vec!["addr=192.168.1.20:41641".to_string()]);
+    assert!(SignedPacket::from_txt(secret_key.public(), &unsigned).is_err());
+}
+
+#[tokio::test]
+async fn nodes_are_resolved_from_the_server() {
+    let server = start_server().await;
+    let config = DiscoveryConfig {
+        server: Some(server.url().unwrap()),
+        ..DiscoveryConfig::default()
+    };
+    tokio::spawn(server.run());
+
+    let secret_key = SecretKey::generate();
+    let discovery = Discovery::new(config.clone(), secret_key.clone()).unwrap();
+    discovery.publish(&node_addr(&secret_key)).await.unwrap();
+
+    let other = Discovery::new(config, SecretKey::generate()).unwrap();
+    let resolved = other.resolve(secret_key.public()).await.unwrap();
+    assert_eq!(resolved, node_addr(&secret_key));
+    let unknown = SecretKey::generate().public();
+    assert!(matches!(
+        other.resolve(unknown).await,
+        Err(DaemonError::NotDiscovered(node_id)) if node_id == unknown
+    ));
+}
+
+#[tokio::test]
+async fn nodes_are_resolved_from_the_dns_zone() {
+    let server = start_server().await;
+    let url = server.url().unwrap();
+    let name_server = server.dns_address().unwrap();
+    tokio::spawn(server.run());
+
+    let secret_key = SecretKey::generate();
+    let publishing = DiscoveryConfig {
+        server: Some(url),
+        ..DiscoveryConfig::default()
+    };
+    let discovery = Discovery::new(publishing, secret_key.clone()).unwrap();
+    discovery.publish(&node_addr(&secret_key)).await.unwrap();
+
+    let resolving = DiscoveryConfig {
+        zone: Some("dns.example.com".to_string()),
+        name_server: Some(name_server),
+        ..DiscoveryConfig::default()
+    };
+    let other = Discovery::new(resolving, SecretKey::generate()).unwrap();
+    let resolved = tokio::time::timeout(Duration::from_secs(5), other.resolve(secret_key.public()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(resolved, node_addr(&secret_key));
+}
+
+#[tokio::test]
+async fn stale_packets_are_rejected() {
+    let server = start_server().await;
+    let url = server.url().unwrap();
+    let name_server = server.dns_address().unwrap();
+    tokio::spawn(server.run());
+
+    // Replayed long after the node signed it
+    let secret_key = SecretKey::generate();
+    let timestamp = signed_ago(MAX_PACKET_AGE + Duration::from_secs(60));
+    let packet = SignedPacket::sign(&secret_key, &node_addr(&secret_key), timestamp).unwrap();
+    assert!(packet.verify() && packet.is_stale());
+    reqwest::Client::new()
+        .put(format!("{}{}", url, secret_key.public()))
+        .body(packet.to_bytes())
+        .send()
+        .await
+        .unwrap()
+        .error_for_status()
+        .unwrap();
+
+    let from_server = DiscoveryConfig {
+        server: Some(url),
+        ..DiscoveryConfig::default()
+    };
+    let from_zone = DiscoveryConfig {
+        zone: Some("dns.example.com".to_string()),
+        name_server: Some(name_server),
+        ..DiscoveryConfig::default()
+    };
+    for config in [from_server, from_zone] {
+        let discovery = Discovery::new(config, SecretKey::generate()).unwrap();
+        let resolved = tokio::time::timeout(
+            Duration::from_secs(5),
+            discovery.resolve(secret_key.public()),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(resolved, Err(DaemonError::NotDiscovered(_))));
+    }
+}
+
+#[tokio::test]
+async fn oversized_responses_are_not_read() {
+    // Answers every request with an endless body
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        while let Ok((mut client, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut request = [0u8; 1024];
+                let _ = client.read(&mut request).await;
+                let head = "HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n";
+                if client.write_all(head.as_bytes()).await.is_ok() {
+                    while client.write_all(&[0u8; 4096]).await.is_ok() {}
+                }
+            });
+        }
+    });
+
+    let config = DiscoveryConfig {
+        server: Some(url.parse().unwrap()),
+        ..DiscoveryConfig::default()
+    };
+    let discovery = Discovery::new(config, SecretKey::generate()).unwrap();
+    let node_id = SecretKey::generate().public();
+    let resolved = tokio::time::timeout(Duration::from_secs(5), discovery.resolve(node_id))
+        .await
+        .unwrap();
+    assert!(matches!(resolved, Err(DaemonError::NotDiscovered(_))));
+}
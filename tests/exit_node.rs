@@ -0,0 +1,369 @@
+//! The tests of the exit node need CAP_NET_ADMIN to give the loopback interface the addresses
+//! of internet hosts, so they are ignored by default. They can be run as an unprivileged user
+//! inside a network namespace:
+//!
+//! ```sh
+//! unshare -rn cargo test --test exit_node -- --ignored
+//! ```
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use iroh_net::{
+    key::SecretKey, magic_endpoint::accept_conn, relay::RelayMode, MagicEndpoint, NodeAddr, NodeId,
+};
+use p2ptun::daemon::{
+    actors::{
+        exit_node::{ExitNode, ExitPolicy},
+        mailbox::{mailbox, Mailbox, OverflowPolicy},
+        peer::Peer,
+        peer_collection::{FailbackPolicy, PeerCollection, PeerCollectionMessage},
+        Actor, Addr,
+    },
+    events::Events,
+    netlink::Netlink,
+    packet::{ip::Flow, Packet},
+};
+use quinn::{RecvStream, SendStream};
+use smoltcp::{
+    phy::ChecksumCapabilities,
+    wire::{
+        IpAddress, IpProtocol, Ipv4Packet, Ipv4Repr, TcpPacket, TcpSeqNumber, UdpPacket, UdpRepr,
+    },
+};
+use tokio::net::{TcpListener, UdpSocket};
+
+const ALPN: &[u8] = b"p2ptun-exit-test";
+/// An address of the benchmarking range, which is neither private nor loopback.
+const HOST: Ipv4Addr = Ipv4Addr::new(198, 18, 0, 1);
+const PRIVATE_HOST: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 1);
+const LINK_LOCAL_HOST: Ipv4Addr = Ipv4Addr::new(169, 254, 0, 1);
+
+/// Brings the loopback interface up, with the addresses of the hosts.
+async fn add_hosts() {
+    let netlink = Netlink::connect().unwrap();
+    let index = netlink.link_index("lo").await.unwrap();
+    let (connection, handle, _) = rtnetlink::new_connection().unwrap();
+    tokio::spawn(connection);
+    handle.link().set(index).up().execute().await.unwrap();
+    for host in [HOST, PRIVATE_HOST, LINK_LOCAL_HOST] {
+        // The tests share the namespace, so the address may be there already
+        let _ = netlink
+            .add_address(index, IpNet::from(IpAddr::V4(host)))
+            .await;
+    }
+}
+
+/// Starts an [ExitNode] for the 10.0.0.0/24 overlay, and returns its address with the mailbox
+/// of the packets it sends to the router.
+fn start_exit_node(policy: ExitPolicy) -> (Addr<Packet>, Mailbox<Packet>) {
+    let (router, router_mailbox) = mailbox(16, OverflowPolicy::Block);
+    let exit_node = ExitNode::new(router, vec!["10.0.0.0/24".parse().unwrap()], policy, None);
+    let address = exit_node.get_addr();
+    tokio::spawn(exit_node.run());
+    (address, router_mailbox)
+}
+
+/// Builds the header of an IPv4 packet with a payload of `payload_len` bytes.
+fn ipv4_packet(
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: IpProtocol,
+    payload_len: usize,
+) -> (Ipv4Repr, Vec<u8>) {
+    let ip = Ipv4Repr {
+        src_addr: source.into(),
+        dst_addr: destination.into(),
+        next_header: protocol,
+        payload_len,
+        hop_limit: 64,
+    };
+    let mut buffer = vec![0u8; ip.buffer_len() + payload_len];
+    ip.emit(
+        &mut Ipv4Packet::new_unchecked(&mut buffer),
+        &ChecksumCapabilities::default(),
+    );
+    (ip, buffer)
+}
+
+fn udp_packet(source: SocketAddr, destination: SocketAddr, payload: &[u8]) -> Arc<[u8]> {
+    let (IpAddr::V4(source_address), IpAddr::V4(destination_address)) =
+        (source.ip(), destination.ip())
+    else {
+        unreachable!();
+    };
+    let udp = UdpRepr {
+        src_port: source.port(),
+        dst_port: destination.port(),
+    };
+    let (ip, mut buffer) = ipv4_packet(
+        source_address,
+        destination_address,
+        IpProtocol::Udp,
+        udp.header_len() + payload.len(),
+    );
+    let mut packet = Ipv4Packet::new_unchecked(&mut buffer);
+    udp.emit(
+        &mut UdpPacket::new_unchecked(packet.payload_mut()),
+        &ip.src_addr.into(),
+        &ip.dst_addr.into(),
+        payload.len(),
+        |buffer| buffer.copy_from_slice(payload),
+        &ChecksumCapabilities::default(),
+    );
+    Arc::from(buffer)
+}
+
+fn tcp_syn(source: SocketAddr, destination: SocketAddr) -> Arc<[u8]> {
+    let (IpAddr::V4(source_address), IpAddr::V4(destination_address)) =
+        (source.ip(), destination.ip())
+    else {
+        unreachable!();
+    };
+    let (_, mut buffer) = ipv4_packet(source_address, destination_address, IpProtocol::Tcp, 20);
+    let mut packet = Ipv4Packet::new_unchecked(&mut buffer);
+    let mut segment = TcpPacket::new_unchecked(packet.payload_mut());
+    segment.set_src_port(source.port());
+    segment.set_dst_port(destination.port());
+    segment.set_seq_number(TcpSeqNumber(1000));
+    segment.set_header_len(20);
+    segment.clear_flags();
+    segment.set_syn(true);
+    segment.set_window_len(64240);
+    segment.fill_checksum(
+        &IpAddress::from(source_address),
+        &IpAddress::from(destination_address),
+    );
+    Arc::from(buffer)
+}
+
+/// Returns the next packet the exit node sends to a peer, if any.
+async fn next_reply(router: &mut Mailbox<Packet>) -> Option<(NodeId, Arc<[u8]>)> {
+    match tokio::time::timeout(Duration::from_secs(2), router.recv()).await {
+        Ok(Some(Packet::Redirected(node_id, data))) => Some((node_id, data)),
+        Ok(packet) => panic!("unexpected packet: {:?}", packet),
+        Err(_) => None,
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs CAP_NET_ADMIN"]
+async fn udp_flows_of_allowed_peers_are_translated() {
+    add_hosts().await;
+    let echo = UdpSocket::bind((HOST, 0)).await.unwrap();
+    let host = echo.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        while let Ok((size, from)) = echo.recv_from(&mut buffer).await {
+            let _ = echo.send_to(&buffer[..size], from).await;
+        }
+    });
+    let allowed = SecretKey::generate().public();
+    let spoofing = SecretKey::generate().public();
+    let (exit_node, mut router) = start_exit_node(ExitPolicy {
+        peers: vec![allowed, spoofing],
+    });
+
+    let peer: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+    exit_node
+        .send_message(Packet::Incoming(allowed, udp_packet(peer, host, b"ping")))
+        .await;
+    let (node_id, reply) = next_reply(&mut router).await.unwrap();
+    assert_eq!(node_id, allowed);
+    let flow = Flow::parse(&reply).unwrap();
+    assert_eq!((flow.source, flow.source_port), (host.ip(), host.port()));
+    assert_eq!(
+        (flow.destination, flow.destination_port),
+        (peer.ip(), peer.port())
+    );
+    let packet = Ipv4Packet::new_checked(&reply[..]).unwrap();
+    assert_eq!(
+        UdpPacket::new_checked(packet.payload()).unwrap().payload(),
+        b"ping"
+    );
+
+    // Another peer can't send from the address of the first one
+    let spoofed: SocketAddr = "10.0.0.2:5001".parse().unwrap();
+    exit_node
+        .send_message(Packet::Incoming(
+            spoofing,
+            udp_packet(spoofed, host, b"ping"),
+        ))
+        .await;
+    assert!(next_reply(&mut router).await.is_none());
+}
+
+#[tokio::test]
+#[ignore = "needs CAP_NET_ADMIN"]
+async fn only_allowed_peers_reach_internet_hosts() {
+    add_hosts().await;
+    let mut listeners = Vec::new();
+    let mut hosts = Vec::new();
+    for address in [HOST, PRIVATE_HOST, LINK_LOCAL_HOST] {
+        let listener = TcpListener::bind((address, 0)).await.unwrap();
+        hosts.push(listener.local_addr().unwrap());
+        listeners.push(listener);
+    }
+    let allowed = SecretKey::generate().public();
+    let other = SecretKey::generate().public();
+    let (exit_node, mut router) = start_exit_node(ExitPolicy {
+        peers: vec![allowed],
+    });
+
+    exit_node
+        .send_message(Packet::Incoming(
+            other,
+            tcp_syn("10.0.0.3:40000".parse().unwrap(), hosts[0]),
+        ))
+        .await;
+    assert!(next_reply(&mut router).await.is_none());
+
+    // The private and link-local hosts are local networks of the node
+    for host in &hosts[1..] {
+        exit_node
+            .send_message(Packet::Incoming(
+                allowed,
+                tcp_syn("10.0.0.2:40000".parse().unwrap(), *host),
+            ))
+            .await;
+        assert!(next_reply(&mut router).await.is_none());
+    }
+
+    exit_node
+        .send_message(Packet::Incoming(
+            allowed,
+            tcp_syn("10.0.0.2:40001".parse().unwrap(), hosts[0]),
+        ))
+        .await;
+    let (node_id, reply) = next_reply(&mut router).await.unwrap();
+    assert_eq!(node_id, allowed);
+    let packet = Ipv4Packet::new_checked(&reply[..]).unwrap();
+    let segment = TcpPacket::new_checked(packet.payload()).unwrap();
+    assert!(segment.syn() && segment.ack());
+}
+
+/// Binds an endpoint reachable only through its local address.
+async fn endpoint() -> MagicEndpoint {
+    MagicEndpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .secret_key(SecretKey::generate())
+        .bind(0)
+        .await
+        .unwrap()
+}
+
+/// A peer connected through the loopback interface.
+struct TestPeer {
+    node_id: NodeId,
+    /// The stream receiving the packets sent to the peer.
+    packets: RecvStream,
+    _connection: (MagicEndpoint, MagicEndpoint, SendStream),
+}
+
+/// Adds a peer to the collection.
+async fn add_peer(peer_collection: &PeerCollection) -> TestPeer {
+    let client = endpoint().await;
+    let server = endpoint().await;
+    let (server_address, _) = server.local_addr().unwrap();
+    let server_address = SocketAddr::from((Ipv4Addr::LOCALHOST, server_address.port()));
+    let server_node_addr = NodeAddr::new(server.node_id()).with_direct_addresses([server_address]);
+
+    let connection = client.connect(server_node_addr, ALPN).await.unwrap();
+    let (mut send_stream, recv_stream) = connection.open_bi().await.unwrap();
+    // The stream is only accepted once data is sent on it
+    send_stream.write_all(&[0]).await.unwrap();
+    let (node_id, _, server_connection) =
+        accept_conn(server.accept().await.unwrap()).await.unwrap();
+    let (peer_send_stream, peer_recv_stream) = server_connection.accept_bi().await.unwrap();
+    let peer = Peer::new(
+        node_id,
+        Actor::<Packet>::get_addr(peer_collection),
+        peer_send_stream,
+        peer_recv_stream,
+        1500,
+    );
+    Actor::<PeerCollectionMessage>::get_addr(peer_collection)
+        .send_message(PeerCollectionMessage::AddPeer(node_id, peer))
+        .await;
+    TestPeer {
+        node_id,
+        packets: recv_stream,
+        _connection: (client, server, send_stream),
+    }
+}
+
+/// Returns the next packet received by a peer, if any.
+async fn received(stream: &mut RecvStream) -> Option<Vec<u8>> {
+    let mut buffer = vec![0u8; 1500];
+    match tokio::time::timeout(Duration::from_millis(500), stream.read(&mut buffer)).await {
+        Ok(Ok(Some(size))) => Some(buffer[..size].to_vec()),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn traffic_leaving_the_overlay_goes_only_to_the_exit_node() {
+    let (router, _router_mailbox) = mailbox(16, OverflowPolicy::Block);
+    let peer_collection = PeerCollection::new(
+        router,
+        vec!["10.0.0.0/24".parse().unwrap()],
+        FailbackPolicy::default(),
+        Events::new(),
+    );
+    let mut exit = add_peer(&peer_collection).await;
+    let mut other = add_peer(&peer_collection).await;
+    let packets = Actor::<Packet>::get_addr(&peer_collection);
+    let messages = Actor::<PeerCollectionMessage>::get_addr(&peer_collection);
+    tokio::spawn(peer_collection.run());
+    messages
+        .send_message(PeerCollectionMessage::SetExit(Some(exit.node_id)))
+        .await;
+    assert_eq!(
+        messages.ask(PeerCollectionMessage::GetExit).await.unwrap(),
+        Some(exit.node_id)
+    );
+
+    let internet = udp_packet(
+        "10.0.0.1:5000".parse().unwrap(),
+        "8.8.8.8:53".parse().unwrap(),
+        b"query",
+    );
+    packets
+        .send_message(Packet::Outgoing(internet.clone()))
+        .await;
+    assert_eq!(
+        received(&mut exit.packets).await.as_deref(),
+        Some(&internet[..])
+    );
+    assert_eq!(received(&mut other.packets).await, None);
+
+    // The traffic of the overlay still goes to every peer
+    let overlay = udp_packet(
+        "10.0.0.1:5000".parse().unwrap(),
+        "10.0.0.3:5000".parse().unwrap(),
+        b"hello",
+    );
+    packets
+        .send_message(Packet::Outgoing(overlay.clone()))
+        .await;
+    assert_eq!(
+        received(&mut exit.packets).await.as_deref(),
+        Some(&overlay[..])
+    );
+    assert_eq!(
+        received(&mut other.packets).await.as_deref(),
+        Some(&overlay[..])
+    );
+
+    // Nothing leaks to the other peers while the exit node is disconnected
+    messages
+        .send_message(PeerCollectionMessage::DisconnectPeer(exit.node_id))
+        .await;
+    packets.send_message(Packet::Outgoing(internet)).await;
+    assert_eq!(received(&mut other.packets).await, None);
+}
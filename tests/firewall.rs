@@ -0,0 +1,207 @@
+use std::{net::IpAddr, sync::Arc, time::Duration};
+
+use iroh_net::{key::SecretKey, NodeId};
+use p2ptun::daemon::{
+    actors::{
+        peer_collection::{FailbackPolicy, PeerCollection, PeerCollectionMessage},
+        Actor, Addr,
+    },
+    events::Events,
+    firewall::{Firewall, PortRange},
+    packet::{
+        ip::{Flow, PROTOCOL_TCP, PROTOCOL_UDP},
+        Packet,
+    },
+    pipeline::{PacketProcessor, Verdict},
+};
+use tokio::sync::mpsc;
+
+/// Builds an IPv4 packet of the given protocol, with a transport header carrying the ports.
+fn ipv4_packet(
+    protocol: u8,
+    source: [u8; 4],
+    destination: [u8; 4],
+    ports: (u16, u16),
+) -> Arc<[u8]> {
+    let mut packet = [0u8; 40];
+    packet[0] = 0x45;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&source);
+    packet[16..20].copy_from_slice(&destination);
+    packet[20..22].copy_from_slice(&ports.0.to_be_bytes());
+    packet[22..24].copy_from_slice(&ports.1.to_be_bytes());
+    Arc::from(packet.as_slice())
+}
+
+/// Creates a firewall with the given rules file content.
+fn firewall(name: &str, rules: &str) -> Firewall {
+    let path = std::env::temp_dir().join(format!(
+        "p2ptun-firewall-{}-{}.toml",
+        name,
+        SecretKey::generate().public()
+    ));
+    std::fs::write(&path, rules).unwrap();
+    let firewall = Firewall::new(path.clone(), Events::new(), None, None).unwrap();
+    std::fs::remove_file(path).unwrap();
+    firewall
+}
+
+fn passes(firewall: &mut Firewall, peer: NodeId, packet: Arc<[u8]>) -> bool {
+    matches!(
+        firewall.process(&Packet::Incoming(peer, packet)),
+        Verdict::Pass
+    )
+}
+
+#[test]
+fn port_ranges_are_parsed() {
+    assert_eq!(
+        "443".parse::<PortRange>(),
+        Ok(PortRange {
+            first: 443,
+            last: 443
+        })
+    );
+    let range: PortRange = "8000-8100".parse().unwrap();
+    assert!(range.contains(8000) && range.contains(8100) && !range.contains(8101));
+    assert!("9-1".parse::<PortRange>().is_err());
+    assert!("https".parse::<PortRange>().is_err());
+    assert!("70000".parse::<PortRange>().is_err());
+}
+
+#[test]
+fn flows_are_parsed_from_ip_headers() {
+    let packet = ipv4_packet(PROTOCOL_TCP, [10, 0, 0, 1], [10, 0, 0, 2], (40000, 443));
+    let flow = Flow::parse(&packet).unwrap();
+    assert_eq!(flow.protocol, PROTOCOL_TCP);
+    assert_eq!(flow.source, IpAddr::from([10, 0, 0, 1]));
+    assert_eq!(flow.destination, IpAddr::from([10, 0, 0, 2]));
+    assert_eq!((flow.source_port, flow.destination_port), (40000, 443));
+    assert_eq!(flow.reversed().reversed(), flow);
+
+    // Later fragments don't carry the ports
+    let mut fragment = packet.to_vec();
+    fragment[7] = 1;
+    let flow = Flow::parse(&fragment).unwrap();
+    assert_eq!((flow.source_port, flow.destination_port), (0, 0));
+
+    let mut ipv6 = [0u8; 48];
+    ipv6[0] = 0x60;
+    ipv6[6] = PROTOCOL_UDP;
+    ipv6[23] = 1;
+    ipv6[39] = 2;
+    ipv6[40..44].copy_from_slice(&[0x13, 0x88, 0x00, 0x35]);
+    let flow = Flow::parse(&ipv6).unwrap();
+    assert_eq!(flow.protocol, PROTOCOL_UDP);
+    assert_eq!(flow.destination, "::2".parse::<IpAddr>().unwrap());
+    assert_eq!((flow.source_port, flow.destination_port), (5000, 53));
+
+    assert_eq!(Flow::parse(&packet[..10]), None);
+    assert_eq!(Flow::parse(b"not an IP packet"), None);
+}
+
+#[test]
+fn first_matching_rule_decides() {
+    let contractor = SecretKey::generate().public();
+    let admin = SecretKey::generate().public();
+    let rules = format!(
+        r#"
+default_action = "deny"
+
+[tags]
+"{contractor}" = ["contractors"]
+
+[[rules]]
+action = "deny"
+tag = "contractors"
+destination = "10.0.0.9/32"
+
+[[rules]]
+action = "allow"
+tag = "contractors"
+protocol = "tcp"
+destination_ports = "443-444"
+
+[[rules]]
+action = "allow"
+peer = "{admin}"
+"#
+    );
+    let mut firewall = firewall("rules", &rules);
+
+    let https = ipv4_packet(PROTOCOL_TCP, [10, 0, 0, 1], [10, 0, 0, 5], (40000, 443));
+    assert!(passes(&mut firewall, contractor, https.clone()));
+    let ssh = ipv4_packet(PROTOCOL_TCP, [10, 0, 0, 1], [10, 0, 0, 5], (40000, 22));
+    assert!(!passes(&mut firewall, contractor, ssh.clone()));
+    let udp = ipv4_packet(PROTOCOL_UDP, [10, 0, 0, 1], [10, 0, 0, 5], (40000, 443));
+    assert!(!passes(&mut firewall, contractor, udp));
+    let denied = ipv4_packet(PROTOCOL_TCP, [10, 0, 0, 1], [10, 0, 0, 9], (40000, 443));
+    assert!(!passes(&mut firewall, contractor, denied));
+
+    assert!(passes(&mut firewall, admin, ssh.clone()));
+    assert!(!passes(
+        &mut firewall,
+        SecretKey::generate().public(),
+        https
+    ));
+    // Packets that aren't IP packets only match rules without L3 or L4 criteria
+    assert!(passes(&mut firewall, admin, Arc::from(&b"\x00"[..])));
+    assert!(!passes(&mut firewall, contractor, Arc::from(&b"\x00"[..])));
+}
+
+#[test]
+fn replies_pass_only_from_the_peer_the_flow_was_sent_to() {
+    let mut firewall = firewall("conntrack", "default_action = \"deny\"");
+    let first = SecretKey::generate().public();
+    let second = SecretKey::generate().public();
+    let request = ipv4_packet(PROTOCOL_TCP, [10, 0, 0, 1], [10, 0, 0, 2], (40000, 443));
+    let reply = ipv4_packet(PROTOCOL_TCP, [10, 0, 0, 2], [10, 0, 0, 1], (443, 40000));
+
+    // Sent to a single peer
+    firewall.process(&Packet::Redirected(first, request.clone()));
+    assert!(!passes(&mut firewall, second, reply.clone()));
+    assert!(passes(&mut firewall, first, reply.clone()));
+
+    // Sent to every peer, bound to the first one replying
+    let request = ipv4_packet(PROTOCOL_UDP, [10, 0, 0, 1], [10, 0, 0, 3], (5000, 53));
+    let reply = ipv4_packet(PROTOCOL_UDP, [10, 0, 0, 3], [10, 0, 0, 1], (53, 5000));
+    firewall.process(&Packet::Outgoing(request.clone()));
+    assert!(passes(&mut firewall, second, reply.clone()));
+    assert!(!passes(&mut firewall, first, reply.clone()));
+    firewall.process(&Packet::Outgoing(request));
+    assert!(!passes(&mut firewall, first, reply.clone()));
+    assert!(passes(&mut firewall, second, reply));
+}
+
+#[tokio::test]
+async fn replies_pass_only_from_the_peer_the_flow_is_routed_to() {
+    let first = SecretKey::generate().public();
+    let second = SecretKey::generate().public();
+    let (router, _packets) = mpsc::channel(4);
+    let peer_collection = PeerCollection::new(
+        Addr::new(router),
+        Vec::new(),
+        FailbackPolicy::default(),
+        Events::new(),
+    );
+    let route_table = peer_collection.route_table();
+    let addr: Addr<PeerCollectionMessage> = peer_collection.get_addr();
+    let task = tokio::spawn(peer_collection.run());
+    addr.send_message(PeerCollectionMessage::AddRoute(
+        "10.0.1.0/24".parse().unwrap(),
+        first,
+    ))
+    .await;
+    let destination: IpAddr = "10.0.1.5".parse().unwrap();
+    while route_table.lookup(destination).is_none() {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    let mut firewall = firewall("routed", "default_action = \"deny\"").route_table(route_table);
+    let request = ipv4_packet(PROTOCOL_UDP, [10, 0, 0, 1], [10, 0, 1, 5], (5000, 53));
+    let reply = ipv4_packet(PROTOCOL_UDP, [10, 0, 1, 5], [10, 0, 0, 1], (53, 5000));
+    firewall.process(&Packet::Outgoing(request));
+    assert!(!passes(&mut firewall, second, reply.clone()));
+    assert!(passes(&mut firewall, first, reply));
+    task.abort();
+}
@@ -0,0 +1,259 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use iroh_net::{
+    key::SecretKey, magic_endpoint::accept_conn, relay::RelayMode, MagicEndpoint, NodeAddr, NodeId,
+};
+use p2ptun::daemon::{
+    firewall::PortRange,
+    forward::{ForwardGrant, ForwardPolicy, ForwardProtocol, Forwarding, Target},
+    shutdown::Shutdown,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+const ALPN: &[u8] = b"p2ptun-forward-test";
+
+/// Binds an endpoint reachable only through its local address.
+async fn endpoint() -> MagicEndpoint {
+    MagicEndpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .secret_key(SecretKey::generate())
+        .bind(0)
+        .await
+        .unwrap()
+}
+
+/// Two connected nodes, the client forwarding through the server.
+struct Nodes {
+    forwarding: Forwarding,
+    server_id: NodeId,
+    _endpoints: (MagicEndpoint, MagicEndpoint),
+    _shutdown: Shutdown,
+}
+
+/// Connects two nodes, the server serving forwards with the given policy.
+async fn connect(policy: ForwardPolicy) -> Nodes {
+    let shutdown = Shutdown::new();
+    let client = endpoint().await;
+    let server = endpoint().await;
+    let (server_address, _) = server.local_addr().unwrap();
+    let server_address = SocketAddr::from((Ipv4Addr::LOCALHOST, server_address.port()));
+    let server_node_addr = NodeAddr::new(server.node_id()).with_direct_addresses([server_address]);
+
+    let server_forwarding = Forwarding::new(policy, shutdown.listener());
+    let accepting = server.clone();
+    tokio::spawn(async move {
+        let connecting = accepting.accept().await.unwrap();
+        let (node_id, _, connection) = accept_conn(connecting).await.unwrap();
+        server_forwarding.attach(node_id, connection);
+    });
+    let client_forwarding = Forwarding::new(ForwardPolicy::default(), shutdown.listener());
+    let connection = client.connect(server_node_addr, ALPN).await.unwrap();
+    client_forwarding.attach(server.node_id(), connection);
+    Nodes {
+        forwarding: client_forwarding,
+        server_id: server.node_id(),
+        _endpoints: (client, server),
+        _shutdown: shutdown,
+    }
+}
+
+/// Returns a grant of the port of `address` on localhost.
+fn grant(protocol: ForwardProtocol, address: SocketAddr) -> ForwardGrant {
+    ForwardGrant {
+        peer: None,
+        protocol: Some(protocol),
+        host: "127.0.0.1".to_string(),
+        ports: PortRange {
+            first: address.port(),
+            last: address.port(),
+        },
+    }
+}
+
+/// Starts a TCP server echoing everything back.
+async fn start_tcp_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    address
+}
+
+#[tokio::test]
+async fn tcp_forward_reaches_a_granted_target() {
+    let echo_server = start_tcp_echo_server().await;
+    let policy = ForwardPolicy {
+        grants: vec![grant(ForwardProtocol::Tcp, echo_server)],
+    };
+    let nodes = connect(policy).await;
+    let forward = nodes
+        .forwarding
+        .forward(
+            ForwardProtocol::Tcp,
+            "127.0.0.1:0".parse().unwrap(),
+            nodes.server_id,
+            echo_server.to_string().parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let mut client = TcpStream::connect(forward.listen).await.unwrap();
+    client.write_all(b"hello forward").await.unwrap();
+    let mut echoed = [0u8; 13];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello forward");
+}
+
+#[tokio::test]
+async fn tcp_forward_to_a_target_not_granted_is_refused() {
+    let echo_server = start_tcp_echo_server().await;
+    let nodes = connect(ForwardPolicy::default()).await;
+    let forward = nodes
+        .forwarding
+        .forward(
+            ForwardProtocol::Tcp,
+            "127.0.0.1:0".parse().unwrap(),
+            nodes.server_id,
+            echo_server.to_string().parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let mut client = TcpStream::connect(forward.listen).await.unwrap();
+    let mut buffer = [0u8; 1];
+    let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buffer))
+        .await
+        .unwrap();
+    assert!(
+        matches!(read, Ok(0) | Err(_)),
+        "the client should be disconnected"
+    );
+}
+
+#[tokio::test]
+async fn udp_forward_carries_replies_back() {
+    let echo_server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let echo_address = echo_server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let mut buffer = [0u8; 1500];
+        while let Ok((size, address)) = echo_server.recv_from(&mut buffer).await {
+            let _ = echo_server.send_to(&buffer[..size], address).await;
+        }
+    });
+    let policy = ForwardPolicy {
+        grants: vec![grant(ForwardProtocol::Udp, echo_address)],
+    };
+    let nodes = connect(policy).await;
+    let forward = nodes
+        .forwarding
+        .forward(
+            ForwardProtocol::Udp,
+            "127.0.0.1:0".parse().unwrap(),
+            nodes.server_id,
+            echo_address.to_string().parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(forward.listen).await.unwrap();
+    let mut buffer = [0u8; 1500];
+    // Datagrams are unreliable, so the request is repeated until a reply arrives
+    let size = tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            client.send(b"hello datagram").await.unwrap();
+            if let Ok(Ok(size)) =
+                tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buffer)).await
+            {
+                return size;
+            }
+        }
+    })
+    .await
+    .unwrap();
+    assert_eq!(&buffer[..size], b"hello datagram");
+}
+
+#[tokio::test]
+async fn udp_replies_from_other_peers_are_dropped() {
+    let shutdown = Shutdown::new();
+    let client = endpoint().await;
+    let server = endpoint().await;
+    let attacker = endpoint().await;
+    let local_addr = |endpoint: &MagicEndpoint| {
+        let (address, _) = endpoint.local_addr().unwrap();
+        NodeAddr::new(endpoint.node_id())
+            .with_direct_addresses([SocketAddr::from((Ipv4Addr::LOCALHOST, address.port()))])
+    };
+
+    let forwarding = Forwarding::new(ForwardPolicy::default(), shutdown.listener());
+    let accepting = client.clone();
+    let attached = forwarding.clone();
+    tokio::spawn(async move {
+        let connecting = accepting.accept().await.unwrap();
+        let (node_id, _, connection) = accept_conn(connecting).await.unwrap();
+        attached.attach(node_id, connection);
+    });
+    let accepting = server.clone();
+    tokio::spawn(async move {
+        // Keeps the connection without serving anything
+        let connecting = accepting.accept().await.unwrap();
+        let connection = connecting.await.unwrap();
+        connection.closed().await;
+    });
+    let connection = client.connect(local_addr(&server), ALPN).await.unwrap();
+    forwarding.attach(server.node_id(), connection);
+    let forward = forwarding
+        .forward(
+            ForwardProtocol::Udp,
+            "127.0.0.1:0".parse().unwrap(),
+            server.node_id(),
+            "127.0.0.1:9".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+
+    // The first flow of the forward goes to the server
+    let local_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    local_client.connect(forward.listen).await.unwrap();
+    local_client.send(b"request").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Another peer replies on that flow
+    let spoofing = attacker.connect(local_addr(&client), ALPN).await.unwrap();
+    let mut buffer = [0u8; 1500];
+    for _ in 0..5 {
+        spoofing
+            .send_datagram(Bytes::from_static(b"\x01\x00\x00\x00\x00spoofed"))
+            .unwrap();
+        let received =
+            tokio::time::timeout(Duration::from_millis(100), local_client.recv(&mut buffer)).await;
+        assert!(received.is_err(), "the spoofed reply should be dropped");
+    }
+}
+
+#[test]
+fn targets_too_long_for_a_datagram_are_rejected() {
+    let host = "a".repeat(249);
+    assert!(format!("{}:65535", host).parse::<Target>().is_ok());
+    assert!(format!("{}a:65535", host).parse::<Target>().is_err());
+    // Hosts with colons are counted with their brackets
+    assert!(format!("{}:1", "a".repeat(252)).parse::<Target>().is_ok());
+    assert!(format!("[{}:a]:1", "a".repeat(250))
+        .parse::<Target>()
+        .is_err());
+}
@@ -0,0 +1,258 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use ipnet::IpNet;
+use iroh_net::{
+    key::SecretKey, magic_endpoint::accept_conn, relay::RelayMode, MagicEndpoint, NodeAddr, NodeId,
+};
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, Mailbox, OverflowPolicy},
+        peer::Peer,
+        peer_collection::{
+            FailbackPolicy, GatewayGroupInfo, PeerCollection, PeerCollectionMessage,
+        },
+        Actor, Addr,
+    },
+    events::{DisconnectReason, Events},
+    packet::Packet,
+};
+use quinn::{RecvStream, SendStream};
+
+const ALPN: &[u8] = b"p2ptun-gateway-test";
+const PREFIX: &str = "192.168.1.0/24";
+
+/// Binds an endpoint reachable only through its local address.
+async fn endpoint() -> MagicEndpoint {
+    MagicEndpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .secret_key(SecretKey::generate())
+        .bind(0)
+        .await
+        .unwrap()
+}
+
+/// A peer connected through the loopback interface.
+struct TestPeer {
+    node_id: NodeId,
+    _connection: (MagicEndpoint, MagicEndpoint, SendStream, RecvStream),
+}
+
+/// A running [PeerCollection].
+struct Collection {
+    messages: Addr<PeerCollectionMessage>,
+    packets: Addr<Packet>,
+    _router: Mailbox<Packet>,
+}
+
+impl Collection {
+    fn start(failback: FailbackPolicy) -> Self {
+        let (router, router_mailbox) = mailbox(16, OverflowPolicy::DropNewest);
+        let peer_collection = PeerCollection::new(
+            router,
+            vec!["10.0.0.0/24".parse().unwrap()],
+            failback,
+            Events::new(),
+        );
+        let messages = Actor::<PeerCollectionMessage>::get_addr(&peer_collection);
+        let packets = Actor::<Packet>::get_addr(&peer_collection);
+        tokio::spawn(peer_collection.run());
+        Self {
+            messages,
+            packets,
+            _router: router_mailbox,
+        }
+    }
+
+    /// Connects a new peer, advertising [PREFIX] with the given priority.
+    async fn add_gateway(&self, priority: u32) -> TestPeer {
+        let client = endpoint().await;
+        let server = endpoint().await;
+        let (server_address, _) = server.local_addr().unwrap();
+        let server_address = SocketAddr::from((Ipv4Addr::LOCALHOST, server_address.port()));
+        let server_node_addr =
+            NodeAddr::new(server.node_id()).with_direct_addresses([server_address]);
+
+        let connection = client.connect(server_node_addr, ALPN).await.unwrap();
+        let (mut send_stream, recv_stream) = connection.open_bi().await.unwrap();
+        // The stream is only accepted once data is sent on it
+        send_stream.write_all(&[0]).await.unwrap();
+        let (node_id, _, server_connection) =
+            accept_conn(server.accept().await.unwrap()).await.unwrap();
+        let (peer_send_stream, peer_recv_stream) = server_connection.accept_bi().await.unwrap();
+        let peer = Peer::new(
+            node_id,
+            self.packets.clone(),
+            peer_send_stream,
+            peer_recv_stream,
+            1500,
+        );
+        self.messages
+            .send_message(PeerCollectionMessage::AddPeer(node_id, peer))
+            .await;
+        self.messages
+            .send_message(PeerCollectionMessage::AddGateway(
+                PREFIX.parse().unwrap(),
+                node_id,
+                priority,
+            ))
+            .await;
+        TestPeer {
+            node_id,
+            _connection: (client, server, send_stream, recv_stream),
+        }
+    }
+
+    async fn set_responsive(&self, peer: &TestPeer, responsive: bool) {
+        self.messages
+            .send_message(PeerCollectionMessage::SetResponsive(
+                peer.node_id,
+                responsive,
+            ))
+            .await;
+    }
+
+    /// Returns the gateways of [PREFIX].
+    async fn group(&self) -> GatewayGroupInfo {
+        let prefix: IpNet = PREFIX.parse().unwrap();
+        self.messages
+            .ask(PeerCollectionMessage::ListGateways)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|group| group.prefix == prefix)
+            .unwrap()
+    }
+}
+
+#[tokio::test]
+async fn gateway_with_the_highest_priority_is_primary() {
+    let collection = Collection::start(FailbackPolicy::Immediate);
+    let low = collection.add_gateway(1).await;
+    let high = collection.add_gateway(2).await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, Some(high.node_id));
+    assert_eq!(group.failovers, 1);
+
+    // A gateway with a lower priority doesn't take over
+    let _lower = collection.add_gateway(0).await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, Some(high.node_id));
+    assert_eq!(group.failovers, 1);
+    assert!(group
+        .gateways
+        .iter()
+        .any(|gateway| gateway.node_id == low.node_id && gateway.priority == 1));
+}
+
+#[tokio::test]
+async fn traffic_fails_over_when_the_primary_stops_answering_keepalives() {
+    let collection = Collection::start(FailbackPolicy::Never);
+    let primary = collection.add_gateway(2).await;
+    let backup = collection.add_gateway(1).await;
+    assert_eq!(collection.group().await.primary, Some(primary.node_id));
+
+    collection.set_responsive(&primary, false).await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, Some(backup.node_id));
+    assert_eq!(group.failovers, 1);
+
+    // The primary is kept while no other gateway answers either
+    collection.set_responsive(&backup, false).await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, Some(backup.node_id));
+    assert_eq!(group.failovers, 1);
+}
+
+#[tokio::test]
+async fn primary_is_kept_without_failback() {
+    let collection = Collection::start(FailbackPolicy::Never);
+    let preferred = collection.add_gateway(2).await;
+    let backup = collection.add_gateway(1).await;
+    collection.set_responsive(&preferred, false).await;
+    collection.set_responsive(&preferred, true).await;
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, Some(backup.node_id));
+    assert_eq!(group.failovers, 1);
+}
+
+#[tokio::test]
+async fn immediate_failback_moves_back_right_away() {
+    let collection = Collection::start(FailbackPolicy::Immediate);
+    let preferred = collection.add_gateway(2).await;
+    let _backup = collection.add_gateway(1).await;
+    collection.set_responsive(&preferred, false).await;
+    collection.set_responsive(&preferred, true).await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, Some(preferred.node_id));
+    assert_eq!(group.failovers, 2);
+}
+
+#[tokio::test]
+async fn delayed_failback_waits_for_the_preferred_gateway() {
+    let collection = Collection::start(FailbackPolicy::Delayed(Duration::from_millis(500)));
+    let preferred = collection.add_gateway(2).await;
+    let backup = collection.add_gateway(1).await;
+    collection.set_responsive(&preferred, false).await;
+    collection.set_responsive(&preferred, true).await;
+    assert_eq!(collection.group().await.primary, Some(backup.node_id));
+
+    // Elected again by the periodic election
+    tokio::time::sleep(Duration::from_millis(2500)).await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, Some(preferred.node_id));
+    assert_eq!(group.failovers, 2);
+}
+
+#[tokio::test]
+async fn losing_the_last_gateway_is_not_a_failover() {
+    let collection = Collection::start(FailbackPolicy::Immediate);
+    let gateway = collection.add_gateway(1).await;
+    collection
+        .messages
+        .send_message(PeerCollectionMessage::DisconnectPeer(gateway.node_id))
+        .await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, None);
+    assert_eq!(group.failovers, 0);
+
+    let recovered = collection.add_gateway(1).await;
+    let group = collection.group().await;
+    assert_eq!(group.primary, Some(recovered.node_id));
+    assert_eq!(group.failovers, 0);
+    assert_eq!(
+        collection
+            .messages
+            .ask(PeerCollectionMessage::GetFailovers)
+            .await
+            .unwrap(),
+        0
+    );
+}
+
+#[tokio::test]
+async fn stops_of_replaced_peers_are_ignored() {
+    let collection = Collection::start(FailbackPolicy::Immediate);
+    let gateway = collection.add_gateway(1).await;
+    // Sent by a peer of the same node the current one replaced
+    collection
+        .messages
+        .send_message(PeerCollectionMessage::PeerStopped(
+            gateway.node_id,
+            u64::MAX,
+            DisconnectReason::Closed,
+        ))
+        .await;
+
+    let peers = collection
+        .messages
+        .ask(PeerCollectionMessage::ListPeers)
+        .await
+        .unwrap();
+    assert!(peers.iter().any(|peer| peer.node_id == gateway.node_id));
+    assert_eq!(collection.group().await.primary, Some(gateway.node_id));
+}
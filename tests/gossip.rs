@@ -0,0 +1,231 @@
+use std::{
+    collections::HashSet,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use iroh_net::{key::SecretKey, ticket::NodeTicket, NodeAddr, NodeId};
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, OverflowPolicy},
+        peer_source::PeerSourceMessage,
+    },
+    admission::AdmissionPolicy,
+    advertisements::{Advertisement, Advertisements},
+    events::{Event, Events},
+    gossip::{Gossip, GossipConfig, SignedNodeAddr, MAX_MEMBERS, MEMBER_TTL},
+    invite::{InviteConfig, Invites},
+    rotation::{RotationConfig, Rotations},
+};
+use tokio::sync::mpsc;
+
+fn node_addr(secret_key: &SecretKey) -> NodeAddr {
+    NodeAddr::new(secret_key.public()).with_direct_addresses(["192.0.2.1:4433".parse().unwrap()])
+}
+
+#[test]
+fn signed_addresses_are_verified() {
+    let secret_key = SecretKey::generate();
+    let signed = SignedNodeAddr::sign(&secret_key, node_addr(&secret_key), 1_700_000_000);
+    assert!(signed.verify());
+}
+
+#[test]
+fn forged_addresses_are_rejected() {
+    let secret_key = SecretKey::generate();
+    let mut signed = SignedNodeAddr::sign(&secret_key, node_addr(&secret_key), 1_700_000_000);
+    signed.node_addr = signed
+        .node_addr
+        .with_direct_addresses(["198.51.100.7:4433".parse().unwrap()]);
+    assert!(!signed.verify());
+
+    // Signed by another node than the one the addresses belong to
+    let forger = SecretKey::generate();
+    let forged = SignedNodeAddr::sign(&forger, node_addr(&secret_key), 1_700_000_000);
+    assert!(!forged.verify());
+
+    let mut reissued = SignedNodeAddr::sign(&secret_key, node_addr(&secret_key), 1_700_000_000);
+    reissued.issued_at += 1;
+    assert!(!reissued.verify());
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// A running [Gossip] of a node.
+struct Node {
+    events: Events,
+    advertisements: Advertisements,
+    /// The members the gossip dialed.
+    dialed: mpsc::UnboundedReceiver<NodeId>,
+}
+
+impl Node {
+    /// Starts the gossip of a new node, admitting `members`, or every node when [None].
+    fn start(members: Option<HashSet<NodeId>>, max_dials: usize) -> Self {
+        let secret_key = SecretKey::generate();
+        let events = Events::new();
+        let advertisements = Advertisements::new(Advertisement::default(), None, events.clone());
+        let invites = Invites::new(
+            InviteConfig::default(),
+            secret_key.clone(),
+            None,
+            events.clone(),
+        )
+        .unwrap();
+        let rotations =
+            Rotations::new(RotationConfig::default(), secret_key.clone(), invites, None);
+
+        // Answers for the peer source, recording the dials
+        let (peer_source, mut peer_source_mailbox) = mailbox(16, OverflowPolicy::Block);
+        let (dialed_sender, dialed) = mpsc::unbounded_channel();
+        let ticket = NodeTicket::new(node_addr(&secret_key)).unwrap();
+        tokio::spawn(async move {
+            while let Some(message) = peer_source_mailbox.recv().await {
+                match message {
+                    PeerSourceMessage::GetTicket(reply) => reply.send(Ok(ticket.clone())),
+                    PeerSourceMessage::DialPeer(node_addr) => {
+                        let _ = dialed_sender.send(node_addr.node_id);
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        let config = GossipConfig {
+            enabled: true,
+            interval: Duration::from_millis(100),
+            max_dials,
+        };
+        let gossip = Gossip::new(
+            config,
+            secret_key,
+            AdmissionPolicy { members },
+            rotations,
+            advertisements.clone(),
+            peer_source,
+            &events,
+        );
+        tokio::spawn(gossip.run());
+        Self {
+            events,
+            advertisements,
+            dialed,
+        }
+    }
+
+    fn gossip(&self, from: NodeId, members: Vec<SignedNodeAddr>) {
+        self.events.emit(Event::Advertised {
+            node_id: from,
+            advertisement: Advertisement {
+                members,
+                ..Default::default()
+            },
+        });
+    }
+
+    /// Returns the members gossiped by this node once they settled, itself included.
+    async fn members(&self) -> HashSet<NodeId> {
+        let mut previous = HashSet::new();
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(150)).await;
+            let members: HashSet<NodeId> = self
+                .advertisements
+                .own()
+                .members
+                .iter()
+                .map(|member| member.node_addr.node_id)
+                .collect();
+            if !members.is_empty() && members == previous {
+                return members;
+            }
+            previous = members;
+        }
+        previous
+    }
+}
+
+fn signed(issued_at: u64) -> SignedNodeAddr {
+    let secret_key = SecretKey::generate();
+    SignedNodeAddr::sign(&secret_key, node_addr(&secret_key), issued_at)
+}
+
+#[tokio::test]
+async fn only_members_gossiped_by_admitted_peers_are_learned() {
+    let peer = SecretKey::generate().public();
+    let stranger = SecretKey::generate().public();
+    let member = signed(now());
+    let not_admitted = signed(now());
+    let node = Node::start(Some(HashSet::from([peer, member.node_addr.node_id])), 8);
+
+    node.gossip(stranger, vec![member.clone()]);
+    assert!(!node.members().await.contains(&member.node_addr.node_id));
+
+    node.gossip(peer, vec![member.clone(), not_admitted.clone()]);
+    let members = node.members().await;
+    assert!(members.contains(&member.node_addr.node_id));
+    assert!(!members.contains(&not_admitted.node_addr.node_id));
+}
+
+#[tokio::test]
+async fn expired_and_future_addresses_are_not_learned() {
+    let peer = SecretKey::generate().public();
+    let fresh = signed(now());
+    let expired = signed(now() - MEMBER_TTL.as_secs() - 1);
+    let future = signed(now() + 24 * 60 * 60);
+    let node = Node::start(None, 8);
+
+    node.gossip(peer, vec![fresh.clone(), expired.clone(), future.clone()]);
+    let members = node.members().await;
+    assert!(members.contains(&fresh.node_addr.node_id));
+    assert!(!members.contains(&expired.node_addr.node_id));
+    assert!(!members.contains(&future.node_addr.node_id));
+}
+
+#[tokio::test]
+async fn known_members_are_bounded() {
+    let peer = SecretKey::generate().public();
+    let node = Node::start(None, 0);
+    let gossiped: Vec<SignedNodeAddr> = (0..MAX_MEMBERS + 10).map(|_| signed(now())).collect();
+    node.gossip(peer, gossiped[..MAX_MEMBERS].to_vec());
+    node.gossip(peer, gossiped[MAX_MEMBERS..].to_vec());
+    let members = node.members().await;
+    // This node is a member too
+    assert!(members.len() <= MAX_MEMBERS + 1);
+    assert!(gossiped[MAX_MEMBERS..]
+        .iter()
+        .all(|member| !members.contains(&member.node_addr.node_id)));
+}
+
+#[tokio::test]
+async fn dials_are_limited_per_interval() {
+    let peer = SecretKey::generate().public();
+    let mut node = Node::start(None, 2);
+    let gossiped: Vec<SignedNodeAddr> = (0..5).map(|_| signed(now())).collect();
+    node.gossip(peer, gossiped.clone());
+
+    let mut dialed = HashSet::new();
+    for expected in [2, 4, 5] {
+        while dialed.len() < expected {
+            let node_id = tokio::time::timeout(Duration::from_secs(2), node.dialed.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            dialed.insert(node_id);
+        }
+        // No more than the limit is dialed before the next interval
+        assert!(node.dialed.try_recv().is_err());
+    }
+    let expected: HashSet<NodeId> = gossiped
+        .iter()
+        .map(|member| member.node_addr.node_id)
+        .collect();
+    assert_eq!(dialed, expected);
+
+    // Members aren't dialed again right away
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(node.dialed.try_recv().is_err());
+}
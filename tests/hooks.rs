@@ -0,0 +1,252 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use iroh_net::key::SecretKey;
+use p2ptun::daemon::{
+    events::{self, DisconnectReason, Event, Events},
+    hooks::{HookConfig, Hooks, PeerDetails},
+    state::{NetworkState, Record, RecordKey, SignedEntry, StateConfig},
+};
+
+/// Writes a hook appending its environment to `output`, and returns its path.
+fn recording_hook(name: &str, output: &Path) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("p2ptun-hook-{}-{}", name, std::process::id()));
+    let script = format!(
+        "#!/bin/sh\nenv | grep '^P2PTUN_' | sort >> '{}'\n",
+        output.display()
+    );
+    std::fs::write(&path, script).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+fn output_path(name: &str) -> PathBuf {
+    let path =
+        std::env::temp_dir().join(format!("p2ptun-hook-{}-{}.out", name, std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Waits until `output` has `lines` lines, and returns them.
+async fn read_lines(output: &Path, lines: usize) -> Vec<String> {
+    for _ in 0..100 {
+        if let Ok(content) = std::fs::read_to_string(output) {
+            let read: Vec<String> = content.lines().map(str::to_string).collect();
+            if read.len() >= lines {
+                return read;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    panic!("the hook didn't write {} lines to {:?}", lines, output);
+}
+
+#[tokio::test]
+async fn peer_hooks_receive_the_peer_details() {
+    let node_id = SecretKey::generate().public();
+    let peer = SecretKey::generate().public();
+    let up_output = output_path("up");
+    let down_output = output_path("down");
+    let config = HookConfig {
+        peer_up: vec![recording_hook("up", &up_output)],
+        peer_down: vec![recording_hook("down", &down_output)],
+        peers: HashMap::from([(
+            peer,
+            PeerDetails {
+                name: Some("laptop".to_string()),
+                address: Some("10.0.0.2".parse().unwrap()),
+            },
+        )]),
+        ..Default::default()
+    };
+    let events = Events::new();
+    let hooks = tokio::spawn(Hooks::new(config, node_id).run(events.subscribe()));
+
+    events.emit(Event::PathChanged {
+        node_id: peer,
+        path: events::Path::Direct {
+            address: "192.0.2.1:4433".parse().unwrap(),
+        },
+    });
+    events.emit(Event::PeerConnected { node_id: peer });
+    assert_eq!(
+        read_lines(&up_output, 6).await,
+        vec![
+            "P2PTUN_EVENT=peer_up".to_string(),
+            format!("P2PTUN_NODE_ID={}", node_id),
+            "P2PTUN_PEER_ADDRESS=10.0.0.2".to_string(),
+            format!("P2PTUN_PEER_ID={}", peer),
+            "P2PTUN_PEER_NAME=laptop".to_string(),
+            "P2PTUN_PEER_PATH=direct".to_string(),
+        ]
+    );
+
+    events.emit(Event::PeerDisconnected {
+        node_id: peer,
+        reason: DisconnectReason::Requested,
+    });
+    let lines = read_lines(&down_output, 7).await;
+    assert!(lines.contains(&"P2PTUN_EVENT=peer_down".to_string()));
+    assert!(lines.contains(&"P2PTUN_DISCONNECT_REASON=requested".to_string()));
+    hooks.abort();
+}
+
+#[tokio::test]
+async fn stopping_waits_for_the_stop_hooks() {
+    let output = output_path("stop");
+    let config = HookConfig {
+        daemon_stop: vec![recording_hook("stop", &output)],
+        ..Default::default()
+    };
+    let events = Events::new();
+    let hooks =
+        tokio::spawn(Hooks::new(config, SecretKey::generate().public()).run(events.subscribe()));
+
+    events.emit(Event::DaemonStopping);
+    tokio::time::timeout(Duration::from_secs(5), hooks)
+        .await
+        .unwrap()
+        .unwrap();
+    let content = std::fs::read_to_string(&output).unwrap();
+    assert!(content
+        .lines()
+        .any(|line| line == "P2PTUN_EVENT=daemon_stop"));
+}
+
+#[tokio::test]
+async fn hooks_running_too_long_are_killed() {
+    let hook = std::env::temp_dir().join(format!("p2ptun-hook-slow-{}", std::process::id()));
+    std::fs::write(&hook, "#!/bin/sh\nsleep 30\n").unwrap();
+    std::fs::set_permissions(&hook, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let config = HookConfig {
+        daemon_stop: vec![hook],
+        timeout: Duration::from_millis(100),
+        ..Default::default()
+    };
+    let events = Events::new();
+    let hooks =
+        tokio::spawn(Hooks::new(config, SecretKey::generate().public()).run(events.subscribe()));
+
+    events.emit(Event::DaemonStopping);
+    tokio::time::timeout(Duration::from_secs(5), hooks)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+/// Writes a hook appending its event to `output` after sleeping for `sleep`, and returns its path.
+fn sleeping_hook(name: &str, sleep: &str, output: &Path) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("p2ptun-hook-{}-{}", name, std::process::id()));
+    let script = format!(
+        "#!/bin/sh\nsleep {}\necho $P2PTUN_EVENT >> '{}'\n",
+        sleep,
+        output.display()
+    );
+    std::fs::write(&path, script).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    path
+}
+
+#[tokio::test]
+async fn hooks_of_a_peer_run_in_the_order_of_its_events() {
+    let peer = SecretKey::generate().public();
+    let output = output_path("order");
+    let config = HookConfig {
+        peer_up: vec![sleeping_hook("order-up", "0.3", &output)],
+        peer_down: vec![sleeping_hook("order-down", "0", &output)],
+        ..Default::default()
+    };
+    let events = Events::new();
+    let hooks =
+        tokio::spawn(Hooks::new(config, SecretKey::generate().public()).run(events.subscribe()));
+
+    events.emit(Event::PeerConnected { node_id: peer });
+    events.emit(Event::PeerDisconnected {
+        node_id: peer,
+        reason: DisconnectReason::Closed,
+    });
+    assert_eq!(read_lines(&output, 2).await, vec!["peer_up", "peer_down"]);
+    hooks.abort();
+}
+
+#[tokio::test]
+async fn peer_details_missing_from_the_config_come_from_the_state() {
+    let authority = SecretKey::generate();
+    let peer = SecretKey::generate();
+    let address: IpAddr = "10.0.0.3".parse().unwrap();
+    let state = NetworkState::new(
+        StateConfig {
+            path: None,
+            authorities: [authority.public()].into(),
+        },
+        SecretKey::generate(),
+        Events::new(),
+    )
+    .unwrap();
+    let entries = [
+        SignedEntry::sign(
+            &authority,
+            RecordKey::Member {
+                node_id: peer.public(),
+            },
+            Some(Record::Member {
+                node_id: peer.public(),
+            }),
+            1,
+        ),
+        SignedEntry::sign(
+            &peer,
+            RecordKey::Name {
+                node_id: peer.public(),
+            },
+            Some(Record::Name {
+                node_id: peer.public(),
+                name: "desktop".to_string(),
+            }),
+            2,
+        ),
+        SignedEntry::sign(
+            &peer,
+            RecordKey::Address { address },
+            Some(Record::Address {
+                address,
+                node_id: peer.public(),
+            }),
+            3,
+        ),
+    ];
+    state.receive(peer.public(), &serde_json::to_vec(&entries).unwrap());
+
+    let output = output_path("state");
+    let config = HookConfig {
+        peer_up: vec![recording_hook("state", &output)],
+        // The configured name wins over the one of the state
+        peers: HashMap::from([(
+            peer.public(),
+            PeerDetails {
+                name: Some("laptop".to_string()),
+                address: None,
+            },
+        )]),
+        ..Default::default()
+    };
+    let events = Events::new();
+    let hooks = tokio::spawn(
+        Hooks::new(config, SecretKey::generate().public())
+            .state(state)
+            .run(events.subscribe()),
+    );
+
+    events.emit(Event::PeerConnected {
+        node_id: peer.public(),
+    });
+    let lines = read_lines(&output, 6).await;
+    assert!(lines.contains(&"P2PTUN_PEER_NAME=laptop".to_string()));
+    assert!(lines.contains(&"P2PTUN_PEER_ADDRESS=10.0.0.3".to_string()));
+    hooks.abort();
+}
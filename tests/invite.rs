@@ -0,0 +1,386 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use iroh_net::{key::SecretKey, relay::RelayMode, MagicEndpoint, NodeAddr, NodeId};
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, Mailbox, OverflowPolicy},
+        peer_collection::PeerCollectionMessage,
+        peer_source::{PeerSource, PeerSourceMessage, ALPN},
+        Actor, Addr,
+    },
+    admission::AdmissionPolicy,
+    advertisements::{Advertisement, Advertisements},
+    events::Events,
+    forward::{ForwardPolicy, Forwarding},
+    invite::{InviteConfig, InviteError, Invites, SignedInvite},
+    packet::Packet,
+    revocation::Revocations,
+    rotation::{RotationConfig, Rotations},
+    shutdown::Shutdown,
+    state::{NetworkState, Record, StateConfig},
+    DaemonError,
+};
+
+/// Returns the state of the node of `secret_key`, with `admin` as its only authority.
+fn state(secret_key: &SecretKey, admin: NodeId) -> NetworkState {
+    NetworkState::new(
+        StateConfig {
+            path: None,
+            authorities: [admin].into(),
+        },
+        secret_key.clone(),
+        Events::new(),
+    )
+    .unwrap()
+}
+
+fn invites(secret_key: &SecretKey) -> Invites {
+    Invites::new(
+        InviteConfig::default(),
+        secret_key.clone(),
+        Some(state(secret_key, secret_key.public())),
+        Events::new(),
+    )
+    .unwrap()
+}
+
+fn issue(invites: &Invites, secret_key: &SecretKey, max_uses: u32) -> SignedInvite {
+    invites
+        .issue(
+            NodeAddr::new(secret_key.public()),
+            Duration::from_secs(3600),
+            max_uses,
+            vec!["laptops".to_string()],
+            Some("10.0.0.7".parse().unwrap()),
+        )
+        .unwrap()
+}
+
+#[test]
+fn invites_are_written_as_text() {
+    let admin = SecretKey::generate();
+    let invite = issue(&invites(&admin), &admin, 1);
+    let parsed: SignedInvite = invite.to_string().parse().unwrap();
+    assert_eq!(parsed, invite);
+    assert!(parsed.verify());
+}
+
+#[test]
+fn invites_are_checked() {
+    let admin = SecretKey::generate();
+    let invite = issue(&invites(&admin), &admin, 1);
+    let now = invite.invite.expires_at - 1;
+    assert_eq!(invite.check("p2ptun", now), Ok(()));
+    assert_eq!(invite.check("other", now), Err(InviteError::WrongNetwork));
+    assert_eq!(
+        invite.check("p2ptun", invite.invite.expires_at),
+        Err(InviteError::Expired)
+    );
+
+    let mut forged = invite.clone();
+    forged.invite.max_uses = 100;
+    assert_eq!(forged.check("p2ptun", now), Err(InviteError::Forged));
+}
+
+#[test]
+fn only_admins_issue_invites() {
+    let admin = SecretKey::generate();
+    let node = SecretKey::generate();
+    let invites = Invites::new(
+        InviteConfig::default(),
+        node.clone(),
+        Some(state(&node, admin.public())),
+        Events::new(),
+    )
+    .unwrap();
+    let issued = invites.issue(
+        NodeAddr::new(node.public()),
+        Duration::from_secs(3600),
+        1,
+        Vec::new(),
+        None,
+    );
+    assert!(matches!(issued, Err(DaemonError::NotAnAdmin)));
+
+    // Nor without the state naming the admins
+    let invites =
+        Invites::new(InviteConfig::default(), admin.clone(), None, Events::new()).unwrap();
+    let issued = invites.issue(
+        NodeAddr::new(admin.public()),
+        Duration::from_secs(3600),
+        1,
+        Vec::new(),
+        None,
+    );
+    assert!(matches!(issued, Err(DaemonError::NotAnAdmin)));
+}
+
+#[test]
+fn invites_admit_up_to_their_uses() {
+    let admin = SecretKey::generate();
+    let invites = invites(&admin);
+    let invite = issue(&invites, &admin, 2);
+    let first = SecretKey::generate().public();
+    let second = SecretKey::generate().public();
+    let third = SecretKey::generate().public();
+
+    assert!(!invites.admits(first));
+    invites.redeem(first, &invite).unwrap();
+    invites.redeem(second, &invite).unwrap();
+    assert!(matches!(
+        invites.redeem(third, &invite),
+        Err(DaemonError::InvalidInvite(InviteError::UsedUp))
+    ));
+    assert!(invites.admits(first) && invites.admits(second) && !invites.admits(third));
+    assert_eq!(invites.tags(first), vec!["laptops".to_string()]);
+    // Used up invites aren't outstanding anymore
+    assert!(invites.outstanding().is_empty());
+}
+
+#[test]
+fn revoked_and_foreign_invites_are_refused() {
+    let admin = SecretKey::generate();
+    let invites = invites(&admin);
+    let invite = issue(&invites, &admin, 1);
+    invites.revoke(invite.invite.id).unwrap();
+    let node = SecretKey::generate().public();
+    assert!(matches!(
+        invites.redeem(node, &invite),
+        Err(DaemonError::InvalidInvite(InviteError::Revoked))
+    ));
+
+    let other = SecretKey::generate();
+    let foreign = issue(&self::invites(&other), &other, 1);
+    assert!(matches!(
+        invites.redeem(node, &foreign),
+        Err(DaemonError::InvalidInvite(InviteError::Unknown))
+    ));
+    assert!(!invites.admits(node));
+}
+
+#[test]
+fn node_is_not_admitted_when_the_invites_can_not_be_persisted() {
+    let admin = SecretKey::generate();
+    let directory = std::env::temp_dir().join(format!("p2ptun-invites-{}", admin.public()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let state = state(&admin, admin.public());
+    let invites = Invites::new(
+        InviteConfig {
+            path: Some(directory.join("invites.json")),
+            ..InviteConfig::default()
+        },
+        admin.clone(),
+        Some(state.clone()),
+        Events::new(),
+    )
+    .unwrap();
+    let invite = issue(&invites, &admin, 1);
+    std::fs::remove_dir_all(&directory).unwrap();
+
+    let node = SecretKey::generate().public();
+    assert!(matches!(
+        invites.redeem(node, &invite),
+        Err(DaemonError::IoError(_))
+    ));
+    assert!(!invites.admits(node));
+    assert!(!state.is_member(node));
+}
+
+/// The peer collection of a [Node], reporting the peers added to it.
+struct Collection {
+    messages: Addr<PeerCollectionMessage>,
+    packets: Addr<Packet>,
+}
+
+impl Actor<PeerCollectionMessage> for Collection {
+    fn get_addr(&self) -> Addr<PeerCollectionMessage> {
+        self.messages.clone()
+    }
+}
+
+impl Actor<Packet> for Collection {
+    fn get_addr(&self) -> Addr<Packet> {
+        self.packets.clone()
+    }
+}
+
+/// A node reachable only through the loopback interface.
+struct Node {
+    node_id: NodeId,
+    address: SocketAddr,
+    peer_source: Addr<PeerSourceMessage>,
+    invites: Invites,
+    state: NetworkState,
+    peers: Mailbox<PeerCollectionMessage>,
+    _packets: Mailbox<Packet>,
+    _shutdown: Shutdown,
+}
+
+impl Node {
+    /// Starts a node admitting `members`, with `admin` as the authority of its state.
+    async fn start(secret_key: SecretKey, admin: NodeId, members: &[NodeId]) -> Self {
+        let events = Events::new();
+        let shutdown = Shutdown::new();
+        let state = state(&secret_key, admin);
+        let advertisements = Advertisements::new(
+            Advertisement::default(),
+            Some(state.clone()),
+            events.clone(),
+        );
+        let invites = Invites::new(
+            InviteConfig::default(),
+            secret_key.clone(),
+            Some(state.clone()),
+            events.clone(),
+        )
+        .unwrap();
+        let revocations = Revocations::new(Some(state.clone()));
+        let rotations = Rotations::new(
+            RotationConfig::default(),
+            secret_key.clone(),
+            invites.clone(),
+            Some(state.clone()),
+        );
+        let endpoint = MagicEndpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .secret_key(secret_key.clone())
+            .bind(0)
+            .await
+            .unwrap();
+        let (local_address, _) = endpoint.local_addr().unwrap();
+        let (messages, peers) = mailbox(64, OverflowPolicy::Block);
+        let (packets, packets_mailbox) = mailbox(16, OverflowPolicy::DropNewest);
+        let peer_source = PeerSource::from_endpoint(
+            &Collection { messages, packets },
+            endpoint,
+            events,
+            Forwarding::new(ForwardPolicy::default(), shutdown.listener()),
+            advertisements,
+            AdmissionPolicy {
+                members: Some(members.iter().copied().collect()),
+            },
+            invites.clone(),
+            revocations,
+            rotations,
+            None,
+        );
+        let peer_source_addr = peer_source.get_addr();
+        tokio::spawn(peer_source.run(shutdown.listener()));
+        Self {
+            node_id: secret_key.public(),
+            address: SocketAddr::from((Ipv4Addr::LOCALHOST, local_address.port())),
+            peer_source: peer_source_addr,
+            invites,
+            state,
+            peers,
+            _packets: packets_mailbox,
+            _shutdown: shutdown,
+        }
+    }
+
+    fn node_addr(&self) -> NodeAddr {
+        NodeAddr::new(self.node_id).with_direct_addresses([self.address])
+    }
+
+    async fn dial(&self, other: &Node) {
+        self.peer_source
+            .send_message(PeerSourceMessage::DialPeer(other.node_addr()))
+            .await;
+    }
+
+    /// Checks whether the node with the given [NodeId] is added as a peer within `timeout`.
+    async fn connected(&mut self, node_id: NodeId, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                if let Some(PeerCollectionMessage::AddPeer(added, _)) = self.peers.recv().await {
+                    if added == node_id {
+                        return;
+                    }
+                }
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+#[tokio::test]
+async fn invited_nodes_are_admitted_by_every_member() {
+    let admin_key = SecretKey::generate();
+    let member_key = SecretKey::generate();
+    let joining_key = SecretKey::generate();
+    let admin_id = admin_key.public();
+    let mut admin = Node::start(admin_key, admin_id, &[member_key.public()]).await;
+    let mut member = Node::start(member_key, admin_id, &[admin_id]).await;
+    let mut joining = Node::start(joining_key, admin_id, &[]).await;
+    admin
+        .state
+        .set(Record::Member {
+            node_id: member.node_id,
+        })
+        .unwrap();
+    member.dial(&admin).await;
+    assert!(
+        admin
+            .connected(member.node_id, Duration::from_secs(5))
+            .await
+    );
+    assert!(member.connected(admin_id, Duration::from_secs(5)).await);
+
+    // Without an invite, the joining node isn't admitted
+    joining.dial(&admin).await;
+    assert!(
+        !admin
+            .connected(joining.node_id, Duration::from_secs(1))
+            .await
+    );
+
+    let invite = admin
+        .invites
+        .issue(
+            admin.node_addr(),
+            Duration::from_secs(3600),
+            1,
+            Vec::new(),
+            Some("10.0.0.7".parse().unwrap()),
+        )
+        .unwrap();
+    let issuer = joining.invites.join(invite).unwrap();
+    assert_eq!(issuer.node_id, admin_id);
+    joining.dial(&admin).await;
+    assert!(
+        admin
+            .connected(joining.node_id, Duration::from_secs(5))
+            .await
+    );
+    assert!(joining.connected(admin_id, Duration::from_secs(5)).await);
+    assert!(admin.state.is_member(joining.node_id));
+    assert_eq!(
+        admin.state.address_owner("10.0.0.7".parse().unwrap()),
+        Some(joining.node_id)
+    );
+
+    // The membership reaches the other members through the state
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while !member.state.is_member(joining.node_id) {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await
+    .unwrap();
+    joining.dial(&member).await;
+    assert!(
+        member
+            .connected(joining.node_id, Duration::from_secs(5))
+            .await
+    );
+    assert!(
+        joining
+            .connected(member.node_id, Duration::from_secs(5))
+            .await
+    );
+}
@@ -0,0 +1,113 @@
+use std::{sync::atomic::Ordering, time::Duration};
+
+use p2ptun::daemon::actors::{
+    mailbox::{mailbox, OverflowPolicy, TrySendError},
+    Addr, AskError, Reply,
+};
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn drop_newest_keeps_the_queued_messages() {
+    let (addr, mut receiver) = mailbox(2, OverflowPolicy::DropNewest);
+    for message in 0..4 {
+        addr.send_message(message).await;
+    }
+    assert_eq!(addr.try_send_message(4), Err(TrySendError::Full(4)));
+    assert_eq!(addr.mailbox_stats().dropped.load(Ordering::Relaxed), 3);
+    assert_eq!(receiver.recv().await, Some(0));
+    assert_eq!(receiver.recv().await, Some(1));
+}
+
+#[tokio::test]
+async fn drop_oldest_keeps_the_latest_messages() {
+    let (addr, mut receiver) = mailbox(2, OverflowPolicy::DropOldest);
+    for message in 0..4 {
+        addr.send_message(message).await;
+    }
+    assert_eq!(addr.mailbox_stats().dropped.load(Ordering::Relaxed), 2);
+    assert_eq!(receiver.recv().await, Some(2));
+    assert_eq!(receiver.recv().await, Some(3));
+}
+
+#[tokio::test]
+async fn block_waits_for_space_without_dropping() {
+    let (addr, mut receiver) = mailbox(1, OverflowPolicy::Block);
+    addr.send_message(0).await;
+    assert_eq!(addr.try_send_message(1), Err(TrySendError::Full(1)));
+    let sender = addr.clone();
+    let blocked = tokio::spawn(async move { sender.send_message(1).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!blocked.is_finished());
+
+    assert_eq!(receiver.recv().await, Some(0));
+    tokio::time::timeout(Duration::from_secs(5), blocked)
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(receiver.recv().await, Some(1));
+    assert_eq!(addr.mailbox_stats().dropped.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn mailbox_closes_when_every_addr_is_dropped() {
+    let (addr, mut receiver) = mailbox(4, OverflowPolicy::Block);
+    let clone = addr.clone();
+    addr.send_message("queued").await;
+    drop(addr);
+    // A clone keeps the mailbox open
+    assert_eq!(receiver.recv().await, Some("queued"));
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), receiver.recv())
+            .await
+            .is_err()
+    );
+    drop(clone);
+    assert_eq!(receiver.recv().await, None);
+}
+
+#[tokio::test]
+async fn sending_to_a_dropped_mailbox_fails() {
+    let (addr, receiver) = mailbox(4, OverflowPolicy::Block);
+    drop(receiver);
+    assert_eq!(addr.try_send_message(1), Err(TrySendError::Closed(1)));
+    // Doesn't wait for space that will never be freed
+    tokio::time::timeout(Duration::from_secs(5), addr.send_message(2))
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn addr_sends_to_a_channel() {
+    let (sender, mut receiver) = mpsc::channel(1);
+    let addr = Addr::new(sender);
+    addr.send_message(1).await;
+    assert_eq!(addr.try_send_message(2), Err(TrySendError::Full(2)));
+    assert_eq!(receiver.recv().await, Some(1));
+    drop(receiver);
+    assert_eq!(addr.try_send_message(3), Err(TrySendError::Closed(3)));
+}
+
+#[tokio::test]
+async fn requests_queued_in_a_dropped_mailbox_fail() {
+    let (addr, receiver) = mailbox::<Reply<u32>>(4, OverflowPolicy::Block);
+    let keeper = receiver.keeper();
+    let requester = addr.clone();
+    let request = tokio::spawn(async move { requester.ask(|reply| reply).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(receiver);
+    assert_eq!(
+        tokio::time::timeout(Duration::from_secs(5), request)
+            .await
+            .unwrap()
+            .unwrap(),
+        Err(AskError::NoReply)
+    );
+
+    // The next instance of the actor doesn't get the stale request
+    let mut receiver = keeper.reopen();
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), receiver.recv())
+            .await
+            .is_err()
+    );
+}
@@ -0,0 +1,203 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+    time::Duration,
+};
+
+use iroh_net::{key::SecretKey, ticket::NodeTicket, NodeAddr};
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, OverflowPolicy},
+        peer_source::PeerSourceMessage,
+    },
+    admission::AdmissionPolicy,
+    advertisements::{Advertisement, Advertisements},
+    events::{Event, Events},
+    invite::{InviteConfig, Invites},
+    mdns::{Announcement, Mdns, MdnsConfig},
+    rotation::{RotationConfig, Rotations},
+};
+use tokio::sync::mpsc;
+
+/// Returns a config discovering nodes on loopback, on a free port.
+fn loopback_config() -> MdnsConfig {
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    MdnsConfig {
+        enabled: true,
+        group: SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), port),
+        interface: Ipv4Addr::LOCALHOST,
+        interval: Duration::from_secs(1),
+        max_dials: 8,
+    }
+}
+
+/// Starts the discovery of the node at `node_addr`, a member of `network_id` admitting the
+/// nodes of `admission`. Returns the nodes it dials, and the events it follows.
+fn start(
+    config: MdnsConfig,
+    node_addr: NodeAddr,
+    network_id: &str,
+    admission: AdmissionPolicy,
+) -> (mpsc::UnboundedReceiver<NodeAddr>, Events) {
+    let secret_key = SecretKey::generate();
+    let events = Events::new();
+    let advertisements = Advertisements::new(Advertisement::default(), None, events.clone());
+    let invites = Invites::new(
+        InviteConfig {
+            network_id: network_id.to_string(),
+            path: None,
+        },
+        secret_key.clone(),
+        None,
+        events.clone(),
+    )
+    .unwrap();
+    let rotations = Rotations::new(RotationConfig::default(), secret_key, invites.clone(), None);
+    let (peer_source, mut receiver) = mailbox(16, OverflowPolicy::Block);
+    let mdns = Mdns::new(
+        config,
+        node_addr.node_id,
+        admission,
+        invites,
+        rotations,
+        advertisements,
+        peer_source,
+        &events,
+    )
+    .unwrap();
+    tokio::spawn(mdns.run());
+
+    // Stands in for the peer source of the node
+    let (dialed, dials) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(message) = receiver.recv().await {
+            match message {
+                PeerSourceMessage::GetTicket(reply) => {
+                    reply.send(Ok(NodeTicket::new(node_addr.clone()).unwrap()));
+                }
+                PeerSourceMessage::DialPeer(node_addr) => {
+                    let _ = dialed.send(node_addr);
+                }
+                PeerSourceMessage::DialNode(_) => {}
+            }
+        }
+    });
+    (dials, events)
+}
+
+/// Returns a node listening on `port` of loopback.
+fn loopback_node(port: u16) -> NodeAddr {
+    NodeAddr::new(SecretKey::generate().public())
+        .with_direct_addresses([SocketAddr::from((Ipv4Addr::LOCALHOST, port))])
+}
+
+#[test]
+fn announcements_are_written_as_dns_sd_records() {
+    let announcement = Announcement {
+        network_id: "p2ptun".to_string(),
+        node_addr: NodeAddr::new(SecretKey::generate().public()).with_direct_addresses([
+            "192.168.1.20:41641".parse().unwrap(),
+            "[fe80::1]:41641".parse().unwrap(),
+        ]),
+    };
+    let message = announcement.to_message().unwrap();
+    assert_eq!(Announcement::from_message(&message), vec![announcement]);
+    assert!(Announcement::from_message(b"not a DNS message").is_empty());
+}
+
+#[tokio::test]
+async fn nodes_on_the_same_network_dial_each_other() {
+    let config = loopback_config();
+    let first = loopback_node(41641);
+    let second = loopback_node(41642);
+
+    let (mut first_dials, _) = start(
+        config.clone(),
+        first.clone(),
+        "p2ptun",
+        AdmissionPolicy::default(),
+    );
+    let (mut second_dials, _) = start(config, second.clone(), "p2ptun", AdmissionPolicy::default());
+
+    let timeout = Duration::from_secs(10);
+    let dialed = tokio::time::timeout(timeout, first_dials.recv()).await;
+    assert_eq!(dialed.unwrap(), Some(second));
+    let dialed = tokio::time::timeout(timeout, second_dials.recv()).await;
+    assert_eq!(dialed.unwrap(), Some(first));
+}
+
+#[tokio::test]
+async fn nodes_of_other_networks_are_not_dialed() {
+    let config = loopback_config();
+    let (mut first_dials, _) = start(
+        config.clone(),
+        loopback_node(41641),
+        "p2ptun",
+        AdmissionPolicy::default(),
+    );
+    let (mut second_dials, _) = start(
+        config,
+        loopback_node(41642),
+        "other",
+        AdmissionPolicy::default(),
+    );
+
+    let timeout = Duration::from_secs(3);
+    assert!(tokio::time::timeout(timeout, first_dials.recv())
+        .await
+        .is_err());
+    assert!(tokio::time::timeout(timeout, second_dials.recv())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn nodes_not_admitted_are_not_dialed() {
+    let config = loopback_config();
+    let first = loopback_node(41641);
+    let second = loopback_node(41642);
+    let admission = AdmissionPolicy {
+        members: Some([SecretKey::generate().public()].into()),
+    };
+    let (mut first_dials, _) = start(config.clone(), first.clone(), "p2ptun", admission);
+    let (mut second_dials, _) = start(config, second, "p2ptun", AdmissionPolicy::default());
+
+    // The second node admits the first one, so the announcements go through
+    let timeout = Duration::from_secs(10);
+    let dialed = tokio::time::timeout(timeout, second_dials.recv()).await;
+    assert_eq!(dialed.unwrap(), Some(first));
+    let timeout = Duration::from_secs(3);
+    assert!(tokio::time::timeout(timeout, first_dials.recv())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn nodes_are_not_dialed_again_at_the_addresses_that_failed() {
+    let config = loopback_config();
+    let second = loopback_node(41642);
+    let (mut first_dials, events) = start(
+        config.clone(),
+        loopback_node(41641),
+        "p2ptun",
+        AdmissionPolicy::default(),
+    );
+    let _second = start(config, second.clone(), "p2ptun", AdmissionPolicy::default());
+
+    let timeout = Duration::from_secs(10);
+    let dialed = tokio::time::timeout(timeout, first_dials.recv()).await;
+    assert_eq!(dialed.unwrap(), Some(second.clone()));
+    events.emit(Event::DialFailed {
+        node_id: second.node_id,
+        reason: "unreachable".to_string(),
+    });
+
+    // Announced every second, but not dialed again before the backoff
+    let timeout = Duration::from_secs(3);
+    assert!(tokio::time::timeout(timeout, first_dials.recv())
+        .await
+        .is_err());
+}
@@ -0,0 +1,312 @@
+use std::{
+    collections::{HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use iroh_net::{
+    key::SecretKey, magic_endpoint::accept_conn, relay::RelayMode, MagicEndpoint, NodeAddr, NodeId,
+};
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, Mailbox, OverflowPolicy},
+        mesh_router::{MeshRoute, MeshRouter, MeshRouterMessage, MAX_HOPS},
+        peer_collection::{FailbackPolicy, PeerCollection, PeerCollectionMessage},
+        Actor,
+    },
+    advertisements::{Advertisement, Advertisements},
+    events::Events,
+    packet::{ip::decrement_hop_limit, Packet},
+    state::{NetworkState, Record, StateConfig},
+};
+
+const ALPN: &[u8] = b"p2ptun-mesh-test";
+
+/// Computes the checksum of an IPv4 header.
+fn checksum(header: &[u8]) -> u16 {
+    let mut sum: u32 = header
+        .chunks(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Builds an IPv4 header with a valid checksum.
+fn ipv4_header(ttl: u8) -> Vec<u8> {
+    let mut header = vec![
+        0x45, 0, 0, 20, 0x12, 0x34, 0, 0, ttl, 17, 0, 0, 10, 77, 0, 1, 10, 77, 0, 3,
+    ];
+    let checksum = checksum(&header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+    header
+}
+
+#[test]
+fn forwarded_ipv4_packet_keeps_a_valid_checksum() {
+    for ttl in [2, 64, 255] {
+        let mut packet = ipv4_header(ttl);
+        assert!(decrement_hop_limit(&mut packet));
+        assert_eq!(packet[8], ttl - 1);
+        assert_eq!(checksum(&packet), 0);
+    }
+}
+
+#[test]
+fn packet_out_of_hops_is_not_forwarded() {
+    let mut ipv4 = ipv4_header(1);
+    assert!(!decrement_hop_limit(&mut ipv4));
+
+    let mut ipv6 = vec![0u8; 40];
+    ipv6[0] = 0x60;
+    ipv6[7] = 2;
+    assert!(decrement_hop_limit(&mut ipv6));
+    assert_eq!(ipv6[7], 1);
+    assert!(!decrement_hop_limit(&mut ipv6));
+}
+
+#[tokio::test]
+async fn packets_to_nodes_of_the_mesh_go_through_the_packet_router() {
+    let (router, mut router_mailbox) = mailbox(16, OverflowPolicy::Block);
+    let peer_collection = PeerCollection::new(
+        router,
+        vec!["10.77.0.0/24".parse().unwrap()],
+        FailbackPolicy::default(),
+        Events::new(),
+    );
+    let messages = Actor::<PeerCollectionMessage>::get_addr(&peer_collection);
+    let packets = Actor::<Packet>::get_addr(&peer_collection);
+    tokio::spawn(peer_collection.run());
+    let from = SecretKey::generate().public();
+    let next_hop = SecretKey::generate().public();
+    messages
+        .send_message(PeerCollectionMessage::SetMeshRoutes(HashMap::from([(
+            "10.77.0.3".parse().unwrap(),
+            next_hop,
+        )])))
+        .await;
+
+    packets
+        .send_message(Packet::Incoming(from, Arc::from(ipv4_header(64))))
+        .await;
+    match router_mailbox.recv().await {
+        Some(Packet::Transit(source, destination, data)) => {
+            assert_eq!((source, destination), (from, next_hop));
+            assert_eq!(data[8], 63);
+        }
+        packet => panic!("unexpected packet: {:?}", packet),
+    }
+
+    // Neither sent back to the peer it came from, nor forwarded without hops left
+    packets
+        .send_message(Packet::Incoming(next_hop, Arc::from(ipv4_header(64))))
+        .await;
+    packets
+        .send_message(Packet::Incoming(from, Arc::from(ipv4_header(1))))
+        .await;
+    assert!(
+        tokio::time::timeout(Duration::from_millis(300), router_mailbox.recv())
+            .await
+            .is_err()
+    );
+}
+
+/// Binds an endpoint reachable only through its local address.
+async fn endpoint() -> MagicEndpoint {
+    MagicEndpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .secret_key(SecretKey::generate())
+        .bind(0)
+        .await
+        .unwrap()
+}
+
+/// Connects two nodes, exchanging their advertisements.
+async fn connect(
+    (client, client_advertisements): (&MagicEndpoint, &Advertisements),
+    (server, server_advertisements): (&MagicEndpoint, &Advertisements),
+) {
+    let (server_address, _) = server.local_addr().unwrap();
+    let server_address = SocketAddr::from((Ipv4Addr::LOCALHOST, server_address.port()));
+    let server_node_addr = NodeAddr::new(server.node_id()).with_direct_addresses([server_address]);
+    let connection = client.connect(server_node_addr, ALPN).await.unwrap();
+    let (_, _, server_connection) = accept_conn(server.accept().await.unwrap()).await.unwrap();
+    client_advertisements.attach(server.node_id(), connection);
+    server_advertisements.attach(client.node_id(), server_connection);
+}
+
+fn address(address: &str) -> IpAddr {
+    address.parse().unwrap()
+}
+
+/// Returns a route to `node_id` through the given number of other nodes.
+fn route(node_id: NodeId, through: usize, rtt_ms: u64, addresses: &[&str]) -> MeshRoute {
+    let mut path: Vec<NodeId> = (0..through)
+        .map(|_| SecretKey::generate().public())
+        .collect();
+    path.push(node_id);
+    MeshRoute {
+        node_id,
+        path,
+        rtt_ms,
+        addresses: addresses.iter().map(|a| address(a)).collect(),
+    }
+}
+
+#[tokio::test]
+async fn routes_are_selected_without_loops_and_with_assigned_addresses() {
+    let endpoint_m = endpoint().await;
+    let node_id = endpoint_m.node_id();
+    let endpoint_a = endpoint().await;
+    let endpoint_b = endpoint().await;
+    let (a, b) = (endpoint_a.node_id(), endpoint_b.node_id());
+    let reached = SecretKey::generate().public();
+    let looping = SecretKey::generate().public();
+    let far = SecretKey::generate().public();
+
+    // The state assigns the addresses of every node
+    let authority = SecretKey::generate();
+    let state = NetworkState::new(
+        StateConfig {
+            path: None,
+            authorities: HashSet::from([authority.public()]),
+        },
+        authority,
+        Events::new(),
+    )
+    .unwrap();
+    for (node, address_of_node) in [
+        (a, "10.77.0.2"),
+        (b, "10.77.0.3"),
+        (reached, "10.77.0.4"),
+        (looping, "10.77.0.5"),
+        (far, "10.77.0.6"),
+    ] {
+        state
+            .set(Record::Address {
+                address: address(address_of_node),
+                node_id: node,
+            })
+            .unwrap();
+    }
+
+    let events = Events::new();
+    let advertisements = Advertisements::new(Advertisement::default(), None, events.clone());
+    let (peer_collection, mut peer_collection_mailbox): (_, Mailbox<PeerCollectionMessage>) =
+        mailbox(16, OverflowPolicy::Block);
+    let mesh_router = MeshRouter::new(
+        node_id,
+        peer_collection,
+        advertisements.clone(),
+        state,
+        &events,
+    );
+    let mesh_router_addr = mesh_router.get_addr();
+    tokio::spawn(mesh_router.run());
+
+    let mut looping_route = route(looping, 1, 1, &["10.77.0.5"]);
+    looping_route.path.insert(0, node_id);
+    let advertisements_a = Advertisements::new(
+        Advertisement {
+            // 10.77.0.3 is the address of b
+            addresses: vec![address("10.77.0.2"), address("10.77.0.3")],
+            mesh_routes: vec![
+                // 10.77.0.9 isn't assigned to the reached node
+                route(reached, 0, 1, &["10.77.0.4", "10.77.0.9"]),
+                looping_route,
+                route(far, MAX_HOPS - 1, 1, &["10.77.0.6"]),
+            ],
+            ..Default::default()
+        },
+        None,
+        Events::new(),
+    );
+    let advertisements_b = Advertisements::new(
+        Advertisement {
+            addresses: vec![address("10.77.0.3")],
+            mesh_routes: vec![route(reached, 0, 10_000, &["10.77.0.4"])],
+            ..Default::default()
+        },
+        None,
+        Events::new(),
+    );
+    connect(
+        (&endpoint_m, &advertisements),
+        (&endpoint_a, &advertisements_a),
+    )
+    .await;
+    connect(
+        (&endpoint_m, &advertisements),
+        (&endpoint_b, &advertisements_b),
+    )
+    .await;
+
+    let expected = HashMap::from([
+        (address("10.77.0.2"), a),
+        (address("10.77.0.3"), b),
+        (address("10.77.0.4"), a),
+    ]);
+    tokio::time::timeout(Duration::from_secs(10), async {
+        loop {
+            if let Some(PeerCollectionMessage::SetMeshRoutes(mesh)) =
+                peer_collection_mailbox.recv().await
+            {
+                if mesh == expected {
+                    break;
+                }
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    let routes = mesh_router_addr
+        .ask(MeshRouterMessage::ListRoutes)
+        .await
+        .unwrap();
+    let mut reachable: Vec<NodeId> = routes.iter().map(|route| route.node_id).collect();
+    reachable.sort();
+    let mut expected_nodes = vec![a, b, reached];
+    expected_nodes.sort();
+    assert_eq!(reachable, expected_nodes);
+    let to_reached = routes
+        .iter()
+        .find(|route| route.node_id == reached)
+        .unwrap();
+    assert_eq!(to_reached.path, vec![a, reached]);
+}
+
+#[tokio::test]
+async fn peers_keep_the_latest_advertisement() {
+    let (endpoint_a, endpoint_b) = (endpoint().await, endpoint().await);
+    let advertisements_a = Advertisements::new(Advertisement::default(), None, Events::new());
+    let advertisements_b = Advertisements::new(Advertisement::default(), None, Events::new());
+    connect(
+        (&endpoint_a, &advertisements_a),
+        (&endpoint_b, &advertisements_b),
+    )
+    .await;
+
+    // Each change is sent on its own stream, which may arrive out of order
+    for priority in 1..=20 {
+        advertisements_a.update_own(|own| own.route_priority = priority);
+    }
+    assert_eq!(advertisements_a.own().version, 20);
+    tokio::time::timeout(Duration::from_secs(10), async {
+        while advertisements_b
+            .peer(endpoint_a.node_id())
+            .map_or(true, |advertisement| advertisement.version < 20)
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let advertisement = advertisements_b.peer(endpoint_a.node_id()).unwrap();
+    assert_eq!(advertisement.route_priority, 20);
+}
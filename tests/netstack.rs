@@ -0,0 +1,145 @@
+use std::net::SocketAddr;
+
+use iroh_net::key::SecretKey;
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, OverflowPolicy},
+        netstack::{NetStack, NetStackConfig, NetStackMessage, PublishedPort},
+        Actor, Addr,
+    },
+    packet::Packet,
+    proxy::{self, ProxyKind},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Starts a [NetStack] whose outgoing packets are delivered to `peer` as incoming packets.
+fn start_netstack(
+    config: NetStackConfig,
+    peer: Addr<Packet>,
+) -> (Addr<Packet>, Addr<NetStackMessage>) {
+    let (router, mut router_mailbox) = mailbox(16, OverflowPolicy::Block);
+    let netstack = NetStack::new(router, &config).unwrap();
+    let packets = Actor::<Packet>::get_addr(&netstack);
+    let messages = Actor::<NetStackMessage>::get_addr(&netstack);
+    tokio::spawn(netstack.run());
+    let node_id = SecretKey::generate().public();
+    tokio::spawn(async move {
+        while let Some(packet) = router_mailbox.recv().await {
+            peer.send_message(Packet::Incoming(node_id, packet.data().clone()))
+                .await;
+        }
+    });
+    (packets, messages)
+}
+
+/// Starts a TCP server echoing everything back.
+async fn start_echo_server() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                let _ = tokio::io::copy(&mut read, &mut write).await;
+            });
+        }
+    });
+    address
+}
+
+/// Starts two stacks connected back to back, the server at 10.0.0.2 publishing port 80 to an
+/// echo server. Returns the messages of the client stack.
+async fn start_overlay() -> Addr<NetStackMessage> {
+    let echo_server = start_echo_server().await;
+
+    // The packets go through forwarding tasks
+    let (to_client, mut client_inbox) = mailbox::<Packet>(16, OverflowPolicy::Block);
+    let (server_packets, _) = start_netstack(
+        NetStackConfig {
+            addresses: vec!["10.0.0.2/24".parse().unwrap()],
+            published_ports: vec![PublishedPort {
+                port: 80,
+                target: echo_server,
+            }],
+            ..Default::default()
+        },
+        to_client,
+    );
+    let (client_packets, client_messages) = start_netstack(
+        NetStackConfig {
+            addresses: vec!["10.0.0.1/24".parse().unwrap()],
+            ..Default::default()
+        },
+        server_packets,
+    );
+    tokio::spawn(async move {
+        while let Some(packet) = client_inbox.recv().await {
+            client_packets.send_message(packet).await;
+        }
+    });
+    client_messages
+}
+
+/// Starts a proxy of the given kind into the overlay, and returns its address.
+async fn start_proxy(kind: ProxyKind, net_stack: Addr<NetStackMessage>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy_address = listener.local_addr().unwrap();
+    tokio::spawn(proxy::serve(listener, kind, net_stack));
+    proxy_address
+}
+
+#[tokio::test]
+async fn socks5_proxy_reaches_a_published_port() {
+    let proxy_address = start_proxy(ProxyKind::Socks5, start_overlay().await).await;
+
+    let mut client = TcpStream::connect(proxy_address).await.unwrap();
+    client.write_all(&[5, 1, 0]).await.unwrap();
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0]);
+    client
+        .write_all(&[5, 1, 0, 1, 10, 0, 0, 2, 0, 80])
+        .await
+        .unwrap();
+    let mut reply = [0u8; 10];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[1], 0, "the connection should succeed");
+
+    client.write_all(b"hello overlay").await.unwrap();
+    let mut echoed = [0u8; 13];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello overlay");
+}
+
+#[tokio::test]
+async fn http_proxy_forwards_data_sent_with_the_request() {
+    let proxy_address = start_proxy(ProxyKind::Http, start_overlay().await).await;
+
+    let mut client = TcpStream::connect(proxy_address).await.unwrap();
+    client
+        .write_all(b"CONNECT 10.0.0.2:80 HTTP/1.1\r\nHost: 10.0.0.2:80\r\n\r\nhello overlay")
+        .await
+        .unwrap();
+    let mut reply = [0u8; 39];
+    client.read_exact(&mut reply).await.unwrap();
+    assert_eq!(&reply, b"HTTP/1.1 200 Connection Established\r\n\r\n");
+    let mut echoed = [0u8; 13];
+    client.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"hello overlay");
+}
+
+#[tokio::test]
+async fn http_proxy_refuses_long_request_lines() {
+    let (net_stack, _mailbox) = mailbox(1, OverflowPolicy::Block);
+    let proxy_address = start_proxy(ProxyKind::Http, net_stack).await;
+
+    let mut client = TcpStream::connect(proxy_address).await.unwrap();
+    // One byte more than the longest head, without ending the line
+    client.write_all(&[b'a'; 8193]).await.unwrap();
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(reply.starts_with(b"HTTP/1.1 431"));
+}
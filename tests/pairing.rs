@@ -0,0 +1,118 @@
+use std::{net::UdpSocket, time::Duration};
+
+use iroh_net::{key::SecretKey, NodeAddr};
+use p2ptun::daemon::pairing::{self, PairingCode, PairingConfig, PairingError, MAX_FAILURES};
+use serde_json::json;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+
+/// Returns a config pairing nodes on localhost, on a free port.
+fn localhost_config() -> PairingConfig {
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    PairingConfig {
+        listen: ([127, 0, 0, 1], port).into(),
+        rendezvous: ([127, 0, 0, 1], port).into(),
+        timeout: Duration::from_secs(10),
+    }
+}
+
+fn node_addr() -> NodeAddr {
+    NodeAddr::new(SecretKey::generate().public())
+        .with_direct_addresses(["192.0.2.1:4433".parse().unwrap()])
+}
+
+#[test]
+fn codes_are_written_as_words() {
+    let code: PairingCode = "7-purple-sausages".parse().unwrap();
+    assert_eq!(code.nameplate, 7);
+    assert_eq!(code.to_string(), "7-purple-sausages");
+
+    let generated = PairingCode::generate();
+    assert_eq!(generated.to_string().parse::<PairingCode>(), Ok(generated));
+    assert!("7-purple-unicorns".parse::<PairingCode>().is_err());
+    assert!("purple-sausages".parse::<PairingCode>().is_err());
+}
+
+#[tokio::test]
+async fn nodes_with_the_same_code_exchange_addresses() {
+    let config = localhost_config();
+    let code = PairingCode::generate();
+    let (offering, joining) = (node_addr(), node_addr());
+
+    let offer = tokio::spawn({
+        let (config, code, offering) = (config.clone(), code.clone(), offering.clone());
+        async move { pairing::offer(&config, &code, offering).await }
+    });
+    let joined = pairing::join(&config, &code, joining.clone())
+        .await
+        .unwrap();
+    let offered = offer.await.unwrap().unwrap();
+
+    assert_eq!(joined, offering);
+    assert_eq!(offered, joining);
+}
+
+#[tokio::test]
+async fn nodes_with_different_codes_fail() {
+    let config = localhost_config();
+    let code: PairingCode = "7-purple-sausages".parse().unwrap();
+    let wrong: PairingCode = "7-purple-pretzel".parse().unwrap();
+
+    let offer = tokio::spawn({
+        let (config, code) = (config.clone(), code.clone());
+        async move { pairing::offer(&config, &code, node_addr()).await }
+    });
+    // The node showing the code only gives up after several wrong guesses
+    for _ in 0..MAX_FAILURES {
+        let joined = pairing::join(&config, &wrong, node_addr()).await;
+        assert!(matches!(joined, Err(PairingError::WrongCode)));
+    }
+    let offered = tokio::time::timeout(Duration::from_secs(5), offer)
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(offered, Err(PairingError::WrongCode)));
+}
+
+#[tokio::test]
+async fn node_sending_first_does_not_hold_up_the_pairing() {
+    let config = localhost_config();
+    let code: PairingCode = "7-purple-sausages".parse().unwrap();
+    let offering = node_addr();
+    let offer = tokio::spawn({
+        let (config, code, offering) = (config.clone(), code.clone(), offering.clone());
+        async move { pairing::offer(&config, &code, offering).await }
+    });
+
+    // Another host sends its message for the nameplate first, with another code
+    let intruder = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_, message) = Spake2::<Ed25519Group>::start_symmetric(
+        &Password::new(b"7-purple-pretzel"),
+        &Identity::new(b"p2ptun-pair"),
+    );
+    let pake =
+        serde_json::to_vec(&json!({"type": "pake", "nameplate": 7, "message": message})).unwrap();
+    let mut buffer = vec![0u8; 8192];
+    tokio::time::timeout(Duration::from_secs(5), async {
+        loop {
+            intruder.send_to(&pake, config.listen).await.unwrap();
+            let answer =
+                tokio::time::timeout(Duration::from_millis(200), intruder.recv_from(&mut buffer));
+            if let Ok(Ok(_)) = answer.await {
+                break;
+            }
+        }
+    })
+    .await
+    .unwrap();
+
+    let joining = node_addr();
+    let joined = pairing::join(&config, &code, joining.clone())
+        .await
+        .unwrap();
+    assert_eq!(joined, offering);
+    assert_eq!(offer.await.unwrap().unwrap(), joining);
+}
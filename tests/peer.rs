@@ -0,0 +1,68 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use iroh_net::{
+    key::SecretKey, magic_endpoint::accept_conn, relay::RelayMode, MagicEndpoint, NodeAddr,
+};
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, OverflowPolicy},
+        peer::Peer,
+    },
+    packet::Packet,
+};
+
+const ALPN: &[u8] = b"p2ptun-peer-test";
+
+/// Binds an endpoint reachable only through its local address.
+async fn endpoint() -> MagicEndpoint {
+    MagicEndpoint::builder()
+        .alpns(vec![ALPN.to_vec()])
+        .relay_mode(RelayMode::Disabled)
+        .secret_key(SecretKey::generate())
+        .bind(0)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn packets_up_to_the_buffer_size_are_received_whole() {
+    let client = endpoint().await;
+    let server = endpoint().await;
+    let (server_address, _) = server.local_addr().unwrap();
+    let server_address = SocketAddr::from((Ipv4Addr::LOCALHOST, server_address.port()));
+    let server_node_addr = NodeAddr::new(server.node_id()).with_direct_addresses([server_address]);
+
+    let connection = client.connect(server_node_addr, ALPN).await.unwrap();
+    let (mut send_stream, _recv_stream) = connection.open_bi().await.unwrap();
+    let packet = vec![7u8; 4000];
+    send_stream.write_all(&packet).await.unwrap();
+
+    let connecting = server.accept().await.unwrap();
+    let (node_id, _, server_connection) = accept_conn(connecting).await.unwrap();
+    let (peer_send_stream, peer_recv_stream) = server_connection.accept_bi().await.unwrap();
+    // Lets the whole packet arrive before the peer reads it
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let (peer_collection, mut received) = mailbox(16, OverflowPolicy::Block);
+    let peer = Peer::new(
+        node_id,
+        peer_collection,
+        peer_send_stream,
+        peer_recv_stream,
+        9000,
+    );
+    tokio::spawn(peer.run());
+
+    let received = tokio::time::timeout(Duration::from_secs(5), received.recv())
+        .await
+        .unwrap();
+    match received {
+        Some(Packet::Incoming(from, data)) => {
+            assert_eq!(from, client.node_id());
+            assert_eq!(&*data, &packet[..]);
+        }
+        packet => panic!("unexpected packet: {:?}", packet),
+    }
+}
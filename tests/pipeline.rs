@@ -0,0 +1,185 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use iroh_net::{key::SecretKey, NodeId};
+use p2ptun::daemon::{
+    actors::{packet_router::PacketRouter, Actor, Addr},
+    packet::Packet,
+    pipeline::{Direction, Outcome, PacketProcessor, Pipeline, Verdict},
+    DaemonError,
+};
+use tokio::sync::mpsc;
+
+/// A stage recording its name in a shared log, then returning a fixed verdict.
+struct Stage {
+    name: String,
+    verdict: Verdict,
+    log: Arc<Mutex<Vec<String>>>,
+}
+
+impl PacketProcessor for Stage {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn process(&mut self, _packet: &Packet) -> Verdict {
+        self.log.lock().unwrap().push(self.name.clone());
+        self.verdict.clone()
+    }
+}
+
+fn pipeline(stages: Vec<(&str, Verdict)>) -> (Pipeline, Arc<Mutex<Vec<String>>>) {
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let mut pipeline = Pipeline::new();
+    for (name, verdict) in stages {
+        pipeline.add_stage(Box::new(Stage {
+            name: name.to_string(),
+            verdict,
+            log: log.clone(),
+        }));
+    }
+    (pipeline, log)
+}
+
+fn packet(data: &[u8]) -> Arc<[u8]> {
+    Arc::from(data)
+}
+
+fn names(names: &[&str]) -> Vec<String> {
+    names.iter().map(|name| name.to_string()).collect()
+}
+
+#[test]
+fn stages_run_in_the_order_of_their_direction() {
+    let (mut pipeline, log) = pipeline(vec![
+        ("first", Verdict::Pass),
+        ("second", Verdict::Pass),
+        ("third", Verdict::Pass),
+    ]);
+    pipeline.process(Packet::Outgoing(packet(b"out")));
+    assert_eq!(*log.lock().unwrap(), names(&["first", "second", "third"]));
+
+    pipeline
+        .set_order(Direction::Incoming, &names(&["third", "first"]))
+        .unwrap();
+    log.lock().unwrap().clear();
+    let peer = SecretKey::generate().public();
+    pipeline.process(Packet::Incoming(peer, packet(b"in")));
+    assert_eq!(*log.lock().unwrap(), names(&["third", "first"]));
+
+    // The other direction keeps its order
+    log.lock().unwrap().clear();
+    pipeline.process(Packet::Redirected(peer, packet(b"out")));
+    assert_eq!(*log.lock().unwrap(), names(&["first", "second", "third"]));
+
+    assert!(matches!(
+        pipeline.set_order(Direction::Outgoing, &names(&["missing"])),
+        Err(DaemonError::UnknownStage(name)) if name == "missing"
+    ));
+}
+
+#[test]
+fn dropped_packets_skip_the_remaining_stages() {
+    let (mut pipeline, log) = pipeline(vec![("drop", Verdict::Drop), ("after", Verdict::Pass)]);
+    assert!(matches!(
+        pipeline.process(Packet::Outgoing(packet(b"out"))),
+        Outcome::Drop
+    ));
+    assert_eq!(*log.lock().unwrap(), names(&["drop"]));
+}
+
+#[test]
+fn modified_packets_keep_their_kind() {
+    let peer = SecretKey::generate().public();
+    let (mut pipeline, _) = pipeline(vec![
+        ("modify", Verdict::Modify(packet(b"modified"))),
+        ("pass", Verdict::Pass),
+    ]);
+    match pipeline.process(Packet::Incoming(peer, packet(b"original"))) {
+        Outcome::Deliver(Packet::Incoming(node_id, data)) => {
+            assert_eq!(node_id, peer);
+            assert_eq!(&*data, b"modified");
+        }
+        outcome => panic!("unexpected outcome: {:?}", outcome),
+    }
+}
+
+#[test]
+fn redirected_packets_carry_the_data_modified_before() {
+    let target: NodeId = SecretKey::generate().public();
+    let (mut pipeline, log) = pipeline(vec![
+        ("modify", Verdict::Modify(packet(b"modified"))),
+        ("redirect", Verdict::Redirect(target)),
+        ("after", Verdict::Drop),
+    ]);
+    match pipeline.process(Packet::Outgoing(packet(b"original"))) {
+        Outcome::Redirect(node_id, data) => {
+            assert_eq!(node_id, target);
+            assert_eq!(&*data, b"modified");
+        }
+        outcome => panic!("unexpected outcome: {:?}", outcome),
+    }
+    assert_eq!(*log.lock().unwrap(), names(&["modify", "redirect"]));
+}
+
+#[test]
+fn transit_packets_run_through_both_directions() {
+    let from = SecretKey::generate().public();
+    let next_hop = SecretKey::generate().public();
+    let (mut pipeline, log) = pipeline(vec![
+        ("incoming", Verdict::Pass),
+        ("outgoing", Verdict::Pass),
+    ]);
+    pipeline
+        .set_order(Direction::Incoming, &names(&["incoming"]))
+        .unwrap();
+    pipeline
+        .set_order(Direction::Outgoing, &names(&["outgoing"]))
+        .unwrap();
+    match pipeline.process(Packet::Transit(from, next_hop, packet(b"transit"))) {
+        Outcome::Deliver(Packet::Redirected(node_id, data)) => {
+            assert_eq!(node_id, next_hop);
+            assert_eq!(&*data, b"transit");
+        }
+        outcome => panic!("unexpected outcome: {:?}", outcome),
+    }
+    assert_eq!(*log.lock().unwrap(), names(&["incoming", "outgoing"]));
+}
+
+#[test]
+fn transit_packets_dropped_as_incoming_are_not_sent_on() {
+    let from = SecretKey::generate().public();
+    let next_hop = SecretKey::generate().public();
+    let (mut pipeline, log) = pipeline(vec![("drop", Verdict::Drop)]);
+    pipeline.set_order(Direction::Outgoing, &[]).unwrap();
+    assert!(matches!(
+        pipeline.process(Packet::Transit(from, next_hop, packet(b"transit"))),
+        Outcome::Drop
+    ));
+    assert_eq!(*log.lock().unwrap(), names(&["drop"]));
+}
+
+#[tokio::test]
+async fn dropped_packets_go_to_the_dropped_packet_receivers() {
+    let (sender, mut dropped) = mpsc::channel(4);
+    let mut packet_router = PacketRouter::new();
+    packet_router.add_dropped_packet_receiver(Addr::new(sender));
+    packet_router.add_stage(Box::new(Stage {
+        name: "drop".to_string(),
+        verdict: Verdict::Drop,
+        log: Default::default(),
+    }));
+    let addr = packet_router.get_addr();
+    let router = tokio::spawn(packet_router.run());
+
+    addr.send_message(Packet::Outgoing(packet(b"dropped")))
+        .await;
+    let packet = tokio::time::timeout(Duration::from_secs(5), dropped.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(matches!(packet, Packet::Outgoing(data) if &*data == b"dropped"));
+    router.abort();
+}
@@ -0,0 +1,23 @@
+;; Sample p2ptun filter plugin.
+;;
+;; Drops incoming IPv4 TCP packets and passes everything else.
+;; See `p2ptun::daemon::wasm_filter` for the description of the ABI.
+(module
+  (memory (export "memory") 2)
+
+  ;; The host buffer is placed at the start of the memory.
+  (func (export "p2ptun_alloc") (param $size i32) (result i32)
+    i32.const 0)
+
+  ;; The buffer holds the 32-byte peer NodeId followed by the packet.
+  (func (export "p2ptun_filter") (param $direction i32) (param $length i32) (result i32)
+    ;; Pass outgoing packets
+    (if (i32.ne (local.get $direction) (i32.const 0))
+      (then (return (i32.const 0))))
+    ;; Pass packets too short to hold an IPv4 header
+    (if (i32.lt_u (local.get $length) (i32.const 20))
+      (then (return (i32.const 0))))
+    ;; Drop the packet if the IP version is 4 and the protocol is TCP (6)
+    (i32.and
+      (i32.eq (i32.shr_u (i32.load8_u (i32.const 32)) (i32.const 4)) (i32.const 4))
+      (i32.eq (i32.load8_u (i32.const 41)) (i32.const 6)))))
@@ -0,0 +1,384 @@
+use std::{
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use iroh_net::{key::SecretKey, relay::RelayMode, MagicEndpoint, NodeAddr, NodeId};
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, Mailbox, OverflowPolicy},
+        peer_collection::PeerCollectionMessage,
+        peer_source::{PeerSource, PeerSourceMessage, ALPN},
+        Actor, Addr,
+    },
+    admission::AdmissionPolicy,
+    advertisements::{Advertisement, Advertisements},
+    events::Events,
+    forward::{ForwardPolicy, Forwarding},
+    invite::{InviteConfig, Invites},
+    packet::Packet,
+    revocation::Revocations,
+    rotation::{RotationConfig, Rotations},
+    shutdown::Shutdown,
+    state::{NetworkState, Record, RecordKey, SignedEntry, StateConfig},
+    DaemonError,
+};
+
+/// Returns the state of the node of `secret_key`, with `admins` as its authorities.
+fn state(
+    secret_key: &SecretKey,
+    admins: &[NodeId],
+    path: Option<&std::path::Path>,
+) -> NetworkState {
+    NetworkState::new(
+        StateConfig {
+            path: path.map(Into::into),
+            authorities: admins.iter().copied().collect(),
+        },
+        secret_key.clone(),
+        Events::new(),
+    )
+    .unwrap()
+}
+
+/// Returns the revocation of the key of `node_id`, signed by `signer` at `timestamp`.
+fn revocation(signer: &SecretKey, node_id: NodeId, timestamp: u64) -> SignedEntry {
+    SignedEntry::sign(
+        signer,
+        RecordKey::Revocation { node_id },
+        Some(Record::Revocation { node_id }),
+        timestamp,
+    )
+}
+
+fn receive(state: &NetworkState, from: NodeId, entries: &[SignedEntry]) {
+    state.receive(from, &serde_json::to_vec(entries).unwrap());
+}
+
+#[test]
+fn only_admins_revoke() {
+    let admin = SecretKey::generate();
+    let node = SecretKey::generate();
+    let revoked = SecretKey::generate().public();
+
+    let revocations = Revocations::new(Some(state(&node, &[admin.public()], None)));
+    assert!(matches!(
+        revocations.revoke(revoked),
+        Err(DaemonError::NotAnAdmin)
+    ));
+    assert!(!revocations.is_revoked(revoked));
+
+    let revocations = Revocations::new(None);
+    assert!(matches!(
+        revocations.revoke(revoked),
+        Err(DaemonError::StateDisabled)
+    ));
+
+    let revocations = Revocations::new(Some(state(&admin, &[admin.public()], None)));
+    revocations.revoke(revoked).unwrap();
+    assert!(revocations.is_revoked(revoked));
+    let list = revocations.list();
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].node_id, revoked);
+    assert_eq!(list[0].issuer, admin.public());
+}
+
+#[test]
+fn revocations_are_persisted_with_the_state() {
+    let admin = SecretKey::generate();
+    let path = std::env::temp_dir().join(format!("p2ptun-revocations-{}.json", admin.public()));
+    let revoked = SecretKey::generate().public();
+
+    let revocations = Revocations::new(Some(state(&admin, &[admin.public()], Some(&path))));
+    revocations.revoke(revoked).unwrap();
+
+    // Loaded again after a restart
+    let restarted = Revocations::new(Some(state(&admin, &[admin.public()], Some(&path))));
+    assert!(restarted.is_revoked(revoked));
+    assert_eq!(restarted.list(), revocations.list());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn revocations_signed_by_other_nodes_are_rejected() {
+    let admin = SecretKey::generate();
+    let node = SecretKey::generate();
+    let revoked = SecretKey::generate().public();
+    let state = state(&SecretKey::generate(), &[admin.public()], None);
+
+    receive(&state, node.public(), &[revocation(&node, revoked, 1)]);
+    assert!(!state.is_revoked(revoked));
+
+    let mut forged = revocation(&admin, revoked, 1);
+    forged.key = RecordKey::Revocation {
+        node_id: node.public(),
+    };
+    forged.record = Some(Record::Revocation {
+        node_id: node.public(),
+    });
+    receive(&state, node.public(), &[forged]);
+    assert!(!state.is_revoked(node.public()));
+    assert!(Revocations::new(Some(state)).list().is_empty());
+}
+
+#[test]
+fn revocations_by_revoked_admins_are_ignored() {
+    let admin = SecretKey::generate();
+    let other = SecretKey::generate();
+    let stolen = SecretKey::generate();
+    let node = SecretKey::generate().public();
+    let admins = [admin.public(), other.public(), stolen.public()];
+
+    // Written after the revocation of the stolen key, or backdated before it
+    for timestamp in [2_000, 500] {
+        let entries = [
+            revocation(&admin, stolen.public(), 1_000),
+            revocation(&stolen, node, timestamp),
+            revocation(&stolen, admin.public(), timestamp),
+        ];
+        // Whatever the order they are received in
+        for reversed in [false, true] {
+            let state = state(&SecretKey::generate(), &admins, None);
+            if reversed {
+                for entry in entries.iter().rev() {
+                    receive(&state, stolen.public(), &[entry.clone()]);
+                }
+            } else {
+                receive(&state, admin.public(), &entries);
+            }
+            assert!(state.is_revoked(stolen.public()));
+            assert!(!state.is_revoked(node));
+            // Two admins revoking each other are both revoked
+            assert!(state.is_revoked(admin.public()));
+            assert!(!state.is_revoked(other.public()));
+
+            receive(&state, other.public(), &[revocation(&other, node, 3_000)]);
+            assert!(state.is_revoked(node));
+        }
+    }
+}
+
+#[test]
+fn revocations_are_not_undone() {
+    let admin = SecretKey::generate();
+    let stolen = SecretKey::generate();
+    let admins = [admin.public(), stolen.public()];
+    let state = state(&admin, &admins, None);
+    receive(
+        &state,
+        admin.public(),
+        &[revocation(&admin, stolen.public(), 1_000)],
+    );
+
+    // The revoked admin removes its revocation
+    let key = RecordKey::Revocation {
+        node_id: stolen.public(),
+    };
+    let removal = SignedEntry::sign(&stolen, key, None, 2_000);
+    assert!(removal.verify() && !removal.is_authorized(&admins.into()));
+    receive(&state, stolen.public(), &[removal]);
+    assert!(state.is_revoked(stolen.public()));
+
+    // Nor is it removed by the admin that wrote it
+    assert!(matches!(
+        state.remove(key),
+        Err(DaemonError::Unauthorized(_))
+    ));
+    assert!(state.is_revoked(stolen.public()));
+
+    // The entries signed by the revoked key are refused
+    let name = Record::Name {
+        node_id: stolen.public(),
+        name: "admin".to_string(),
+    };
+    let entry = SignedEntry::sign(&stolen, name.key(), Some(name), 3_000);
+    receive(&state, stolen.public(), &[entry]);
+    assert!(state
+        .records()
+        .iter()
+        .all(|info| info.signer != stolen.public()));
+}
+
+#[tokio::test]
+async fn revocations_are_waited_for() {
+    let admin = SecretKey::generate();
+    let revoked = SecretKey::generate().public();
+    let state = state(&SecretKey::generate(), &[admin.public()], None);
+    let revocations = Revocations::new(Some(state.clone()));
+    let waiting = tokio::spawn({
+        let revocations = revocations.clone();
+        async move { revocations.revoked(revoked).await }
+    });
+    receive(&state, admin.public(), &[revocation(&admin, revoked, 1)]);
+    tokio::time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .unwrap()
+        .unwrap();
+}
+
+/// The peer collection of a [Node], reporting the messages sent to it.
+struct Collection {
+    messages: Addr<PeerCollectionMessage>,
+    packets: Addr<Packet>,
+}
+
+impl Actor<PeerCollectionMessage> for Collection {
+    fn get_addr(&self) -> Addr<PeerCollectionMessage> {
+        self.messages.clone()
+    }
+}
+
+impl Actor<Packet> for Collection {
+    fn get_addr(&self) -> Addr<Packet> {
+        self.packets.clone()
+    }
+}
+
+/// A node reachable only through the loopback interface.
+struct Node {
+    node_id: NodeId,
+    address: SocketAddr,
+    peer_source: Addr<PeerSourceMessage>,
+    revocations: Revocations,
+    peers: Mailbox<PeerCollectionMessage>,
+    _packets: Mailbox<Packet>,
+    _shutdown: Shutdown,
+}
+
+impl Node {
+    /// Starts a node admitting `members`, with `admin` as the authority of its state.
+    async fn start(secret_key: SecretKey, admin: NodeId, members: &[NodeId]) -> Self {
+        let events = Events::new();
+        let shutdown = Shutdown::new();
+        let state = state(&secret_key, &[admin], None);
+        let advertisements = Advertisements::new(
+            Advertisement::default(),
+            Some(state.clone()),
+            events.clone(),
+        );
+        let invites = Invites::new(
+            InviteConfig::default(),
+            secret_key.clone(),
+            Some(state.clone()),
+            events.clone(),
+        )
+        .unwrap();
+        let revocations = Revocations::new(Some(state.clone()));
+        let rotations = Rotations::new(
+            RotationConfig::default(),
+            secret_key.clone(),
+            invites.clone(),
+            Some(state),
+        );
+        let endpoint = MagicEndpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .secret_key(secret_key.clone())
+            .bind(0)
+            .await
+            .unwrap();
+        let (local_address, _) = endpoint.local_addr().unwrap();
+        let (messages, peers) = mailbox(64, OverflowPolicy::Block);
+        let (packets, packets_mailbox) = mailbox(16, OverflowPolicy::DropNewest);
+        let peer_source = PeerSource::from_endpoint(
+            &Collection { messages, packets },
+            endpoint,
+            events,
+            Forwarding::new(ForwardPolicy::default(), shutdown.listener()),
+            advertisements,
+            AdmissionPolicy {
+                members: Some(members.iter().copied().collect()),
+            },
+            invites,
+            revocations.clone(),
+            rotations,
+            None,
+        );
+        let peer_source_addr = peer_source.get_addr();
+        tokio::spawn(peer_source.run(shutdown.listener()));
+        Self {
+            node_id: secret_key.public(),
+            address: SocketAddr::from((Ipv4Addr::LOCALHOST, local_address.port())),
+            peer_source: peer_source_addr,
+            revocations,
+            peers,
+            _packets: packets_mailbox,
+            _shutdown: shutdown,
+        }
+    }
+
+    fn node_addr(&self) -> NodeAddr {
+        NodeAddr::new(self.node_id).with_direct_addresses([self.address])
+    }
+
+    async fn dial(&self, other: &Node) {
+        self.peer_source
+            .send_message(PeerSourceMessage::DialPeer(other.node_addr()))
+            .await;
+    }
+
+    /// Checks whether a message matching `expected` is sent to the peer collection within
+    /// `timeout`.
+    async fn received(
+        &mut self,
+        expected: impl Fn(&PeerCollectionMessage) -> bool,
+        timeout: Duration,
+    ) -> bool {
+        tokio::time::timeout(timeout, async {
+            loop {
+                if let Some(message) = self.peers.recv().await {
+                    if expected(&message) {
+                        return;
+                    }
+                }
+            }
+        })
+        .await
+        .is_ok()
+    }
+
+    /// Checks whether the node with the given [NodeId] is added as a peer within `timeout`.
+    async fn connected(&mut self, node_id: NodeId, timeout: Duration) -> bool {
+        self.received(
+            |message| {
+                matches!(message, PeerCollectionMessage::AddPeer(added, _) if *added == node_id)
+            },
+            timeout,
+        )
+        .await
+    }
+
+    /// Checks whether the peer with the given [NodeId] is disconnected within `timeout`.
+    async fn disconnected(&mut self, node_id: NodeId, timeout: Duration) -> bool {
+        self.received(
+            |message| {
+                matches!(message, PeerCollectionMessage::DisconnectPeer(peer) if *peer == node_id)
+            },
+            timeout,
+        )
+        .await
+    }
+}
+
+#[tokio::test]
+async fn revoked_nodes_are_disconnected_and_refused() {
+    let admin_key = SecretKey::generate();
+    let member_key = SecretKey::generate();
+    let admin_id = admin_key.public();
+    let member_id = member_key.public();
+    let mut admin = Node::start(admin_key, admin_id, &[member_id]).await;
+    let mut member = Node::start(member_key, admin_id, &[admin_id]).await;
+    member.dial(&admin).await;
+    assert!(admin.connected(member_id, Duration::from_secs(5)).await);
+    assert!(member.connected(admin_id, Duration::from_secs(5)).await);
+
+    admin.revocations.revoke(member_id).unwrap();
+    assert!(admin.disconnected(member_id, Duration::from_secs(5)).await);
+
+    // Neither accepted nor dialed anymore
+    member.dial(&admin).await;
+    assert!(!admin.connected(member_id, Duration::from_secs(1)).await);
+    admin.dial(&member).await;
+    assert!(!admin.connected(member_id, Duration::from_secs(1)).await);
+    assert!(!member.connected(admin_id, Duration::from_secs(1)).await);
+}
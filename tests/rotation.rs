@@ -0,0 +1,273 @@
+use std::time::Duration;
+
+use iroh_net::{key::SecretKey, NodeAddr, NodeId};
+use p2ptun::daemon::{
+    admission::AdmissionPolicy,
+    events::{Event, Events},
+    invite::{InviteConfig, Invites},
+    rotation::{read_key, RotationConfig, Rotations},
+    state::{NetworkState, Record, RecordKey, SignedEntry, StateConfig},
+    DaemonError,
+};
+
+fn state(secret_key: &SecretKey, authorities: &[NodeId], events: Events) -> NetworkState {
+    let config = StateConfig {
+        path: None,
+        authorities: authorities.iter().copied().collect(),
+    };
+    NetworkState::new(config, secret_key.clone(), events).unwrap()
+}
+
+/// Returns the replacement of `predecessor` by `successor`, written at `timestamp`.
+fn succession(predecessor: &SecretKey, successor: &SecretKey, timestamp: u64) -> SignedEntry {
+    SignedEntry::sign(
+        predecessor,
+        RecordKey::Succession {
+            predecessor: predecessor.public(),
+        },
+        Some(Record::succession(predecessor.public(), successor)),
+        timestamp,
+    )
+}
+
+fn receive(state: &NetworkState, entries: &[SignedEntry]) {
+    let from = SecretKey::generate().public();
+    state.receive(from, &serde_json::to_vec(entries).unwrap());
+}
+
+#[test]
+fn successions_are_signed_by_both_keys() {
+    let predecessor = SecretKey::generate();
+    let successor = SecretKey::generate();
+    let authorities = [SecretKey::generate().public()].into();
+    let entry = succession(&predecessor, &successor, 1);
+    assert!(entry.verify() && entry.is_authorized(&authorities));
+
+    // Not countersigned by the successor
+    let other = SecretKey::generate().public();
+    let mut forged = entry.clone();
+    forged.record = Some(Record::Succession {
+        predecessor: predecessor.public(),
+        successor: other,
+        countersignature: match entry.record.clone() {
+            Some(Record::Succession {
+                countersignature, ..
+            }) => countersignature,
+            _ => unreachable!(),
+        },
+    });
+    assert!(!forged.verify());
+
+    // Not signed by the predecessor
+    let stranger = SecretKey::generate();
+    let replaced = SignedEntry::sign(
+        &stranger,
+        entry.key,
+        Some(Record::succession(predecessor.public(), &successor)),
+        1,
+    );
+    assert!(replaced.verify() && !replaced.is_authorized(&authorities));
+
+    assert!(!succession(&predecessor, &predecessor, 1).verify());
+}
+
+#[test]
+fn keys_are_replaced_once() {
+    let predecessor = SecretKey::generate();
+    let first = SecretKey::generate();
+    let second = SecretKey::generate();
+    let entries = [
+        succession(&predecessor, &first, 1_000),
+        succession(&predecessor, &second, 2_000),
+    ];
+
+    // Whatever the order they are received in
+    for reversed in [false, true] {
+        let state = state(&SecretKey::generate(), &[], Events::new());
+        if reversed {
+            for entry in entries.iter().rev() {
+                receive(&state, &[entry.clone()]);
+            }
+        } else {
+            receive(&state, &entries);
+        }
+        assert_eq!(state.successor(predecessor.public()), Some(first.public()));
+    }
+}
+
+#[test]
+fn keys_in_a_lineage_are_not_taken_over() {
+    let first = SecretKey::generate();
+    let second = SecretKey::generate();
+    let third = SecretKey::generate();
+    let other = SecretKey::generate();
+    let state = state(&SecretKey::generate(), &[], Events::new());
+    receive(&state, &[succession(&first, &second, 1_000)]);
+    receive(&state, &[succession(&second, &third, 2_000)]);
+    assert_eq!(
+        state.lineage(third.public()),
+        vec![third.public(), second.public(), first.public()]
+    );
+
+    // A key replacing another can't replace a second one, nor can a replaced key come back
+    receive(&state, &[succession(&other, &third, 3_000)]);
+    receive(&state, &[succession(&other, &first, 3_000)]);
+    assert_eq!(state.successor(other.public()), None);
+    assert_eq!(
+        state.lineage(third.public()),
+        vec![third.public(), second.public(), first.public()]
+    );
+}
+
+#[test]
+fn revoked_keys_are_not_replaced() {
+    let admin = SecretKey::generate();
+    let stolen = SecretKey::generate();
+    let node = SecretKey::generate();
+    let state = state(&admin, &[admin.public()], Events::new());
+    state
+        .set(Record::Revocation {
+            node_id: stolen.public(),
+        })
+        .unwrap();
+    receive(
+        &state,
+        &[succession(&stolen, &SecretKey::generate(), 1_000)],
+    );
+    assert_eq!(state.successor(stolen.public()), None);
+
+    // Revoking a replaced key revokes the keys that replaced it
+    let successor = SecretKey::generate();
+    receive(&state, &[succession(&node, &successor, 1_000)]);
+    assert!(!state.is_revoked(successor.public()));
+    state
+        .set(Record::Revocation {
+            node_id: node.public(),
+        })
+        .unwrap();
+    assert!(state.is_revoked(successor.public()));
+}
+
+#[test]
+fn new_keys_are_written_before_they_are_published() {
+    let admin = SecretKey::generate();
+    let path = std::env::temp_dir().join(format!("p2ptun-key-{}", admin.public()));
+    let state = state(&admin, &[admin.public()], Events::new());
+    let invites =
+        Invites::new(InviteConfig::default(), admin.clone(), None, Events::new()).unwrap();
+
+    let rotations = Rotations::new(
+        RotationConfig::default(),
+        admin.clone(),
+        invites.clone(),
+        Some(state.clone()),
+    );
+    assert!(matches!(rotations.rotate(), Err(DaemonError::NoKeyFile)));
+
+    let config = RotationConfig {
+        key_path: Some(path.clone()),
+    };
+    let rotations = Rotations::new(config, admin.clone(), invites, Some(state.clone()));
+    let successor = rotations.rotate().unwrap();
+    assert_eq!(read_key(&path).unwrap().unwrap().public(), successor);
+    assert_eq!(state.successor(admin.public()), Some(successor));
+    assert_eq!(rotations.list().len(), 1);
+
+    assert!(matches!(rotations.rotate(), Err(DaemonError::KeyReplaced)));
+    assert_eq!(read_key(&path).unwrap().unwrap().public(), successor);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn grants_move_to_the_new_key() {
+    let admin = SecretKey::generate();
+    let node = SecretKey::generate();
+    let successor = SecretKey::generate();
+    let events = Events::new();
+    let state = state(&admin, &[admin.public()], events.clone());
+    let invites = Invites::new(
+        InviteConfig::default(),
+        admin.clone(),
+        Some(state.clone()),
+        events.clone(),
+    )
+    .unwrap();
+    let invite = invites
+        .issue(
+            NodeAddr::new(admin.public()),
+            Duration::from_secs(3600),
+            1,
+            vec!["laptops".to_string()],
+            None,
+        )
+        .unwrap();
+    invites.redeem(node.public(), &invite).unwrap();
+    let rotations = Rotations::new(
+        RotationConfig::default(),
+        admin.clone(),
+        invites.clone(),
+        Some(state.clone()),
+    );
+    let mut receiver = events.subscribe();
+    tokio::spawn(rotations.clone().run(events.subscribe()));
+
+    // The node writes the succession signed with its previous key
+    receive(&state, &[succession(&node, &successor, 1_700_000_000_000)]);
+    let successor = successor.public();
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !matches!(receiver.recv().await.unwrap(), Event::KeyRotated { .. }) {}
+        while !invites.admits(successor) || !state.is_member(successor) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    assert!(!invites.admits(node.public()));
+    assert_eq!(invites.tags(successor), vec!["laptops".to_string()]);
+    assert!(state.is_member(successor) && !state.is_member(node.public()));
+    let admission = AdmissionPolicy {
+        members: Some([node.public()].into()),
+    };
+    assert!(rotations.admits(&admission, successor));
+    assert!(!rotations.admits(&admission, node.public()));
+    assert_eq!(rotations.lineage(successor), vec![successor, node.public()]);
+}
+
+#[test]
+fn records_move_to_the_new_key() {
+    let authority = SecretKey::generate();
+    let predecessor = SecretKey::generate().public();
+    let successor = SecretKey::generate().public();
+    let address = "10.0.0.2".parse().unwrap();
+    let state = state(&authority, &[authority.public()], Events::new());
+    for record in [
+        Record::Member {
+            node_id: predecessor,
+        },
+        Record::Name {
+            node_id: predecessor,
+            name: "laptop".to_string(),
+        },
+        Record::Address {
+            address,
+            node_id: predecessor,
+        },
+    ] {
+        state.set(record).unwrap();
+    }
+
+    state.transfer(predecessor, successor).unwrap();
+    let records: Vec<Record> = state
+        .records()
+        .into_iter()
+        .map(|info| info.record)
+        .collect();
+    assert_eq!(records.len(), 3);
+    assert!(records.iter().all(|record| record.node_id() == successor));
+    assert!(records.contains(&Record::Name {
+        node_id: successor,
+        name: "laptop".to_string(),
+    }));
+    assert_eq!(state.address_owner(address), Some(successor));
+}
@@ -0,0 +1,158 @@
+//! The tests need CAP_NET_ADMIN, so they are ignored by default.
+//! They can be run as an unprivileged user inside a network namespace:
+//!
+//! ```sh
+//! unshare -rn cargo test --test routes -- --ignored
+//! ```
+
+use std::time::Duration;
+
+use ipnet::IpNet;
+use iroh_net::key::SecretKey;
+use p2ptun::daemon::{
+    actors::{
+        route_manager::{
+            RouteManager, RouteManagerMessage, EXIT_RULE_PRIORITIES, EXIT_TABLE, ROUTE_PROTOCOL,
+        },
+        Actor,
+    },
+    events::{DisconnectReason, Event, Events},
+    netlink::Netlink,
+    shutdown::Shutdown,
+};
+use tun::configure;
+
+const INTERFACE: &str = "p2ptun-test";
+const EXIT_INTERFACE: &str = "p2ptun-exit";
+const OTHER_INTERFACE: &str = "p2ptun-other";
+
+/// Returns the prefixes of the routes to the interface with the given index marked with
+/// [ROUTE_PROTOCOL], sorted.
+async fn routed_prefixes(netlink: &Netlink, index: u32) -> Vec<String> {
+    let mut prefixes: Vec<String> = netlink
+        .routes(index, ROUTE_PROTOCOL)
+        .await
+        .unwrap()
+        .iter()
+        .filter_map(|route| route.destination_prefix())
+        .map(|(address, prefix_len)| format!("{}/{}", address, prefix_len))
+        .collect();
+    prefixes.sort();
+    prefixes
+}
+
+/// Returns the number of routes to the interface with the given index marked with
+/// [ROUTE_PROTOCOL] in [EXIT_TABLE].
+async fn exit_route_count(netlink: &Netlink, index: u32) -> usize {
+    netlink
+        .routes(index, ROUTE_PROTOCOL)
+        .await
+        .unwrap()
+        .iter()
+        .filter(|route| route.header.table == EXIT_TABLE)
+        .count()
+}
+
+#[tokio::test]
+#[ignore = "needs CAP_NET_ADMIN"]
+async fn routes_follow_peers_and_are_cleaned_up() {
+    let _tun = tun::create(configure().name(INTERFACE).up()).unwrap();
+    let netlink = Netlink::connect().unwrap();
+    let index = netlink.link_index(INTERFACE).await.unwrap();
+    let overlay: IpNet = "10.77.0.0/16".parse().unwrap();
+    let subnet: IpNet = "192.168.77.0/24".parse().unwrap();
+    let stale: IpNet = "172.31.77.0/24".parse().unwrap();
+    let other: IpNet = "172.31.78.0/24".parse().unwrap();
+
+    // A route left by a crashed daemon is removed on start
+    netlink
+        .add_route(index, stale, ROUTE_PROTOCOL)
+        .await
+        .unwrap();
+    // The routes of another daemon are left alone
+    let _other_tun = tun::create(configure().name(OTHER_INTERFACE).up()).unwrap();
+    let other_index = netlink.link_index(OTHER_INTERFACE).await.unwrap();
+    netlink
+        .add_route(other_index, other, ROUTE_PROTOCOL)
+        .await
+        .unwrap();
+    let events = Events::new();
+    let route_manager = RouteManager::new(INTERFACE, &[overlay], events.clone())
+        .await
+        .unwrap();
+    assert_eq!(routed_prefixes(&netlink, index).await, ["10.77.0.0/16"]);
+
+    let address = route_manager.get_addr();
+    let shutdown = Shutdown::new();
+    let task = tokio::spawn(route_manager.run(shutdown.listener()));
+
+    // A route through a peer is removed when the peer disconnects
+    let peer = SecretKey::generate().public();
+    address
+        .ask(|reply| RouteManagerMessage::AddRoute(subnet, peer, reply))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        routed_prefixes(&netlink, index).await,
+        ["10.77.0.0/16", "192.168.77.0/24"]
+    );
+    events.emit(Event::PeerDisconnected {
+        node_id: peer,
+        reason: DisconnectReason::Closed,
+    });
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while routed_prefixes(&netlink, index).await.len() > 1 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    // All routes are removed on shutdown
+    shutdown.trigger();
+    task.await.unwrap();
+    assert!(routed_prefixes(&netlink, index).await.is_empty());
+    assert_eq!(
+        routed_prefixes(&netlink, other_index).await,
+        ["172.31.78.0/24"]
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs CAP_NET_ADMIN"]
+async fn exit_routes_are_added_and_cleaned_up() {
+    let _tun = tun::create(configure().name(EXIT_INTERFACE).up()).unwrap();
+    let netlink = Netlink::connect().unwrap();
+    let index = netlink.link_index(EXIT_INTERFACE).await.unwrap();
+    let route_manager = RouteManager::new(EXIT_INTERFACE, &[], Events::new())
+        .await
+        .unwrap();
+    let address = route_manager.get_addr();
+    let shutdown = Shutdown::new();
+    let task = tokio::spawn(route_manager.run(shutdown.listener()));
+    let set_exit = |enabled| {
+        let address = address.clone();
+        async move {
+            address
+                .ask(|reply| RouteManagerMessage::SetExit(enabled, reply))
+                .await
+                .unwrap()
+                .unwrap()
+        }
+    };
+
+    // The default routes and the rules are removed when the exit node isn't used anymore
+    set_exit(true).await;
+    assert_eq!(exit_route_count(&netlink, index).await, 2);
+    set_exit(false).await;
+    assert_eq!(exit_route_count(&netlink, index).await, 0);
+    assert_eq!(netlink.remove_rules(EXIT_RULE_PRIORITIES).await.unwrap(), 0);
+
+    // They are removed on shutdown too
+    set_exit(true).await;
+    shutdown.trigger();
+    task.await.unwrap();
+    assert_eq!(exit_route_count(&netlink, index).await, 0);
+    assert_eq!(netlink.remove_rules(EXIT_RULE_PRIORITIES).await.unwrap(), 0);
+}
@@ -0,0 +1,342 @@
+use std::collections::HashSet;
+
+use iroh_net::{key::SecretKey, NodeId};
+use p2ptun::daemon::{
+    events::Events,
+    state::{NetworkState, Record, RecordKey, SignedEntry, StateConfig},
+    DaemonError,
+};
+
+fn state(authorities: &[NodeId]) -> NetworkState {
+    let config = StateConfig {
+        path: None,
+        authorities: authorities.iter().copied().collect(),
+    };
+    NetworkState::new(config, SecretKey::generate(), Events::new()).unwrap()
+}
+
+fn records(state: &NetworkState) -> Vec<Record> {
+    let mut records: Vec<Record> = state
+        .records()
+        .into_iter()
+        .map(|info| info.record)
+        .collect();
+    records.sort_by_key(|record| serde_json::to_string(record).unwrap());
+    records
+}
+
+#[test]
+fn replicas_converge_whatever_the_order() {
+    let authority = SecretKey::generate();
+    let node = SecretKey::generate();
+    let address = "10.0.0.2".parse().unwrap();
+    let entries = vec![
+        SignedEntry::sign(
+            &authority,
+            RecordKey::Member {
+                node_id: node.public(),
+            },
+            Some(Record::Member {
+                node_id: node.public(),
+            }),
+            1,
+        ),
+        SignedEntry::sign(
+            &node,
+            RecordKey::Address { address },
+            Some(Record::Address {
+                address,
+                node_id: node.public(),
+            }),
+            2,
+        ),
+        // Written concurrently, wins over the claim of the node
+        SignedEntry::sign(
+            &authority,
+            RecordKey::Address { address },
+            Some(Record::Address {
+                address,
+                node_id: authority.public(),
+            }),
+            3,
+        ),
+        SignedEntry::sign(
+            &node,
+            RecordKey::Name {
+                node_id: node.public(),
+            },
+            None,
+            4,
+        ),
+    ];
+
+    let first = state(&[authority.public()]);
+    first.receive(node.public(), &serde_json::to_vec(&entries).unwrap());
+    let second = state(&[authority.public()]);
+    for entry in entries.iter().rev() {
+        second.receive(node.public(), &serde_json::to_vec(&[entry]).unwrap());
+    }
+    // Merging the same entries again changes nothing
+    second.receive(node.public(), &serde_json::to_vec(&entries).unwrap());
+
+    assert_eq!(records(&first), records(&second));
+    assert_eq!(first.address_owner(address), Some(authority.public()));
+}
+
+#[test]
+fn unauthorized_entries_are_rejected() {
+    let authority = SecretKey::generate();
+    let node = SecretKey::generate();
+    let other = SecretKey::generate();
+    let authorities: HashSet<NodeId> = [authority.public()].into();
+
+    let own_name = SignedEntry::sign(
+        &node,
+        RecordKey::Name {
+            node_id: node.public(),
+        },
+        Some(Record::Name {
+            node_id: node.public(),
+            name: "laptop".to_string(),
+        }),
+        1,
+    );
+    assert!(own_name.verify() && own_name.is_authorized(&authorities));
+
+    let other_name = SignedEntry::sign(
+        &node,
+        RecordKey::Name {
+            node_id: other.public(),
+        },
+        Some(Record::Name {
+            node_id: other.public(),
+            name: "laptop".to_string(),
+        }),
+        1,
+    );
+    assert!(!other_name.is_authorized(&authorities));
+
+    let revocation = SignedEntry::sign(
+        &node,
+        RecordKey::Revocation {
+            node_id: other.public(),
+        },
+        Some(Record::Revocation {
+            node_id: other.public(),
+        }),
+        1,
+    );
+    assert!(!revocation.is_authorized(&authorities));
+
+    let mut forged = own_name.clone();
+    forged.signer = other.public();
+    assert!(!forged.verify());
+
+    let mut mismatched = own_name;
+    mismatched.key = RecordKey::Name {
+        node_id: other.public(),
+    };
+    assert!(!mismatched.verify());
+}
+
+#[test]
+fn records_of_revoked_nodes_are_ignored() {
+    let authority = SecretKey::generate();
+    let node = SecretKey::generate();
+    let state = state(&[authority.public()]);
+    let entries = vec![
+        SignedEntry::sign(
+            &node,
+            RecordKey::Name {
+                node_id: node.public(),
+            },
+            Some(Record::Name {
+                node_id: node.public(),
+                name: "laptop".to_string(),
+            }),
+            1,
+        ),
+        SignedEntry::sign(
+            &authority,
+            RecordKey::Revocation {
+                node_id: node.public(),
+            },
+            Some(Record::Revocation {
+                node_id: node.public(),
+            }),
+            2,
+        ),
+    ];
+    state.receive(authority.public(), &serde_json::to_vec(&entries).unwrap());
+
+    assert!(state.is_revoked(node.public()));
+    assert_eq!(
+        records(&state),
+        vec![Record::Revocation {
+            node_id: node.public()
+        }]
+    );
+}
+
+#[test]
+fn only_members_claim_free_addresses() {
+    let authority = SecretKey::generate();
+    let member = SecretKey::generate();
+    let outsider = SecretKey::generate();
+    let address = "10.0.0.2".parse().unwrap();
+    let claim = |node: &SecretKey, address, timestamp| {
+        SignedEntry::sign(
+            node,
+            RecordKey::Address { address },
+            Some(Record::Address {
+                address,
+                node_id: node.public(),
+            }),
+            timestamp,
+        )
+    };
+    let state = state(&[authority.public()]);
+
+    state.receive(
+        outsider.public(),
+        &serde_json::to_vec(&[claim(&outsider, address, 1)]).unwrap(),
+    );
+    assert_eq!(state.address_owner(address), None);
+
+    let membership = SignedEntry::sign(
+        &authority,
+        RecordKey::Member {
+            node_id: member.public(),
+        },
+        Some(Record::Member {
+            node_id: member.public(),
+        }),
+        2,
+    );
+    state.receive(
+        authority.public(),
+        &serde_json::to_vec(&[membership, claim(&member, address, 3)]).unwrap(),
+    );
+    assert_eq!(state.address_owner(address), Some(member.public()));
+
+    // Another member can't take the address over
+    let other = SecretKey::generate();
+    let other_membership = SignedEntry::sign(
+        &authority,
+        RecordKey::Member {
+            node_id: other.public(),
+        },
+        Some(Record::Member {
+            node_id: other.public(),
+        }),
+        4,
+    );
+    state.receive(
+        authority.public(),
+        &serde_json::to_vec(&[other_membership, claim(&other, address, 5)]).unwrap(),
+    );
+    assert_eq!(state.address_owner(address), Some(member.public()));
+}
+
+#[test]
+fn nodes_outside_the_network_can_not_claim_addresses() {
+    let node = SecretKey::generate();
+    let config = StateConfig {
+        path: None,
+        authorities: [SecretKey::generate().public()].into(),
+    };
+    let state = NetworkState::new(config, node.clone(), Events::new()).unwrap();
+    assert!(matches!(
+        state.set(Record::Address {
+            address: "10.0.0.2".parse().unwrap(),
+            node_id: node.public(),
+        }),
+        Err(DaemonError::Unauthorized(_))
+    ));
+}
+
+/// Returns the claim of `address` by the node of `secret_key`, written at `timestamp`.
+fn claim(secret_key: &SecretKey, address: std::net::IpAddr, timestamp: u64) -> SignedEntry {
+    SignedEntry::sign(
+        secret_key,
+        RecordKey::Address { address },
+        Some(Record::Address {
+            address,
+            node_id: secret_key.public(),
+        }),
+        timestamp,
+    )
+}
+
+/// Returns the membership of `node_id`, written by `authority` at `timestamp`.
+fn membership(authority: &SecretKey, node_id: NodeId, timestamp: u64) -> SignedEntry {
+    SignedEntry::sign(
+        authority,
+        RecordKey::Member { node_id },
+        Some(Record::Member { node_id }),
+        timestamp,
+    )
+}
+
+#[test]
+fn claims_received_before_the_membership_take_effect() {
+    let authority = SecretKey::generate();
+    let node = SecretKey::generate();
+    let address = "10.0.0.2".parse().unwrap();
+    let state = state(&[authority.public()]);
+
+    state.receive(
+        node.public(),
+        &serde_json::to_vec(&[claim(&node, address, 2)]).unwrap(),
+    );
+    assert_eq!(state.address_owner(address), None);
+    state.receive(
+        authority.public(),
+        &serde_json::to_vec(&[membership(&authority, node.public(), 1)]).unwrap(),
+    );
+    assert_eq!(state.address_owner(address), Some(node.public()));
+}
+
+#[test]
+fn concurrent_claims_converge() {
+    let authority = SecretKey::generate();
+    let first = SecretKey::generate();
+    let second = SecretKey::generate();
+    let address = "10.0.0.2".parse().unwrap();
+    let memberships = [
+        membership(&authority, first.public(), 1),
+        membership(&authority, second.public(), 1),
+    ];
+    let replicas = [state(&[authority.public()]), state(&[authority.public()])];
+    for replica in &replicas {
+        replica.receive(
+            authority.public(),
+            &serde_json::to_vec(&memberships).unwrap(),
+        );
+    }
+
+    // Each member claims the address on its side of a partition
+    replicas[0].receive(
+        first.public(),
+        &serde_json::to_vec(&[claim(&first, address, 10)]).unwrap(),
+    );
+    replicas[1].receive(
+        second.public(),
+        &serde_json::to_vec(&[claim(&second, address, 11)]).unwrap(),
+    );
+    assert_eq!(replicas[1].address_owner(address), Some(second.public()));
+
+    // Then the partition heals
+    replicas[0].receive(
+        second.public(),
+        &serde_json::to_vec(&[claim(&second, address, 11)]).unwrap(),
+    );
+    replicas[1].receive(
+        first.public(),
+        &serde_json::to_vec(&[claim(&first, address, 10)]).unwrap(),
+    );
+    for replica in &replicas {
+        assert_eq!(replica.address_owner(address), Some(first.public()));
+    }
+    assert_eq!(records(&replicas[0]), records(&replicas[1]));
+}
@@ -0,0 +1,200 @@
+use std::time::Duration;
+
+use ipnet::IpNet;
+use iroh_net::{key::SecretKey, NodeId};
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, Mailbox, OverflowPolicy},
+        peer_collection::PeerCollectionMessage,
+        subnet_router::{
+            RouteGrant, RoutePolicy, SubnetRouteState, SubnetRouter, SubnetRouterMessage,
+        },
+        Actor, Addr,
+    },
+    advertisements::{Advertisement, Advertisements},
+    events::{DisconnectReason, Event, Events},
+};
+use tokio::sync::broadcast;
+
+/// A [SubnetRouter] routing through a fake peer collection.
+struct Router {
+    address: Addr<SubnetRouterMessage>,
+    peer_collection: Mailbox<PeerCollectionMessage>,
+    events: Events,
+    subscriber: broadcast::Receiver<Event>,
+}
+
+/// Starts a [SubnetRouter] accepting the prefixes in `granted` from every peer.
+fn start_router(granted: &str, local: &str) -> Router {
+    let events = Events::new();
+    let (peer_collection, peer_collection_mailbox) = mailbox(16, OverflowPolicy::Block);
+    let policy = RoutePolicy {
+        grants: vec![RouteGrant {
+            peer: None,
+            prefix: granted.parse().unwrap(),
+        }],
+    };
+    let router = SubnetRouter::new(
+        policy,
+        vec![local.parse().unwrap()],
+        peer_collection,
+        None,
+        Advertisements::new(Advertisement::default(), None, events.clone()),
+        events.clone(),
+    );
+    let address = router.get_addr();
+    tokio::spawn(router.run());
+    Router {
+        address,
+        peer_collection: peer_collection_mailbox,
+        subscriber: events.subscribe(),
+        events,
+    }
+}
+
+impl Router {
+    fn advertise(&self, node_id: NodeId, routes: &[&str]) {
+        self.events.emit(Event::Advertised {
+            node_id,
+            advertisement: Advertisement {
+                routes: routes.iter().map(|route| route.parse().unwrap()).collect(),
+                ..Default::default()
+            },
+        });
+    }
+
+    /// Returns the next gateway added to or removed from the peer collection,
+    /// with the peer when added.
+    async fn next_route_change(&mut self) -> (IpNet, Option<NodeId>) {
+        let message = tokio::time::timeout(Duration::from_secs(5), self.peer_collection.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        match message {
+            PeerCollectionMessage::AddGateway(prefix, node_id, _) => (prefix, Some(node_id)),
+            PeerCollectionMessage::RemoveGateway(prefix, _) => (prefix, None),
+            _ => panic!("unexpected message"),
+        }
+    }
+
+    /// Returns the next conflict reported.
+    async fn next_conflict(&mut self) -> (IpNet, NodeId, Option<NodeId>) {
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Event::RouteConflict {
+                    prefix,
+                    node_id,
+                    conflicting,
+                } = self.subscriber.recv().await.unwrap()
+                {
+                    return (prefix, node_id, conflicting);
+                }
+            }
+        })
+        .await
+        .unwrap()
+    }
+
+    async fn state(&self, prefix: &str, node_id: NodeId) -> SubnetRouteState {
+        let prefix: IpNet = prefix.parse().unwrap();
+        self.address
+            .ask(SubnetRouterMessage::ListRoutes)
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|route| route.prefix == prefix && route.node_id == node_id)
+            .unwrap()
+            .state
+    }
+}
+
+#[tokio::test]
+async fn conflicting_route_is_routed_once_the_first_is_withdrawn() {
+    let mut router = start_router("192.168.0.0/16", "10.77.0.0/16");
+    let first = SecretKey::generate().public();
+    let second = SecretKey::generate().public();
+
+    router.advertise(first, &["192.168.10.0/24", "172.16.0.0/12"]);
+    assert_eq!(
+        router.next_route_change().await,
+        ("192.168.10.0/24".parse().unwrap(), Some(first))
+    );
+    assert_eq!(
+        router.state("172.16.0.0/12", first).await,
+        SubnetRouteState::NotAllowed
+    );
+
+    router.advertise(second, &["192.168.10.128/25"]);
+    assert_eq!(
+        router.next_conflict().await,
+        ("192.168.10.128/25".parse().unwrap(), second, Some(first))
+    );
+    assert_eq!(
+        router.state("192.168.10.128/25", second).await,
+        SubnetRouteState::Conflict
+    );
+
+    router.events.emit(Event::PeerDisconnected {
+        node_id: first,
+        reason: DisconnectReason::Closed,
+    });
+    assert_eq!(
+        router.next_route_change().await,
+        ("192.168.10.0/24".parse().unwrap(), None)
+    );
+    assert_eq!(
+        router.next_route_change().await,
+        ("192.168.10.128/25".parse().unwrap(), Some(second))
+    );
+}
+
+#[tokio::test]
+async fn route_overlapping_the_overlay_is_a_conflict() {
+    let mut router = start_router("10.0.0.0/8", "10.77.0.0/16");
+    let peer = SecretKey::generate().public();
+
+    router.advertise(peer, &["10.77.1.0/24"]);
+    assert_eq!(
+        router.next_conflict().await,
+        ("10.77.1.0/24".parse().unwrap(), peer, None)
+    );
+    assert_eq!(
+        router.state("10.77.1.0/24", peer).await,
+        SubnetRouteState::Conflict
+    );
+}
+
+#[tokio::test]
+async fn same_route_advertised_twice_adds_a_gateway() {
+    let mut router = start_router("192.168.0.0/16", "10.77.0.0/16");
+    let first = SecretKey::generate().public();
+    let second = SecretKey::generate().public();
+
+    router.advertise(first, &["192.168.10.0/24"]);
+    assert_eq!(
+        router.next_route_change().await,
+        ("192.168.10.0/24".parse().unwrap(), Some(first))
+    );
+    router.advertise(second, &["192.168.10.0/24"]);
+    assert_eq!(
+        router.next_route_change().await,
+        ("192.168.10.0/24".parse().unwrap(), Some(second))
+    );
+    assert_eq!(
+        router.state("192.168.10.0/24", second).await,
+        SubnetRouteState::Installed
+    );
+
+    router.events.emit(Event::PeerDisconnected {
+        node_id: first,
+        reason: DisconnectReason::Closed,
+    });
+    assert_eq!(
+        router.next_route_change().await,
+        ("192.168.10.0/24".parse().unwrap(), None)
+    );
+    assert_eq!(
+        router.state("192.168.10.0/24", second).await,
+        SubnetRouteState::Installed
+    );
+}
@@ -0,0 +1,228 @@
+use std::{
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use p2ptun::daemon::{
+    actors::{
+        mailbox::{mailbox, Mailbox, OverflowPolicy},
+        packet_router::PacketRouter,
+        Actor, Addr, AskError, Reply,
+    },
+    events::{Event, Events},
+    packet::Packet,
+    pipeline::{PacketProcessor, Verdict},
+    shutdown::Shutdown,
+    supervisor::{ExitReason, RestartPolicy, Supervisor},
+    DaemonError,
+};
+use tokio::sync::{broadcast::Receiver, mpsc};
+
+const QUICK_RESTART: RestartPolicy = RestartPolicy::Restart {
+    initial_backoff: Duration::from_millis(10),
+    max_backoff: Duration::from_millis(10),
+    max_restarts: 2,
+};
+
+async fn next_exit(events: &mut Receiver<Event>) -> (String, ExitReason, bool) {
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        if let Event::ActorExited {
+            name,
+            reason,
+            restarting,
+        } = event
+        {
+            return (name, reason, restarting);
+        }
+    }
+}
+
+#[tokio::test]
+async fn panicked_actors_are_restarted() {
+    let events = Events::new();
+    let mut exits = events.subscribe();
+    let mut supervisor = Supervisor::new(Shutdown::new(), events);
+    let starts = Arc::new(AtomicU32::new(0));
+    let counter = starts.clone();
+    supervisor.spawn("flaky", QUICK_RESTART, move || {
+        let start = counter.fetch_add(1, Ordering::SeqCst);
+        async move {
+            if start == 0 {
+                panic!("first start");
+            }
+            std::future::pending::<()>().await;
+        }
+    });
+
+    let (name, reason, restarting) = next_exit(&mut exits).await;
+    assert_eq!(name, "flaky");
+    assert_eq!(
+        reason,
+        ExitReason::Panicked {
+            message: "first start".to_string()
+        }
+    );
+    assert!(restarting);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(starts.load(Ordering::SeqCst), 2);
+    supervisor.stop(Duration::from_secs(1)).await;
+}
+
+#[tokio::test]
+async fn escalated_actors_stop_the_daemon() {
+    let events = Events::new();
+    let mut exits = events.subscribe();
+    let mut supervisor = Supervisor::new(Shutdown::new(), events);
+    supervisor.spawn_once("critical", async {});
+
+    let result = tokio::time::timeout(Duration::from_secs(5), supervisor.wait())
+        .await
+        .unwrap();
+    assert!(matches!(
+        result,
+        Err(DaemonError::ActorDied(name, ExitReason::Returned)) if name == "critical"
+    ));
+    assert_eq!(
+        next_exit(&mut exits).await,
+        ("critical".to_string(), ExitReason::Returned, false)
+    );
+}
+
+#[tokio::test]
+async fn actors_stopping_too_often_stop_the_daemon() {
+    let mut supervisor = Supervisor::new(Shutdown::new(), Events::new());
+    let starts = Arc::new(AtomicU32::new(0));
+    let counter = starts.clone();
+    supervisor.spawn("failing", QUICK_RESTART, move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        async {}
+    });
+
+    let result = tokio::time::timeout(Duration::from_secs(5), supervisor.wait())
+        .await
+        .unwrap();
+    assert!(matches!(result, Err(DaemonError::ActorDied(name, _)) if name == "failing"));
+    // The first start and `max_restarts` restarts
+    assert_eq!(starts.load(Ordering::SeqCst), 3);
+}
+
+#[tokio::test]
+async fn actors_are_not_restarted_after_the_shutdown() {
+    let shutdown = Shutdown::new();
+    let mut supervisor = Supervisor::new(shutdown.clone(), Events::new());
+    let starts = Arc::new(AtomicU32::new(0));
+    let counter = starts.clone();
+    let listener = shutdown.listener();
+    supervisor.spawn("stoppable", QUICK_RESTART, move || {
+        counter.fetch_add(1, Ordering::SeqCst);
+        listener.clone().run_until(std::future::pending())
+    });
+
+    shutdown.trigger();
+    let result = tokio::time::timeout(Duration::from_secs(5), supervisor.wait())
+        .await
+        .unwrap();
+    assert!(result.is_ok());
+    supervisor.stop(Duration::from_secs(1)).await;
+    assert_eq!(starts.load(Ordering::SeqCst), 1);
+}
+
+/// Replies with the number of messages, and panics on a zero.
+async fn counter(mut receiver: Mailbox<(u32, Reply<u32>)>) {
+    while let Some((value, reply)) = receiver.recv().await {
+        assert_ne!(value, 0, "zero received");
+        reply.send(value + 1);
+    }
+}
+
+#[tokio::test]
+async fn addresses_stay_valid_across_restarts() {
+    let (addr, receiver) = mailbox(4, OverflowPolicy::Block);
+    let keeper = receiver.keeper();
+    let mut first = Some(receiver);
+    let mut supervisor = Supervisor::new(Shutdown::new(), Events::new());
+    supervisor.spawn("counter", QUICK_RESTART, move || {
+        counter(first.take().unwrap_or_else(|| keeper.reopen()))
+    });
+
+    assert_eq!(addr.ask(|reply| (1, reply)).await, Ok(2));
+    // The request is dropped along with the panicked actor
+    assert_eq!(addr.ask(|reply| (0, reply)).await, Err(AskError::NoReply));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(addr.ask(|reply| (2, reply)).await, Ok(3));
+    supervisor.stop(Duration::from_secs(1)).await;
+}
+
+#[tokio::test]
+async fn asking_fails_without_a_reply() {
+    let (addr, mut receiver) = mailbox::<Reply<()>>(4, OverflowPolicy::Block);
+    let actor = tokio::spawn(async move {
+        // Drops the first request, keeps the second one unanswered
+        drop(receiver.recv().await);
+        let kept = receiver.recv().await;
+        std::future::pending::<()>().await;
+        drop(kept);
+    });
+    assert_eq!(addr.ask(|reply| reply).await, Err(AskError::NoReply));
+    assert_eq!(
+        addr.ask_with_timeout(|reply| reply, Duration::from_millis(50))
+            .await,
+        Err(AskError::Timeout)
+    );
+
+    actor.abort();
+    let _ = actor.await;
+    assert_eq!(addr.ask(|reply| reply).await, Err(AskError::Closed));
+}
+
+/// A stage panicking on the first packet, then passing the others.
+struct PanicOnce {
+    panicked: bool,
+}
+
+impl PacketProcessor for PanicOnce {
+    fn name(&self) -> &str {
+        "panic_once"
+    }
+
+    fn process(&mut self, _packet: &Packet) -> Verdict {
+        if !std::mem::replace(&mut self.panicked, true) {
+            panic!("first packet");
+        }
+        Verdict::Pass
+    }
+}
+
+#[tokio::test]
+async fn restarted_packet_routers_keep_their_receivers_and_stages() {
+    let mut packet_router = PacketRouter::new();
+    let (sender, mut receiver) = mpsc::channel(4);
+    packet_router.add_outgoing_packet_receiver(Addr::new(sender));
+    packet_router.add_stage(Box::new(PanicOnce { panicked: false }));
+    let addr = packet_router.get_addr();
+    let mut restart = packet_router.restarter();
+    let mut first = Some(packet_router);
+    let mut supervisor = Supervisor::new(Shutdown::new(), Events::new());
+    supervisor.spawn("packet_router", QUICK_RESTART, move || {
+        first.take().unwrap_or_else(&mut restart).run()
+    });
+
+    addr.send_message(Packet::Outgoing(Arc::from(&b"first"[..])))
+        .await;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    addr.send_message(Packet::Outgoing(Arc::from(&b"second"[..])))
+        .await;
+    let packet = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&packet.data()[..], b"second");
+    supervisor.stop(Duration::from_secs(1)).await;
+}
@@ -0,0 +1,125 @@
+use std::{sync::Arc, time::Duration};
+
+use iroh_net::key::SecretKey;
+use p2ptun::daemon::{
+    packet::Packet,
+    pipeline::{PacketProcessor, Verdict},
+    wasm_filter::WasmFilter,
+    DaemonError,
+};
+
+/// Builds a minimal IPv4 packet carrying the given protocol.
+fn ipv4_packet(protocol: u8) -> Arc<[u8]> {
+    let mut packet = [0u8; 28];
+    packet[0] = 0x45;
+    packet[9] = protocol;
+    packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+    packet[16..20].copy_from_slice(&[10, 0, 0, 2]);
+    Arc::from(packet.as_slice())
+}
+
+fn sample_filter() -> WasmFilter {
+    let wasm = wat::parse_file("tests/plugins/drop_incoming_tcp.wat").unwrap();
+    WasmFilter::from_bytes("drop_incoming_tcp", &wasm).unwrap()
+}
+
+#[test]
+fn sample_plugin_drops_incoming_tcp() {
+    let mut filter = sample_filter();
+    let peer = SecretKey::generate().public();
+    assert_eq!(filter.name(), "wasm:drop_incoming_tcp");
+    assert!(matches!(
+        filter.process(&Packet::Incoming(peer, ipv4_packet(6))),
+        Verdict::Drop
+    ));
+    assert!(matches!(
+        filter.process(&Packet::Incoming(peer, ipv4_packet(17))),
+        Verdict::Pass
+    ));
+    assert!(matches!(
+        filter.process(&Packet::Outgoing(ipv4_packet(6))),
+        Verdict::Pass
+    ));
+}
+
+#[test]
+fn plugin_running_out_of_fuel_drops_packets() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (memory (export "memory") 2)
+            (func (export "p2ptun_alloc") (param i32) (result i32) i32.const 0)
+            (func (export "p2ptun_filter") (param i32 i32) (result i32)
+                (loop $forever (br $forever))
+                i32.const 0))"#,
+    )
+    .unwrap();
+    let mut filter = WasmFilter::from_bytes("loop", &wasm).unwrap();
+    for _ in 0..2 {
+        assert!(matches!(
+            filter.process(&Packet::Outgoing(ipv4_packet(17))),
+            Verdict::Drop
+        ));
+    }
+}
+
+#[test]
+fn plugin_without_exports_is_rejected() {
+    let wasm = wat::parse_str("(module)").unwrap();
+    assert!(WasmFilter::from_bytes("empty", &wasm).is_err());
+}
+
+#[test]
+fn plugin_answering_too_late_drops_packets() {
+    let mut filter = sample_filter().time_limit(Duration::ZERO);
+    assert!(matches!(
+        filter.process(&Packet::Outgoing(ipv4_packet(17))),
+        Verdict::Drop
+    ));
+}
+
+#[test]
+fn swapped_plugin_is_used_for_the_next_packets() {
+    let mut filter = sample_filter();
+    let peer = SecretKey::generate().public();
+    let pass_all = wat::parse_str(
+        r#"(module
+            (memory (export "memory") 2)
+            (func (export "p2ptun_alloc") (param i32) (result i32) i32.const 0)
+            (func (export "p2ptun_filter") (param i32 i32) (result i32) i32.const 0))"#,
+    )
+    .unwrap();
+    filter.handle().swap(&pass_all).unwrap();
+    assert!(matches!(
+        filter.process(&Packet::Incoming(peer, ipv4_packet(6))),
+        Verdict::Pass
+    ));
+
+    // A module that can't be loaded leaves the current one in place
+    assert!(filter.handle().swap(b"not a module").is_err());
+    assert!(matches!(
+        filter.process(&Packet::Incoming(peer, ipv4_packet(6))),
+        Verdict::Pass
+    ));
+}
+
+#[test]
+fn plugin_with_a_buffer_out_of_its_memory_is_rejected() {
+    let mut filter = sample_filter();
+    let out_of_bounds = wat::parse_str(
+        r#"(module
+            (memory (export "memory") 1)
+            (func (export "p2ptun_alloc") (param i32) (result i32) i32.const 0)
+            (func (export "p2ptun_filter") (param i32 i32) (result i32) i32.const 0))"#,
+    )
+    .unwrap();
+    assert!(matches!(
+        filter.handle().swap(&out_of_bounds),
+        Err(DaemonError::WasmBufferOutOfBounds(0))
+    ));
+    // The previous module is still used
+    let peer = SecretKey::generate().public();
+    assert!(matches!(
+        filter.process(&Packet::Incoming(peer, ipv4_packet(6))),
+        Verdict::Drop
+    ));
+}